@@ -3,6 +3,10 @@ use std::{
     fmt::{Display, Write},
 };
 
+use serde_json::Value;
+
+use crate::debug;
+
 #[allow(dead_code)]
 pub enum Bencode {
     String(String),
@@ -42,11 +46,27 @@ impl Display for Bencode {
     }
 }
 
+impl Bencode {
+    /// The value as JSON, strings escaped unlike the Display output.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Bencode::Integer(i) => Value::from(*i),
+            Bencode::String(s) => Value::from(s.as_str()),
+            Bencode::List(l) => l.iter().map(Bencode::to_json).collect(),
+            Bencode::Dictionary(hm) => hm
+                .iter()
+                .map(|(key, value)| (key.clone(), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub fn decode_bencoded_value(encoded_value: &str) -> (Bencode, &str) {
     // If encoded_value starts with a digit, it's a number
     let bencode_identifier = encoded_value.chars().next().unwrap();
-    eprintln!("{bencode_identifier}, {encoded_value}");
+    debug!("{bencode_identifier}, {encoded_value}");
     match bencode_identifier {
         'i' => {
             if let Some((n, rest)) =
@@ -81,13 +101,10 @@ pub fn decode_bencoded_value(encoded_value: &str) -> (Bencode, &str) {
                 let (key, reminder) = decode_bencoded_value(rest);
                 let (value, reminder) = decode_bencoded_value(reminder);
 
-                match key {
-                    Bencode::String(s) => {
-                        eprintln!("key: {s}, value: {value}");
-                        values.insert(s, value);
-                        rest = reminder;
-                    }
-                    _ => {}
+                if let Bencode::String(s) = key {
+                    debug!("key: {s}, value: {value}");
+                    values.insert(s, value);
+                    rest = reminder;
                 }
             }
 
@@ -0,0 +1,168 @@
+use anyhow::bail;
+
+/// A bitfield representing the pieces a peer (or we) have.
+/// The high bit in the first byte corresponds to piece index 0.
+/// Bits that are cleared indicate a missing piece, and set bits indicate a valid and available piece.
+/// Spare bits at the end are set to zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    num_pieces: usize,
+}
+
+impl Bitfield {
+    /// Creates an empty bitfield able to hold `num_pieces` pieces.
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            bytes: vec![0; bytes_for(num_pieces)],
+            num_pieces,
+        }
+    }
+
+    /// Creates a bitfield with every piece set.
+    pub fn full(num_pieces: usize) -> Self {
+        let mut bitfield = Self::new(num_pieces);
+        for index in 0..num_pieces {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// Parses the payload of a bitfield message.
+    /// A bitfield of the wrong length or with any of the spare bits set is considered invalid.
+    pub fn from_payload(payload: &[u8], num_pieces: usize) -> anyhow::Result<Self> {
+        let expected = bytes_for(num_pieces);
+        if payload.len() != expected {
+            bail!(
+                "bitfield has {} bytes, expected {} for {} pieces",
+                payload.len(),
+                expected,
+                num_pieces
+            );
+        }
+
+        let spare_bits = expected * 8 - num_pieces;
+        if spare_bits > 0 {
+            let mask = (1u8 << spare_bits) - 1;
+            if payload[expected - 1] & mask != 0 {
+                bail!("bitfield has spare bits set");
+            }
+        }
+
+        Ok(Self {
+            bytes: payload.to_vec(),
+            num_pieces,
+        })
+    }
+
+    /// Returns whether the piece at `index` is set.
+    pub fn get(&self, index: usize) -> bool {
+        if index >= self.num_pieces {
+            return false;
+        }
+        self.bytes[index / 8] & (1 << (7 - index % 8)) != 0
+    }
+
+    /// Marks the piece at `index` as available.
+    pub fn set(&mut self, index: usize) {
+        if index < self.num_pieces {
+            self.bytes[index / 8] |= 1 << (7 - index % 8);
+        }
+    }
+
+    /// Marks the piece at `index` as missing.
+    pub fn clear(&mut self, index: usize) {
+        if index < self.num_pieces {
+            self.bytes[index / 8] &= !(1 << (7 - index % 8));
+        }
+    }
+
+    /// Number of pieces that are set.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Number of pieces the bitfield describes.
+    pub fn len(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_pieces == 0
+    }
+
+    /// Returns whether every piece is set.
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.num_pieces
+    }
+
+    /// Iterates over the indices of the pieces that are set.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_pieces).filter(|&index| self.get(index))
+    }
+
+    /// The raw bytes, as sent in a bitfield message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Number of bytes needed to hold one bit per piece.
+fn bytes_for(num_pieces: usize) -> usize {
+    (num_pieces + 7) >> 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_bit_is_the_first_piece() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0);
+        bitfield.set(9);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.get(0) && bitfield.get(9) && !bitfield.get(1));
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 9]);
+
+        bitfield.clear(0);
+        assert_eq!(bitfield.count(), 1);
+    }
+
+    #[test]
+    fn out_of_range_pieces_are_ignored() {
+        let mut bitfield = Bitfield::new(3);
+        bitfield.set(3);
+        assert!(!bitfield.get(3));
+        assert_eq!(bitfield.count(), 0);
+    }
+
+    #[test]
+    fn full_bitfield_keeps_spare_bits_clear() {
+        let bitfield = Bitfield::full(10);
+        assert_eq!(bitfield.as_bytes(), &[0xff, 0b1100_0000]);
+        assert!(bitfield.is_complete());
+    }
+
+    #[test]
+    fn parses_payloads() {
+        let bitfield = Bitfield::from_payload(&[0b1010_0000], 3).unwrap();
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 2]);
+        assert!(!bitfield.is_complete());
+    }
+
+    #[test]
+    fn rejects_payloads_of_the_wrong_length() {
+        assert!(Bitfield::from_payload(&[0, 0], 8).is_err());
+        assert!(Bitfield::from_payload(&[], 1).is_err());
+    }
+
+    #[test]
+    fn rejects_spare_bits() {
+        assert!(Bitfield::from_payload(&[0b0001_0000], 3).is_err());
+        assert!(Bitfield::from_payload(&[0b1110_0000], 3).is_ok());
+    }
+}
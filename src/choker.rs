@@ -0,0 +1,377 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+use crate::{random, warn};
+
+/// How often the choker reconsiders which peers to unchoke.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic unchoke moves to another peer every third round, so every 30 seconds.
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+/// Number of peers we upload to at the same time, one of them is the optimistic unchoke.
+const UNCHOKE_SLOTS: usize = 4;
+
+/// A peer that didn't send us a block for this long while we wanted one is snubbing us.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Peers that connected recently are three times as likely to get the optimistic unchoke,
+/// so they get a chance to get pieces to trade.
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+
+/// What the choker knows about a single connection.
+/// The connection updates the counters, the choker publishes its decision.
+pub struct PeerStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    peer_interested: AtomicBool,
+    am_interested: AtomicBool,
+    last_block: Mutex<Instant>,
+    connected_at: Instant,
+    unchoke: watch::Sender<bool>,
+}
+
+impl PeerStats {
+    fn new() -> Self {
+        let (unchoke, _) = watch::channel(false);
+        Self {
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            peer_interested: AtomicBool::new(false),
+            am_interested: AtomicBool::new(false),
+            last_block: Mutex::new(Instant::now()),
+            connected_at: Instant::now(),
+            unchoke,
+        }
+    }
+
+    /// Counts the bytes of a block the peer sent us.
+    pub fn record_download(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        *self.last_block.lock().unwrap() = Instant::now();
+    }
+
+    /// Counts the bytes of a block we sent the peer.
+    pub fn record_upload(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Keeps the choker up to date with the interest of both sides.
+    /// Returns true when the peer just became interested.
+    pub fn set_interest(&self, peer_interested: bool, am_interested: bool) -> bool {
+        let was_peer_interested = self
+            .peer_interested
+            .swap(peer_interested, Ordering::Relaxed);
+        let was_interested = self.am_interested.swap(am_interested, Ordering::Relaxed);
+        if am_interested && !was_interested {
+            // the snub timer starts when we start wanting something.
+            *self.last_block.lock().unwrap() = Instant::now();
+        }
+        peer_interested && !was_peer_interested
+    }
+
+    /// Whether the peer keeps us waiting for blocks.
+    pub fn is_snubbed(&self) -> bool {
+        self.am_interested.load(Ordering::Relaxed)
+            && self.last_block.lock().unwrap().elapsed() > SNUB_TIMEOUT
+    }
+
+    /// The choker's decision for this peer, true when it should be unchoked.
+    pub fn decisions(&self) -> watch::Receiver<bool> {
+        self.unchoke.subscribe()
+    }
+}
+
+struct ChokerPeer {
+    stats: Arc<PeerStats>,
+    /// The counters at the previous round, to compute rates.
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+/// Tit-for-tat choking: we upload to the peers that give us the best download rates,
+/// or that we upload to the fastest once we are seeding.
+/// An extra optimistic unchoke slot rotates among the other peers so we discover better ones.
+#[derive(Default)]
+pub struct Choker {
+    peers: Mutex<HashMap<SocketAddr, ChokerPeer>>,
+    optimistic: Mutex<Option<SocketAddr>>,
+    round: AtomicU64,
+}
+
+impl Choker {
+    /// Starts tracking a connection, it stays choked until the next round.
+    pub fn register(&self, addr: SocketAddr) -> Arc<PeerStats> {
+        let stats = Arc::new(PeerStats::new());
+        self.peers.lock().unwrap().insert(
+            addr,
+            ChokerPeer {
+                stats: stats.clone(),
+                last_downloaded: 0,
+                last_uploaded: 0,
+            },
+        );
+        stats
+    }
+
+    pub fn unregister(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    /// Unchokes a peer that just became interested right away when an unchoke slot is free,
+    /// instead of making it wait for the next round.
+    pub fn peer_interested(&self, addr: &SocketAddr) {
+        let peers = self.peers.lock().unwrap();
+        let unchoked = peers
+            .values()
+            .filter(|peer| *peer.stats.unchoke.borrow())
+            .count();
+        if unchoked < UNCHOKE_SLOTS {
+            if let Some(peer) = peers.get(addr) {
+                peer.stats.unchoke.send_replace(true);
+            }
+        }
+    }
+
+    /// Runs one round of choking, `seeding` ranks the peers by upload rate instead of download rate.
+    pub fn rechoke(&self, seeding: bool) {
+        let round = self.round.fetch_add(1, Ordering::Relaxed);
+        let mut peers = self.peers.lock().unwrap();
+
+        // bytes transferred since the last round, the interval is the same for everyone.
+        let mut rates: Vec<(SocketAddr, u64)> = Vec::new();
+        for (addr, peer) in peers.iter_mut() {
+            let downloaded = peer.stats.downloaded.load(Ordering::Relaxed);
+            let uploaded = peer.stats.uploaded.load(Ordering::Relaxed);
+            let rate = if seeding {
+                uploaded - peer.last_uploaded
+            } else {
+                downloaded - peer.last_downloaded
+            };
+            peer.last_downloaded = downloaded;
+            peer.last_uploaded = uploaded;
+
+            // snubbing peers only get a chance through the optimistic unchoke.
+            if peer.stats.peer_interested.load(Ordering::Relaxed)
+                && (seeding || !peer.stats.is_snubbed())
+            {
+                rates.push((*addr, rate));
+            }
+        }
+        rates.sort_by_key(|(_, rate)| Reverse(*rate));
+        let regular: Vec<SocketAddr> = rates
+            .iter()
+            .take(UNCHOKE_SLOTS - 1)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        // the optimistic unchoke only moves every few rounds.
+        let rounds_since_rotation = round % OPTIMISTIC_ROUNDS;
+        let mut optimistic = self.optimistic.lock().unwrap();
+        let keep_optimistic = optimistic.is_some_and(|addr| {
+            rounds_since_rotation > 0
+                && !regular.contains(&addr)
+                && peers
+                    .get(&addr)
+                    .is_some_and(|peer| peer.stats.peer_interested.load(Ordering::Relaxed))
+        });
+        if !keep_optimistic {
+            *optimistic = pick_optimistic(&peers, &regular).unwrap_or_else(|err| {
+                warn!("no optimistic unchoke this round: {err}");
+                None
+            });
+        }
+
+        for (addr, peer) in peers.iter() {
+            let unchoke = regular.contains(addr) || *optimistic == Some(*addr);
+            peer.stats.unchoke.send_if_modified(|current| {
+                let changed = *current != unchoke;
+                *current = unchoke;
+                changed
+            });
+        }
+    }
+}
+
+/// Picks a random interested peer outside of the regular unchoke slots,
+/// peers that connected recently are more likely to be picked.
+fn pick_optimistic(
+    peers: &HashMap<SocketAddr, ChokerPeer>,
+    regular: &[SocketAddr],
+) -> io::Result<Option<SocketAddr>> {
+    let candidates: Vec<(SocketAddr, u64)> = peers
+        .iter()
+        .filter(|(addr, peer)| {
+            !regular.contains(addr) && peer.stats.peer_interested.load(Ordering::Relaxed)
+        })
+        .map(|(addr, peer)| {
+            let weight = if peer.stats.connected_at.elapsed() < NEW_PEER_AGE {
+                3
+            } else {
+                1
+            };
+            (*addr, weight)
+        })
+        .collect();
+
+    let total: u64 = candidates.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+        return Ok(None);
+    }
+    let mut target = random::below(total)?;
+    for (addr, weight) in candidates {
+        if target < weight {
+            return Ok(Some(addr));
+        }
+        target -= weight;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Registers interested peers that sent us `downloaded` bytes each.
+    fn peers(choker: &Choker, downloaded: &[usize]) -> Vec<Arc<PeerStats>> {
+        downloaded
+            .iter()
+            .enumerate()
+            .map(|(index, &bytes)| {
+                let stats = choker.register(addr(index as u16));
+                stats.set_interest(true, true);
+                stats.record_download(bytes);
+                stats
+            })
+            .collect()
+    }
+
+    fn unchoked(stats: &[Arc<PeerStats>]) -> Vec<bool> {
+        stats
+            .iter()
+            .map(|stats| *stats.decisions().borrow())
+            .collect()
+    }
+
+    #[test]
+    fn unchokes_the_fastest_peers_and_one_optimistic() {
+        let choker = Choker::default();
+        let stats = peers(&choker, &[10, 50, 40, 30, 20]);
+        choker.rechoke(false);
+
+        let unchoked = unchoked(&stats);
+        assert!(unchoked[1] && unchoked[2] && unchoked[3]);
+        // the optimistic unchoke is one of the two slower peers.
+        assert_eq!(unchoked.iter().filter(|&&unchoked| unchoked).count(), 4);
+        assert!(unchoked[0] != unchoked[4]);
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let choker = Choker::default();
+        let stats = peers(&choker, &[10, 20]);
+        stats[0].set_interest(false, true);
+        choker.rechoke(false);
+        assert_eq!(unchoked(&stats), [false, true]);
+    }
+
+    #[test]
+    fn seeding_ranks_peers_by_upload() {
+        let choker = Choker::default();
+        let stats = peers(&choker, &[0, 0, 0, 0, 0]);
+        for (index, stats) in stats.iter().enumerate().take(3) {
+            stats.record_upload(100 - index);
+        }
+        stats[3].record_download(1000);
+        choker.rechoke(true);
+        let unchoked = unchoked(&stats);
+        assert!(unchoked[0] && unchoked[1] && unchoked[2]);
+    }
+
+    #[test]
+    fn rates_count_since_the_previous_round() {
+        let choker = Choker::default();
+        let stats = peers(&choker, &[100, 0, 0, 0, 0]);
+        choker.rechoke(false);
+        assert!(unchoked(&stats)[0]);
+        for (index, stats) in stats.iter().enumerate().skip(1) {
+            stats.record_download(10 * index);
+        }
+        choker.rechoke(false);
+        let unchoked = unchoked(&stats);
+        assert!(unchoked[2] && unchoked[3] && unchoked[4]);
+    }
+
+    #[test]
+    fn snubbing_peers_lose_their_regular_slot() {
+        let choker = Choker::default();
+        let stats = peers(&choker, &[10, 20, 30, 40]);
+        if let Some(long_ago) = Instant::now().checked_sub(SNUB_TIMEOUT * 2) {
+            *stats[3].last_block.lock().unwrap() = long_ago;
+            assert!(stats[3].is_snubbed());
+        }
+        choker.rechoke(false);
+        let unchoked = unchoked(&stats);
+        // the snubbing peer can only be the optimistic unchoke, which is then the only one left.
+        assert!(unchoked.iter().all(|&unchoked| unchoked));
+        assert_eq!(*choker.optimistic.lock().unwrap(), Some(addr(3)));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_few_rounds() {
+        let choker = Choker::default();
+        let stats = peers(&choker, &[40, 30, 20, 0, 0, 0]);
+        choker.rechoke(false);
+        let optimistic = *choker.optimistic.lock().unwrap();
+        assert!(optimistic.is_some());
+        for _ in 1..OPTIMISTIC_ROUNDS {
+            // the fastest peers keep their regular slots.
+            for (index, stats) in stats.iter().enumerate().take(3) {
+                stats.record_download(40 - 10 * index);
+            }
+            choker.rechoke(false);
+            assert_eq!(*choker.optimistic.lock().unwrap(), optimistic);
+        }
+        assert_eq!(unchoked(&stats).iter().filter(|&&u| u).count(), 4);
+    }
+
+    #[test]
+    fn interested_peers_take_free_slots_right_away() {
+        let choker = Choker::default();
+        let stats = choker.register(addr(1));
+        assert!(!*stats.decisions().borrow());
+        assert!(stats.set_interest(true, false));
+        assert!(!stats.set_interest(true, false));
+        choker.peer_interested(&addr(1));
+        assert!(*stats.decisions().borrow());
+    }
+
+    #[test]
+    fn no_free_slot_keeps_new_peers_choked() {
+        let choker = Choker::default();
+        let stats = peers(&choker, &[1; UNCHOKE_SLOTS]);
+        for index in 0..UNCHOKE_SLOTS {
+            choker.peer_interested(&addr(index as u16));
+        }
+        let late = choker.register(addr(100));
+        late.set_interest(true, false);
+        choker.peer_interested(&addr(100));
+        assert!(unchoked(&stats).iter().all(|&unchoked| unchoked));
+        assert!(!*late.decisions().borrow());
+    }
+}
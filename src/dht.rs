@@ -0,0 +1,1101 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use futures_util::{future::join_all, stream::FuturesUnordered, StreamExt};
+use serde::{de, Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::oneshot,
+    time::timeout,
+};
+
+use crate::{debug, warn};
+use crate::{ipfilter::IpFilter, random};
+
+/// Number of nodes in each bucket of the routing table,
+/// and number of closest nodes a lookup converges on.
+const K: usize = 8;
+
+/// Number of queries a lookup keeps in flight at the same time.
+const ALPHA: usize = 3;
+
+/// How long we wait for a node to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A node that failed to answer this many queries in a row is bad, and gets replaced.
+const MAX_FAILURES: u32 = 2;
+
+/// Nodes we haven't heard from for this long are pinged, to know whether they are still there.
+const QUESTIONABLE_AGE: Duration = Duration::from_secs(15 * 60);
+
+/// The secret tokens are derived from changes this often,
+/// tokens derived from the previous secret are still accepted.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Announced peers are forgotten after this long, unless they announce again.
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// How often we ping questionable nodes, rotate the secret and save the node cache.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of peers in a get_peers response, so it fits in a single datagram.
+const MAX_VALUES: usize = 50;
+
+/// Maximum number of peers we store for each info hash, and of info hashes.
+const MAX_STORED_PEERS: usize = 200;
+const MAX_STORED_TORRENTS: usize = 1000;
+
+/// Well known nodes to join the DHT through, when we know no other node.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Nodes and info hashes share the same 160 bits space.
+pub type NodeId = [u8; 20];
+
+/// A KRPC message, a query, a response or an error, bencoded in a single datagram.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Krpc {
+    /// Transaction id, echoed back by the response.
+    #[serde(with = "serde_bytes")]
+    t: Vec<u8>,
+
+    /// "q" for a query, "r" for a response and "e" for an error.
+    y: String,
+
+    /// The method of a query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+
+    /// The arguments of a query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<Arguments>,
+
+    /// The values of a response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<Values>,
+
+    /// The code and message of an error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<KrpcError>,
+}
+
+/// The code and message of an error, a list of two elements.
+#[derive(Debug, PartialEq)]
+struct KrpcError(i64, String);
+
+impl Serialize for KrpcError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.0, &self.1).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for KrpcError {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = KrpcError;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of an error code and message")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<KrpcError, A::Error> {
+                let code = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let message = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                // the list must be read to its end, a tuple would leave it open.
+                while seq.next_element::<de::IgnoredAny>()?.is_some() {}
+                Ok(KrpcError(code, message))
+            }
+        }
+
+        deserializer.deserialize_seq(Visitor)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Arguments {
+    /// The id of the querying node.
+    id: ByteBuf,
+
+    /// The node find_node looks for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+
+    /// The torrent get_peers and announce_peer are about.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+
+    /// The port announce_peer tells the peer listens on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+
+    /// The token get_peers handed out, announce_peer must send it back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+
+    /// When 1 the port the query came from is used instead of `port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Values {
+    /// The id of the responding node.
+    id: ByteBuf,
+
+    /// Compact node info of the closest nodes the responding node knows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+
+    /// Compact peer info of the peers of a torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+
+    /// The token needed to announce ourselves to the responding node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+/// The errors defined by BEP 5.
+const PROTOCOL_ERROR: i64 = 203;
+const METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Clone, Debug)]
+struct Node {
+    id: NodeId,
+    addr: SocketAddrV4,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// The nodes we know, bucketed by the number of leading bits they share with our id.
+/// Close to our id the buckets cover a small part of the space, so we know it well.
+struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// The bucket of `id`, None for our own id.
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let zeros = distance.iter().position(|&byte| byte != 0)?;
+        Some(zeros * 8 + distance[zeros].leading_zeros() as usize)
+    }
+
+    /// Records that we heard from a node, adding it when its bucket has room
+    /// or holds a bad node.
+    fn insert(&mut self, id: NodeId, addr: SocketAddrV4) {
+        let Some(index) = self.bucket(&id) else {
+            return;
+        };
+        // a node that changed its id replaces its old entry.
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain(|node| node.addr != addr || node.id == id);
+        }
+
+        let bucket = &mut self.buckets[index];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return;
+        }
+
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(bad) = bucket.iter_mut().find(|node| node.failures >= MAX_FAILURES) {
+            *bad = node;
+        }
+    }
+
+    /// Records that the node at `addr` didn't answer.
+    fn failed(&mut self, addr: SocketAddrV4) {
+        for node in self.buckets.iter_mut().flatten() {
+            if node.addr == addr {
+                node.failures += 1;
+            }
+        }
+    }
+
+    /// The `n` good nodes closest to `target`.
+    fn closest(&self, target: &NodeId, n: usize) -> Vec<(NodeId, SocketAddrV4)> {
+        let mut nodes: Vec<&Node> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.failures < MAX_FAILURES)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes
+            .into_iter()
+            .take(n)
+            .map(|node| (node.id, node.addr))
+            .collect()
+    }
+
+    /// Nodes we haven't heard from for a while.
+    fn questionable(&self) -> Vec<SocketAddrV4> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.last_seen.elapsed() > QUESTIONABLE_AGE)
+            .map(|node| node.addr)
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+/// The secrets the tokens are derived from.
+struct Secrets {
+    current: [u8; 8],
+    previous: [u8; 8],
+    rotated_at: Instant,
+}
+
+impl Secrets {
+    fn new() -> io::Result<Self> {
+        let secret = random::bytes()?;
+        Ok(Self {
+            current: secret,
+            previous: secret,
+            rotated_at: Instant::now(),
+        })
+    }
+
+    fn rotate_if_due(&mut self) -> io::Result<()> {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION {
+            let secret = random::bytes()?;
+            self.previous = self.current;
+            self.current = secret;
+            self.rotated_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// The token we give the node at `ip`, it proves the node asked us for peers recently.
+    fn token(&self, ip: &Ipv4Addr) -> Vec<u8> {
+        derive_token(&self.current, ip)
+    }
+
+    fn is_valid(&self, token: &[u8], ip: &Ipv4Addr) -> bool {
+        token == derive_token(&self.current, ip) || token == derive_token(&self.previous, ip)
+    }
+}
+
+fn derive_token(secret: &[u8; 8], ip: &Ipv4Addr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(ip.octets());
+    hasher.update(secret);
+    hasher.finalize()[..8].to_vec()
+}
+
+/// The routing table saved between runs, so we don't need the bootstrap nodes every time.
+#[derive(Deserialize, Serialize)]
+struct NodeCache {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    /// Compact node info of every node in the routing table.
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+}
+
+/// What a lookup found.
+struct Lookup {
+    /// The peers of the info hash, for get_peers lookups.
+    peers: HashSet<SocketAddrV4>,
+    /// The closest nodes that answered, with the token they gave us.
+    closest: Vec<(SocketAddrV4, Option<ByteBuf>)>,
+}
+
+/// A node of the mainline DHT (BEP 5), a Kademlia distributed hash table over UDP
+/// that maps info hashes to the peers of the torrent, so peers can be found without a tracker.
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+
+    /// The queries waiting for an answer by transaction id, with the node they were sent to.
+    pending: Mutex<HashMap<u16, (SocketAddrV4, oneshot::Sender<Krpc>)>>,
+    next_transaction: AtomicU16,
+
+    secrets: Mutex<Secrets>,
+
+    /// The peers that announced themselves to us, by info hash.
+    peers: Mutex<HashMap<NodeId, HashMap<SocketAddrV4, Instant>>>,
+
+    /// The nodes we joined through, to join again when we lose every node.
+    bootstrap: Mutex<Vec<String>>,
+
+    /// Where the routing table is saved.
+    cache: Option<PathBuf>,
+
+    /// The nodes we neither query nor answer.
+    ip_filter: Mutex<Arc<IpFilter>>,
+}
+
+impl Dht {
+    /// Starts a node listening on `port`, restoring the routing table saved in `cache` if any.
+    pub async fn bind(port: u16, cache: Option<PathBuf>) -> anyhow::Result<Arc<Self>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+            .await
+            .with_context(|| format!("bind dht socket on port {port} fail"))?;
+
+        // a missing cache is expected on the first run.
+        let cached = cache.as_deref().filter(|path| path.exists());
+        let cached = cached.and_then(|path| match load_cache(path) {
+            Ok(cached) => Some(cached),
+            Err(err) => {
+                warn!("not using the dht node cache: {err:#}");
+                None
+            }
+        });
+        let id = cached
+            .as_ref()
+            .and_then(|cached| cached.id.as_slice().try_into().ok())
+            .map_or_else(random::bytes, Ok)?;
+
+        let mut table = RoutingTable::new(id);
+        if let Some(cached) = &cached {
+            for (id, addr) in parse_nodes(&cached.nodes) {
+                table.insert(id, addr);
+            }
+        }
+
+        let dht = Arc::new(Self {
+            id,
+            socket: Arc::new(socket),
+            table: Mutex::new(table),
+            pending: Mutex::default(),
+            next_transaction: AtomicU16::new(0),
+            secrets: Mutex::new(Secrets::new()?),
+            peers: Mutex::default(),
+            bootstrap: Mutex::default(),
+            cache,
+            ip_filter: Mutex::default(),
+        });
+        tokio::spawn(receive(dht.socket.clone(), Arc::downgrade(&dht)));
+        tokio::spawn(maintain(Arc::downgrade(&dht)));
+        Ok(dht)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// The port we listen on, which we tell peers with the port message.
+    pub fn port(&self) -> u16 {
+        self.socket
+            .local_addr()
+            .map(|addr| addr.port())
+            .unwrap_or(0)
+    }
+
+    /// Stops talking to the nodes `ip_filter` blocks, the peers they know
+    /// are still found through the others.
+    pub fn set_ip_filter(&self, ip_filter: Arc<IpFilter>) {
+        *self.ip_filter.lock().unwrap() = ip_filter;
+    }
+
+    fn is_blocked(&self, addr: &SocketAddrV4) -> bool {
+        self.ip_filter
+            .lock()
+            .unwrap()
+            .is_blocked((*addr.ip()).into())
+    }
+
+    /// Number of nodes in the routing table.
+    pub fn num_nodes(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// Joins the DHT through the `nodes`, given as host:port, and fills the routing table
+    /// with the nodes close to us.
+    pub async fn bootstrap(&self, nodes: &[String]) -> anyhow::Result<()> {
+        *self.bootstrap.lock().unwrap() = nodes.to_vec();
+
+        let mut addrs = Vec::new();
+        for node in nodes {
+            match lookup_host(node.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(err) => debug!("failed to resolve dht node {node}: {err}"),
+            }
+        }
+        join_all(addrs.into_iter().map(|addr| {
+            self.query(
+                addr,
+                "find_node",
+                Arguments {
+                    target: Some(ByteBuf::from(self.id.to_vec())),
+                    ..self.arguments()
+                },
+            )
+        }))
+        .await;
+
+        self.lookup(self.id, false).await;
+        if self.num_nodes() == 0 {
+            bail!("no dht node answered");
+        }
+        Ok(())
+    }
+
+    /// Checks the node at `addr` is alive, adding it to the routing table.
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<NodeId> {
+        let SocketAddr::V4(addr) = addr else {
+            bail!("the dht only supports ipv4 nodes");
+        };
+        let values = self.query(addr, "ping", self.arguments()).await?;
+        node_id(&values.id).context("invalid node id")
+    }
+
+    /// Pings a node in the background, for nodes peers tell us about with the port message.
+    pub fn add_node(self: &Arc<Self>, addr: SocketAddr) {
+        let dht = self.clone();
+        tokio::spawn(async move {
+            dht.ping(addr).await.ok();
+        });
+    }
+
+    /// Finds the peers of `info_hash`.
+    pub async fn get_peers(&self, info_hash: NodeId) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        lookup.peers.into_iter().map(SocketAddr::V4).collect()
+    }
+
+    /// Finds the peers of `info_hash`, and tells the closest nodes we are a peer
+    /// listening on `port`.
+    pub async fn announce(&self, info_hash: NodeId, port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        join_all(lookup.closest.into_iter().filter_map(|(addr, token)| {
+            let arguments = Arguments {
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                port: Some(port),
+                token: Some(token?),
+                implied_port: Some(0),
+                ..self.arguments()
+            };
+            Some(self.query(addr, "announce_peer", arguments))
+        }))
+        .await;
+        lookup.peers.into_iter().map(SocketAddr::V4).collect()
+    }
+
+    /// Saves the routing table to the cache, if there is one.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.cache else {
+            return Ok(());
+        };
+        let nodes = {
+            let table = self.table.lock().unwrap();
+            let nodes: Vec<(NodeId, SocketAddrV4)> = table
+                .buckets
+                .iter()
+                .flatten()
+                .filter(|node| node.failures < MAX_FAILURES)
+                .map(|node| (node.id, node.addr))
+                .collect();
+            compact_nodes(&nodes)
+        };
+        let cache = NodeCache {
+            id: self.id.to_vec(),
+            nodes,
+        };
+        fs::write(path, serde_bencode::to_bytes(&cache)?)
+            .with_context(|| format!("write dht node cache {path:?} fail"))
+    }
+
+    /// Arguments with our id, every query carries it.
+    fn arguments(&self) -> Arguments {
+        Arguments {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    /// Sends a query to `addr` and waits for its response.
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        arguments: Arguments,
+    ) -> anyhow::Result<Values> {
+        if self.is_blocked(&addr) {
+            bail!("dht node {addr} is filtered out");
+        }
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction, (addr, sender));
+
+        let message = Krpc {
+            t: transaction.to_be_bytes().to_vec(),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(arguments),
+            ..Default::default()
+        };
+        let reply = async {
+            self.socket
+                .send_to(&serde_bencode::to_bytes(&message)?, addr)
+                .await?;
+            timeout(QUERY_TIMEOUT, receiver)
+                .await
+                .with_context(|| format!("dht node {addr} didn't answer {method}"))?
+                .context("dht query dropped")
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&transaction);
+
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(err) => {
+                self.table.lock().unwrap().failed(addr);
+                return Err(err);
+            }
+        };
+        if let Some(KrpcError(code, message)) = reply.e {
+            bail!("dht node {addr} answered {method} with error {code}: {message}");
+        }
+        let values = reply.r.context("dht response without values")?;
+        let id = node_id(&values.id).context("dht response with an invalid node id")?;
+        self.table.lock().unwrap().insert(id, addr);
+        Ok(values)
+    }
+
+    /// Iteratively queries the nodes closest to `target`, each answer bringing closer nodes,
+    /// until the closest nodes we know all answered.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        #[derive(PartialEq)]
+        enum State {
+            Waiting,
+            Querying,
+            Answered(Option<ByteBuf>),
+            Failed,
+        }
+
+        // keyed by distance, so the candidates are sorted closest first.
+        let mut candidates: BTreeMap<NodeId, (SocketAddrV4, State)> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|(id, addr)| (distance(&id, &target), (addr, State::Waiting)))
+            .collect();
+        let mut peers = HashSet::new();
+
+        let method = if get_peers { "get_peers" } else { "find_node" };
+        let query = |distance: NodeId, addr: SocketAddrV4| {
+            let target = Some(ByteBuf::from(target.to_vec()));
+            let arguments = if get_peers {
+                Arguments {
+                    info_hash: target,
+                    ..self.arguments()
+                }
+            } else {
+                Arguments {
+                    target,
+                    ..self.arguments()
+                }
+            };
+            async move { (distance, self.query(addr, method, arguments).await) }
+        };
+
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            // query the closest nodes we didn't query yet, as long as they are among
+            // the K closest nodes that may still answer.
+            let next: Vec<(NodeId, SocketAddrV4)> = candidates
+                .iter()
+                .filter(|(_, (_, state))| *state != State::Failed)
+                .take(K)
+                .filter(|(_, (_, state))| *state == State::Waiting)
+                .map(|(distance, (addr, _))| (*distance, *addr))
+                .take(ALPHA - in_flight.len())
+                .collect();
+            for (distance, addr) in next {
+                if let Some((_, state)) = candidates.get_mut(&distance) {
+                    *state = State::Querying;
+                }
+                in_flight.push(query(distance, addr));
+            }
+
+            let Some((distance, result)) = in_flight.next().await else {
+                break;
+            };
+            let state = match result {
+                Ok(values) => {
+                    for value in values.values.iter().flatten() {
+                        if let Some(peer) = parse_peer(value) {
+                            peers.insert(peer);
+                        }
+                    }
+                    for (id, addr) in
+                        parse_nodes(values.nodes.as_deref().map_or(&[], Vec::as_slice))
+                    {
+                        if id != self.id {
+                            candidates
+                                .entry(self::distance(&id, &target))
+                                .or_insert((addr, State::Waiting));
+                        }
+                    }
+                    State::Answered(values.token)
+                }
+                Err(_) => State::Failed,
+            };
+            if let Some(candidate) = candidates.get_mut(&distance) {
+                candidate.1 = state;
+            }
+        }
+
+        let closest = candidates
+            .into_values()
+            .filter_map(|(addr, state)| match state {
+                State::Answered(token) => Some((addr, token)),
+                _ => None,
+            })
+            .take(K)
+            .collect();
+        Lookup { peers, closest }
+    }
+
+    /// Handles a datagram from `from`, answering queries and handing responses to the queries waiting for them.
+    async fn handle(&self, message: Krpc, from: SocketAddrV4) {
+        match message.y.as_str() {
+            "q" => {
+                let reply = match self.answer(&message, from) {
+                    Ok(values) => Krpc {
+                        t: message.t,
+                        y: "r".to_string(),
+                        r: Some(values),
+                        ..Default::default()
+                    },
+                    Err((code, error)) => Krpc {
+                        t: message.t,
+                        y: "e".to_string(),
+                        e: Some(KrpcError(code, error.to_string())),
+                        ..Default::default()
+                    },
+                };
+                if let Ok(bytes) = serde_bencode::to_bytes(&reply) {
+                    self.socket.send_to(&bytes, from).await.ok();
+                }
+            }
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_slice()) else {
+                    return;
+                };
+                let transaction = u16::from_be_bytes(transaction);
+                let mut pending = self.pending.lock().unwrap();
+                // only the node we queried may answer.
+                if pending
+                    .get(&transaction)
+                    .is_some_and(|(addr, _)| *addr == from)
+                {
+                    if let Some((_, sender)) = pending.remove(&transaction) {
+                        sender.send(message).ok();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Answers a query from another node.
+    fn answer(&self, query: &Krpc, from: SocketAddrV4) -> Result<Values, (i64, &'static str)> {
+        let (Some(method), Some(arguments)) = (&query.q, &query.a) else {
+            return Err((PROTOCOL_ERROR, "invalid query"));
+        };
+        let id = node_id(&arguments.id).ok_or((PROTOCOL_ERROR, "invalid id"))?;
+        self.table.lock().unwrap().insert(id, from);
+
+        let mut values = Values {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        match method.as_str() {
+            "ping" => {}
+            "find_node" => {
+                let target = arguments
+                    .target
+                    .as_deref()
+                    .and_then(|bytes| node_id(bytes))
+                    .ok_or((PROTOCOL_ERROR, "invalid target"))?;
+                let closest = self.table.lock().unwrap().closest(&target, K);
+                values.nodes = Some(ByteBuf::from(compact_nodes(&closest)));
+            }
+            "get_peers" => {
+                let info_hash = arguments
+                    .info_hash
+                    .as_deref()
+                    .and_then(|bytes| node_id(bytes))
+                    .ok_or((PROTOCOL_ERROR, "invalid info hash"))?;
+                values.token = Some(ByteBuf::from(self.secrets.lock().unwrap().token(from.ip())));
+
+                let peers: Vec<ByteBuf> = self
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .get(&info_hash)
+                    .into_iter()
+                    .flat_map(|peers| peers.keys())
+                    .take(MAX_VALUES)
+                    .map(|peer| ByteBuf::from(compact_peer(peer).to_vec()))
+                    .collect();
+                if peers.is_empty() {
+                    let closest = self.table.lock().unwrap().closest(&info_hash, K);
+                    values.nodes = Some(ByteBuf::from(compact_nodes(&closest)));
+                } else {
+                    values.values = Some(peers);
+                }
+            }
+            "announce_peer" => {
+                let info_hash = arguments
+                    .info_hash
+                    .as_deref()
+                    .and_then(|bytes| node_id(bytes))
+                    .ok_or((PROTOCOL_ERROR, "invalid info hash"))?;
+                let token = arguments
+                    .token
+                    .as_deref()
+                    .ok_or((PROTOCOL_ERROR, "missing token"))?;
+                if !self.secrets.lock().unwrap().is_valid(token, from.ip()) {
+                    return Err((PROTOCOL_ERROR, "invalid token"));
+                }
+                let port = if arguments.implied_port == Some(1) {
+                    from.port()
+                } else {
+                    arguments.port.ok_or((PROTOCOL_ERROR, "missing port"))?
+                };
+
+                let mut torrents = self.peers.lock().unwrap();
+                if torrents.len() >= MAX_STORED_TORRENTS && !torrents.contains_key(&info_hash) {
+                    return Ok(values);
+                }
+                let peers = torrents.entry(info_hash).or_default();
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                if peers.len() < MAX_STORED_PEERS || peers.contains_key(&peer) {
+                    peers.insert(peer, Instant::now());
+                }
+            }
+            _ => return Err((METHOD_UNKNOWN, "method unknown")),
+        }
+        Ok(values)
+    }
+
+    /// Rotates the secret, forgets expired peers, checks on the nodes we didn't hear from
+    /// and joins again when we lost every node.
+    async fn maintain(&self) {
+        if let Err(err) = self.secrets.lock().unwrap().rotate_if_due() {
+            warn!("keeping the dht token secret: {err}");
+        }
+        self.peers.lock().unwrap().retain(|_, peers| {
+            peers.retain(|_, announced| announced.elapsed() < PEER_EXPIRY);
+            !peers.is_empty()
+        });
+
+        let questionable = self.table.lock().unwrap().questionable();
+        join_all(
+            questionable
+                .into_iter()
+                .map(|addr| self.query(addr, "ping", self.arguments())),
+        )
+        .await;
+
+        if self.num_nodes() < K {
+            let nodes = self.bootstrap.lock().unwrap().clone();
+            if let Err(err) = self.bootstrap(&nodes).await {
+                warn!("dht bootstrap failed: {err:#}");
+            }
+        }
+        if let Err(err) = self.save() {
+            warn!("{err:#}");
+        }
+    }
+}
+
+/// Reads the datagrams sent to the node, until it is dropped.
+async fn receive(socket: Arc<UdpSocket>, dht: Weak<Dht>) {
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let Ok((length, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Some(dht) = dht.upgrade() else {
+            return;
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        if dht.is_blocked(&from) {
+            continue;
+        }
+        // malformed datagrams are ignored.
+        if let Ok(message) = serde_bencode::from_bytes::<Krpc>(&buffer[..length]) {
+            dht.handle(message, from).await;
+        }
+    }
+}
+
+async fn maintain(dht: Weak<Dht>) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    // the first tick completes right away.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(dht) = dht.upgrade() else {
+            return;
+        };
+        dht.maintain().await;
+    }
+}
+
+fn load_cache(path: &Path) -> anyhow::Result<NodeCache> {
+    let bytes = fs::read(path).with_context(|| format!("read dht node cache {path:?} fail"))?;
+    serde_bencode::from_bytes(&bytes).context("decode dht node cache fail")
+}
+
+/// The xor distance of two ids, compared as a big endian number.
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    distance
+}
+
+fn node_id(bytes: &[u8]) -> Option<NodeId> {
+    bytes.try_into().ok()
+}
+
+/// Compact peer info, the ip followed by the port in network byte order.
+fn compact_peer(addr: &SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+fn parse_peer(compact: &[u8]) -> Option<SocketAddrV4> {
+    let compact: [u8; 6] = compact.try_into().ok()?;
+    let ip = Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]);
+    Some(SocketAddrV4::new(
+        ip,
+        u16::from_be_bytes([compact[4], compact[5]]),
+    ))
+}
+
+/// Compact node info, the node id followed by its compact peer info.
+fn compact_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> Vec<u8> {
+    let mut compact = Vec::with_capacity(nodes.len() * 26);
+    for (id, addr) in nodes {
+        compact.extend_from_slice(id);
+        compact.extend_from_slice(&compact_peer(addr));
+    }
+    compact
+}
+
+fn parse_nodes(compact: &[u8]) -> impl Iterator<Item = (NodeId, SocketAddrV4)> + '_ {
+    compact.chunks_exact(26).filter_map(|chunk| {
+        let id = node_id(&chunk[..20])?;
+        let addr = parse_peer(&chunk[20..])?;
+        Some((id, addr))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn node() -> (Arc<Dht>, SocketAddrV4) {
+        let dht = Dht::bind(0, None).await.unwrap();
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, dht.port());
+        (dht, addr)
+    }
+
+    #[test]
+    fn queries_are_encoded_as_in_the_spec() {
+        let ping = Krpc {
+            t: b"aa".to_vec(),
+            y: "q".to_string(),
+            q: Some("ping".to_string()),
+            a: Some(Arguments {
+                id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_bencode::to_bytes(&ping).unwrap(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+
+        let error: Krpc =
+            serde_bencode::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee")
+                .unwrap();
+        assert_eq!(
+            error.e,
+            Some(KrpcError(201, "A Generic Error Ocurred".to_string()))
+        );
+    }
+
+    #[test]
+    fn compact_nodes_round_trip() {
+        let nodes = vec![
+            ([1; 20], SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881)),
+            (
+                [2; 20],
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 51413),
+            ),
+        ];
+        let compact = compact_nodes(&nodes);
+        assert_eq!(compact.len(), 52);
+        assert_eq!(parse_nodes(&compact).collect::<Vec<_>>(), nodes);
+        // a truncated trailing node is ignored.
+        assert_eq!(parse_nodes(&compact[..40]).count(), 1);
+    }
+
+    #[test]
+    fn buckets_hold_k_nodes_closest_first() {
+        let mut table = RoutingTable::new([0; 20]);
+        assert_eq!(table.bucket(&[0; 20]), None);
+        let mut far = [0; 20];
+        far[0] = 0x80;
+        assert_eq!(table.bucket(&far), Some(0));
+
+        for i in 0..K as u8 + 2 {
+            let mut id = far;
+            id[19] = i;
+            table.insert(id, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + i as u16));
+        }
+        assert_eq!(table.len(), K);
+
+        let mut close = [0; 20];
+        close[19] = 1;
+        table.insert(close, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2000));
+        assert_eq!(table.closest(&[0; 20], 1)[0].0, close);
+    }
+
+    #[test]
+    fn tokens_outlive_one_rotation_only() {
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let mut secrets = Secrets::new().unwrap();
+        let token = secrets.token(&ip);
+        assert!(secrets.is_valid(&token, &ip));
+        assert!(!secrets.is_valid(&token, &Ipv4Addr::new(10, 0, 0, 2)));
+
+        for valid in [true, false] {
+            secrets.rotated_at = Instant::now() - TOKEN_ROTATION;
+            secrets.rotate_if_due().unwrap();
+            assert_eq!(secrets.is_valid(&token, &ip), valid);
+        }
+    }
+
+    #[tokio::test]
+    async fn nodes_find_each_other_and_the_announced_peers() {
+        let (a, a_addr) = node().await;
+        let (b, b_addr) = node().await;
+        let (c, c_addr) = node().await;
+        let (d, _) = node().await;
+
+        assert_eq!(a.ping(b_addr.into()).await.unwrap(), b.id());
+        assert_eq!(b.ping(c_addr.into()).await.unwrap(), c.id());
+        // d only knows a, and finds the others through it.
+        d.bootstrap(&[a_addr.to_string()]).await.unwrap();
+        assert_eq!(d.num_nodes(), 3);
+
+        let info_hash = [7; 20];
+        assert!(a.get_peers(info_hash).await.is_empty());
+        d.announce(info_hash, 4242).await;
+        let peers = c.get_peers(info_hash).await;
+        assert_eq!(peers, [SocketAddr::from((Ipv4Addr::LOCALHOST, 4242))]);
+    }
+
+    #[tokio::test]
+    async fn announces_need_a_valid_token() {
+        let (a, _) = node().await;
+        let (_b, b_addr) = node().await;
+        let announce = |token: Vec<u8>| Arguments {
+            info_hash: Some(ByteBuf::from(vec![7; 20])),
+            port: Some(4242),
+            token: Some(ByteBuf::from(token)),
+            ..a.arguments()
+        };
+
+        let err = a
+            .query(b_addr, "announce_peer", announce(vec![0; 8]))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid token"), "{err:#}");
+
+        let values = a
+            .query(
+                b_addr,
+                "get_peers",
+                Arguments {
+                    info_hash: Some(ByteBuf::from(vec![7; 20])),
+                    ..a.arguments()
+                },
+            )
+            .await
+            .unwrap();
+        let token = values.token.unwrap().into_vec();
+        a.query(b_addr, "announce_peer", announce(token))
+            .await
+            .unwrap();
+        assert_eq!(
+            a.get_peers([7; 20]).await,
+            [SocketAddr::from((Ipv4Addr::LOCALHOST, 4242))]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_are_errors() {
+        let (a, _) = node().await;
+        let (_b, b_addr) = node().await;
+        let err = a.query(b_addr, "vote", a.arguments()).await.unwrap_err();
+        assert!(err.to_string().contains("204"), "{err:#}");
+    }
+}
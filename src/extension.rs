@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The name of the peer exchange extension (BEP 11).
+pub const UT_PEX: &str = "ut_pex";
+
+/// The message id we want to receive ut_pex messages with.
+pub const UT_PEX_ID: u8 = 1;
+
+/// The name of the metadata exchange extension (BEP 9).
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The message id we want to receive ut_metadata messages with.
+pub const UT_METADATA_ID: u8 = 2;
+
+/// The id of the extension handshake, the other ids are assigned by the handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The extension handshake (BEP 10), sent by both sides right after the bitfield.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Maps the name of each extension the sender supports to the message id
+    /// it wants to receive it with, an id of 0 disables the extension.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// The port the sender listens on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+
+    /// Name and version of the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+
+    /// The size of the info dictionary, from peers offering it with ut_metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    /// Our handshake, advertising the extensions we support and the port we listen on.
+    pub fn new(port: u16) -> Self {
+        Self {
+            m: BTreeMap::from([(UT_PEX.to_string(), UT_PEX_ID)]),
+            p: Some(port),
+            v: Some(format!("bittorrent-rust {}", env!("CARGO_PKG_VERSION"))),
+            metadata_size: None,
+        }
+    }
+
+    /// Leaves peer exchange out, for private torrents.
+    pub fn without_pex(mut self) -> Self {
+        self.m.remove(UT_PEX);
+        self
+    }
+
+    /// Offers the info dictionary of `size` bytes to peers that only have a magnet link.
+    pub fn with_metadata_size(mut self, size: usize) -> Self {
+        self.m.insert(UT_METADATA.to_string(), UT_METADATA_ID);
+        self.metadata_size = Some(size);
+        self
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        serde_bencode::from_bytes(bytes).context("decode extension handshake fail")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("encode extension handshake fail")
+    }
+}
@@ -0,0 +1,61 @@
+use std::net::Ipv4Addr;
+
+use sha1::{Digest, Sha1};
+
+/// Number of pieces a peer may request from us while choked.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Computes the pieces a peer at `ip` may request while choked, as described in BEP 6.
+/// Both sides can compute the same set, so it can't be abused by changing peer ids.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], num_pieces: usize) -> Vec<u32> {
+    let count = ALLOWED_FAST_COUNT.min(num_pieces);
+    let mut set = Vec::with_capacity(count);
+
+    // the last byte of the ip is masked, so peers of the same /24 get the same set.
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xFFFFFF00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks(4) {
+            if set.len() >= count {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("sha1 is 20 bytes"));
+            let index = (y as u64 % num_pieces as u64) as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_bep_example() {
+        let set = allowed_fast_set(Ipv4Addr::new(80, 4, 4, 200), &[0xaa; 20], 1313);
+        assert_eq!(set[..9], [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+        assert_eq!(set.len(), ALLOWED_FAST_COUNT);
+    }
+
+    #[test]
+    fn peers_of_the_same_subnet_get_the_same_set() {
+        let info_hash = [1; 20];
+        assert_eq!(
+            allowed_fast_set(Ipv4Addr::new(10, 0, 0, 1), &info_hash, 100),
+            allowed_fast_set(Ipv4Addr::new(10, 0, 0, 254), &info_hash, 100)
+        );
+    }
+
+    #[test]
+    fn small_torrents_allow_every_piece_once() {
+        let mut set = allowed_fast_set(Ipv4Addr::new(10, 0, 0, 1), &[2; 20], 3);
+        set.sort();
+        assert_eq!(set, [0, 1, 2]);
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::bail;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// The fast extension (BEP 6) is advertised by the third least significant bit of the last reserved byte.
+const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+
+/// The DHT (BEP 5) is advertised by the last bit of the reserved bytes.
+const DHT: (usize, u8) = (7, 0x01);
+
+/// The extension protocol (BEP 10) is advertised by the 20th bit from the right.
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+/// Support for v2 torrents (BEP 52) is advertised by the fourth most significant bit of the last reserved byte.
+const V2: (usize, u8) = (7, 0x10);
+
+/// The handshake is the first message sent by both sides of a peer connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    /// eight reserved bytes, used to advertise protocol extensions.
+    pub reserved: [u8; 8],
+    /// sha1 infohash of the torrent the connection is about.
+    pub info_hash: [u8; 20],
+    /// the id the peer chose for itself.
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// Our handshake, advertising the extensions we support.
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+        reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+        reserved[DHT.0] |= DHT.1;
+        Self {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Advertises support for v2 torrents, if the torrent has v2 metadata.
+    pub fn with_v2(mut self, v2: bool) -> Self {
+        if v2 {
+            self.reserved[V2.0] |= V2.1;
+        }
+        self
+    }
+
+    /// Whether the sender of this handshake supports the fast extension.
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[FAST_EXTENSION.0] & FAST_EXTENSION.1 != 0
+    }
+
+    /// Whether the sender of this handshake runs a DHT node.
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[DHT.0] & DHT.1 != 0
+    }
+
+    /// Whether the sender of this handshake supports the extension protocol.
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0
+    }
+
+    /// Whether the sender of this handshake supports v2 torrents.
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[V2.0] & V2.1 != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; 68] {
+        let mut message = [0u8; 68];
+
+        // length of the protocol string (BitTorrent protocol) which is 19 (1 byte)
+        message[0] = 19;
+
+        // the string BitTorrent protocol (19 bytes)
+        message[1..20].copy_from_slice(PROTOCOL);
+
+        // eight reserved bytes (8 bytes)
+        message[20..28].copy_from_slice(&self.reserved);
+
+        // sha1 infohash (20 bytes) (NOT the hexadecimal representation, which is 40 bytes long)
+        message[28..48].copy_from_slice(&self.info_hash);
+
+        // peer id (20 bytes)
+        message[48..].copy_from_slice(&self.peer_id);
+
+        message
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> anyhow::Result<()> {
+        stream.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
+
+    /// Reads a handshake, rejecting anything that doesn't speak the BitTorrent protocol.
+    pub async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Self> {
+        let mut buffer = [0u8; 68];
+        stream.read_exact(&mut buffer).await?;
+        if buffer[0] != 19 || &buffer[1..20] != PROTOCOL {
+            bail!("peer doesn't speak the BitTorrent protocol");
+        }
+
+        let mut handshake = Self {
+            reserved: [0; 8],
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+        };
+        handshake.reserved.copy_from_slice(&buffer[20..28]);
+        handshake.info_hash.copy_from_slice(&buffer[28..48]);
+        handshake.peer_id.copy_from_slice(&buffer[48..]);
+        Ok(handshake)
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+
+use crate::warn;
+
+/// eMule entries with an access level below this are blocked, the ones above are allowed.
+const EMULE_BLOCK_LEVEL: u32 = 128;
+
+/// Address ranges we neither connect to nor accept connections from.
+/// Lookups are a binary search over sorted ranges, so lists of hundreds of thousands
+/// of ranges are fine.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    /// Sorted, not overlapping nor adjacent, ends included.
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Builds a filter blocking the ranges from `start` to `end`, ends included.
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (IpAddr, IpAddr)>) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for (start, end) in ranges {
+            match (start, end) {
+                (IpAddr::V4(start), IpAddr::V4(end)) => v4.push((start.into(), end.into())),
+                (IpAddr::V6(start), IpAddr::V6(end)) => v6.push((start.into(), end.into())),
+                // parse_line doesn't give mixed ranges.
+                _ => {}
+            }
+        }
+        Self {
+            v4: merge(v4, |ip| ip.saturating_add(1)),
+            v6: merge(v6, |ip| ip.saturating_add(1)),
+        }
+    }
+
+    /// Loads the ranges of the lists at `paths`, each in eMule .dat, PeerGuardian .p2p
+    /// or CIDR format, which may be mixed. Lines that don't parse are skipped.
+    pub fn load(paths: &[impl AsRef<Path>]) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            let text = fs::read(path)
+                .with_context(|| format!("read ip filter {} fail", path.display()))?;
+            let mut invalid = 0;
+            for line in String::from_utf8_lossy(&text).lines() {
+                match parse_line(line) {
+                    Ok(Some(range)) => ranges.push(range),
+                    Ok(None) => {}
+                    Err(_) => invalid += 1,
+                }
+            }
+            if invalid > 0 {
+                warn!(
+                    "skipped {invalid} invalid lines of ip filter {}",
+                    path.display()
+                );
+            }
+        }
+        Ok(Self::from_ranges(ranges))
+    }
+
+    /// Whether connections to and from `ip` are blocked.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, ip.into()),
+            IpAddr::V6(ip) => contains(&self.v6, ip.into()),
+        }
+    }
+
+    /// Number of blocked ranges, once overlapping ones are merged.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Sorts the ranges and merges the overlapping and adjacent ones,
+/// `next` giving the address after one.
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>, next: fn(T) -> T) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last)) if start <= next(*last) => *last = end.max(*last),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let after = ranges.partition_point(|(start, _)| *start <= ip);
+    after > 0 && ip <= ranges[after - 1].1
+}
+
+/// Parses a line of a filter list into the range it blocks, `None` for blank lines,
+/// comments and eMule entries that allow the range. Takes:
+/// - eMule: `001.002.003.000 - 001.002.003.255 , 000 , description`
+/// - PeerGuardian: `description:1.2.3.0-1.2.3.255`
+/// - CIDR: `1.2.3.0/24`, or a single address or a plain `start-end` range.
+fn parse_line(line: &str) -> anyhow::Result<Option<(IpAddr, IpAddr)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return Ok(None);
+    }
+
+    // a description may have commas or colons, so try the formats by what comes first.
+    let mut fields = line.split(',');
+    if let Some(Ok(range)) = fields.next().map(parse_range) {
+        let level = match fields.next() {
+            Some(level) => level.trim().parse().context("invalid access level")?,
+            None => 0,
+        };
+        return Ok((level < EMULE_BLOCK_LEVEL).then_some(range));
+    }
+    if let Some(range) = line
+        .rsplit_once(':')
+        .and_then(|(_, range)| parse_range(range).ok())
+    {
+        return Ok(Some(range));
+    }
+    if let Some((ip, prefix)) = line.split_once('/') {
+        return parse_cidr(ip, prefix).map(Some);
+    }
+    let ip = parse_ip(line)?;
+    Ok(Some((ip, ip)))
+}
+
+/// Parses a range like `1.2.3.0 - 1.2.3.255`.
+fn parse_range(s: &str) -> anyhow::Result<(IpAddr, IpAddr)> {
+    // ipv6 addresses don't have dashes.
+    let (start, end) = s.split_once('-').context("range without a dash")?;
+    let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+    if start.is_ipv4() != end.is_ipv4() {
+        bail!("range {s} mixes ipv4 and ipv6");
+    }
+    if start > end {
+        bail!("range {s} ends before it starts");
+    }
+    Ok((start, end))
+}
+
+fn parse_cidr(ip: &str, prefix: &str) -> anyhow::Result<(IpAddr, IpAddr)> {
+    let ip = parse_ip(ip)?;
+    let prefix: u32 = prefix.trim().parse().context("invalid prefix length")?;
+    match ip {
+        IpAddr::V4(ip) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Ok((
+                Ipv4Addr::from(start).into(),
+                Ipv4Addr::from(start | !mask).into(),
+            ))
+        }
+        IpAddr::V6(ip) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Ok((
+                Ipv6Addr::from(start).into(),
+                Ipv6Addr::from(start | !mask).into(),
+            ))
+        }
+        _ => bail!("prefix length {prefix} too long for {ip}"),
+    }
+}
+
+/// Parses an address, ipv4 ones may have leading zeros like in eMule lists.
+fn parse_ip(s: &str) -> anyhow::Result<IpAddr> {
+    let s = s.trim();
+    if s.contains(':') {
+        return Ok(s
+            .parse::<Ipv6Addr>()
+            .with_context(|| format!("invalid address {s}"))?
+            .into());
+    }
+    let octets: Vec<u8> = s
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("invalid address {s}"))?;
+    let octets: [u8; 4] = octets
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid address {s}"))?;
+    Ok(Ipv4Addr::from(octets).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(start: &str, end: &str) -> Option<(IpAddr, IpAddr)> {
+        Some((ip(start), ip(end)))
+    }
+
+    #[test]
+    fn lines_of_every_format_parse() {
+        for (line, parsed) in [
+            (
+                "001.002.003.000 - 001.002.003.255 , 000 , Some, ISP",
+                range("1.2.3.0", "1.2.3.255"),
+            ),
+            ("1.2.3.0 - 1.2.3.255 , 200 , allowed", None),
+            ("Bad: people:5.6.7.8-5.6.7.9", range("5.6.7.8", "5.6.7.9")),
+            ("10.0.0.0/8", range("10.0.0.0", "10.255.255.255")),
+            ("10.1.2.3/32", range("10.1.2.3", "10.1.2.3")),
+            ("0.0.0.0/0", range("0.0.0.0", "255.255.255.255")),
+            (
+                "2001:db8::/32",
+                range("2001:db8::", "2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"),
+            ),
+            ("9.9.9.9", range("9.9.9.9", "9.9.9.9")),
+            ("  # comment", None),
+            ("// comment", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_line(line).unwrap(), parsed, "{line}");
+        }
+        for invalid in [
+            "1.2.3.4 - 1.2.3.0",
+            "1.2.3.0 - ::1",
+            "1.2.3.0/33",
+            "1.2.3",
+            "1.2.3.256",
+            "1.2.3.0 - 1.2.3.255 , high",
+            "nonsense",
+        ] {
+            assert!(parse_line(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_merge() {
+        let filter = IpFilter::from_ranges([
+            (ip("1.0.0.10"), ip("1.0.0.20")),
+            (ip("1.0.0.0"), ip("1.0.0.9")),
+            (ip("1.0.0.15"), ip("1.0.0.30")),
+            (ip("1.0.0.40"), ip("1.0.0.50")),
+            (ip("::1"), ip("::1")),
+        ]);
+        assert_eq!(filter.len(), 3);
+        for (addr, blocked) in [
+            ("1.0.0.0", true),
+            ("1.0.0.30", true),
+            ("1.0.0.31", false),
+            ("1.0.0.45", true),
+            ("1.0.0.51", false),
+            ("0.255.255.255", false),
+            ("::1", true),
+            ("::2", false),
+            // ipv4 mapped ipv6 addresses are the ipv4 ones.
+            ("::ffff:1.0.0.5", true),
+        ] {
+            assert_eq!(filter.is_blocked(ip(addr)), blocked, "{addr}");
+        }
+        assert!(IpFilter::default().is_empty());
+        // ranges up to the last address don't overflow.
+        let filter = IpFilter::from_ranges([
+            (ip("255.255.255.0"), ip("255.255.255.255")),
+            (ip("255.255.255.255"), ip("255.255.255.255")),
+        ]);
+        assert_eq!(filter.len(), 1);
+        assert!(filter.is_blocked(ip("255.255.255.255")));
+    }
+
+    #[test]
+    fn lists_load_skipping_invalid_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let (emule, p2p) = (
+            dir.path().join("ipfilter.dat"),
+            dir.path().join("level1.p2p"),
+        );
+        fs::write(&emule, "1.0.0.0 - 1.0.0.255 , 000 , a\ngarbage\n").unwrap();
+        fs::write(&p2p, "b:2.0.0.0-2.0.0.255\n3.0.0.0/24\n").unwrap();
+
+        let filter = IpFilter::load(&[&emule, &p2p]).unwrap();
+        assert_eq!(filter.len(), 3);
+        assert!(filter.is_blocked(ip("2.0.0.7")));
+        assert!(IpFilter::load(&[dir.path().join("missing")]).is_err());
+    }
+}
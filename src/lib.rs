@@ -1,6 +1,39 @@
 pub const BLOCK_MAX: usize = 1 << 14;
 
+pub mod base64;
 pub mod bendecoder;
+pub mod bitfield;
+pub mod choker;
+pub mod dht;
+pub mod extension;
+pub mod fast;
+pub mod handshake;
+pub mod ipfilter;
+pub mod logging;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+pub mod metadata;
+pub mod mse;
+pub mod peer;
+pub mod peer_list;
 pub mod peer_message;
+pub mod pex;
+pub mod picker;
+pub mod priority;
+pub mod progress;
+pub mod proxy;
+pub mod random;
+pub mod ratelimit;
+pub mod rpc;
+pub mod server;
+pub mod session;
+pub mod sha256;
+pub mod storage;
+pub mod swarm;
 pub mod torrent;
 pub mod tracker;
+pub mod transmission;
+pub mod transport;
+pub mod utp;
+pub mod webseed;
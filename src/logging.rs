@@ -0,0 +1,85 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+};
+
+/// How much a message matters, the ones above the maximum level are dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Something doesn't work, like a tracker or the DHT.
+    Warn,
+    /// Something worth knowing happened, like banning a peer.
+    Info,
+    /// The comings and goings of peers and the like.
+    Debug,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        })
+    }
+}
+
+type Logger = Box<dyn Fn(Level, &str) + Send + Sync>;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Sends the messages to `logger` instead of stderr. Only the first logger is kept,
+/// returns whether it was this one.
+pub fn set_logger(logger: impl Fn(Level, &str) + Send + Sync + 'static) -> bool {
+    LOGGER.set(Box::new(logger)).is_ok()
+}
+
+/// Drops the messages less important than `level`, info by default.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Used by the macros, which skip formatting messages that would be dropped.
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments) {
+    let message = args.to_string();
+    match LOGGER.get() {
+        Some(logger) => logger(level, &message),
+        None => eprintln!("{level}: {message}"),
+    }
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Warn) {
+            $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Info) {
+            $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Debug) {
+            $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)*));
+        }
+    };
+}
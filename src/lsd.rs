@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use tokio::{net::UdpSocket, sync::mpsc};
+
+use crate::{random, warn};
+
+/// The port and multicast groups of local service discovery (BEP 14).
+const LSD_PORT: u16 = 6771;
+const GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// How often a torrent should be announced on the local network.
+pub const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The same peer announcing the same torrent again within this time is ignored,
+/// announcing more than once a minute is against the spec.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Announces the torrents we are active in to the local network with multicast,
+/// and hands the peers announcing the same torrents over to their swarms,
+/// so machines on the same network connect directly.
+pub struct Lsd {
+    v4: Option<Arc<UdpSocket>>,
+    v6: Option<Arc<UdpSocket>>,
+
+    /// Sent with our announcements, so we recognize them when they loop back to us.
+    cookie: String,
+
+    /// Where the peers of each torrent are handed over, keyed by info hash.
+    torrents: Mutex<HashMap<[u8; 20], mpsc::UnboundedSender<SocketAddr>>>,
+
+    /// When each peer last announced each torrent.
+    seen: Mutex<HashMap<(SocketAddr, [u8; 20]), Instant>>,
+}
+
+impl Lsd {
+    /// Joins the multicast groups. When another program already uses the LSD port,
+    /// we can still announce ourselves but won't hear the other peers.
+    pub async fn bind() -> anyhow::Result<Arc<Self>> {
+        let (v4, v4_receiving) = match bind_v4().await {
+            Ok((socket, receiving)) => (Some(Arc::new(socket)), receiving),
+            Err(err) => {
+                warn!("lsd over ipv4 unavailable: {err:#}");
+                (None, false)
+            }
+        };
+        // ipv6 is a bonus, plenty of networks don't have it.
+        let v6 = bind_v6().await.ok().map(Arc::new);
+        let v6_receiving = v6.is_some();
+        if v4.is_none() && v6.is_none() {
+            bail!("no lsd socket could be opened");
+        }
+
+        let lsd = Arc::new(Self {
+            v4,
+            v6,
+            cookie: hex::encode(random::bytes::<8>()?),
+            torrents: Mutex::default(),
+            seen: Mutex::default(),
+        });
+        for (socket, receiving) in [(&lsd.v4, v4_receiving), (&lsd.v6, v6_receiving)] {
+            if let (Some(socket), true) = (socket, receiving) {
+                tokio::spawn(receive(socket.clone(), Arc::downgrade(&lsd)));
+            }
+        }
+        Ok(lsd)
+    }
+
+    /// Hands the peers announcing `info_hash` over to `peers`.
+    pub fn register(&self, info_hash: [u8; 20], peers: mpsc::UnboundedSender<SocketAddr>) {
+        self.torrents.lock().unwrap().insert(info_hash, peers);
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    /// Tells the local network we are a peer of the `info_hashes`, listening on `port`.
+    pub async fn announce(&self, info_hashes: &[[u8; 20]], port: u16) -> anyhow::Result<()> {
+        if let Some(socket) = &self.v4 {
+            let message = self.message(&format!("{GROUP_V4}:{LSD_PORT}"), info_hashes, port);
+            socket
+                .send_to(message.as_bytes(), (GROUP_V4, LSD_PORT))
+                .await
+                .context("lsd announce over ipv4 fail")?;
+        }
+        if let Some(socket) = &self.v6 {
+            let message = self.message(&format!("[{GROUP_V6}]:{LSD_PORT}"), info_hashes, port);
+            // ipv6 multicast often isn't routed at all, that's not worth failing for.
+            socket
+                .send_to(message.as_bytes(), (GROUP_V6, LSD_PORT))
+                .await
+                .ok();
+        }
+        Ok(())
+    }
+
+    /// A BT-SEARCH message, an HTTP request over UDP.
+    fn message(&self, host: &str, info_hashes: &[[u8; 20]], port: u16) -> String {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {port}\r\n");
+        for info_hash in info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        message.push_str(&format!("cookie: {}\r\n\r\n\r\n", self.cookie));
+        message
+    }
+
+    /// Hands the peer that sent `message` from `from` over to the swarms of the torrents it announced.
+    fn handle(&self, message: &str, from: SocketAddr) {
+        let Some(announce) = Announce::parse(message) else {
+            return;
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) || announce.port == 0 {
+            return;
+        }
+
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| at.elapsed() < MIN_ANNOUNCE_INTERVAL);
+        let mut torrents = self.torrents.lock().unwrap();
+        for info_hash in announce.info_hashes {
+            if seen.insert((peer, info_hash), Instant::now()).is_some() {
+                continue;
+            }
+            if let Some(peers) = torrents.get(&info_hash) {
+                // the swarm is gone, stop listening for it.
+                if peers.send(peer).is_err() {
+                    torrents.remove(&info_hash);
+                }
+            }
+        }
+    }
+}
+
+/// The parts of a BT-SEARCH message we use.
+struct Announce {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+impl Announce {
+    fn parse(message: &str) -> Option<Self> {
+        let mut lines = message.lines();
+        if lines.next()?.trim_end() != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut announce = Self {
+            port: 0,
+            info_hashes: Vec::new(),
+            cookie: None,
+        };
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            // header names are case insensitive, like in HTTP.
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => announce.port = value.parse().ok()?,
+                "infohash" => {
+                    let mut info_hash = [0; 20];
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        announce.info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => announce.cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(announce)
+    }
+}
+
+/// Binds the LSD port and joins the ipv4 group, falling back to a socket that can only send.
+/// The boolean tells whether the socket receives announcements.
+async fn bind_v4() -> anyhow::Result<(UdpSocket, bool)> {
+    match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LSD_PORT)).await {
+        Ok(socket) => {
+            socket
+                .join_multicast_v4(GROUP_V4, Ipv4Addr::UNSPECIFIED)
+                .context("join lsd multicast group fail")?;
+            Ok((socket, true))
+        }
+        Err(err) => {
+            warn!("not receiving lsd announcements, port {LSD_PORT} is taken: {err}");
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+            Ok((socket, false))
+        }
+    }
+}
+
+async fn bind_v6() -> anyhow::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, LSD_PORT)).await?;
+    socket.join_multicast_v6(&GROUP_V6, 0)?;
+    Ok(socket)
+}
+
+/// Reads the announcements sent to the group, until the LSD is dropped.
+async fn receive(socket: Arc<UdpSocket>, lsd: Weak<Lsd>) {
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let Ok((length, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Some(lsd) = lsd.upgrade() else {
+            return;
+        };
+        if let Ok(message) = std::str::from_utf8(&buffer[..length]) {
+            lsd.handle(message, from);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lsd(cookie: &str) -> Lsd {
+        Lsd {
+            v4: None,
+            v6: None,
+            cookie: cookie.to_string(),
+            torrents: Mutex::default(),
+            seen: Mutex::default(),
+        }
+    }
+
+    fn from() -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 7], 6771))
+    }
+
+    #[test]
+    fn messages_parse_back() {
+        let message = lsd("abc").message("239.192.152.143:6771", &[[1; 20], [2; 20]], 6881);
+        assert!(message.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert!(message.ends_with("\r\n\r\n\r\n"));
+
+        let announce = Announce::parse(&message).unwrap();
+        assert_eq!(announce.port, 6881);
+        assert_eq!(announce.info_hashes, [[1; 20], [2; 20]]);
+        assert_eq!(announce.cookie.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        let message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHOST: x\r\nport: 51413\r\nINFOHASH: {}\r\ninfohash: nothex\r\n\r\n",
+            hex::encode([3; 20])
+        );
+        let announce = Announce::parse(&message).unwrap();
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes, [[3; 20]]);
+        assert_eq!(announce.cookie, None);
+
+        assert!(Announce::parse("M-SEARCH * HTTP/1.1\r\nPort: 1\r\n").is_none());
+        assert!(Announce::parse("BT-SEARCH * HTTP/1.1\r\nPort: x\r\n").is_none());
+    }
+
+    #[test]
+    fn announcing_peers_reach_their_swarm_once() {
+        let ours = lsd("ours");
+        let (sender, mut peers) = mpsc::unbounded_channel();
+        ours.register([1; 20], sender);
+
+        let message = lsd("theirs").message("h", &[[1; 20], [2; 20]], 6881);
+        ours.handle(&message, from());
+        assert_eq!(
+            peers.try_recv().unwrap(),
+            SocketAddr::from(([192, 168, 1, 7], 6881))
+        );
+        // announcing again within a minute is ignored.
+        ours.handle(&message, from());
+        assert!(peers.try_recv().is_err());
+    }
+
+    #[test]
+    fn our_own_and_portless_announcements_are_ignored() {
+        let ours = lsd("ours");
+        let (sender, mut peers) = mpsc::unbounded_channel();
+        ours.register([1; 20], sender);
+
+        ours.handle(&ours.message("h", &[[1; 20]], 6881), from());
+        ours.handle(&lsd("theirs").message("h", &[[1; 20]], 0), from());
+        assert!(peers.try_recv().is_err());
+    }
+
+    #[test]
+    fn gone_swarms_are_unregistered() {
+        let ours = lsd("ours");
+        let (sender, peers) = mpsc::unbounded_channel();
+        ours.register([1; 20], sender);
+        drop(peers);
+
+        ours.handle(&lsd("theirs").message("h", &[[1; 20]], 6881), from());
+        assert!(ours.torrents.lock().unwrap().is_empty());
+    }
+}
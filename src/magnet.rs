@@ -0,0 +1,281 @@
+use std::{fmt, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context};
+use reqwest::Url;
+use tokio::{task::JoinSet, time::timeout};
+
+use crate::{debug, warn};
+use crate::{
+    dht::Dht,
+    ipfilter::IpFilter,
+    metadata,
+    mse::EncryptionPolicy,
+    torrent::Torrent,
+    tracker::{self, Announce, Event},
+    transport::Transports,
+    webseed::percent_encode,
+};
+
+/// How long a peer has to hand over the whole info dictionary.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many peers we ask for the info dictionary at the same time.
+const MAX_PARALLEL_PEERS: usize = 8;
+
+/// A magnet link (BEP 9), the info hash of a torrent and hints where to find it.
+/// The rest of the metainfo comes from the peers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Magnet {
+    /// The info hash peers know the torrent by, the truncated v2 one for v2 only torrents.
+    pub info_hash: [u8; 20],
+    /// The SHA256 info hash of v2 torrents (BEP 52), given as a urn:btmh multihash.
+    pub info_hash_v2: Option<[u8; 32]>,
+    /// The display name, until the peers tell us the real one.
+    pub name: Option<String>,
+    /// Trackers to announce to.
+    pub trackers: Vec<String>,
+    /// Peers to connect to directly.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).context("invalid magnet link")?;
+        if url.scheme() != "magnet" {
+            bail!("magnet links start with magnet:?");
+        }
+
+        let mut info_hash = None;
+        let mut info_hash_v2 = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    } else if let Some(hash) = value.strip_prefix("urn:btmh:") {
+                        info_hash_v2 = Some(parse_multihash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => warn!("ignoring invalid peer {value} in magnet link"),
+                },
+                _ => {}
+            }
+        }
+        // v2 only torrents are known by their truncated v2 info hash.
+        let info_hash =
+            info_hash.or_else(|| info_hash_v2.map(|hash| hash[..20].try_into().unwrap()));
+        Ok(Self {
+            info_hash: info_hash.context("magnet link has no urn:btih nor urn:btmh info hash")?,
+            info_hash_v2,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+/// A v1 info hash in hex, or in base32 as older magnet links have it.
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("invalid hex info hash")?,
+        32 => base32_decode(hash).context("invalid base32 info hash")?,
+        _ => bail!("info hash must be 40 hex or 32 base32 characters"),
+    };
+    Ok(bytes.try_into().unwrap())
+}
+
+/// A SHA256 multihash in hex, the 0x12 hash function code and the 0x20 length first.
+fn parse_multihash(hash: &str) -> anyhow::Result<[u8; 32]> {
+    let Some(hash) = hash.strip_prefix("1220") else {
+        bail!("only sha256 multihashes are supported");
+    };
+    let bytes = hex::decode(hash).context("invalid hex multihash")?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("sha256 multihash must have 32 bytes"))
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("magnet:?")?;
+        let mut separator = "";
+        // hybrid torrents have both hashes, v2 only ones just the multihash.
+        if self
+            .info_hash_v2
+            .is_none_or(|hash| hash[..20] != self.info_hash)
+        {
+            write!(f, "xt=urn:btih:{}", hex::encode(self.info_hash))?;
+            separator = "&";
+        }
+        if let Some(hash) = self.info_hash_v2 {
+            write!(f, "{separator}xt=urn:btmh:1220{}", hex::encode(hash))?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, "&dn={}", percent_encode(name))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", percent_encode(tracker))?;
+        }
+        for peer in &self.peers {
+            write!(f, "&x.pe={peer}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes unpadded RFC 4648 base32.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+impl Magnet {
+    /// The link to `torrent`, with its name and trackers.
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self {
+            info_hash: torrent.info_hash_bytes(),
+            info_hash_v2: torrent.info_hash_v2(),
+            name: Some(torrent.info.name.clone()),
+            trackers: torrent.trackers().into_iter().flatten().collect(),
+            peers: Vec::new(),
+        }
+    }
+
+    /// The name to show until we have the metainfo.
+    pub fn display_name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| hex::encode(self.info_hash))
+    }
+
+    /// Finds peers through the trackers, the peers in the link and the DHT, and downloads
+    /// the info dictionary from the first one that has it, skipping the ones `ip_filter` blocks.
+    pub async fn fetch_torrent(
+        &self,
+        port: u16,
+        dht: Option<&Arc<Dht>>,
+        transports: &Transports,
+        encryption: EncryptionPolicy,
+        ip_filter: &IpFilter,
+    ) -> anyhow::Result<Torrent> {
+        let mut peers = self.peers.clone();
+        let announce = Announce {
+            info_hash: self.info_hash,
+            peer_id: transports.peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: Event::None,
+        };
+        for url in &self.trackers {
+            let proxy = transports.proxy.as_deref();
+            match tracker::announce(url, &announce, proxy).await {
+                Ok(response) => peers.extend(
+                    response
+                        .all_peers()
+                        .iter()
+                        .map(|peer| SocketAddr::V4(peer.addr())),
+                ),
+                Err(err) => warn!("announce to {url} failed: {err:#}"),
+            }
+        }
+        if let Some(dht) = dht {
+            peers.extend(dht.get_peers(self.info_hash).await);
+        }
+        peers.retain(|addr| !ip_filter.is_blocked(addr.ip()));
+        peers.sort();
+        peers.dedup();
+
+        let mut peers = peers.into_iter();
+        let mut fetches = JoinSet::new();
+        loop {
+            while fetches.len() < MAX_PARALLEL_PEERS {
+                let Some(addr) = peers.next() else {
+                    break;
+                };
+                let info_hash = self.info_hash;
+                let transports = transports.clone();
+                fetches.spawn(async move {
+                    let fetch = metadata::fetch(info_hash, addr, &transports, encryption);
+                    match timeout(METADATA_TIMEOUT, fetch).await {
+                        Ok(fetched) => fetched,
+                        Err(_) => bail!("peer {addr} took too long to send the metadata"),
+                    }
+                });
+            }
+            let Some(fetched) = fetches.join_next().await else {
+                bail!("no peer sent the metadata");
+            };
+            match fetched.context("metadata fetch panicked")? {
+                Ok(info) => return self.torrent_from_info(&info),
+                Err(err) => debug!("{err:#}"),
+            }
+        }
+    }
+
+    fn torrent_from_info(&self, bytes: &[u8]) -> anyhow::Result<Torrent> {
+        let mut torrent = Torrent::from_info_bytes(bytes)?;
+        torrent.announce = self.trackers.first().cloned().unwrap_or_default();
+        torrent.announce_list = self.trackers.iter().map(|url| vec![url.clone()]).collect();
+        // the peer checked the bytes hash to our info hash, the torrent must agree on how to hash them.
+        if !torrent.info_hashes().contains(&self.info_hash) {
+            bail!("the info dictionary doesn't match the info hash");
+        }
+        Ok(torrent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+
+    #[test]
+    fn torrents_from_info_keep_unknown_keys_and_the_trackers() {
+        let mut info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend([7; 20]);
+        info.extend(b"6:source3:abce");
+        let info_hash = hex::encode(<[u8; 20]>::from(Sha1::digest(&info)));
+        let magnet: Magnet = format!(
+            "magnet:?xt=urn:btih:{info_hash}&tr=http%3A%2F%2Fa.invalid&tr=http%3A%2F%2Fb.invalid"
+        )
+        .parse()
+        .unwrap();
+
+        let torrent = magnet.torrent_from_info(&info).unwrap();
+        assert_eq!(hex::encode(torrent.info_hash_bytes()), info_hash);
+        assert_eq!(
+            torrent.trackers(),
+            [vec!["http://a.invalid"], vec!["http://b.invalid"]]
+        );
+
+        let other = [&info[..info.len() - 2], b"de"].concat();
+        assert!(magnet.torrent_from_info(&other).is_err());
+    }
+}
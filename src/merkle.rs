@@ -0,0 +1,295 @@
+use anyhow::bail;
+
+use crate::{bitfield::Bitfield, sha256::Sha256, storage::Storage, torrent::Torrent, BLOCK_MAX};
+
+/// A node of the merkle tree of a file in a v2 torrent (BEP 52).
+pub type Hash = [u8; 32];
+
+/// The most hashes a peer may ask for in a single hash request.
+pub const MAX_HASHES: usize = 512;
+
+/// The hash of a node from the hashes of its two children.
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+/// The root of a subtree of 2^`level` leaves past the end of the file, whose leaves are all zeros.
+pub fn pad_hash(level: u32) -> Hash {
+    let mut hash = [0; 32];
+    for _ in 0..level {
+        hash = hash_pair(&hash, &hash);
+    }
+    hash
+}
+
+/// The leaves of the tree of `data`, the hashes of its 16 KiB blocks. The last block may be shorter.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_MAX).map(Sha256::digest).collect()
+}
+
+/// The layers of the tree whose base layer is `hashes` at `level`, padded to `width` hashes
+/// which must be a power of two. The first layer is the base, the last one the root.
+pub fn layers(hashes: &[Hash], width: usize, level: u32) -> Vec<Vec<Hash>> {
+    let mut base = hashes.to_vec();
+    base.resize(width.max(1), pad_hash(level));
+
+    let mut layers = vec![base];
+    while let Some(layer) = layers.last().filter(|layer| layer.len() > 1) {
+        let parents = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        layers.push(parents);
+    }
+    layers
+}
+
+/// The root of the tree whose base layer is `hashes` at `level`, padded to `width` hashes.
+pub fn root(hashes: &[Hash], width: usize, level: u32) -> Hash {
+    layers(hashes, width, level).last().unwrap()[0]
+}
+
+/// Whether `hashes`, answering `request` for a layer `height` levels below the root,
+/// prove themselves up to the pieces root: the hashes asked for come first, then their uncles.
+pub fn verify_proof(request: &HashRequest, hashes: &[Hash], height: u32) -> bool {
+    let length = request.length as usize;
+    let span = length.trailing_zeros();
+    if !length.is_power_of_two()
+        || span > height
+        || !(request.index as usize).is_multiple_of(length)
+        || hashes.len() != length + (height - span) as usize
+    {
+        return false;
+    }
+    let mut node = root(&hashes[..length], length, request.base_layer);
+    let mut position = request.index as usize >> span;
+    for uncle in &hashes[length..] {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position >>= 1;
+    }
+    node == request.pieces_root
+}
+
+/// The part of a file's merkle tree a hash request asks for, hashes and hash reject messages repeat it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashRequest {
+    /// The root of the file's tree.
+    pub pieces_root: Hash,
+    /// The layer the hashes are from, the leaves are layer 0.
+    pub base_layer: u32,
+    /// The first hash wanted, a multiple of `length`.
+    pub index: u32,
+    /// How many hashes are wanted, a power of two.
+    pub length: u32,
+    /// How many layers of uncle hashes proving the hashes up to the root are wanted.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub const SIZE: usize = 48;
+
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<Self> {
+        if payload.len() < Self::SIZE {
+            bail!("hash request must be {} bytes", Self::SIZE);
+        }
+        let number = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().unwrap());
+        Ok(Self {
+            pieces_root: payload[..32].try_into().unwrap(),
+            base_layer: number(32),
+            index: number(36),
+            length: number(40),
+            proof_layers: number(44),
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.extend(self.pieces_root);
+        payload.extend(self.base_layer.to_be_bytes());
+        payload.extend(self.index.to_be_bytes());
+        payload.extend(self.length.to_be_bytes());
+        payload.extend(self.proof_layers.to_be_bytes());
+        payload
+    }
+}
+
+/// The hashes answering `request`, those asked for followed by the uncle hashes proving them
+/// from the lowest layer up, or `None` if it must be rejected. The layers above the piece layer
+/// come from the piece layers of the metainfo, those below it are only available within
+/// a single piece we have, whose blocks are hashed again.
+pub fn answer(
+    torrent: &Torrent,
+    storage: &Storage,
+    have: &Bitfield,
+    request: &HashRequest,
+) -> anyhow::Result<Option<Vec<Hash>>> {
+    let index = request.index as usize;
+    let length = request.length as usize;
+    if !length.is_power_of_two()
+        || !(2..=MAX_HASHES).contains(&length)
+        || !index.is_multiple_of(length)
+    {
+        return Ok(None);
+    }
+    let Some(file) = torrent.file_with_root(&request.pieces_root) else {
+        return Ok(None);
+    };
+
+    let piece_length = torrent.info.piece_length;
+    let piece_level = (piece_length / BLOCK_MAX).trailing_zeros();
+    let leaves = file.length.div_ceil(BLOCK_MAX);
+
+    // files spanning several pieces have their piece layer in the metainfo.
+    let upper = if file.length > piece_length {
+        let Some(layer) = torrent.piece_layer(&request.pieces_root) else {
+            return Ok(None);
+        };
+        Some(layers(&layer, layer.len().next_power_of_two(), piece_level))
+    } else {
+        None
+    };
+    let height = match &upper {
+        Some(upper) => piece_level + upper.len() as u32 - 1,
+        None => leaves.next_power_of_two().trailing_zeros(),
+    };
+    let base = request.base_layer;
+    if base > height || index + length > 1 << (height - base) {
+        return Ok(None);
+    }
+
+    let lower = match &upper {
+        Some(_) if base >= piece_level => None,
+        _ => {
+            // the hashes must all be in one piece.
+            if upper.is_some() && length << base > 1 << piece_level {
+                return Ok(None);
+            }
+            let piece = (index << base) >> piece_level;
+            let piece_index = file.offset / piece_length + piece;
+            if !have.get(piece_index) {
+                return Ok(None);
+            }
+            let mut data = storage.read_piece(piece_index, torrent.piece_size(piece_index))?;
+            // hybrid torrents pad the last piece of a file.
+            data.truncate(file.length - piece * piece_length);
+            let width = match &upper {
+                Some(_) => 1 << piece_level,
+                None => leaves.next_power_of_two(),
+            };
+            Some((piece, layers(&block_hashes(&data), width, 0)))
+        }
+    };
+
+    let hash_at = |level: u32, position: usize| match (&upper, &lower) {
+        (Some(upper), _) if level >= piece_level => {
+            upper[(level - piece_level) as usize].get(position).copied()
+        }
+        (_, Some((piece, lower))) => {
+            let first = match &upper {
+                Some(_) => piece << (piece_level - level),
+                None => 0,
+            };
+            lower[level as usize]
+                .get(position.checked_sub(first)?)
+                .copied()
+        }
+        _ => None,
+    };
+    Ok(collect_hashes(hash_at, request, height))
+}
+
+/// The hashes asked for by `request`, then their uncles up to `height`, the layer of the root.
+fn collect_hashes(
+    hash_at: impl Fn(u32, usize) -> Option<Hash>,
+    request: &HashRequest,
+    height: u32,
+) -> Option<Vec<Hash>> {
+    let index = request.index as usize;
+    let length = request.length as usize;
+    let mut hashes = (index..index + length)
+        .map(|position| hash_at(request.base_layer, position))
+        .collect::<Option<Vec<_>>>()?;
+
+    // the requested hashes prove the layers up to their subtree root themselves.
+    let span = length.trailing_zeros();
+    let subtree = request.base_layer + span;
+    let uncles = request
+        .proof_layers
+        .saturating_sub(span)
+        .min(height - subtree);
+    let mut position = index >> span;
+    for level in subtree..subtree + uncles {
+        hashes.push(hash_at(level, position ^ 1)?);
+        position >>= 1;
+    }
+    Some(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(byte: u8) -> Hash {
+        Sha256::digest(&[byte])
+    }
+
+    #[test]
+    fn trees_are_padded_with_zero_subtrees() {
+        assert_eq!(pad_hash(0), [0; 32]);
+        assert_eq!(pad_hash(2), hash_pair(&pad_hash(1), &pad_hash(1)));
+
+        let (a, b, c) = (leaf(1), leaf(2), leaf(3));
+        let expected = hash_pair(&hash_pair(&a, &b), &hash_pair(&c, &[0; 32]));
+        assert_eq!(root(&[a, b, c], 4, 0), expected);
+        let layers = layers(&[a, b, c], 4, 0);
+        assert_eq!(layers.iter().map(Vec::len).collect::<Vec<_>>(), [4, 2, 1]);
+    }
+
+    #[test]
+    fn hash_requests_round_trip() {
+        let request = HashRequest {
+            pieces_root: leaf(1),
+            base_layer: 2,
+            index: 512,
+            length: 512,
+            proof_layers: 3,
+        };
+        let payload = request.to_payload();
+        assert_eq!(payload.len(), HashRequest::SIZE);
+        assert_eq!(HashRequest::from_payload(&payload).unwrap(), request);
+        assert!(HashRequest::from_payload(&payload[..47]).is_err());
+    }
+
+    #[test]
+    fn proofs_lead_to_the_root() {
+        let layer: Vec<Hash> = (0..8).map(leaf).collect();
+        let layers = layers(&layer, 8, 2);
+        let request = HashRequest {
+            pieces_root: layers[3][0],
+            base_layer: 2,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        // the two hashes asked for, then the uncle of their parent and of their grandparent.
+        let mut hashes = vec![layer[4], layer[5], layers[1][3], layers[2][0]];
+        assert!(verify_proof(&request, &hashes, 3));
+
+        // the uncles' order matters, as do all the hashes.
+        hashes.swap(2, 3);
+        assert!(!verify_proof(&request, &hashes, 3));
+        hashes.swap(2, 3);
+        hashes[0][0] ^= 1;
+        assert!(!verify_proof(&request, &hashes, 3));
+        hashes[0][0] ^= 1;
+        // one uncle short of the root.
+        assert!(!verify_proof(&request, &hashes[..3], 3));
+    }
+}
@@ -0,0 +1,217 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, Context};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio_util::codec::Framed;
+
+use crate::{
+    extension::{ExtensionHandshake, HANDSHAKE_ID, UT_METADATA, UT_METADATA_ID},
+    mse::EncryptionPolicy,
+    peer::PeerConnection,
+    peer_message::{Message, MessageFramer, MessageTag},
+    transport::Transports,
+    BLOCK_MAX,
+};
+
+/// The largest info dictionary we accept from a peer.
+const MAX_METADATA_SIZE: usize = 16 << 20;
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+/// A ut_metadata message (BEP 9), the info dictionary is exchanged in 16 KiB pieces.
+/// Data messages are followed by the piece, after the bencoded header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub fn request(piece: usize) -> Self {
+        Self {
+            msg_type: REQUEST,
+            piece,
+            total_size: None,
+        }
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.msg_type == REQUEST
+    }
+
+    pub fn piece(&self) -> usize {
+        self.piece
+    }
+
+    /// Parses the header of the message, returning it with the piece data that follows.
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        let length = bencode_length(payload).context("invalid metadata message")?;
+        let message = serde_bencode::from_bytes(&payload[..length])
+            .context("decode metadata message fail")?;
+        Ok((message, &payload[length..]))
+    }
+
+    pub fn to_payload(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("encode metadata message fail")
+    }
+
+    /// The answer to a request for a piece of `info`, the piece itself or a reject.
+    pub fn answer(&self, info: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // the piece comes from the peer, far past the end it overflows.
+        let start = self
+            .piece
+            .checked_mul(BLOCK_MAX)
+            .filter(|&start| start < info.len());
+        let Some(start) = start else {
+            return Self {
+                msg_type: REJECT,
+                piece: self.piece,
+                total_size: None,
+            }
+            .to_payload();
+        };
+        let mut payload = Self {
+            msg_type: DATA,
+            piece: self.piece,
+            total_size: Some(info.len()),
+        }
+        .to_payload()?;
+        payload.extend_from_slice(&info[start..(start + BLOCK_MAX).min(info.len())]);
+        Ok(payload)
+    }
+}
+
+/// Downloads the info dictionary with the given info hash from the peer at `addr`,
+/// checking it against the info hash.
+pub async fn fetch(
+    info_hash: [u8; 20],
+    addr: SocketAddr,
+    transports: &Transports,
+    encryption: EncryptionPolicy,
+) -> anyhow::Result<Vec<u8>> {
+    let (stream, handshake) =
+        PeerConnection::open(info_hash, false, addr, transports, encryption).await?;
+    if !handshake.supports_extensions() {
+        bail!("peer {addr} doesn't support the extension protocol");
+    }
+    let mut framed = Framed::new(stream, MessageFramer);
+
+    // we don't listen for this torrent yet, nor have anything to exchange peers about.
+    let mut ours = ExtensionHandshake::new(0).without_pex();
+    ours.p = None;
+    ours.m.insert(UT_METADATA.to_string(), UT_METADATA_ID);
+    framed
+        .send(Message::new_extended(HANDSHAKE_ID, &ours.to_bytes()?))
+        .await?;
+
+    let mut metadata = Vec::new();
+    let mut received = Vec::new();
+    while let Some(message) = framed.next().await {
+        let message = message.with_context(|| format!("peer {addr} failed"))?;
+        if message.tag != MessageTag::Extended {
+            continue;
+        }
+        let Some((&id, payload)) = message.payload.split_first() else {
+            bail!("peer {addr} sent an empty extended message");
+        };
+
+        if id == HANDSHAKE_ID {
+            let theirs = ExtensionHandshake::from_bytes(payload)?;
+            let Some(&their_id) = theirs.m.get(UT_METADATA).filter(|&&id| id != 0) else {
+                bail!("peer {addr} doesn't share metadata");
+            };
+            let size = theirs
+                .metadata_size
+                .filter(|&size| (1..=MAX_METADATA_SIZE).contains(&size))
+                .with_context(|| format!("peer {addr} sent no valid metadata size"))?;
+            metadata = vec![0; size];
+            received = vec![false; size.div_ceil(BLOCK_MAX)];
+            for piece in 0..received.len() {
+                let request = MetadataMessage::request(piece).to_payload()?;
+                framed
+                    .send(Message::new_extended(their_id, &request))
+                    .await?;
+            }
+        } else if id == UT_METADATA_ID {
+            let (header, data) = MetadataMessage::from_payload(payload)
+                .with_context(|| format!("peer {addr} sent an invalid metadata message"))?;
+            let start = header
+                .piece
+                .checked_mul(BLOCK_MAX)
+                .filter(|&start| start < metadata.len());
+            match (header.msg_type, start) {
+                (DATA, Some(start)) => {
+                    let end = (start + BLOCK_MAX).min(metadata.len());
+                    if data.len() != end - start {
+                        bail!(
+                            "peer {addr} sent metadata piece {} of the wrong size",
+                            header.piece
+                        );
+                    }
+                    metadata[start..end].copy_from_slice(data);
+                    received[header.piece] = true;
+                }
+                (REJECT, _) => bail!("peer {addr} rejected our metadata request"),
+                _ => {}
+            }
+            if !received.is_empty() && received.iter().all(|&piece| piece) {
+                if <[u8; 20]>::from(Sha1::digest(&metadata)) != info_hash {
+                    bail!("peer {addr} sent metadata that doesn't match the info hash");
+                }
+                return Ok(metadata);
+            }
+        }
+    }
+    bail!("peer {addr} closed the connection before sending the metadata")
+}
+
+/// The length of the bencoded value at the start of `bytes`.
+pub(crate) fn bencode_length(bytes: &[u8]) -> Option<usize> {
+    match bytes.first()? {
+        b'i' => Some(bytes.iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut at = 1;
+            while *bytes.get(at)? != b'e' {
+                at += bencode_length(&bytes[at..])?;
+            }
+            Some(at + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|&b| b == b':')?;
+            let length: usize = std::str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            let end = colon + 1 + length;
+            (end <= bytes.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_past_the_end_are_rejected() {
+        let info = vec![7; BLOCK_MAX + 10];
+        let answer = MetadataMessage::request(1).answer(&info).unwrap();
+        let (header, data) = MetadataMessage::from_payload(&answer).unwrap();
+        assert_eq!(
+            (header.msg_type, header.total_size),
+            (DATA, Some(info.len()))
+        );
+        assert_eq!(data, &info[BLOCK_MAX..]);
+
+        for piece in [2, usize::MAX / BLOCK_MAX + 1, i64::MAX as usize] {
+            let answer = MetadataMessage::request(piece).answer(&info).unwrap();
+            let (header, data) = MetadataMessage::from_payload(&answer).unwrap();
+            assert_eq!((header.msg_type, header.piece), (REJECT, piece));
+            assert!(data.is_empty());
+        }
+    }
+}
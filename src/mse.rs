@@ -0,0 +1,729 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::random;
+
+/// The prime of the Diffie-Hellman key exchange, 768 bits, the generator is 2.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                     020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                     4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// Length of the public keys and the shared secret, in bytes.
+const KEY_LENGTH: usize = 96;
+
+/// Limbs of 64 bits in a number modulo the prime, least significant first.
+const LIMBS: usize = KEY_LENGTH / 8;
+
+/// The longest padding either side may send.
+const MAX_PAD: usize = 512;
+
+/// The verification constant, sent encrypted so each side can check the keys match.
+const VC: [u8; 8] = [0; 8];
+
+/// The methods offered in crypto_provide and picked in crypto_select.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// The first bytes of a plaintext handshake, how unencrypted incoming connections are recognized.
+const PLAINTEXT_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Whether peer connections are encrypted with message stream encryption.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Only plaintext connections.
+    Disabled,
+    /// Outgoing connections try encryption first and fall back to plaintext,
+    /// incoming connections may use either.
+    #[default]
+    Enabled,
+    /// Only encrypted connections, the payload is always RC4 encrypted.
+    Forced,
+}
+
+impl EncryptionPolicy {
+    /// The payload encryption methods we accept.
+    fn crypto_provide(self) -> u32 {
+        match self {
+            Self::Disabled => CRYPTO_PLAINTEXT,
+            Self::Enabled => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            Self::Forced => CRYPTO_RC4,
+        }
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "enabled" => Ok(Self::Enabled),
+            "forced" => Ok(Self::Forced),
+            _ => bail!("encryption must be disabled, enabled or forced"),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Disabled => "disabled",
+            Self::Enabled => "enabled",
+            Self::Forced => "forced",
+        })
+    }
+}
+
+/// A peer connection that may be RC4 encrypted, read and written like the underlying stream.
+pub struct CryptoStream<S> {
+    inner: S,
+
+    /// Bytes already read from the peer and decrypted, handed out before reading more.
+    pending: Vec<u8>,
+
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+
+    /// Encrypted bytes not written to the stream yet, the keystream already moved past them.
+    outgoing: Vec<u8>,
+    written: usize,
+}
+
+impl<S> CryptoStream<S> {
+    /// A stream that isn't encrypted at all.
+    pub fn plaintext(inner: S) -> Self {
+        Self::new(inner, Vec::new(), None, None)
+    }
+
+    fn new(inner: S, pending: Vec<u8>, decrypt: Option<Rc4>, encrypt: Option<Rc4>) -> Self {
+        Self {
+            inner,
+            pending,
+            decrypt,
+            encrypt,
+            outgoing: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Whether the payload is encrypted, the negotiation itself always is.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CryptoStream<S> {
+    /// Writes out the bytes we encrypted earlier.
+    fn poll_outgoing(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.outgoing.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.outgoing.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CryptoStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.pending.is_empty() {
+            let length = this.pending.len().min(buf.remaining());
+            buf.put_slice(&this.pending[..length]);
+            this.pending.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CryptoStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // once encrypted the bytes are as good as sent, the keystream can't go back.
+        ready!(this.poll_outgoing(cx))?;
+        this.outgoing.extend_from_slice(buf);
+        if let Some(encrypt) = &mut this.encrypt {
+            encrypt.apply(&mut this.outgoing);
+        }
+        if let Poll::Ready(Err(err)) = this.poll_outgoing(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Negotiates encryption on a connection we opened to a peer of the torrent `info_hash`.
+/// The bittorrent handshake is sent afterwards through the returned stream.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> anyhow::Result<CryptoStream<S>> {
+    let private: [u8; 20] = random::bytes()?;
+    stream.write_all(&public_key(&private)).await?;
+    write_pad(&mut stream).await?;
+
+    let mut their_key = [0; KEY_LENGTH];
+    stream
+        .read_exact(&mut their_key)
+        .await
+        .context("peer doesn't support encryption")?;
+    let secret = shared_secret(&their_key, &private)?;
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    // the info hash is obfuscated, the receiver finds it among the torrents it serves.
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+
+    let mut header = VC.to_vec();
+    header.extend(policy.crypto_provide().to_be_bytes());
+    // no padding and no initial payload, the handshake follows the negotiation.
+    header.extend(0u16.to_be_bytes());
+    header.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut header);
+    message.extend(header);
+    stream.write_all(&message).await?;
+
+    // the receiver's padding is followed by the encrypted verification constant.
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    synchronize(&mut stream, &vc).await?;
+
+    let mut select = [0; 6];
+    stream.read_exact(&mut select).await?;
+    decrypt.apply(&mut select);
+    let crypto_select = u32::from_be_bytes(select[..4].try_into()?);
+    skip_pad(&mut stream, &mut decrypt, &select[4..]).await?;
+
+    match crypto_select & policy.crypto_provide() {
+        CRYPTO_RC4 => Ok(CryptoStream::new(
+            stream,
+            Vec::new(),
+            Some(decrypt),
+            Some(encrypt),
+        )),
+        CRYPTO_PLAINTEXT => Ok(CryptoStream::plaintext(stream)),
+        _ => bail!("peer selected unsupported encryption {crypto_select:#x}"),
+    }
+}
+
+/// Negotiates encryption on a connection a peer opened to us, for one of the torrents `info_hashes`.
+/// Plaintext connections are let through when the policy allows it, the handshake is read from the returned stream.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> anyhow::Result<CryptoStream<S>> {
+    let mut their_key = [0; KEY_LENGTH];
+    stream.read_exact(&mut their_key[..20]).await?;
+    if &their_key[..20] == PLAINTEXT_HEADER {
+        if policy == EncryptionPolicy::Forced {
+            bail!("peer didn't encrypt the connection");
+        }
+        return Ok(CryptoStream::new(
+            stream,
+            their_key[..20].to_vec(),
+            None,
+            None,
+        ));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        bail!("peer wants an encrypted connection");
+    }
+    stream.read_exact(&mut their_key[20..]).await?;
+
+    let private: [u8; 20] = random::bytes()?;
+    let secret = shared_secret(&their_key, &private)?;
+    stream.write_all(&public_key(&private)).await?;
+    write_pad(&mut stream).await?;
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut obfuscated = [0; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| {
+            let req2 = hash(&[b"req2", info_hash.as_slice()]);
+            req2.iter().zip(req3).map(|(a, b)| a ^ b).eq(obfuscated)
+        })
+        .context("peer wants a torrent we don't serve")?;
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut header = [0; 14];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        bail!("peer sent an invalid verification constant");
+    }
+    let crypto_provide = u32::from_be_bytes(header[8..12].try_into()?);
+    skip_pad(&mut stream, &mut decrypt, &header[12..]).await?;
+
+    // the initial payload is the start of the handshake, if the peer sent it already.
+    let mut length = [0; 2];
+    stream.read_exact(&mut length).await?;
+    decrypt.apply(&mut length);
+    let mut initial_payload = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut initial_payload).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let crypto_select = match crypto_provide & policy.crypto_provide() {
+        provided if provided & CRYPTO_RC4 != 0 => CRYPTO_RC4,
+        provided if provided & CRYPTO_PLAINTEXT != 0 => CRYPTO_PLAINTEXT,
+        _ => bail!("peer provides no encryption we accept ({crypto_provide:#x})"),
+    };
+    let mut message = VC.to_vec();
+    message.extend(crypto_select.to_be_bytes());
+    message.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut message);
+    stream.write_all(&message).await?;
+
+    if crypto_select == CRYPTO_PLAINTEXT {
+        return Ok(CryptoStream::new(stream, initial_payload, None, None));
+    }
+    Ok(CryptoStream::new(
+        stream,
+        initial_payload,
+        Some(decrypt),
+        Some(encrypt),
+    ))
+}
+
+/// Sends a random amount of random padding after our public key, so the negotiation has no fixed length.
+async fn write_pad<S: AsyncWrite + Unpin>(stream: &mut S) -> anyhow::Result<()> {
+    let mut pad = vec![0; random::below(MAX_PAD as u64 + 1)? as usize];
+    random::fill(&mut pad)?;
+    stream.write_all(&pad).await?;
+    Ok(())
+}
+
+/// Reads and discards padding whose length is the two bytes `length`.
+async fn skip_pad<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    decrypt: &mut Rc4,
+    length: &[u8],
+) -> anyhow::Result<()> {
+    let length = u16::from_be_bytes(length.try_into()?) as usize;
+    if length > MAX_PAD {
+        bail!("peer sent {length} bytes of padding");
+    }
+    let mut pad = vec![0; length];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    Ok(())
+}
+
+/// Reads until `marker`, which comes after the other side's padding of unknown length.
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> anyhow::Result<()> {
+    let mut received = Vec::with_capacity(MAX_PAD + marker.len());
+    while !received.ends_with(marker) {
+        if received.len() == MAX_PAD + marker.len() {
+            bail!("peer's encryption handshake is out of sync");
+        }
+        received.push(stream.read_u8().await?);
+    }
+    Ok(())
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// RC4 whose first kilobyte of keystream is discarded, as the spec requires.
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::keyed(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    /// Plain RC4, with its weak first bytes.
+    fn keyed(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts `data` in place, it's the same operation.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/// Our public key for the `private` one, 2^private mod the prime.
+fn public_key(private: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut generator = [0; KEY_LENGTH];
+    generator[KEY_LENGTH - 1] = 2;
+    Modulus::prime().pow(&generator, private)
+}
+
+/// The secret both sides share, their public key to the power of our private one.
+fn shared_secret(their_key: &[u8; KEY_LENGTH], private: &[u8]) -> anyhow::Result<[u8; KEY_LENGTH]> {
+    let modulus = Modulus::prime();
+    let key = to_limbs(their_key);
+    if (key[1..].iter().all(|&limb| limb == 0) && key[0] <= 1) || !less_than(&key, &modulus.n) {
+        bail!("peer sent an invalid public key");
+    }
+    Ok(modulus.pow(their_key, private))
+}
+
+type Limbs = [u64; LIMBS];
+
+/// Arithmetic modulo an odd number, in Montgomery form.
+struct Modulus {
+    n: Limbs,
+    /// -n^-1 mod 2^64.
+    n_inv: u64,
+    /// 2^(128 * LIMBS) mod n, multiplying by it brings numbers into Montgomery form.
+    r2: Limbs,
+}
+
+impl Modulus {
+    fn prime() -> Self {
+        let mut prime = [0; KEY_LENGTH];
+        hex::decode_to_slice(PRIME, &mut prime).expect("the prime is valid hex");
+        let n = to_limbs(&prime);
+
+        let mut inverse = 1u64;
+        for _ in 0..6 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(n[0].wrapping_mul(inverse)));
+        }
+
+        // doubling 1 twice the bit length of the number of times gives r^2.
+        let mut r2 = [0; LIMBS];
+        r2[0] = 1;
+        for _ in 0..2 * 64 * LIMBS {
+            let carry = shift_left(&mut r2);
+            if carry || !less_than(&r2, &n) {
+                subtract(&mut r2, &n);
+            }
+        }
+
+        Self {
+            n,
+            n_inv: inverse.wrapping_neg(),
+            r2,
+        }
+    }
+
+    /// `base` to the power of `exponent`, both big endian.
+    fn pow(&self, base: &[u8; KEY_LENGTH], exponent: &[u8]) -> [u8; KEY_LENGTH] {
+        let mut one = [0; LIMBS];
+        one[0] = 1;
+        let base = self.multiply(&to_limbs(base), &self.r2);
+        let mut result = self.multiply(&one, &self.r2);
+        for byte in exponent {
+            for bit in (0..8).rev() {
+                result = self.multiply(&result, &result);
+                if (byte >> bit) & 1 == 1 {
+                    result = self.multiply(&result, &base);
+                }
+            }
+        }
+        from_limbs(&self.multiply(&result, &one))
+    }
+
+    /// Montgomery multiplication, a * b / 2^(64 * LIMBS) mod n.
+    fn multiply(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u64; LIMBS + 2];
+        for &a in a {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let sum = t[j] as u128 + a as u128 * b[j] as u128 + carry as u128;
+                t[j] = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            let sum = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS] = sum as u64;
+            t[LIMBS + 1] = (sum >> 64) as u64;
+
+            let m = t[0].wrapping_mul(self.n_inv);
+            let sum = t[0] as u128 + m as u128 * self.n[0] as u128;
+            let mut carry = (sum >> 64) as u64;
+            for j in 1..LIMBS {
+                let sum = t[j] as u128 + m as u128 * self.n[j] as u128 + carry as u128;
+                t[j - 1] = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            let sum = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS - 1] = sum as u64;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 64) as u64;
+        }
+
+        let mut result: Limbs = t[..LIMBS].try_into().unwrap();
+        if t[LIMBS] != 0 || !less_than(&result, &self.n) {
+            subtract(&mut result, &self.n);
+        }
+        result
+    }
+}
+
+fn to_limbs(bytes: &[u8; KEY_LENGTH]) -> Limbs {
+    let mut limbs = [0; LIMBS];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.rchunks(8)) {
+        *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+    }
+    limbs
+}
+
+fn from_limbs(limbs: &Limbs) -> [u8; KEY_LENGTH] {
+    let mut bytes = [0; KEY_LENGTH];
+    for (limb, chunk) in limbs.iter().zip(bytes.rchunks_mut(8)) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn less_than(a: &Limbs, b: &Limbs) -> bool {
+    a.iter().rev().cmp(b.iter().rev()).is_lt()
+}
+
+/// a -= b, wrapping around.
+fn subtract(a: &mut Limbs, b: &Limbs) {
+    let mut borrow = false;
+    for (a, &b) in a.iter_mut().zip(b) {
+        let (difference, borrow1) = a.overflowing_sub(b);
+        let (difference, borrow2) = difference.overflowing_sub(borrow as u64);
+        *a = difference;
+        borrow = borrow1 || borrow2;
+    }
+}
+
+/// a <<= 1, returning the bit shifted out.
+fn shift_left(a: &mut Limbs) -> bool {
+    let mut carry = 0;
+    for limb in a.iter_mut() {
+        let next = *limb >> 63;
+        *limb = (*limb << 1) | carry;
+        carry = next;
+    }
+    carry == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [4; 20];
+
+    #[test]
+    fn rc4_matches_the_reference_keystream() {
+        let mut data = *b"Plaintext";
+        Rc4::keyed(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+
+        // the encryption keystream starts a kilobyte in.
+        let mut skipped = [0; 1024 + 16];
+        Rc4::keyed(b"Key").apply(&mut skipped);
+        let mut keystream = [0; 16];
+        Rc4::new(b"Key").apply(&mut keystream);
+        assert_eq!(keystream, skipped[1024..]);
+    }
+
+    #[test]
+    fn modular_exponentiation() {
+        let mut exponent = [0; 20];
+        exponent[19] = 16;
+        assert_eq!(public_key(&exponent)[KEY_LENGTH - 3..], [1, 0, 0]);
+
+        // fermat's little theorem: 2^(p - 1) = 1 mod p.
+        let mut p_minus_one = [0; KEY_LENGTH];
+        hex::decode_to_slice(PRIME, &mut p_minus_one).unwrap();
+        p_minus_one[KEY_LENGTH - 1] -= 1;
+        let mut one = [0; KEY_LENGTH];
+        one[KEY_LENGTH - 1] = 1;
+        assert_eq!(public_key(&p_minus_one), one);
+    }
+
+    #[test]
+    fn both_sides_compute_the_same_secret() {
+        let (a, b): ([u8; 20], [u8; 20]) = (random::bytes().unwrap(), random::bytes().unwrap());
+        let secret_a = shared_secret(&public_key(&b), &a).unwrap();
+        let secret_b = shared_secret(&public_key(&a), &b).unwrap();
+        assert_eq!(secret_a, secret_b);
+    }
+
+    #[test]
+    fn weak_public_keys_are_refused() {
+        let private: [u8; 20] = random::bytes().unwrap();
+        let mut key = [0; KEY_LENGTH];
+        assert!(shared_secret(&key, &private).is_err());
+        key[KEY_LENGTH - 1] = 1;
+        assert!(shared_secret(&key, &private).is_err());
+        hex::decode_to_slice(PRIME, &mut key).unwrap();
+        assert!(shared_secret(&key, &private).is_err());
+    }
+
+    /// Negotiates over an in-memory connection, with `info_hashes` served by the receiver.
+    async fn negotiate(
+        initiator: EncryptionPolicy,
+        receiver: EncryptionPolicy,
+        info_hashes: &[[u8; 20]],
+    ) -> (
+        anyhow::Result<CryptoStream<DuplexStream>>,
+        anyhow::Result<CryptoStream<DuplexStream>>,
+    ) {
+        let (ours, theirs) = duplex(4096);
+        tokio::join!(
+            initiate(ours, &INFO_HASH, initiator),
+            accept(theirs, info_hashes, receiver)
+        )
+    }
+
+    /// Sends a message each way and checks what arrived.
+    async fn exchange(mut a: CryptoStream<DuplexStream>, mut b: CryptoStream<DuplexStream>) {
+        a.write_all(b"hello").await.unwrap();
+        let mut received = [0; 5];
+        b.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        b.write_all(b"world").await.unwrap();
+        a.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"world");
+    }
+
+    #[tokio::test]
+    async fn encrypted_connections_carry_data() {
+        let (a, b) = negotiate(
+            EncryptionPolicy::Forced,
+            EncryptionPolicy::Enabled,
+            &[[1; 20], INFO_HASH],
+        )
+        .await;
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_encrypted() && b.is_encrypted());
+        exchange(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn plaintext_payload_is_selected_when_preferred() {
+        let (a, b) = negotiate(
+            EncryptionPolicy::Enabled,
+            EncryptionPolicy::Disabled,
+            &[INFO_HASH],
+        )
+        .await;
+        // the receiver refuses the encrypted negotiation altogether.
+        assert!(b.is_err());
+        assert!(a.is_err());
+
+        let (ours, theirs) = duplex(4096);
+        let (a, b) = tokio::join!(
+            initiate(ours, &INFO_HASH, EncryptionPolicy::Disabled),
+            accept(theirs, &[INFO_HASH], EncryptionPolicy::Enabled)
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert!(!a.is_encrypted() && !b.is_encrypted());
+        exchange(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn unknown_torrents_are_refused() {
+        let (_, b) = negotiate(
+            EncryptionPolicy::Enabled,
+            EncryptionPolicy::Enabled,
+            &[[1; 20]],
+        )
+        .await;
+        assert!(b.is_err());
+    }
+
+    #[tokio::test]
+    async fn plaintext_handshakes_pass_through() {
+        let (mut ours, theirs) = duplex(4096);
+        ours.write_all(PLAINTEXT_HEADER).await.unwrap();
+        ours.write_all(b"rest").await.unwrap();
+        let mut stream = accept(theirs, &[INFO_HASH], EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        let mut received = [0; 24];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received[..20], PLAINTEXT_HEADER);
+        assert_eq!(&received[20..], b"rest");
+
+        let (mut ours, theirs) = duplex(4096);
+        ours.write_all(PLAINTEXT_HEADER).await.unwrap();
+        assert!(accept(theirs, &[INFO_HASH], EncryptionPolicy::Forced)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,1540 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use crate::debug;
+use crate::{
+    bitfield::Bitfield,
+    extension::{ExtensionHandshake, HANDSHAKE_ID, UT_METADATA, UT_METADATA_ID},
+    handshake::Handshake,
+    merkle::{self, Hash, HashRequest},
+    metadata::MetadataMessage,
+    mse::{self, CryptoStream, EncryptionPolicy},
+    peer_message::{Message, MessageFramer, MessageTag},
+    ratelimit::Throttle,
+    storage::{DiskPool, Storage},
+    torrent::Torrent,
+    transport::{PeerStream, Transports},
+    BLOCK_MAX,
+};
+
+/// How long we wait for the handshakes to complete once connected.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long we wait for the first message after the handshake.
+/// Peers that have no pieces are allowed to skip the bitfield message entirely.
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we wait for the peer to unchoke us before giving up on it.
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long we wait for any message while we have outstanding requests.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of block requests we keep in flight on a single connection.
+const MAX_OUTSTANDING_REQUESTS: usize = 5;
+
+/// The largest block a peer may request from us, asking for more is a protocol violation.
+const MAX_REQUEST_LENGTH: usize = BLOCK_MAX;
+
+/// Maximum number of requests from the peer we queue before dropping new ones.
+const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// Maximum number of piece suggestions we remember from a peer.
+const MAX_SUGGESTED_PIECES: usize = 16;
+
+/// The state each side of the connection keeps about the other.
+/// Connections start out choked and not interested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerState {
+    /// We are choking the peer, we won't answer its requests.
+    pub am_choking: bool,
+    /// We told the peer we want pieces it has.
+    pub am_interested: bool,
+    /// The peer is choking us, it won't answer our requests.
+    pub peer_choking: bool,
+    /// The peer told us it wants pieces we have.
+    pub peer_interested: bool,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+/// A block of a piece, the unit of a request message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Block {
+    /// Parses the payload of a request or cancel message.
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<Self> {
+        if payload.len() != 12 {
+            bail!("request must contain index, begin and length");
+        }
+        Ok(Self {
+            piece: u32::from_be_bytes(payload[0..4].try_into()?),
+            begin: u32::from_be_bytes(payload[4..8].try_into()?),
+            length: u32::from_be_bytes(payload[8..12].try_into()?),
+        })
+    }
+
+    /// Splits the piece at `piece_index` whose size is `piece_size` into blocks of at most `BLOCK_MAX` bytes.
+    pub fn split_piece(piece_index: u32, piece_size: usize) -> VecDeque<Block> {
+        (0..piece_size)
+            .step_by(BLOCK_MAX)
+            .map(|begin| Block {
+                piece: piece_index,
+                begin: begin as u32,
+                length: BLOCK_MAX.min(piece_size - begin) as u32,
+            })
+            .collect()
+    }
+}
+
+/// The blocks of a piece we are downloading from a peer.
+struct PieceDownload {
+    index: u32,
+    data: Vec<u8>,
+    /// Blocks we haven't requested yet, or that the peer dropped or rejected.
+    pending: VecDeque<Block>,
+    /// Blocks we requested and are waiting for.
+    outstanding: Vec<Block>,
+}
+
+/// A connection to a single peer, along with what we know about the pieces it has.
+pub struct PeerConnection {
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    framed: Framed<CryptoStream<PeerStream>, MessageFramer>,
+
+    /// The pieces the peer has, built from its bitfield and updated by have messages.
+    bitfield: Bitfield,
+
+    /// The choke and interest state of both sides.
+    state: PeerState,
+
+    /// Both sides support the fast extension.
+    fast: bool,
+
+    /// Pieces the peer lets us request while it chokes us.
+    allowed_fast: HashSet<u32>,
+
+    /// Pieces we let the peer request while we choke it.
+    granted_fast: HashSet<u32>,
+
+    /// Pieces the peer suggested we download, most recent last.
+    suggested: VecDeque<u32>,
+
+    /// Both sides support the extension protocol.
+    extensions: bool,
+
+    /// The message ids the peer assigned to the extensions it supports.
+    extension_ids: HashMap<String, u8>,
+
+    /// The port the peer listens on, from its extension handshake.
+    listen_port: Option<u16>,
+
+    /// We opened the connection, so the peer accepts incoming connections.
+    outbound: bool,
+
+    /// The peer runs a DHT node.
+    dht: bool,
+
+    /// The port of the peer's DHT node, from its port message.
+    dht_port: Option<u16>,
+
+    /// Requests the peer made that we haven't answered yet.
+    requests: VecDeque<Block>,
+
+    /// Hash requests (BEP 52) the peer made that we haven't answered yet.
+    hash_requests: VecDeque<HashRequest>,
+
+    /// The peer supports v2 torrents, so it may answer our hash requests.
+    v2: bool,
+
+    /// Hashes the peer sent for our hash requests, not checked yet.
+    received_hashes: VecDeque<(HashRequest, Vec<Hash>)>,
+
+    /// Requests for pieces of the info dictionary (BEP 9) we haven't answered yet.
+    metadata_requests: VecDeque<MetadataMessage>,
+
+    /// The piece we are downloading from the peer.
+    download: Option<PieceDownload>,
+
+    /// The last piece whose blocks all arrived, waiting to be verified.
+    completed: Option<(u32, Vec<u8>)>,
+
+    /// The last have message announced a piece the peer didn't have yet.
+    new_have: bool,
+
+    /// The bitfield message is only valid as the first message after the handshake.
+    received_message: bool,
+
+    /// Same goes for the bitfield we send.
+    sent_message: bool,
+
+    /// The rate limits our traffic with the peer counts against.
+    throttle: Throttle,
+}
+
+impl PeerConnection {
+    /// Connects to `addr`, makes the handshake and reads the peer's bitfield if it sends one.
+    /// With encryption enabled, a peer that doesn't support it gets a plaintext connection instead.
+    pub async fn connect(
+        torrent: &Torrent,
+        addr: SocketAddr,
+        transports: &Transports,
+        encryption: EncryptionPolicy,
+    ) -> anyhow::Result<Self> {
+        let (stream, handshake) = Self::open(
+            torrent.info_hash_bytes(),
+            torrent.has_v2(),
+            addr,
+            transports,
+            encryption,
+        )
+        .await?;
+
+        let mut peer = Self::from_stream(stream, addr, &handshake, torrent.num_pieces());
+        peer.outbound = true;
+        peer.receive_bitfield().await?;
+        Ok(peer)
+    }
+
+    /// Connects to `addr` and exchanges the handshakes for `info_hash`, before we know
+    /// anything else about the torrent. Returns the stream and the peer's handshake.
+    pub(crate) async fn open(
+        info_hash: [u8; 20],
+        v2: bool,
+        addr: SocketAddr,
+        transports: &Transports,
+        encryption: EncryptionPolicy,
+    ) -> anyhow::Result<(CryptoStream<PeerStream>, Handshake)> {
+        let open =
+            |encrypted| Self::open_with(info_hash, v2, addr, transports, encrypted, encryption);
+        match encryption {
+            EncryptionPolicy::Disabled => open(false).await,
+            EncryptionPolicy::Forced => open(true).await,
+            EncryptionPolicy::Enabled => match open(true).await {
+                Ok(opened) => Ok(opened),
+                // peers that don't support encryption usually just hang up on us.
+                Err(_) => open(false).await,
+            },
+        }
+    }
+
+    /// Opens the connection, negotiates encryption if `encrypted` and exchanges the handshakes.
+    async fn open_with(
+        info_hash: [u8; 20],
+        v2: bool,
+        addr: SocketAddr,
+        transports: &Transports,
+        encrypted: bool,
+        encryption: EncryptionPolicy,
+    ) -> anyhow::Result<(CryptoStream<PeerStream>, Handshake)> {
+        let stream = transports.connect(addr).await?;
+        timeout(CONNECT_TIMEOUT, async {
+            let mut stream = if encrypted {
+                mse::initiate(stream, &info_hash, encryption)
+                    .await
+                    .context("encryption handshake failed")?
+            } else {
+                CryptoStream::plaintext(stream)
+            };
+
+            debug!("connected to {addr}");
+            Handshake::new(info_hash, transports.peer_id)
+                .with_v2(v2)
+                .write(&mut stream)
+                .await
+                .context("handshake failed")?;
+            let handshake = Handshake::read(&mut stream).await?;
+            if handshake.info_hash != info_hash {
+                bail!("peer {addr} answered the handshake with another info hash");
+            }
+            if handshake.peer_id == transports.peer_id {
+                bail!("peer {addr} is ourselves");
+            }
+            Ok((stream, handshake))
+        })
+        .await
+        .with_context(|| format!("handshake with peer {addr} timed out"))?
+    }
+
+    /// Wraps a stream on which both handshakes were already exchanged,
+    /// `handshake` is the one the peer sent.
+    pub fn from_stream(
+        stream: CryptoStream<PeerStream>,
+        addr: SocketAddr,
+        handshake: &Handshake,
+        num_pieces: usize,
+    ) -> Self {
+        Self {
+            addr,
+            peer_id: handshake.peer_id,
+            framed: Framed::new(stream, MessageFramer),
+            bitfield: Bitfield::new(num_pieces),
+            state: PeerState::default(),
+            // we always advertise the fast extension.
+            fast: handshake.supports_fast(),
+            allowed_fast: HashSet::new(),
+            granted_fast: HashSet::new(),
+            suggested: VecDeque::new(),
+            extensions: handshake.supports_extensions(),
+            extension_ids: HashMap::new(),
+            listen_port: None,
+            outbound: false,
+            dht: handshake.supports_dht(),
+            dht_port: None,
+            requests: VecDeque::new(),
+            hash_requests: VecDeque::new(),
+            v2: handshake.supports_v2(),
+            received_hashes: VecDeque::new(),
+            metadata_requests: VecDeque::new(),
+            download: None,
+            completed: None,
+            new_have: false,
+            received_message: false,
+            sent_message: false,
+            throttle: Throttle::default(),
+        }
+    }
+
+    /// Limits the traffic with the peer from now on.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+
+    /// Reads the peer's bitfield if it sends one, it is the first message after the handshake.
+    /// The extension handshake may come before it.
+    pub async fn receive_bitfield(&mut self) -> anyhow::Result<()> {
+        let mut left = FIRST_MESSAGE_TIMEOUT;
+        while !self.received_message {
+            self.throttle.ready_to_receive().await;
+            let waiting = tokio::time::Instant::now();
+            match timeout(left, self.framed.next()).await {
+                Ok(message) => self.received(message)?,
+                Err(_) => break,
+            };
+            left = left.saturating_sub(waiting.elapsed());
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// The pieces the peer has announced so far.
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Whether the last have message announced a piece missing from the peer's bitfield,
+    /// repeated haves and haves for pieces of the bitfield don't change its availability.
+    pub fn announced_new_piece(&self) -> bool {
+        self.new_have
+    }
+
+    /// The current choke and interest state of the connection.
+    pub fn state(&self) -> PeerState {
+        self.state
+    }
+
+    /// Whether both sides support the fast extension.
+    pub fn supports_fast(&self) -> bool {
+        self.fast
+    }
+
+    /// Pieces the peer lets us request while it chokes us.
+    pub fn allowed_fast(&self) -> &HashSet<u32> {
+        &self.allowed_fast
+    }
+
+    /// The address the peer accepts connections on, when we know it.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        match (self.outbound, self.listen_port) {
+            (true, _) => Some(self.addr),
+            (false, Some(port)) => Some(SocketAddr::new(self.addr.ip(), port)),
+            (false, None) => None,
+        }
+    }
+
+    /// Whether we opened the connection.
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    /// The port of the peer's DHT node, once it told us.
+    pub fn dht_port(&self) -> Option<u16> {
+        self.dht_port
+    }
+
+    /// The message id the peer wants to receive the extension `name` with,
+    /// None when it doesn't support it.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extension_ids.get(name).copied()
+    }
+
+    /// Pieces the peer suggested we download, most recent first.
+    pub fn suggested(&self) -> impl Iterator<Item = u32> + '_ {
+        self.suggested.iter().rev().copied()
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.bitfield.get(index)
+    }
+
+    /// We are interested in a peer as long as it has a piece that we don't.
+    pub fn is_interesting(&self, ours: &Bitfield) -> bool {
+        self.bitfield.iter().any(|index| !ours.get(index))
+    }
+
+    /// Tells the peer whether we are interested, if that changed since we last told it.
+    pub async fn update_interest(&mut self, ours: &Bitfield) -> anyhow::Result<()> {
+        self.set_interested(self.is_interesting(ours)).await
+    }
+
+    pub async fn set_interested(&mut self, interested: bool) -> anyhow::Result<()> {
+        if interested == self.state.am_interested {
+            return Ok(());
+        }
+
+        let tag = if interested {
+            MessageTag::Interested
+        } else {
+            MessageTag::NotInterested
+        };
+        self.send(Message {
+            tag,
+            payload: Vec::new(),
+        })
+        .await
+        .context("send interest message fail")?;
+        self.state.am_interested = interested;
+        Ok(())
+    }
+
+    /// Chokes or unchokes the peer, if that changed since we last told it.
+    pub async fn set_choking(&mut self, choking: bool) -> anyhow::Result<()> {
+        if choking == self.state.am_choking {
+            return Ok(());
+        }
+
+        let tag = if choking {
+            MessageTag::Choke
+        } else {
+            MessageTag::Unchoke
+        };
+        self.send(Message {
+            tag,
+            payload: Vec::new(),
+        })
+        .await
+        .context("send choke message fail")?;
+        self.state.am_choking = choking;
+        if !choking {
+            return Ok(());
+        }
+
+        // choking discards every request the peer made,
+        // with the fast extension each one is explicitly rejected,
+        // except for the pieces it may request while choked.
+        let requests = std::mem::take(&mut self.requests);
+        for block in requests {
+            if !self.fast {
+                continue;
+            }
+            if self.granted_fast.contains(&block.piece) {
+                self.requests.push_back(block);
+            } else {
+                self.send(Message::new_reject(block.piece, block.begin, block.length))
+                    .await
+                    .context("send reject message fail")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the peer which pieces we have, this must be the first message we send.
+    /// Without the fast extension nothing is sent when we have no pieces at all,
+    /// with it have all and have none replace the bitfield when they apply.
+    pub async fn send_bitfield(&mut self, have: &Bitfield) -> anyhow::Result<()> {
+        if have.count() == 0 && !self.fast {
+            return Ok(());
+        }
+        if self.sent_message {
+            bail!("the bitfield must be the first message sent to a peer");
+        }
+
+        let message = if self.fast && have.count() == 0 {
+            Message {
+                tag: MessageTag::HaveNone,
+                payload: Vec::new(),
+            }
+        } else if self.fast && have.is_complete() {
+            Message {
+                tag: MessageTag::HaveAll,
+                payload: Vec::new(),
+            }
+        } else {
+            Message {
+                tag: MessageTag::Bitfield,
+                payload: have.as_bytes().to_vec(),
+            }
+        };
+        self.send(message)
+            .await
+            .context("send bitfield message fail")
+    }
+
+    /// Tells the peer which extensions we support, this needs the extension protocol.
+    pub async fn send_extension_handshake(
+        &mut self,
+        handshake: &ExtensionHandshake,
+    ) -> anyhow::Result<()> {
+        if !self.extensions {
+            return Ok(());
+        }
+        self.send_extended(HANDSHAKE_ID, &handshake.to_bytes()?)
+            .await
+    }
+
+    /// Sends an extension message, `id` is the one the peer assigned to the extension.
+    pub async fn send_extended(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<()> {
+        self.send(Message::new_extended(id, payload))
+            .await
+            .context("send extended message fail")
+    }
+
+    /// Tells the peer the port of our DHT node, when it runs one too.
+    pub async fn send_port(&mut self, port: u16) -> anyhow::Result<()> {
+        if !self.dht {
+            return Ok(());
+        }
+        self.send(Message::new_port(port))
+            .await
+            .context("send port message fail")
+    }
+
+    /// Lets the peer request `pieces` even while we choke it, this needs the fast extension.
+    pub async fn send_allowed_fast(&mut self, pieces: &[u32]) -> anyhow::Result<()> {
+        if !self.fast {
+            return Ok(());
+        }
+        for &index in pieces {
+            self.send(Message::new_piece_index(MessageTag::AllowedFast, index))
+                .await
+                .context("send allowed fast message fail")?;
+            self.granted_fast.insert(index);
+        }
+        Ok(())
+    }
+
+    /// Tells the peer we just got the piece at `index`.
+    pub async fn send_have(&mut self, index: usize) -> anyhow::Result<()> {
+        self.send(Message::new_piece_index(MessageTag::Have, index as u32))
+            .await
+            .context("send have message fail")
+    }
+
+    async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        self.sent_message |= !is_out_of_band(&message);
+        self.throttle.ready_to_send().await;
+        self.throttle.sent(wire_size(&message));
+        self.framed.send(message).await?;
+        Ok(())
+    }
+
+    /// Updates the peer state from the messages it sends.
+    pub fn handle_message(&mut self, message: &Message) -> anyhow::Result<()> {
+        let first = !self.received_message;
+        self.received_message |= !is_out_of_band(message);
+
+        match message.tag {
+            MessageTag::Choke => {
+                self.state.peer_choking = true;
+                // the peer discards our requests when it chokes us,
+                // with the fast extension it rejects them explicitly instead.
+                if let (Some(download), false) = (self.download.as_mut(), self.fast) {
+                    while let Some(block) = download.outstanding.pop() {
+                        download.pending.push_front(block);
+                    }
+                }
+            }
+            MessageTag::Unchoke => self.state.peer_choking = false,
+            MessageTag::Interested => self.state.peer_interested = true,
+            MessageTag::NotInterested => self.state.peer_interested = false,
+            MessageTag::Bitfield => {
+                if !first {
+                    bail!("peer {} sent a bitfield after other messages", self.addr);
+                }
+                self.bitfield = Bitfield::from_payload(&message.payload, self.bitfield.len())
+                    .with_context(|| format!("invalid bitfield from peer {}", self.addr))?;
+            }
+            MessageTag::HaveAll | MessageTag::HaveNone => {
+                self.require_fast(&message.tag)?;
+                if !first {
+                    bail!(
+                        "peer {} sent {:?} after other messages",
+                        self.addr,
+                        message.tag
+                    );
+                }
+                self.bitfield = if message.tag == MessageTag::HaveAll {
+                    Bitfield::full(self.bitfield.len())
+                } else {
+                    Bitfield::new(self.bitfield.len())
+                };
+            }
+            MessageTag::Have => {
+                let index = self.piece_index(message)?;
+                self.new_have = !self.bitfield.get(index as usize);
+                self.bitfield.set(index as usize);
+            }
+            MessageTag::SuggestPiece => {
+                self.require_fast(&message.tag)?;
+                let index = self.piece_index(message)?;
+                self.suggested.retain(|&suggested| suggested != index);
+                if self.suggested.len() >= MAX_SUGGESTED_PIECES {
+                    self.suggested.pop_front();
+                }
+                self.suggested.push_back(index);
+            }
+            MessageTag::AllowedFast => {
+                self.require_fast(&message.tag)?;
+                let index = self.piece_index(message)?;
+                self.allowed_fast.insert(index);
+            }
+            MessageTag::Port => {
+                let port: [u8; 2] = message
+                    .payload
+                    .as_slice()
+                    .try_into()
+                    .context("port message must contain a 2 bytes port")?;
+                self.dht_port = Some(u16::from_be_bytes(port));
+            }
+            MessageTag::Extended => {
+                if !self.extensions {
+                    bail!(
+                        "peer {} sent an extended message without the extension protocol",
+                        self.addr
+                    );
+                }
+                let Some((&id, payload)) = message.payload.split_first() else {
+                    bail!("peer {} sent an empty extended message", self.addr);
+                };
+                // the other extension messages are handled by the caller.
+                if id == HANDSHAKE_ID {
+                    let handshake = ExtensionHandshake::from_bytes(payload).with_context(|| {
+                        format!("invalid extension handshake from {}", self.addr)
+                    })?;
+                    // later handshakes only update what changed, an id of 0 disables an extension.
+                    for (name, id) in handshake.m {
+                        if id == 0 {
+                            self.extension_ids.remove(&name);
+                        } else {
+                            self.extension_ids.insert(name, id);
+                        }
+                    }
+                    if let Some(port) = handshake.p.filter(|&port| port > 0) {
+                        self.listen_port = Some(port);
+                    }
+                } else if id == UT_METADATA_ID {
+                    let (request, _) = MetadataMessage::from_payload(payload)
+                        .with_context(|| format!("invalid metadata message from {}", self.addr))?;
+                    if request.is_request() && self.metadata_requests.len() < MAX_QUEUED_REQUESTS {
+                        self.metadata_requests.push_back(request);
+                    }
+                }
+            }
+            MessageTag::RejectRequest => {
+                self.require_fast(&message.tag)?;
+                let block = Block::from_payload(&message.payload)?;
+                // the rejected block goes back in line, to be requested when possible.
+                if let Some(download) = self.download.as_mut() {
+                    if let Some(position) = download.outstanding.iter().position(|b| *b == block) {
+                        download.outstanding.swap_remove(position);
+                        download.pending.push_front(block);
+                    }
+                }
+            }
+            MessageTag::Request => {
+                let block = Block::from_payload(&message.payload)?;
+                if block.length as usize > MAX_REQUEST_LENGTH {
+                    bail!(
+                        "peer {} requested {} bytes, more than the {MAX_REQUEST_LENGTH} we allow",
+                        self.addr,
+                        block.length
+                    );
+                }
+                // requests made while choked are dropped,
+                // with the fast extension they are rejected when served.
+                if (!self.state.am_choking || self.fast)
+                    && self.requests.len() < MAX_QUEUED_REQUESTS
+                    && !self.requests.contains(&block)
+                {
+                    self.requests.push_back(block);
+                }
+            }
+            MessageTag::Cancel => {
+                let block = Block::from_payload(&message.payload)?;
+                self.requests.retain(|request| *request != block);
+            }
+            MessageTag::Piece => self.receive_block(&message.payload)?,
+            MessageTag::HashRequest => {
+                let request = HashRequest::from_payload(&message.payload)?;
+                if self.hash_requests.len() < MAX_QUEUED_REQUESTS {
+                    self.hash_requests.push_back(request);
+                }
+            }
+            MessageTag::Hashes => {
+                let request = HashRequest::from_payload(&message.payload)?;
+                let hashes = &message.payload[HashRequest::SIZE..];
+                if !hashes.len().is_multiple_of(32) {
+                    bail!("hashes message with a partial hash");
+                }
+                if self.received_hashes.len() < MAX_QUEUED_REQUESTS {
+                    let hashes = hashes.chunks_exact(32).map(|hash| hash.try_into().unwrap());
+                    self.received_hashes.push_back((request, hashes.collect()));
+                }
+            }
+            // another peer may have the hashes.
+            MessageTag::HashReject => {}
+        }
+        Ok(())
+    }
+
+    /// Messages of the fast extension are a protocol violation unless both sides support it.
+    fn require_fast(&self, tag: &MessageTag) -> anyhow::Result<()> {
+        if !self.fast {
+            bail!("peer {} sent {tag:?} without the fast extension", self.addr);
+        }
+        Ok(())
+    }
+
+    /// Parses the piece index of have, suggest piece and allowed fast messages.
+    fn piece_index(&self, message: &Message) -> anyhow::Result<u32> {
+        let index: [u8; 4] = message
+            .payload
+            .as_slice()
+            .try_into()
+            .context("message must contain a 4 bytes piece index")?;
+        let index = u32::from_be_bytes(index);
+        if index as usize >= self.bitfield.len() {
+            bail!(
+                "peer {} sent piece {index} which is out of range",
+                self.addr
+            );
+        }
+        Ok(index)
+    }
+
+    /// Reads the next message and updates the peer state from it.
+    /// The peer closing the stream is reported as a disconnect error.
+    pub async fn next_message(&mut self) -> anyhow::Result<Message> {
+        // not reading makes the peer slow down once the buffers fill up.
+        self.throttle.ready_to_receive().await;
+        let message = self.framed.next().await;
+        self.received(message)
+    }
+
+    /// Same as `next_message`, but fails if nothing arrives within `duration`.
+    /// Waiting for our own rate limits doesn't count against the peer.
+    pub async fn next_message_within(&mut self, duration: Duration) -> anyhow::Result<Message> {
+        self.throttle.ready_to_receive().await;
+        let message = timeout(duration, self.framed.next())
+            .await
+            .with_context(|| format!("peer {} timed out", self.addr))?;
+        self.received(message)
+    }
+
+    /// Counts and handles what was read from the stream, `None` once it ended.
+    fn received(&mut self, message: Option<std::io::Result<Message>>) -> anyhow::Result<Message> {
+        let message = match message {
+            Some(message) => message.context("receiving message from peer fail")?,
+            None => bail!("peer {} closed the connection", self.addr),
+        };
+        self.throttle.received(wire_size(&message));
+        self.handle_message(&message).context(ProtocolViolation)?;
+        Ok(message)
+    }
+
+    /// Returns the next message only if it already arrived.
+    pub fn try_next_message(&mut self) -> anyhow::Result<Option<Message>> {
+        match self.next_message().now_or_never() {
+            Some(message) => message.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Starts downloading the piece at `piece_index` whose size is `piece_size`,
+    /// its blocks are requested by `request_blocks` as long as the peer doesn't choke us.
+    pub fn start_piece(&mut self, piece_index: u32, piece_size: usize) {
+        self.download = Some(PieceDownload {
+            index: piece_index,
+            data: vec![0u8; piece_size],
+            pending: Block::split_piece(piece_index, piece_size),
+            outstanding: Vec::new(),
+        });
+    }
+
+    /// The piece we are downloading from the peer, if any.
+    pub fn current_piece(&self) -> Option<u32> {
+        self.download.as_ref().map(|download| download.index)
+    }
+
+    /// Whether we are waiting for blocks we requested.
+    pub fn has_outstanding_requests(&self) -> bool {
+        self.download
+            .as_ref()
+            .is_some_and(|download| !download.outstanding.is_empty())
+    }
+
+    /// Stops downloading the current piece, cancelling the blocks still in flight.
+    pub async fn cancel_piece(&mut self) -> anyhow::Result<()> {
+        let Some(download) = self.download.take() else {
+            return Ok(());
+        };
+        for block in download.outstanding {
+            self.send(Message::new_cancel(block.piece, block.begin, block.length))
+                .await
+                .context("sending cancel message fail")?;
+        }
+        Ok(())
+    }
+
+    /// Whether the peer would answer requests for the current piece,
+    /// either it doesn't choke us or it allowed the piece while choked.
+    fn can_request(&self) -> bool {
+        match &self.download {
+            Some(download) => {
+                !self.state.peer_choking || self.allowed_fast.contains(&download.index)
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the piece whose blocks all arrived, if any.
+    pub fn take_completed_piece(&mut self) -> Option<(u32, Vec<u8>)> {
+        self.completed.take()
+    }
+
+    /// Sends requests for the blocks of the current piece, keeping a few of them in flight.
+    pub async fn request_blocks(&mut self) -> anyhow::Result<()> {
+        if !self.can_request() {
+            return Ok(());
+        }
+        loop {
+            let Some(download) = self.download.as_mut() else {
+                return Ok(());
+            };
+            if download.outstanding.len() >= MAX_OUTSTANDING_REQUESTS {
+                return Ok(());
+            }
+            let Some(block) = download.pending.pop_front() else {
+                return Ok(());
+            };
+            download.outstanding.push(block);
+            self.send(Message::new_request(block.piece, block.begin, block.length))
+                .await
+                .context("sending request message fail")?;
+        }
+    }
+
+    /// Stores the block of a piece message into the current piece.
+    fn receive_block(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        // the piece message payload structure
+        // [0..4] -> index
+        // [4..8] -> begin
+        // [8..] -> block data usually 2^14 bytes long
+        if payload.len() < 8 {
+            bail!("peer {} sent a truncated piece message", self.addr);
+        }
+        let index = u32::from_be_bytes(payload[0..4].try_into()?);
+        let begin = u32::from_be_bytes(payload[4..8].try_into()?);
+        let data = &payload[8..];
+
+        let Some(download) = self.download.as_mut() else {
+            // a block we didn't ask for (or cancelled), ignore it.
+            return Ok(());
+        };
+        let matches = |block: &Block| {
+            block.piece == index && block.begin == begin && block.length as usize == data.len()
+        };
+        if let Some(position) = download.outstanding.iter().position(matches) {
+            download.outstanding.swap_remove(position);
+        } else if let Some(position) = download.pending.iter().position(matches) {
+            // the block was requeued after a choke but arrived anyway.
+            download.pending.remove(position);
+        } else {
+            return Ok(());
+        }
+        download.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+
+        if download.pending.is_empty() && download.outstanding.is_empty() {
+            let download = self.download.take().expect("checked above");
+            self.completed = Some((download.index, download.data));
+        }
+        Ok(())
+    }
+
+    /// Downloads the piece at `piece_index` whose size is `piece_size` from this peer.
+    ///
+    /// Requests are pipelined, when the peer chokes us in the middle of the piece
+    /// the outstanding requests are dropped by the peer, so we queue them again
+    /// and wait to be unchoked.
+    pub async fn download_piece(
+        &mut self,
+        piece_index: u32,
+        piece_size: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        if !self.has_piece(piece_index as usize) {
+            bail!("peer {} doesn't have piece {piece_index}", self.addr);
+        }
+
+        self.set_interested(true).await?;
+        self.start_piece(piece_index, piece_size);
+
+        loop {
+            if !self.can_request() {
+                self.next_message_within(UNCHOKE_TIMEOUT).await?;
+            } else {
+                self.request_blocks().await?;
+                self.next_message_within(REQUEST_TIMEOUT).await?;
+            }
+            if let Some((_, data)) = self.take_completed_piece() {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Answers the oldest queued request with a piece message read from `storage` by `disk`.
+    /// Requests for pieces we don't have are a protocol violation, unless the fast
+    /// extension is used in which case they are rejected, as are requests while choked.
+    pub async fn serve_request(
+        &mut self,
+        storage: &Arc<Storage>,
+        disk: &DiskPool,
+        have: &Bitfield,
+    ) -> anyhow::Result<usize> {
+        let Some(block) = self.requests.pop_front() else {
+            return Ok(0);
+        };
+        let allowed = !self.state.am_choking || self.granted_fast.contains(&block.piece);
+        if !allowed || !have.get(block.piece as usize) {
+            if self.fast {
+                self.send(Message::new_reject(block.piece, block.begin, block.length))
+                    .await
+                    .context("send reject message fail")?;
+                return Ok(0);
+            }
+            if allowed {
+                return Err(anyhow!(
+                    "peer {} requested piece {} which we don't have",
+                    self.addr,
+                    block.piece
+                ))
+                .context(ProtocolViolation);
+            }
+            return Ok(0);
+        }
+
+        let data = disk.read_block(storage, block).await?;
+        let length = data.len();
+        let mut payload = Vec::with_capacity(8 + length);
+        payload.extend(block.piece.to_be_bytes());
+        payload.extend(block.begin.to_be_bytes());
+        payload.extend(data);
+        self.send(Message {
+            tag: MessageTag::Piece,
+            payload,
+        })
+        .await
+        .context("sending piece message fail")?;
+        Ok(length)
+    }
+
+    /// Whether the peer is waiting for blocks from us.
+    pub fn has_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    /// Whether the peer supports v2 torrents.
+    pub fn supports_v2(&self) -> bool {
+        self.v2
+    }
+
+    /// Asks the peer for hashes of the merkle tree of a file.
+    pub async fn send_hash_request(&mut self, request: &HashRequest) -> anyhow::Result<()> {
+        self.send(Message::new_hash_request(request))
+            .await
+            .context("sending hash request fail")
+    }
+
+    /// The next hashes the peer sent us, with the request they answer.
+    pub fn take_hashes(&mut self) -> Option<(HashRequest, Vec<Hash>)> {
+        self.received_hashes.pop_front()
+    }
+
+    /// Whether the peer is waiting for hashes from us.
+    pub fn has_hash_requests(&self) -> bool {
+        !self.hash_requests.is_empty()
+    }
+
+    /// Answers the queued hash requests with the hashes of `torrent`, rejecting the ones
+    /// we can't answer. Hashes below the piece layer come from the pieces we `have`.
+    pub async fn serve_hash_requests(
+        &mut self,
+        torrent: &Torrent,
+        storage: &Storage,
+        have: &Bitfield,
+    ) -> anyhow::Result<()> {
+        while let Some(request) = self.hash_requests.pop_front() {
+            let message = match merkle::answer(torrent, storage, have, &request)? {
+                Some(hashes) => Message::new_hashes(&request, &hashes),
+                None => Message::new_hash_reject(&request),
+            };
+            self.send(message)
+                .await
+                .context("sending hashes message fail")?;
+        }
+        Ok(())
+    }
+
+    /// Whether the peer is waiting for pieces of the info dictionary from us.
+    pub fn has_metadata_requests(&self) -> bool {
+        !self.metadata_requests.is_empty()
+    }
+
+    /// Answers the queued metadata requests with the pieces of `info`, the bencoded info dictionary.
+    pub async fn serve_metadata_requests(&mut self, info: &[u8]) -> anyhow::Result<()> {
+        let Some(id) = self.extension_id(UT_METADATA) else {
+            self.metadata_requests.clear();
+            return Ok(());
+        };
+        while let Some(request) = self.metadata_requests.pop_front() {
+            self.send_extended(id, &request.answer(info)?).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The context of the errors of peers that broke the protocol, like sending a bitfield late
+/// or a block we didn't ask for. Such peers get banned for a while.
+#[derive(Debug)]
+pub struct ProtocolViolation;
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("protocol violation")
+    }
+}
+
+/// The bytes a message takes on the wire, with its length prefix and tag.
+fn wire_size(message: &Message) -> usize {
+    message.payload.len() + 5
+}
+
+/// The extension handshake and port messages may come before the bitfield,
+/// so they don't count as the first message.
+fn is_out_of_band(message: &Message) -> bool {
+    match message.tag {
+        MessageTag::Extended => message.payload.first() == Some(&HANDSHAKE_ID),
+        MessageTag::Port => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{
+        ratelimit::{RateLimits, Rates},
+        torrent::testing,
+    };
+
+    /// A connection whose handshake advertised the fast extension and the extension protocol,
+    /// along with the other end of its stream.
+    async fn connection(num_pieces: usize) -> (PeerConnection, TcpStream) {
+        connection_with(Handshake::new([0; 20], [1; 20]), num_pieces).await
+    }
+
+    /// A connection to a peer that sent `handshake`.
+    async fn connection_with(
+        handshake: Handshake,
+        num_pieces: usize,
+    ) -> (PeerConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (ours, theirs) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let stream = CryptoStream::plaintext(PeerStream::from(ours.unwrap()));
+        let peer = PeerConnection::from_stream(stream, addr, &handshake, num_pieces);
+        (peer, theirs.unwrap().0)
+    }
+
+    /// A connection to a peer without any extension.
+    async fn plain_connection(num_pieces: usize) -> (PeerConnection, TcpStream) {
+        let mut handshake = Handshake::new([0; 20], [1; 20]);
+        handshake.reserved = [0; 8];
+        connection_with(handshake, num_pieces).await
+    }
+
+    fn message(tag: MessageTag) -> Message {
+        Message {
+            tag,
+            payload: Vec::new(),
+        }
+    }
+
+    fn block(piece: u32, begin: u32, data: &[u8]) -> Message {
+        let mut payload = Vec::new();
+        payload.extend(piece.to_be_bytes());
+        payload.extend(begin.to_be_bytes());
+        payload.extend(data);
+        Message {
+            tag: MessageTag::Piece,
+            payload,
+        }
+    }
+
+    /// Reads the messages the connection sent until `count` arrived.
+    async fn sent(stream: &mut Framed<TcpStream, MessageFramer>, count: usize) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            messages.push(stream.next().await.unwrap().unwrap());
+        }
+        messages
+    }
+
+    fn have(index: u32) -> Message {
+        Message::new_piece_index(MessageTag::Have, index)
+    }
+
+    #[tokio::test]
+    async fn only_haves_for_new_pieces_are_counted() {
+        let (mut peer, _stream) = connection(16).await;
+        peer.handle_message(&Message {
+            tag: MessageTag::Bitfield,
+            payload: vec![0b1000_0000, 0],
+        })
+        .unwrap();
+
+        peer.handle_message(&have(0)).unwrap();
+        assert!(!peer.announced_new_piece());
+        peer.handle_message(&have(3)).unwrap();
+        assert!(peer.announced_new_piece());
+        peer.handle_message(&have(3)).unwrap();
+        assert!(!peer.announced_new_piece());
+        assert_eq!(peer.bitfield().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn have_out_of_range_is_a_violation() {
+        let (mut peer, _stream) = connection(16).await;
+        assert!(peer.handle_message(&have(16)).is_err());
+    }
+
+    #[tokio::test]
+    async fn late_bitfield_is_a_violation() {
+        let (mut peer, _stream) = connection(16).await;
+        peer.handle_message(&have(1)).unwrap();
+        let bitfield = Message {
+            tag: MessageTag::Bitfield,
+            payload: vec![0, 0],
+        };
+        assert!(peer.handle_message(&bitfield).is_err());
+    }
+
+    #[tokio::test]
+    async fn connections_start_choked_and_not_interested() {
+        let (peer, _stream) = connection(1).await;
+        assert_eq!(peer.state(), PeerState::default());
+        assert!(peer.state().am_choking && peer.state().peer_choking);
+        assert!(!peer.state().am_interested && !peer.state().peer_interested);
+    }
+
+    #[tokio::test]
+    async fn choke_during_transfer_requeues_outstanding_blocks() {
+        let (mut peer, stream) = plain_connection(1).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        let piece_size = 2 * BLOCK_MAX + 10;
+
+        peer.handle_message(&message(MessageTag::Unchoke)).unwrap();
+        peer.start_piece(0, piece_size);
+        peer.request_blocks().await.unwrap();
+        assert_eq!(sent(&mut remote, 3).await.len(), 3);
+
+        peer.handle_message(&block(0, 0, &[1; BLOCK_MAX])).unwrap();
+        peer.handle_message(&message(MessageTag::Choke)).unwrap();
+        assert!(!peer.has_outstanding_requests());
+        // nothing is requested while choked.
+        peer.request_blocks().await.unwrap();
+        assert!(!peer.has_outstanding_requests());
+
+        peer.handle_message(&message(MessageTag::Unchoke)).unwrap();
+        peer.request_blocks().await.unwrap();
+        let requests = sent(&mut remote, 2).await;
+        let mut begins: Vec<_> = requests
+            .iter()
+            .map(|request| Block::from_payload(&request.payload).unwrap().begin)
+            .collect();
+        begins.sort();
+        assert_eq!(begins, vec![BLOCK_MAX as u32, 2 * BLOCK_MAX as u32]);
+
+        peer.handle_message(&block(0, BLOCK_MAX as u32, &[2; BLOCK_MAX]))
+            .unwrap();
+        peer.handle_message(&block(0, 2 * BLOCK_MAX as u32, &[3; 10]))
+            .unwrap();
+        let (index, data) = peer.take_completed_piece().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(data.len(), piece_size);
+        assert_eq!(&data[piece_size - 10..], &[3; 10]);
+    }
+
+    #[tokio::test]
+    async fn blocks_we_did_not_ask_for_are_ignored() {
+        let (mut peer, _stream) = plain_connection(1).await;
+        peer.handle_message(&block(0, 0, &[1; 4])).unwrap();
+        peer.start_piece(0, 8);
+        peer.handle_message(&block(0, 0, &[1; 4])).unwrap();
+        assert!(peer.take_completed_piece().is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_end_is_a_disconnect() {
+        let (mut peer, stream) = connection(1).await;
+        drop(stream);
+        let error = peer.next_message().await.unwrap_err();
+        assert!(error.to_string().contains("closed the connection"));
+    }
+
+    #[tokio::test]
+    async fn silent_peers_time_out() {
+        let (mut peer, _stream) = connection(1).await;
+        let result = peer.next_message_within(Duration::from_millis(50)).await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn our_rate_limits_dont_time_peers_out() {
+        let (mut peer, stream) = connection(1).await;
+        let limits = RateLimits::new(Rates {
+            download: 1000,
+            upload: 0,
+        });
+        let throttle = Throttle::default().with_limits(limits);
+        // half a second in debt.
+        throttle.received(1500);
+        peer.set_throttle(throttle);
+        let mut remote = Framed::new(stream, MessageFramer);
+        remote.send(message(MessageTag::Unchoke)).await.unwrap();
+
+        let message = peer
+            .next_message_within(Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert_eq!(message.tag, MessageTag::Unchoke);
+    }
+
+    #[tokio::test]
+    async fn interest_is_only_sent_when_it_changes() {
+        let (mut peer, stream) = connection(1).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        peer.set_interested(true).await.unwrap();
+        peer.set_interested(true).await.unwrap();
+        peer.set_interested(false).await.unwrap();
+        let tags: Vec<_> = sent(&mut remote, 2)
+            .await
+            .into_iter()
+            .map(|message| message.tag)
+            .collect();
+        assert_eq!(
+            tags,
+            vec![MessageTag::Interested, MessageTag::NotInterested]
+        );
+        assert!(!peer.state().am_interested);
+    }
+
+    #[tokio::test]
+    async fn connecting_to_ourselves_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a peer that echoes our handshake, as our own listener would.
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let handshake = Handshake::read(&mut stream).await.unwrap();
+            handshake.write(&mut stream).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let transports = Transports::new().unwrap();
+        let opened = PeerConnection::open(
+            [3; 20],
+            false,
+            addr,
+            &transports,
+            EncryptionPolicy::Disabled,
+        )
+        .await;
+        let error = opened.err().expect("a connection to ourselves must fail");
+        assert!(error.to_string().contains("is ourselves"));
+    }
+
+    fn request(piece: u32, begin: u32, length: u32) -> Message {
+        Message::new_request(piece, begin, length)
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_from_storage() {
+        let torrent = testing::torrent("a", &[("a", &[7; 10])], 4);
+        let storage = Arc::new(Storage::temporary(&torrent).unwrap());
+        storage.write_piece(1, &[7; 4]).unwrap();
+        let mut have = Bitfield::new(3);
+        have.set(1);
+
+        let (mut peer, stream) = plain_connection(3).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        peer.set_choking(false).await.unwrap();
+        peer.handle_message(&request(1, 1, 2)).unwrap();
+        assert_eq!(
+            peer.serve_request(&storage, &DiskPool::default(), &have)
+                .await
+                .unwrap(),
+            2
+        );
+
+        let sent = sent(&mut remote, 2).await;
+        assert_eq!(sent[0].tag, MessageTag::Unchoke);
+        assert_eq!(sent[1].tag, MessageTag::Piece);
+        assert_eq!(sent[1].payload, [0, 0, 0, 1, 0, 0, 0, 1, 7, 7]);
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_a_violation() {
+        let (mut peer, _stream) = plain_connection(1).await;
+        let oversized = request(0, 0, MAX_REQUEST_LENGTH as u32 + 1);
+        assert!(peer.handle_message(&oversized).is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelled_and_choked_requests_are_not_served() {
+        let (mut peer, _stream) = plain_connection(1).await;
+        peer.handle_message(&request(0, 0, 4)).unwrap();
+        assert!(!peer.has_requests());
+
+        peer.set_choking(false).await.unwrap();
+        peer.handle_message(&request(0, 0, 4)).unwrap();
+        peer.handle_message(&request(0, 0, 4)).unwrap();
+        assert!(peer.has_requests());
+        peer.handle_message(&Message {
+            tag: MessageTag::Cancel,
+            ..request(0, 0, 4)
+        })
+        .unwrap();
+        assert!(!peer.has_requests());
+
+        // choking drops the queued requests of peers without the fast extension.
+        peer.handle_message(&request(0, 0, 4)).unwrap();
+        peer.set_choking(true).await.unwrap();
+        assert!(!peer.has_requests());
+    }
+
+    #[tokio::test]
+    async fn requests_for_missing_pieces_are_a_violation() {
+        let torrent = testing::torrent("a", &[("a", &[7; 10])], 4);
+        let storage = Arc::new(Storage::temporary(&torrent).unwrap());
+        let (mut peer, _stream) = plain_connection(3).await;
+        peer.set_choking(false).await.unwrap();
+        peer.handle_message(&request(0, 0, 4)).unwrap();
+        assert!(peer
+            .serve_request(&storage, &DiskPool::default(), &Bitfield::new(3))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn bitfield_is_skipped_without_pieces() {
+        let (mut peer, stream) = plain_connection(3).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        peer.send_bitfield(&Bitfield::new(3)).await.unwrap();
+        peer.send_have(2).await.unwrap();
+        let sent = sent(&mut remote, 1).await;
+        assert_eq!(sent[0].tag, MessageTag::Have);
+
+        // the bitfield can't follow other messages.
+        assert!(peer.send_bitfield(&Bitfield::full(3)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fast_messages_need_the_fast_extension() {
+        let (mut peer, _stream) = plain_connection(8).await;
+        assert!(peer.handle_message(&message(MessageTag::HaveAll)).is_err());
+        let (mut peer, _stream) = plain_connection(8).await;
+        let suggest = Message::new_piece_index(MessageTag::SuggestPiece, 1);
+        assert!(peer.handle_message(&suggest).is_err());
+    }
+
+    #[tokio::test]
+    async fn have_all_and_have_none_replace_the_bitfield() {
+        let (mut peer, _stream) = connection(10).await;
+        peer.handle_message(&message(MessageTag::HaveAll)).unwrap();
+        assert!(peer.bitfield().is_complete());
+        assert!(peer.handle_message(&message(MessageTag::HaveNone)).is_err());
+
+        let (mut peer, _stream) = connection(10).await;
+        peer.handle_message(&message(MessageTag::HaveNone)).unwrap();
+        assert_eq!(peer.bitfield().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejected_blocks_are_requested_again() {
+        let (mut peer, stream) = connection(1).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        peer.handle_message(&message(MessageTag::Unchoke)).unwrap();
+        peer.start_piece(0, BLOCK_MAX);
+        peer.request_blocks().await.unwrap();
+        sent(&mut remote, 1).await;
+
+        // with the fast extension a choke doesn't drop the requests, rejects do.
+        peer.handle_message(&message(MessageTag::Choke)).unwrap();
+        assert!(peer.has_outstanding_requests());
+        let reject = Message::new_reject(0, 0, BLOCK_MAX as u32);
+        peer.handle_message(&reject).unwrap();
+        assert!(!peer.has_outstanding_requests());
+    }
+
+    #[tokio::test]
+    async fn allowed_fast_pieces_are_requested_while_choked() {
+        let (mut peer, stream) = connection(2).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        let allowed = Message::new_piece_index(MessageTag::AllowedFast, 1);
+        peer.handle_message(&allowed).unwrap();
+        assert!(peer.allowed_fast().contains(&1));
+
+        peer.start_piece(0, 4);
+        peer.request_blocks().await.unwrap();
+        assert!(!peer.has_outstanding_requests());
+        peer.start_piece(1, 4);
+        peer.request_blocks().await.unwrap();
+        assert!(peer.has_outstanding_requests());
+        assert_eq!(sent(&mut remote, 1).await[0].tag, MessageTag::Request);
+    }
+
+    #[tokio::test]
+    async fn choking_rejects_requests_except_allowed_fast_ones() {
+        let torrent = testing::torrent("a", &[("a", &[7; 8])], 4);
+        let storage = Arc::new(Storage::temporary(&torrent).unwrap());
+        let (mut peer, stream) = connection(2).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        peer.send_allowed_fast(&[1]).await.unwrap();
+        peer.set_choking(false).await.unwrap();
+        peer.handle_message(&request(0, 0, 4)).unwrap();
+        peer.handle_message(&request(1, 0, 4)).unwrap();
+        peer.set_choking(true).await.unwrap();
+
+        let sent_messages = sent(&mut remote, 4).await;
+        assert_eq!(sent_messages[3].tag, MessageTag::RejectRequest);
+        assert_eq!(
+            Block::from_payload(&sent_messages[3].payload)
+                .unwrap()
+                .piece,
+            0
+        );
+        // the allowed fast request is still served, and missing pieces get rejected.
+        assert_eq!(
+            peer.serve_request(&storage, &DiskPool::default(), &Bitfield::full(2))
+                .await
+                .unwrap(),
+            4
+        );
+        peer.handle_message(&request(0, 0, 4)).unwrap();
+        assert_eq!(
+            peer.serve_request(&storage, &DiskPool::default(), &Bitfield::full(2))
+                .await
+                .unwrap(),
+            0
+        );
+        let tags: Vec<_> = sent(&mut remote, 2)
+            .await
+            .into_iter()
+            .map(|message| message.tag)
+            .collect();
+        assert_eq!(tags, [MessageTag::Piece, MessageTag::RejectRequest]);
+    }
+
+    #[tokio::test]
+    async fn suggestions_are_remembered_most_recent_first() {
+        let (mut peer, _stream) = connection(32).await;
+        for index in [1, 2, 1] {
+            let suggest = Message::new_piece_index(MessageTag::SuggestPiece, index);
+            peer.handle_message(&suggest).unwrap();
+        }
+        assert_eq!(peer.suggested().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn empty_bitfield_is_have_none_with_the_fast_extension() {
+        let (mut peer, stream) = connection(3).await;
+        let mut remote = Framed::new(stream, MessageFramer);
+        peer.send_bitfield(&Bitfield::new(3)).await.unwrap();
+        assert_eq!(sent(&mut remote, 1).await[0].tag, MessageTag::HaveNone);
+    }
+
+    #[tokio::test]
+    async fn hashes_are_queued_for_the_swarm() {
+        let handshake = Handshake::new([0; 20], [1; 20]).with_v2(true);
+        let (mut peer, stream) = connection_with(handshake, 4).await;
+        assert!(peer.supports_v2());
+        let request = HashRequest {
+            pieces_root: [3; 32],
+            base_layer: 0,
+            index: 0,
+            length: 2,
+            proof_layers: 0,
+        };
+
+        peer.send_hash_request(&request).await.unwrap();
+        let mut remote = Framed::new(stream, MessageFramer);
+        let sent = sent(&mut remote, 1).await;
+        assert_eq!(sent[0].tag, MessageTag::HashRequest);
+        assert_eq!(sent[0].payload, request.to_payload());
+
+        peer.handle_message(&Message::new_hash_reject(&request))
+            .unwrap();
+        assert!(peer.take_hashes().is_none());
+        peer.handle_message(&Message::new_hashes(&request, &[[4; 32], [5; 32]]))
+            .unwrap();
+        assert_eq!(peer.take_hashes(), Some((request, vec![[4; 32], [5; 32]])));
+
+        let mut partial = Message::new_hashes(&request, &[[4; 32]]);
+        partial.payload.pop();
+        assert!(peer.handle_message(&partial).is_err());
+    }
+}
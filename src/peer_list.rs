@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::info;
+
+/// How long we wait before connecting again to a peer that failed once,
+/// doubled with every failure after that.
+const RETRY_BASE: Duration = Duration::from_secs(30);
+
+/// The longest we wait before trying a failing peer again.
+const MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+
+/// How long a peer that broke the protocol or sent a piece that failed the hash check
+/// is banned.
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// What we remember about the peers we connected to: the ones that refused or timed out
+/// are tried again later and later, the ones that misbehaved are banned for a while.
+/// Swarms may share it, a peer banned in one torrent is banned in all of them.
+#[derive(Debug, Default)]
+pub struct PeerList {
+    failures: Mutex<HashMap<SocketAddr, Failures>>,
+    /// By IP, a banned peer coming back on another port is still banned.
+    bans: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    retry_at: Instant,
+}
+
+impl PeerList {
+    /// Whether we may connect to `addr` now, it isn't banned nor waiting after failing.
+    pub fn may_connect(&self, addr: SocketAddr) -> bool {
+        !self.is_banned(addr.ip())
+            && self
+                .failures
+                .lock()
+                .unwrap()
+                .get(&addr)
+                .is_none_or(|failures| failures.retry_at <= Instant::now())
+    }
+
+    /// Whether the peer at `ip` is banned, then we don't accept its connections either.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&ip) {
+            Some(&until) if until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Records that connecting to `addr` failed, it is tried again after a while.
+    pub fn connect_failed(&self, addr: SocketAddr) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        // peers that stopped failing long ago are forgotten.
+        failures.retain(|_, failures| now < failures.retry_at + MAX_RETRY);
+        let failures = failures.entry(addr).or_insert(Failures {
+            count: 0,
+            retry_at: now,
+        });
+        failures.count += 1;
+        let backoff = RETRY_BASE.saturating_mul(1 << (failures.count - 1).min(16));
+        failures.retry_at = now + backoff.min(MAX_RETRY);
+    }
+
+    /// Records that we connected to `addr`, forgetting its failures.
+    pub fn connected(&self, addr: SocketAddr) {
+        self.failures.lock().unwrap().remove(&addr);
+    }
+
+    /// How many times in a row connecting to `addr` failed.
+    pub fn failures(&self, addr: SocketAddr) -> u32 {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .map_or(0, |failures| failures.count)
+    }
+
+    /// Bans the peer at `addr` for a while.
+    pub fn ban(&self, addr: SocketAddr, reason: &str) {
+        info!("banning {} for {BAN_DURATION:?}: {reason}", addr.ip());
+        let now = Instant::now();
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|_, until| *until > now);
+        bans.insert(addr.ip(), now + BAN_DURATION);
+    }
+
+    /// The banned peers, with how long they stay banned.
+    pub fn banned(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        self.bans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (*ip, *until - now))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    /// How long after now `addr` is tried again.
+    fn retry_in(list: &PeerList, addr: SocketAddr) -> Duration {
+        let failures = list.failures.lock().unwrap();
+        failures[&addr].retry_at - Instant::now()
+    }
+
+    #[test]
+    fn failing_peers_wait_longer_and_longer() {
+        let list = PeerList::default();
+        assert!(list.may_connect(addr(1)));
+
+        list.connect_failed(addr(1));
+        assert!(!list.may_connect(addr(1)));
+        assert!(list.may_connect(addr(2)));
+        assert_eq!(list.failures(addr(1)), 1);
+        assert!(retry_in(&list, addr(1)) > RETRY_BASE - Duration::from_secs(1));
+
+        list.connect_failed(addr(1));
+        list.connect_failed(addr(1));
+        assert_eq!(list.failures(addr(1)), 3);
+        let wait = retry_in(&list, addr(1));
+        assert!(wait > 4 * RETRY_BASE - Duration::from_secs(1) && wait <= 4 * RETRY_BASE);
+
+        for _ in 0..40 {
+            list.connect_failed(addr(1));
+        }
+        assert!(retry_in(&list, addr(1)) <= MAX_RETRY);
+
+        list.connected(addr(1));
+        assert_eq!(list.failures(addr(1)), 0);
+        assert!(list.may_connect(addr(1)));
+    }
+
+    #[test]
+    fn bans_cover_every_port_until_they_expire() {
+        let list = PeerList::default();
+        list.ban(addr(1), "sent a bad piece");
+        assert!(list.is_banned(addr(1).ip()));
+        assert!(!list.may_connect(addr(2)));
+        assert!(list.may_connect(SocketAddr::from(([10, 0, 0, 2], 1))));
+
+        let banned = list.banned();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].0, addr(1).ip());
+        assert!(banned[0].1 > BAN_DURATION - Duration::from_secs(1));
+
+        // the ban runs out.
+        list.bans
+            .lock()
+            .unwrap()
+            .insert(addr(1).ip(), Instant::now());
+        assert!(list.banned().is_empty());
+        assert!(!list.is_banned(addr(1).ip()));
+        assert!(list.bans.lock().unwrap().is_empty());
+        assert!(list.may_connect(addr(1)));
+    }
+}
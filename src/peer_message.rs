@@ -2,6 +2,8 @@ use anyhow::bail;
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
+use crate::merkle::{Hash, HashRequest};
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -22,13 +24,27 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    // DHT (BEP 5)
+    Port = 9,
+    // Fast extension (BEP 6)
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    // Extension protocol (BEP 10)
+    Extended = 20,
+    // BitTorrent v2 (BEP 52)
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
 }
 
 impl TryFrom<u8> for MessageTag {
     type Error = String;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        return match value {
+        match value {
             0 => Ok(MessageTag::Choke),
             1 => Ok(MessageTag::Unchoke),
             2 => Ok(MessageTag::Interested),
@@ -38,8 +54,18 @@ impl TryFrom<u8> for MessageTag {
             6 => Ok(MessageTag::Request),
             7 => Ok(MessageTag::Piece),
             8 => Ok(MessageTag::Cancel),
+            9 => Ok(MessageTag::Port),
+            13 => Ok(MessageTag::SuggestPiece),
+            14 => Ok(MessageTag::HaveAll),
+            15 => Ok(MessageTag::HaveNone),
+            16 => Ok(MessageTag::RejectRequest),
+            17 => Ok(MessageTag::AllowedFast),
+            20 => Ok(MessageTag::Extended),
+            21 => Ok(MessageTag::HashRequest),
+            22 => Ok(MessageTag::Hashes),
+            23 => Ok(MessageTag::HashReject),
             _ => Err("invalid tag".to_string()),
-        };
+        }
     }
 }
 
@@ -76,18 +102,7 @@ impl Message {
         length_bytes.copy_from_slice(&value[..4]);
         let length = u32::from_be_bytes(length_bytes) as usize;
 
-        let tag = match value[4] {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            _ => panic!("invalid tag"),
-        };
+        let tag = MessageTag::try_from(value[4]).expect("invalid tag");
 
         let payload = value[5..].to_vec();
         Some((Self { payload, tag }, length))
@@ -105,6 +120,75 @@ impl Message {
         }
     }
 
+    pub fn new_reject(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            tag: MessageTag::RejectRequest,
+            ..Self::new_request(index, begin, length)
+        }
+    }
+
+    /// A message whose payload is a single piece index, such as have, suggest piece and allowed fast.
+    pub fn new_piece_index(tag: MessageTag, index: u32) -> Self {
+        Self {
+            tag,
+            payload: index.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// An extension protocol message, `id` is the one the receiver assigned to the extension.
+    pub fn new_extended(id: u8, payload: &[u8]) -> Self {
+        let mut message = Vec::with_capacity(1 + payload.len());
+        message.push(id);
+        message.extend_from_slice(payload);
+
+        Self {
+            tag: MessageTag::Extended,
+            payload: message,
+        }
+    }
+
+    /// Tells the peer the port our DHT node listens on.
+    pub fn new_port(port: u16) -> Self {
+        Self {
+            tag: MessageTag::Port,
+            payload: port.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn new_hash_request(request: &HashRequest) -> Self {
+        Self {
+            tag: MessageTag::HashRequest,
+            payload: request.to_payload(),
+        }
+    }
+
+    /// Answers a hash request with the hashes and the uncle hashes proving them.
+    pub fn new_hashes(request: &HashRequest, hashes: &[Hash]) -> Self {
+        let mut payload = request.to_payload();
+        for hash in hashes {
+            payload.extend(hash);
+        }
+
+        Self {
+            tag: MessageTag::Hashes,
+            payload,
+        }
+    }
+
+    pub fn new_hash_reject(request: &HashRequest) -> Self {
+        Self {
+            tag: MessageTag::HashReject,
+            payload: request.to_payload(),
+        }
+    }
+
+    pub fn new_cancel(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            tag: MessageTag::Cancel,
+            ..Self::new_request(index, begin, length)
+        }
+    }
+
     pub fn read_block(&self) -> anyhow::Result<Vec<u8>> {
         match self.tag {
             MessageTag::Piece => Ok(self.payload[9..].to_vec()),
@@ -166,20 +250,12 @@ impl Decoder for MessageFramer {
             return Ok(None);
         }
 
-        let tag = match src[4] {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            tag => {
+        let tag = match MessageTag::try_from(src[4]) {
+            Ok(tag) => tag,
+            Err(_) => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
-                    format!("Unknown message type {}.", tag),
+                    format!("Unknown message type {}.", src[4]),
                 ))
             }
         };
@@ -225,3 +301,52 @@ impl Encoder<Message> for MessageFramer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(message: Message) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        MessageFramer.encode(message, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn fast_extension_messages_round_trip() {
+        for tag in [
+            MessageTag::SuggestPiece,
+            MessageTag::AllowedFast,
+            MessageTag::Have,
+        ] {
+            let mut buffer = encode(Message::new_piece_index(tag.clone(), 7));
+            assert_eq!(buffer[..], [0, 0, 0, 5, tag.clone() as u8, 0, 0, 0, 7]);
+            let decoded = MessageFramer.decode(&mut buffer).unwrap().unwrap();
+            assert_eq!(decoded.tag, tag);
+            assert_eq!(decoded.payload, [0, 0, 0, 7]);
+        }
+
+        let mut buffer = encode(Message::new_reject(1, 2, 3));
+        let decoded = MessageFramer.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(decoded.tag, MessageTag::RejectRequest);
+        assert_eq!(decoded.payload.len(), 12);
+    }
+
+    #[test]
+    fn keep_alives_are_skipped_and_partial_frames_wait() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 0, 0, 0, 0, 1][..]);
+        assert!(MessageFramer.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(&[MessageTag::HaveAll as u8]);
+        let decoded = MessageFramer.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(decoded.tag, MessageTag::HaveAll);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn unknown_and_oversized_frames_are_refused() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 1, 10][..]);
+        assert!(MessageFramer.decode(&mut buffer).is_err());
+        let mut buffer = BytesMut::from(&[0, 1, 0, 1, 7][..]);
+        assert!(MessageFramer.decode(&mut buffer).is_err());
+    }
+}
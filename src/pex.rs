@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Peers must not send more than one peer exchange message per minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages arriving sooner than this after the previous one are ignored,
+/// with some slack for timers that don't fire exactly on time.
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+/// Maximum number of added and of dropped peers in a single message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// The peer prefers encrypted connections.
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+/// The peer is a seed, it has every piece.
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer supports uTP.
+pub const FLAG_UTP: u8 = 0x04;
+/// The peer supports the holepunch extension.
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+/// The peer accepts incoming connections, we connected to it.
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// A peer exchange message (BEP 11), the peers the sender connected to
+/// and disconnected from since its previous message, in compact form.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PexMessage {
+    #[serde(default, with = "serde_bytes")]
+    added: Vec<u8>,
+
+    /// One byte of flags for each added IPv4 peer.
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    added_flags: Vec<u8>,
+
+    #[serde(default, with = "serde_bytes")]
+    added6: Vec<u8>,
+
+    /// One byte of flags for each added IPv6 peer.
+    #[serde(rename = "added6.f", default, with = "serde_bytes")]
+    added6_flags: Vec<u8>,
+
+    #[serde(default, with = "serde_bytes")]
+    dropped: Vec<u8>,
+
+    #[serde(default, with = "serde_bytes")]
+    dropped6: Vec<u8>,
+}
+
+impl PexMessage {
+    pub fn new(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Self {
+        let mut message = Self::default();
+        for &(addr, flags) in added {
+            match addr {
+                SocketAddr::V4(_) => {
+                    write_compact(&mut message.added, addr);
+                    message.added_flags.push(flags);
+                }
+                SocketAddr::V6(_) => {
+                    write_compact(&mut message.added6, addr);
+                    message.added6_flags.push(flags);
+                }
+            }
+        }
+        for &addr in dropped {
+            match addr {
+                SocketAddr::V4(_) => write_compact(&mut message.dropped, addr),
+                SocketAddr::V6(_) => write_compact(&mut message.dropped6, addr),
+            }
+        }
+        message
+    }
+
+    /// The peers the sender connected to, with their flags.
+    /// Missing flags are treated as no flags at all.
+    pub fn added(&self) -> Vec<(SocketAddr, u8)> {
+        let v4 = read_compact(&self.added, 4)
+            .enumerate()
+            .map(|(i, addr)| (addr, self.added_flags.get(i).copied().unwrap_or(0)));
+        let v6 = read_compact(&self.added6, 16)
+            .enumerate()
+            .map(|(i, addr)| (addr, self.added6_flags.get(i).copied().unwrap_or(0)));
+        v4.chain(v6).take(MAX_PEX_PEERS).collect()
+    }
+
+    /// The peers the sender disconnected from.
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        read_compact(&self.dropped, 4)
+            .chain(read_compact(&self.dropped6, 16))
+            .take(MAX_PEX_PEERS)
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        serde_bencode::from_bytes(bytes).context("decode pex message fail")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("encode pex message fail")
+    }
+}
+
+/// Appends the ip followed by the port in network byte order.
+fn write_compact(buffer: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buffer.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buffer.extend_from_slice(&ip.octets()),
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Parses compact peers whose ip is `ip_len` bytes long, a truncated last peer is ignored.
+fn read_compact(buffer: &[u8], ip_len: usize) -> impl Iterator<Item = SocketAddr> + '_ {
+    buffer.chunks_exact(ip_len + 2).map(move |chunk| {
+        let ip = if ip_len == 4 {
+            let octets: [u8; 4] = chunk[..4].try_into().expect("chunk holds an ipv4");
+            IpAddr::V4(Ipv4Addr::from(octets))
+        } else {
+            let octets: [u8; 16] = chunk[..16].try_into().expect("chunk holds an ipv6");
+            IpAddr::V6(Ipv6Addr::from(octets))
+        };
+        let port = u16::from_be_bytes([chunk[ip_len], chunk[ip_len + 1]]);
+        SocketAddr::new(ip, port)
+    })
+}
+
+/// The peer exchange state of a single connection.
+#[derive(Debug, Default)]
+pub struct PexState {
+    /// The peers we told the peer about, so only the changes are sent.
+    sent: HashSet<SocketAddr>,
+
+    /// When we last looked for changes to send.
+    last_sent: Option<Instant>,
+
+    /// When the peer sent its previous message.
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    /// When the next message may be sent, right away for the first one.
+    pub fn next_send(&self) -> Instant {
+        match self.last_sent {
+            Some(last) => last + PEX_INTERVAL,
+            None => Instant::now(),
+        }
+    }
+
+    /// The message telling the peer about the changes to `peers` since the previous one,
+    /// `peers` maps each peer we are connected to, to the address it listens on and its flags.
+    /// Returns None when nothing changed.
+    pub fn update(
+        &mut self,
+        peers: &HashMap<SocketAddr, (SocketAddr, u8)>,
+        exclude: SocketAddr,
+    ) -> Option<PexMessage> {
+        self.last_sent = Some(Instant::now());
+        let current: HashMap<SocketAddr, u8> = peers
+            .iter()
+            .filter(|(&addr, _)| addr != exclude)
+            .map(|(_, &(listen, flags))| (listen, flags))
+            .collect();
+
+        let added: Vec<(SocketAddr, u8)> = current
+            .iter()
+            .filter(|(addr, _)| !self.sent.contains(addr))
+            .map(|(&addr, &flags)| (addr, flags))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|addr| !current.contains_key(addr))
+            .copied()
+            .take(MAX_PEX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        self.sent.extend(added.iter().map(|(addr, _)| *addr));
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        Some(PexMessage::new(&added, &dropped))
+    }
+
+    /// Whether a message the peer just sent should be used,
+    /// peers that send them too often are ignored.
+    pub fn accept(&mut self) -> bool {
+        let now = Instant::now();
+        if self
+            .last_received
+            .is_some_and(|last| now - last < MIN_RECEIVE_INTERVAL)
+        {
+            return false;
+        }
+        self.last_received = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port))
+    }
+
+    #[test]
+    fn messages_round_trip_both_families() {
+        let message = PexMessage::new(
+            &[(v4(1), FLAG_SEED), (v6(2), FLAG_REACHABLE)],
+            &[v4(3), v6(4)],
+        );
+        assert_eq!(message.added, [10, 0, 0, 1, 0, 1]);
+        assert_eq!(message.added_flags, [FLAG_SEED]);
+
+        let decoded = PexMessage::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            decoded.added(),
+            [(v4(1), FLAG_SEED), (v6(2), FLAG_REACHABLE)]
+        );
+        assert_eq!(decoded.dropped(), [v4(3), v6(4)]);
+    }
+
+    #[test]
+    fn missing_flags_and_truncated_peers_are_tolerated() {
+        let decoded =
+            PexMessage::from_bytes(b"d5:added9:\x0a\x00\x00\x01\x00\x01\x0a\x00\x00e").unwrap();
+        assert_eq!(decoded.added(), [(v4(1), 0)]);
+        assert!(decoded.dropped().is_empty());
+    }
+
+    #[test]
+    fn only_changes_are_sent() {
+        let mut state = PexState::default();
+        let mut peers = HashMap::new();
+        peers.insert(v4(1), (v4(1), 0));
+        peers.insert(v4(2), (v4(20), FLAG_SEED));
+
+        // the peer we send to isn't told about itself.
+        let first = state.update(&peers, v4(1)).unwrap();
+        assert_eq!(first.added(), [(v4(20), FLAG_SEED)]);
+        assert!(state.update(&peers, v4(1)).is_none());
+
+        peers.remove(&v4(2));
+        peers.insert(v4(3), (v4(3), 0));
+        let second = state.update(&peers, v4(1)).unwrap();
+        assert_eq!(second.added(), [(v4(3), 0)]);
+        assert_eq!(second.dropped(), [v4(20)]);
+    }
+
+    #[test]
+    fn messages_hold_at_most_fifty_peers() {
+        let mut state = PexState::default();
+        let peers: HashMap<_, _> = (0..80).map(|port| (v4(port), (v4(port), 0))).collect();
+        let message = state.update(&peers, v4(1000)).unwrap();
+        assert_eq!(message.added().len(), MAX_PEX_PEERS);
+        assert_eq!(state.update(&peers, v4(1000)).unwrap().added().len(), 30);
+    }
+
+    #[test]
+    fn messages_sent_too_often_are_ignored() {
+        let mut state = PexState::default();
+        assert!(state.next_send() <= Instant::now());
+        assert!(state.accept());
+        assert!(!state.accept());
+
+        state.update(&HashMap::new(), v4(1));
+        assert!(state.next_send() > Instant::now() + MIN_RECEIVE_INTERVAL);
+    }
+}
@@ -0,0 +1,251 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use crate::{bitfield::Bitfield, priority::FilePriority};
+
+/// Decides which piece to download next from which peer.
+/// Pieces of higher priority are picked first, then rarest first,
+/// so the pieces few peers have spread quickly.
+pub struct PiecePicker {
+    /// The pieces we have downloaded and verified.
+    have: Bitfield,
+
+    /// How much we want each piece, skipped pieces aren't downloaded.
+    priorities: Vec<FilePriority>,
+
+    /// How many of the connected peers have each piece.
+    availability: Vec<u32>,
+
+    /// How many peers we are currently downloading each piece from.
+    in_progress: HashMap<usize, usize>,
+}
+
+impl PiecePicker {
+    pub fn new(have: Bitfield, wanted: Bitfield) -> Self {
+        let priorities = (0..have.len())
+            .map(|index| {
+                if wanted.get(index) {
+                    FilePriority::Normal
+                } else {
+                    FilePriority::Skip
+                }
+            })
+            .collect();
+        Self {
+            availability: vec![0; have.len()],
+            have,
+            priorities,
+            in_progress: HashMap::new(),
+        }
+    }
+
+    /// Changes how much we want each piece, pieces already being downloaded are finished.
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        assert_eq!(
+            priorities.len(),
+            self.have.len(),
+            "a priority for each piece"
+        );
+        self.priorities = priorities;
+    }
+
+    fn wanted(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.priorities.len()).filter(|&index| self.priorities[index] != FilePriority::Skip)
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// Whether we have every piece we want.
+    pub fn is_complete(&self) -> bool {
+        self.wanted().all(|index| self.have.get(index))
+    }
+
+    /// Number of bytes of the wanted pieces we are still missing, given the size of each piece.
+    pub fn left(&self, piece_size: impl Fn(usize) -> usize) -> usize {
+        self.wanted()
+            .filter(|&index| !self.have.get(index))
+            .map(piece_size)
+            .sum()
+    }
+
+    /// Of the wanted pieces, the number we have and the number of them.
+    pub fn wanted_count(&self) -> (usize, usize) {
+        self.wanted().fold((0, 0), |(have, wanted), index| {
+            (have + self.have.get(index) as usize, wanted + 1)
+        })
+    }
+
+    /// Of the wanted pieces, the bytes we have and their total size.
+    pub fn wanted_size(&self, piece_size: impl Fn(usize) -> usize) -> (u64, u64) {
+        self.wanted().fold((0, 0), |(have, wanted), index| {
+            let size = piece_size(index) as u64;
+            let had = if self.have.get(index) { size } else { 0 };
+            (have + had, wanted + size)
+        })
+    }
+
+    fn is_needed(&self, index: usize) -> bool {
+        self.priorities[index] != FilePriority::Skip && !self.have.get(index)
+    }
+
+    /// We are interested in a peer as long as it has a piece we want and don't have.
+    pub fn is_interesting(&self, peer: &Bitfield) -> bool {
+        peer.iter().any(|index| self.is_needed(index))
+    }
+
+    /// Counts the pieces of a peer that just connected or sent its bitfield.
+    pub fn add_peer(&mut self, peer: &Bitfield) {
+        for index in peer.iter() {
+            self.availability[index] += 1;
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected.
+    pub fn remove_peer(&mut self, peer: &Bitfield) {
+        for index in peer.iter() {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+
+    /// Counts a piece a peer announced with a have message.
+    pub fn add_have(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Picks the most wanted and then rarest needed piece the peer has that nobody else is downloading.
+    /// When every needed piece is already being downloaded (the end game),
+    /// the same piece is downloaded from several peers so a slow one doesn't hold us up.
+    pub fn pick(&mut self, peer: &Bitfield) -> Option<usize> {
+        let candidates = || peer.iter().filter(|&index| self.is_needed(index));
+
+        let index = candidates()
+            .filter(|index| !self.in_progress.contains_key(index))
+            .min_by_key(|&index| (Reverse(self.priorities[index]), self.availability[index]))
+            .or_else(|| candidates().min_by_key(|index| self.in_progress.get(index).copied()))?;
+
+        *self.in_progress.entry(index).or_default() += 1;
+        Some(index)
+    }
+
+    /// Picks the first of `candidates` that the peer has and we still need, preferring
+    /// the ones nobody else is downloading. Used for suggested and allowed fast pieces.
+    pub fn pick_among(
+        &mut self,
+        peer: &Bitfield,
+        candidates: impl IntoIterator<Item = usize>,
+    ) -> Option<usize> {
+        let candidates: Vec<usize> = candidates
+            .into_iter()
+            .filter(|&index| index < peer.len() && peer.get(index) && self.is_needed(index))
+            .collect();
+        let index = candidates
+            .iter()
+            .find(|index| !self.in_progress.contains_key(index))
+            .or_else(|| candidates.first())
+            .copied()?;
+
+        *self.in_progress.entry(index).or_default() += 1;
+        Some(index)
+    }
+
+    /// Gives up on a piece we picked, so it can be picked again.
+    pub fn release(&mut self, index: usize) {
+        if let Some(count) = self.in_progress.get_mut(&index) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_progress.remove(&index);
+            }
+        }
+    }
+
+    /// Marks a verified piece as done.
+    /// Returns false when another peer completed it first.
+    pub fn complete(&mut self, index: usize) -> bool {
+        self.in_progress.remove(&index);
+        if self.have.get(index) {
+            return false;
+        }
+        self.have.set(index);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(num_pieces: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(num_pieces);
+        for &index in pieces {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn picks_the_rarest_piece_first() {
+        let mut picker = PiecePicker::new(Bitfield::new(3), Bitfield::full(3));
+        picker.add_peer(&bitfield(3, &[0, 1, 2]));
+        picker.add_peer(&bitfield(3, &[0, 2]));
+        picker.add_have(0);
+
+        assert_eq!(picker.pick(&Bitfield::full(3)), Some(1));
+        assert_eq!(picker.pick(&Bitfield::full(3)), Some(2));
+        assert_eq!(picker.pick(&Bitfield::full(3)), Some(0));
+    }
+
+    #[test]
+    fn removing_a_peer_forgets_its_pieces() {
+        let mut picker = PiecePicker::new(Bitfield::new(2), Bitfield::full(2));
+        let peer = bitfield(2, &[0]);
+        picker.add_peer(&peer);
+        picker.add_peer(&bitfield(2, &[1]));
+        picker.add_have(1);
+        picker.remove_peer(&peer);
+
+        // piece 0 is now the rarest.
+        assert_eq!(picker.pick(&Bitfield::full(2)), Some(0));
+    }
+
+    #[test]
+    fn higher_priority_wins_over_rarity() {
+        let mut picker = PiecePicker::new(Bitfield::new(2), Bitfield::full(2));
+        picker.set_priorities(vec![FilePriority::Normal, FilePriority::High]);
+        picker.add_peer(&bitfield(2, &[0, 1]));
+        picker.add_peer(&bitfield(2, &[1]));
+
+        assert_eq!(picker.pick(&Bitfield::full(2)), Some(1));
+    }
+
+    #[test]
+    fn skipped_and_owned_pieces_are_not_picked() {
+        let mut picker = PiecePicker::new(bitfield(3, &[0]), bitfield(3, &[0, 1]));
+        assert!(picker.is_interesting(&bitfield(3, &[1])));
+        assert!(!picker.is_interesting(&bitfield(3, &[0, 2])));
+        assert_eq!(picker.pick(&bitfield(3, &[0, 2])), None);
+        assert_eq!(picker.left(|_| 10), 10);
+    }
+
+    #[test]
+    fn end_game_downloads_a_piece_twice() {
+        let mut picker = PiecePicker::new(Bitfield::new(1), Bitfield::full(1));
+        assert_eq!(picker.pick(&Bitfield::full(1)), Some(0));
+        assert_eq!(picker.pick(&Bitfield::full(1)), Some(0));
+
+        assert!(picker.complete(0));
+        assert!(!picker.complete(0));
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn released_pieces_are_picked_again_first() {
+        let mut picker = PiecePicker::new(Bitfield::new(2), Bitfield::full(2));
+        picker.add_peer(&bitfield(2, &[0]));
+        assert_eq!(picker.pick(&Bitfield::full(2)), Some(1));
+        picker.release(1);
+        assert_eq!(picker.pick(&bitfield(2, &[0, 1])), Some(1));
+    }
+}
@@ -0,0 +1,309 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context};
+use regex::Regex;
+
+use crate::torrent::{file_slices, Torrent};
+
+/// How much we want a file of the torrent, the pieces of higher priority files are picked first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilePriority {
+    /// Not downloaded, unless a piece it shares with a wanted file brings part of it.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for FilePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => bail!("priority must be skip, low, normal or high"),
+        }
+    }
+}
+
+impl fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Skip => "skip",
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        })
+    }
+}
+
+/// The priority of each piece, that of the most wanted file it overlaps.
+/// `files` are the priorities of the files of `torrent`, padding included.
+pub fn piece_priorities(torrent: &Torrent, files: &[FilePriority]) -> Vec<FilePriority> {
+    let entries = torrent.files();
+    (0..torrent.num_pieces())
+        .map(|index| {
+            let offset = index * torrent.info.piece_length;
+            file_slices(entries, offset, torrent.piece_size(index))
+                .iter()
+                .filter(|slice| !entries[slice.file].pad)
+                .map(|slice| files.get(slice.file).copied().unwrap_or_default())
+                .max()
+                .unwrap_or(FilePriority::Skip)
+        })
+        .collect()
+}
+
+/// The priorities of the files of `torrent` from the `only` and `skip` patterns.
+/// With `only` patterns the files they don't match are skipped, then the files
+/// matching a `skip` pattern are. See [`match_files`] for the patterns.
+pub fn select_files(
+    torrent: &Torrent,
+    only: &[String],
+    skip: &[String],
+) -> anyhow::Result<Vec<FilePriority>> {
+    let only = (!only.is_empty())
+        .then(|| match_files(torrent, only))
+        .transpose()?;
+    let skip = match_files(torrent, skip)?;
+    let priorities = torrent
+        .files()
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            let wanted = only.as_ref().is_none_or(|only| only[index]) && !skip[index];
+            if wanted && !file.pad {
+                FilePriority::Normal
+            } else {
+                FilePriority::Skip
+            }
+        })
+        .collect();
+    Ok(priorities)
+}
+
+/// Which files of `torrent`, padding included, match any of `patterns`. A pattern is either
+/// a list of file indexes and ranges like `0,3-5`, counting the files without padding,
+/// or a glob like `*.mkv` or `docs/**`. Globs without a slash match the file name,
+/// the others the path in the torrent. Padding files never match.
+pub fn match_files(torrent: &Torrent, patterns: &[String]) -> anyhow::Result<Vec<bool>> {
+    let files = torrent.files();
+    let num_files = files.iter().filter(|file| !file.pad).count();
+    let patterns = patterns
+        .iter()
+        .map(|pattern| FilePattern::parse(pattern, num_files))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut index = 0;
+    let mut matched = Vec::with_capacity(files.len());
+    for file in files {
+        if file.pad {
+            matched.push(false);
+            continue;
+        }
+        matched.push(
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches(index, &file.path)),
+        );
+        index += 1;
+    }
+    Ok(matched)
+}
+
+enum FilePattern {
+    Indexes(Vec<(usize, usize)>),
+    Glob { regex: Regex, name_only: bool },
+}
+
+impl FilePattern {
+    fn parse(pattern: &str, num_files: usize) -> anyhow::Result<Self> {
+        let is_index_list = !pattern.is_empty()
+            && pattern
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == ',' || c == '-');
+        if !is_index_list {
+            return Ok(Self::Glob {
+                regex: glob_regex(pattern)?,
+                name_only: !pattern.contains('/'),
+            });
+        }
+
+        let mut ranges = Vec::new();
+        for part in pattern.split(',').filter(|part| !part.is_empty()) {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let first: usize = first
+                .parse()
+                .with_context(|| format!("invalid file index range {part}"))?;
+            let last: usize = last
+                .parse()
+                .with_context(|| format!("invalid file index range {part}"))?;
+            if first > last || last >= num_files {
+                bail!("file index range {part} is not within the {num_files} files");
+            }
+            ranges.push((first, last));
+        }
+        Ok(Self::Indexes(ranges))
+    }
+
+    fn matches(&self, index: usize, path: &[String]) -> bool {
+        match self {
+            Self::Indexes(ranges) => ranges
+                .iter()
+                .any(|&(first, last)| first <= index && index <= last),
+            Self::Glob { regex, name_only } => match (name_only, path.last()) {
+                (true, Some(name)) => regex.is_match(name),
+                _ => regex.is_match(&path.join("/")),
+            },
+        }
+    }
+}
+
+/// A regex matching what the glob matches: `*` and `?` stay within a path component, `**` doesn't.
+fn glob_regex(glob: &str) -> anyhow::Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).with_context(|| format!("invalid glob {glob}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{torrent::testing, BLOCK_MAX};
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    fn torrent() -> Torrent {
+        testing::torrent(
+            "dir",
+            &[
+                ("a.mkv", &[1; 10]),
+                ("docs/readme.txt", &[2; 10]),
+                ("docs/deep/b.mkv", &[3; 10]),
+                ("c.txt", &[4; 10]),
+            ],
+            16,
+        )
+    }
+
+    #[test]
+    fn priorities_parse_back() {
+        for priority in [
+            FilePriority::Skip,
+            FilePriority::Low,
+            FilePriority::Normal,
+            FilePriority::High,
+        ] {
+            assert_eq!(
+                priority.to_string().parse::<FilePriority>().unwrap(),
+                priority
+            );
+        }
+        assert!("urgent".parse::<FilePriority>().is_err());
+        assert!(
+            FilePriority::Skip < FilePriority::Low && FilePriority::Normal < FilePriority::High
+        );
+    }
+
+    #[test]
+    fn globs_stay_within_components_unless_doubled() {
+        let regex = glob_regex("docs/*.txt").unwrap();
+        assert!(regex.is_match("docs/readme.txt"));
+        assert!(!regex.is_match("docs/deep/readme.txt"));
+        assert!(glob_regex("docs/**")
+            .unwrap()
+            .is_match("docs/deep/readme.txt"));
+        assert!(glob_regex("?.mkv").unwrap().is_match("a.mkv"));
+        assert!(!glob_regex("?.mkv").unwrap().is_match("ab.mkv"));
+        // regex syntax in globs is literal.
+        assert!(!glob_regex("a+.mkv").unwrap().is_match("aa.mkv"));
+    }
+
+    #[test]
+    fn files_match_by_name_path_or_index() {
+        let torrent = torrent();
+        let matched = |list: &[&str]| match_files(&torrent, &patterns(list)).unwrap();
+        assert_eq!(matched(&["*.mkv"]), [true, false, true, false]);
+        assert_eq!(matched(&["docs/*"]), [false, true, false, false]);
+        assert_eq!(matched(&["docs/**"]), [false, true, true, false]);
+        assert_eq!(matched(&["0,2-3"]), [true, false, true, true]);
+        assert_eq!(matched(&["1", "*.txt"]), [false, true, false, true]);
+        assert_eq!(matched(&[]), [false; 4]);
+
+        for invalid in ["4", "2-1", "1-"] {
+            assert!(
+                match_files(&torrent, &patterns(&[invalid])).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_then_skip_select_the_files() {
+        let torrent = torrent();
+        let select = |only: &[&str], skip: &[&str]| {
+            select_files(&torrent, &patterns(only), &patterns(skip)).unwrap()
+        };
+        use FilePriority::{Normal, Skip};
+        assert_eq!(select(&[], &[]), [Normal; 4]);
+        assert_eq!(select(&["docs/**"], &[]), [Skip, Normal, Normal, Skip]);
+        assert_eq!(select(&["docs/**"], &["*.mkv"]), [Skip, Normal, Skip, Skip]);
+        assert_eq!(select(&[], &["0-1"]), [Skip, Skip, Normal, Normal]);
+    }
+
+    #[test]
+    fn pieces_take_the_priority_of_their_most_wanted_file() {
+        use FilePriority::{High, Low, Normal, Skip};
+        // 10 byte files in 16 byte pieces: the pieces cover files 0-1, 1-3 and 3.
+        let torrent = torrent();
+        assert_eq!(
+            piece_priorities(&torrent, &[Skip, Low, Skip, High]),
+            [Low, High, High]
+        );
+        assert_eq!(
+            piece_priorities(&torrent, &[High, Skip, Low, Skip]),
+            [High, Low, Skip]
+        );
+        assert_eq!(piece_priorities(&torrent, &[Skip; 4]), [Skip; 3]);
+        // files without a priority are normal.
+        assert_eq!(piece_priorities(&torrent, &[Low]), [Normal; 3]);
+    }
+
+    #[test]
+    fn padding_never_matches_nor_raises_priorities() {
+        use FilePriority::{High, Normal, Skip};
+        let torrent = testing::torrent_v2("dir", &[("a", &[1; 10]), ("b", &[2; 10])], BLOCK_MAX);
+        // a, its padding and b.
+        assert_eq!(torrent.files().len(), 3);
+        assert_eq!(
+            match_files(&torrent, &patterns(&["*", "0-1"])).unwrap(),
+            [true, false, true]
+        );
+        assert_eq!(
+            select_files(&torrent, &[], &[]).unwrap(),
+            [Normal, Skip, Normal]
+        );
+        assert_eq!(
+            piece_priorities(&torrent, &[Skip, High, Skip]),
+            [Skip, Skip]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// How long a rate takes to follow a change, the older samples weigh less and less.
+const RATE_TIME_CONSTANT: Duration = Duration::from_secs(5);
+
+/// How a torrent is doing, published by its swarm whenever a piece completes
+/// and every second with the rates.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    /// Of the pieces we want, the ones we have and all of them.
+    pub pieces_done: usize,
+    pub pieces_wanted: usize,
+    /// The same in bytes.
+    pub bytes_done: u64,
+    pub bytes_wanted: u64,
+    /// Piece data received and sent since the swarm started.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes per second, averaged over the last seconds.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: usize,
+    /// Seconds until we have every piece we want at the current rate,
+    /// none while nothing is downloading.
+    pub eta_secs: Option<u64>,
+}
+
+impl Progress {
+    pub fn is_complete(&self) -> bool {
+        self.pieces_done == self.pieces_wanted
+    }
+
+    /// How much of what we want we have, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        if self.bytes_wanted == 0 {
+            return 1.0;
+        }
+        self.bytes_done as f64 / self.bytes_wanted as f64
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        self.eta_secs.map(Duration::from_secs)
+    }
+
+    /// Updates the estimate from what is left and the download rate.
+    pub(crate) fn estimate(&mut self) {
+        let left = self.bytes_wanted.saturating_sub(self.bytes_done);
+        self.eta_secs = match (left, self.download_rate) {
+            (0, _) => Some(0),
+            (_, 0) => None,
+            (left, rate) => Some(left.div_ceil(rate)),
+        };
+    }
+}
+
+/// Turns a running total of bytes into a rate, smoothed so it doesn't jump with every piece.
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    last_total: u64,
+    rate: f64,
+}
+
+impl RateMeter {
+    /// Takes the total `elapsed` after the previous sample, returns the bytes per second.
+    pub fn sample(&mut self, total: u64, elapsed: Duration) -> u64 {
+        if elapsed.is_zero() {
+            return self.rate.round() as u64;
+        }
+        let instant = total.saturating_sub(self.last_total) as f64 / elapsed.as_secs_f64();
+        let weight = 1.0 - (-elapsed.as_secs_f64() / RATE_TIME_CONSTANT.as_secs_f64()).exp();
+        self.rate += (instant - self.rate) * weight;
+        self.last_total = total;
+        self.rate.round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(bytes_done: u64, bytes_wanted: u64, download_rate: u64) -> Progress {
+        let mut progress = Progress {
+            pieces_done: bytes_done.div_ceil(10) as usize,
+            pieces_wanted: bytes_wanted.div_ceil(10) as usize,
+            bytes_done,
+            bytes_wanted,
+            download_rate,
+            ..Progress::default()
+        };
+        progress.estimate();
+        progress
+    }
+
+    #[test]
+    fn fractions_and_estimates_follow_what_is_left() {
+        let halfway = progress(50, 100, 10);
+        assert!(!halfway.is_complete());
+        assert_eq!(halfway.fraction(), 0.5);
+        assert_eq!(halfway.eta(), Some(Duration::from_secs(5)));
+        // a partial second still takes a second.
+        assert_eq!(progress(50, 100, 40).eta_secs, Some(2));
+
+        let stalled = progress(50, 100, 0);
+        assert_eq!(stalled.eta(), None);
+
+        let done = progress(100, 100, 0);
+        assert!(done.is_complete());
+        assert_eq!(done.fraction(), 1.0);
+        assert_eq!(done.eta(), Some(Duration::ZERO));
+        // wanting nothing is being done.
+        assert_eq!(progress(0, 0, 0).fraction(), 1.0);
+    }
+
+    #[test]
+    fn rates_are_smoothed() {
+        let mut meter = RateMeter::default();
+        let second = Duration::from_secs(1);
+        let first = meter.sample(1000, second);
+        assert!(first > 0 && first < 1000, "{first}");
+
+        // a steady rate is approached.
+        let mut total = 1000;
+        let mut rate = first;
+        for _ in 0..60 {
+            total += 1000;
+            rate = meter.sample(total, second);
+        }
+        assert!((995..=1000).contains(&rate), "{rate}");
+
+        // a stall brings it down, but not at once.
+        let stalled = meter.sample(total, second);
+        assert!(stalled > 0 && stalled < rate, "{stalled}");
+        // no time passing changes nothing.
+        assert_eq!(meter.sample(total + 5000, Duration::ZERO), stalled);
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    sync::OnceLock,
+};
+
+/// The operating system's source of random bytes, opened once.
+static URANDOM: OnceLock<File> = OnceLock::new();
+
+/// Opens the operating system's source of random bytes, at startup, so a missing one is
+/// reported there rather than in the middle of a session.
+pub fn init() -> io::Result<()> {
+    urandom().map(drop)
+}
+
+fn urandom() -> io::Result<&'static File> {
+    match URANDOM.get() {
+        Some(urandom) => Ok(urandom),
+        None => {
+            let urandom = File::open("/dev/urandom")?;
+            Ok(URANDOM.get_or_init(|| urandom))
+        }
+    }
+}
+
+/// Fills `buffer` with random bytes from the operating system, fit for key material.
+pub fn fill(buffer: &mut [u8]) -> io::Result<()> {
+    urandom()?.read_exact(buffer)
+}
+
+/// `N` random bytes from the operating system.
+pub fn bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    fill(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A random number in `0..bound`, `bound` must not be zero.
+pub fn below(bound: u64) -> io::Result<u64> {
+    // rejecting the values of the incomplete last range keeps every number equally likely.
+    let zone = u64::MAX - u64::MAX % bound;
+    loop {
+        let value = u64::from_be_bytes(bytes()?);
+        if value < zone {
+            return Ok(value % bound);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_differ_between_calls() {
+        assert_ne!(bytes::<16>().unwrap(), bytes::<16>().unwrap());
+    }
+
+    #[test]
+    fn below_stays_in_range() {
+        for bound in [1, 2, 7, 1000] {
+            for _ in 0..100 {
+                assert!(below(bound).unwrap() < bound);
+            }
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// The longest a connection sleeps before looking at the rate again,
+/// so raising a limit takes effect soon.
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Bytes per second in each direction, zero for no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rates {
+    #[serde(default)]
+    pub download: usize,
+    #[serde(default)]
+    pub upload: usize,
+}
+
+/// Parses a rate in bytes per second, with an optional k or m suffix for KiB/s or MiB/s.
+pub fn parse_rate(s: &str) -> anyhow::Result<usize> {
+    let lower = s.to_ascii_lowercase();
+    let (number, unit) = match lower.strip_suffix(['k', 'm']) {
+        Some(number) if lower.ends_with('k') => (number, 1 << 10),
+        Some(number) => (number, 1 << 20),
+        None => (lower.as_str(), 1),
+    };
+    let number: usize = number
+        .parse()
+        .with_context(|| format!("invalid rate {s}, expected bytes per second like 500k"))?;
+    number
+        .checked_mul(unit)
+        .with_context(|| format!("rate {s} is too large"))
+}
+
+/// A token bucket holding up to a second worth of bytes. Transfers may take more than
+/// there is, the ones after them then wait until the bucket is filled back up.
+pub struct RateLimiter {
+    /// Shared with the limiters following this one.
+    rate: Arc<AtomicUsize>,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: Arc<AtomicUsize>) -> Self {
+        let tokens = rate.load(Ordering::Relaxed) as f64;
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    fn rate(&self) -> usize {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Takes `bytes` from the bucket, going into debt if there aren't as many.
+    fn consume(&self, bytes: usize) {
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(rate);
+        bucket.tokens -= bytes as f64;
+    }
+
+    /// Waits until the bucket is out of debt.
+    async fn ready(&self) {
+        loop {
+            let wait = {
+                let rate = self.rate();
+                if rate == 0 {
+                    return;
+                }
+                let mut bucket = self.bucket.lock().unwrap();
+                bucket.refill(rate);
+                if bucket.tokens >= 0.0 {
+                    return;
+                }
+                Duration::from_secs_f64(-bucket.tokens / rate as f64)
+            };
+            tokio::time::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last = now;
+    }
+}
+
+/// A limit in each direction. Clones share the buckets, so everything holding
+/// a clone shares the bandwidth.
+#[derive(Clone)]
+pub struct RateLimits {
+    download: Arc<RateLimiter>,
+    upload: Arc<RateLimiter>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::new(Rates::default())
+    }
+}
+
+impl RateLimits {
+    pub fn new(rates: Rates) -> Self {
+        Self {
+            download: Arc::new(RateLimiter::new(Arc::new(rates.download.into()))),
+            upload: Arc::new(RateLimiter::new(Arc::new(rates.upload.into()))),
+        }
+    }
+
+    pub fn rates(&self) -> Rates {
+        Rates {
+            download: self.download.rate(),
+            upload: self.upload.rate(),
+        }
+    }
+
+    /// Changes the rates, for the followers too.
+    pub fn set_rates(&self, rates: Rates) {
+        self.download.rate.store(rates.download, Ordering::Relaxed);
+        self.upload.rate.store(rates.upload, Ordering::Relaxed);
+    }
+
+    /// Limits with buckets of their own at the same rates, which follow later changes.
+    /// Gives every connection the same limit.
+    pub fn follower(&self) -> Self {
+        Self {
+            download: Arc::new(RateLimiter::new(self.download.rate.clone())),
+            upload: Arc::new(RateLimiter::new(self.upload.rate.clone())),
+        }
+    }
+}
+
+/// The limits the traffic of a connection counts against, like the session's,
+/// the torrent's and the connection's own.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    levels: Vec<RateLimits>,
+}
+
+impl Throttle {
+    pub fn with_limits(mut self, limits: RateLimits) -> Self {
+        self.levels.push(limits);
+        self
+    }
+
+    pub(crate) async fn ready_to_receive(&self) {
+        for level in &self.levels {
+            level.download.ready().await;
+        }
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        for level in &self.levels {
+            level.download.consume(bytes);
+        }
+    }
+
+    pub(crate) async fn ready_to_send(&self) {
+        for level in &self.levels {
+            level.upload.ready().await;
+        }
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        for level in &self.levels {
+            level.upload.consume(bytes);
+        }
+    }
+}
+
+/// When the alternate limits apply, a time of day on some days of the week like
+/// `mon-fri 08:00-18:00 +02:00`. Without days it is every day, without an offset
+/// the times are UTC. A range past midnight like `fri 22:00-06:00` is fine, the days are
+/// those it starts on: that one lasts until saturday 06:00.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    /// Monday first.
+    days: [bool; 7],
+    /// Minutes after midnight.
+    start: u32,
+    end: u32,
+    /// Minutes ahead of UTC.
+    utc_offset: i32,
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace().peekable();
+        let days = match parts.next_if(|part| part.starts_with(|c: char| c.is_alphabetic())) {
+            Some(days) => parse_days(days)?,
+            None => [true; 7],
+        };
+        let (start, end) = parts
+            .next()
+            .and_then(|range| range.split_once('-'))
+            .context("schedule needs a time range like 08:00-18:00")?;
+        let utc_offset = match parts.next() {
+            Some(offset) => parse_offset(offset)?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            bail!("schedule has too many parts, expected like mon-fri 08:00-18:00 +02:00");
+        }
+        Ok(Self {
+            days,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            utc_offset,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.days != [true; 7] {
+            let days: Vec<_> = DAYS
+                .iter()
+                .zip(self.days)
+                .filter(|(_, on)| *on)
+                .map(|(day, _)| *day)
+                .collect();
+            write!(f, "{} ", days.join(","))?;
+        }
+        let time = |minutes: u32| format!("{:02}:{:02}", minutes / 60, minutes % 60);
+        write!(f, "{}-{}", time(self.start), time(self.end))?;
+        if self.utc_offset != 0 {
+            let sign = if self.utc_offset < 0 { '-' } else { '+' };
+            let offset = self.utc_offset.unsigned_abs();
+            write!(f, " {sign}{:02}:{:02}", offset / 60, offset % 60)?;
+        }
+        Ok(())
+    }
+}
+
+impl Schedule {
+    /// Whether the schedule is on right now.
+    pub fn is_active(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.is_active_at(now.as_secs())
+    }
+
+    /// Whether the schedule is on `secs` after the Unix epoch.
+    fn is_active_at(&self, secs: u64) -> bool {
+        let minutes = (secs / 60) as i64 + self.utc_offset as i64;
+        let minute_of_day = minutes.rem_euclid(24 * 60) as u32;
+        // the epoch was on a thursday.
+        let weekday = (minutes.div_euclid(24 * 60) + 3).rem_euclid(7) as usize;
+        if self.start <= self.end {
+            self.days[weekday] && (self.start..self.end).contains(&minute_of_day)
+        } else if minute_of_day >= self.start {
+            self.days[weekday]
+        } else {
+            // after midnight, the range started the day before.
+            minute_of_day < self.end && self.days[(weekday + 6) % 7]
+        }
+    }
+}
+
+/// Parses days like `mon-fri` or `sat,sun`.
+fn parse_days(s: &str) -> anyhow::Result<[bool; 7]> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|day| name.eq_ignore_ascii_case(day))
+            .with_context(|| format!("invalid day {name}, expected mon, tue, ... sun"))
+    };
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (day(first)?, day(last)?);
+                // a range like fri-mon goes over the weekend.
+                let len = (last + 7 - first) % 7 + 1;
+                for offset in 0..len {
+                    days[(first + offset) % 7] = true;
+                }
+            }
+            None => days[day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+/// Parses a time of day like `08:30` into minutes after midnight.
+fn parse_time(s: &str) -> anyhow::Result<u32> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .with_context(|| format!("invalid time {s}, expected like 08:30"))?;
+    let (hours, minutes): (u32, u32) = (
+        hours.parse().context("invalid hours")?,
+        minutes.parse().context("invalid minutes")?,
+    );
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        bail!("invalid time {s}");
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// Parses an offset from UTC like `+02:00` or `-05:30` into minutes.
+fn parse_offset(s: &str) -> anyhow::Result<i32> {
+    let (sign, time) = match s.strip_prefix('-') {
+        Some(time) => (-1, time),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let minutes = parse_time(time).with_context(|| format!("invalid utc offset {s}"))?;
+    Ok(sign * minutes as i32)
+}
+
+/// How fast a session may transfer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bandwidth {
+    /// Over all torrents.
+    pub global: Rates,
+    /// For each connection.
+    pub per_peer: Rates,
+    /// Over all torrents instead of `global` while the schedule is on.
+    pub alternate: Rates,
+    pub schedule: Option<Schedule>,
+}
+
+impl Bandwidth {
+    /// Whether the alternate limits apply right now.
+    pub fn alternate_active(&self) -> bool {
+        self.schedule.as_ref().is_some_and(Schedule::is_active)
+    }
+
+    /// The limits over all torrents right now.
+    pub fn current(&self) -> Rates {
+        if self.alternate_active() {
+            self.alternate
+        } else {
+            self.global
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_take_unit_suffixes() {
+        assert_eq!(parse_rate("500").unwrap(), 500);
+        assert_eq!(parse_rate("500k").unwrap(), 500 << 10);
+        assert_eq!(parse_rate("2M").unwrap(), 2 << 20);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("1g").is_err());
+        assert_eq!(
+            parse_rate(&format!("{}m", usize::MAX >> 20)).unwrap(),
+            usize::MAX >> 20 << 20
+        );
+        let err = parse_rate(&format!("{}m", usize::MAX >> 19)).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+        assert!(parse_rate("99999999999999999999999k").is_err());
+    }
+
+    #[test]
+    fn schedules_parse_back() {
+        for (schedule, shown) in [
+            ("08:00-18:00", "08:00-18:00"),
+            ("mon-fri 08:00-18:00", "mon,tue,wed,thu,fri 08:00-18:00"),
+            (
+                "fri-mon 22:00-06:00 +02:00",
+                "mon,fri,sat,sun 22:00-06:00 +02:00",
+            ),
+            ("SAT,sun 00:00-24:00 -05:30", "sat,sun 00:00-24:00 -05:30"),
+        ] {
+            let parsed: Schedule = schedule.parse().unwrap();
+            assert_eq!(parsed.to_string(), shown);
+            assert_eq!(shown.parse::<Schedule>().unwrap(), parsed);
+        }
+        for invalid in [
+            "",
+            "mon-fri",
+            "08:00",
+            "25:00-26:00",
+            "08:60-09:00",
+            "someday 08:00-09:00",
+            "08:00-09:00 +02:00 extra",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{invalid}");
+        }
+    }
+
+    /// Seconds after the epoch on the `day`th of January 1970, a thursday the 1st.
+    fn at(day: u64, hours: u64, minutes: u64) -> u64 {
+        ((day - 1) * 24 * 60 + hours * 60 + minutes) * 60
+    }
+
+    #[test]
+    fn schedules_are_active_on_their_days_and_hours() {
+        let office: Schedule = "mon-fri 08:00-18:00".parse().unwrap();
+        assert!(office.is_active_at(at(1, 8, 0)));
+        assert!(office.is_active_at(at(1, 17, 59)));
+        assert!(!office.is_active_at(at(1, 18, 0)));
+        assert!(!office.is_active_at(at(1, 7, 59)));
+        // the 3rd was a saturday.
+        assert!(!office.is_active_at(at(3, 12, 0)));
+        assert!(office.is_active_at(at(5, 12, 0)));
+
+        // past midnight, and the days are those the range starts on.
+        let night: Schedule = "fri 22:00-06:00".parse().unwrap();
+        assert!(night.is_active_at(at(2, 23, 0)));
+        assert!(night.is_active_at(at(3, 1, 0)));
+        assert!(!night.is_active_at(at(3, 6, 0)));
+        assert!(!night.is_active_at(at(2, 1, 0)));
+        assert!(!night.is_active_at(at(1, 23, 0)));
+        assert!(!night.is_active_at(at(3, 23, 0)));
+
+        // 08:00 at +02:00 is 06:00 UTC.
+        let ahead: Schedule = "08:00-09:00 +02:00".parse().unwrap();
+        assert!(ahead.is_active_at(at(1, 6, 30)));
+        assert!(!ahead.is_active_at(at(1, 8, 30)));
+    }
+
+    #[test]
+    fn bandwidth_switches_to_the_alternate_rates_on_schedule() {
+        let mut bandwidth = Bandwidth {
+            global: Rates {
+                download: 100,
+                upload: 10,
+            },
+            alternate: Rates {
+                download: 1,
+                upload: 1,
+            },
+            ..Bandwidth::default()
+        };
+        assert_eq!(bandwidth.current(), bandwidth.global);
+        bandwidth.schedule = Some("00:00-24:00".parse().unwrap());
+        assert!(bandwidth.alternate_active());
+        assert_eq!(bandwidth.current(), bandwidth.alternate);
+    }
+
+    #[tokio::test]
+    async fn transfers_over_the_rate_wait_for_the_bucket() {
+        let limits = RateLimits::new(Rates {
+            download: 10_000,
+            upload: 0,
+        });
+        let throttle = Throttle::default().with_limits(limits.clone());
+
+        // a second worth of bytes is there to begin with.
+        let start = Instant::now();
+        throttle.received(10_000);
+        throttle.ready_to_receive().await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        throttle.received(2_000);
+        throttle.ready_to_receive().await;
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(150), "{waited:?}");
+        assert!(waited < Duration::from_secs(1), "{waited:?}");
+
+        // without a limit nothing waits.
+        throttle.sent(1 << 30);
+        let start = Instant::now();
+        throttle.ready_to_send().await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn followers_share_the_rate_but_not_the_bucket() {
+        let shared = RateLimits::new(Rates {
+            download: 1_000,
+            upload: 1_000,
+        });
+        let follower = shared.follower();
+        shared.clone().download.consume(5_000);
+        let start = Instant::now();
+        follower.download.ready().await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        shared.set_rates(Rates {
+            download: 0,
+            upload: 7,
+        });
+        assert_eq!(follower.rates(), shared.rates());
+        // lifting the limit lets the one in debt go too.
+        shared.download.ready().await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
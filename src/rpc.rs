@@ -0,0 +1,992 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::{TcpListener, UnixListener, UnixStream},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::warn;
+use crate::{
+    magnet::Magnet,
+    priority::{match_files, select_files, FilePriority},
+    session::{Session, TorrentStatus},
+    torrent::Torrent,
+    transmission::{TransmissionRpc, TRANSMISSION_RPC_PATH},
+};
+
+/// Where the daemon listens for control requests by default.
+pub const DEFAULT_RPC_ENDPOINT: &str = "127.0.0.1:6880";
+
+/// The largest request body we read.
+const MAX_BODY: usize = 16 << 20;
+/// The longest request line or header we read, with its line break.
+const MAX_HEADER_LINE: usize = 8 << 10;
+/// The most headers a request may have.
+const MAX_HEADERS: usize = 100;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The request was valid but the session couldn't carry it out.
+const SESSION_ERROR: i64 = -32000;
+
+/// Where the daemon accepts JSON-RPC 2.0 requests: an address for requests POSTed over HTTP
+/// as `application/json` to an ip address or localhost, or `unix:PATH` for a Unix socket
+/// taking one request per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcEndpoint {
+    Http(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for RpcEndpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => Ok(Self::Http(
+                s.parse()
+                    .context("endpoint must be host:port or unix:PATH")?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RpcEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl fmt::Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::new(SESSION_ERROR, format!("{err:#}"))
+    }
+}
+
+/// Answers control requests for a session until it is told to shut down.
+pub struct RpcServer {
+    session: Arc<Session>,
+    /// Cancelled by the shutdown method, for the daemon to stop.
+    shutdown: CancellationToken,
+    /// Answers Transmission clients next to our own requests over HTTP.
+    transmission: Option<TransmissionRpc>,
+}
+
+impl RpcServer {
+    pub fn new(session: Arc<Session>, shutdown: CancellationToken) -> Self {
+        Self {
+            session,
+            shutdown,
+            transmission: None,
+        }
+    }
+
+    /// Also accepts Transmission's RPC protocol on [`TRANSMISSION_RPC_PATH`].
+    pub fn with_transmission(mut self) -> io::Result<Self> {
+        self.transmission = Some(TransmissionRpc::new(self.session.clone())?);
+        Ok(self)
+    }
+
+    /// Accepts requests on `endpoint` until the server is shut down.
+    pub async fn serve(self: Arc<Self>, endpoint: &RpcEndpoint) -> anyhow::Result<()> {
+        match endpoint {
+            RpcEndpoint::Http(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("bind rpc endpoint {addr} fail"))?;
+                loop {
+                    let (stream, addr) = tokio::select! {
+                        accepted = listener.accept() => accepted?,
+                        _ = self.shutdown.cancelled() => return Ok(()),
+                    };
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = server.serve_http(stream).await {
+                            warn!("rpc client {addr} failed: {err:#}");
+                        }
+                    });
+                }
+            }
+            RpcEndpoint::Unix(path) => {
+                // a socket left behind by a daemon that didn't shut down cleanly, anything
+                // else is there by mistake and stays.
+                match std::fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => {
+                        std::fs::remove_file(path).context("remove stale rpc socket fail")?;
+                    }
+                    Ok(_) => bail!("rpc socket {} exists and isn't a socket", path.display()),
+                    Err(_) => {}
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("bind rpc socket {} fail", path.display()))?;
+                let result = loop {
+                    let stream = tokio::select! {
+                        accepted = listener.accept() => match accepted {
+                            Ok((stream, _)) => stream,
+                            Err(err) => break Err(err.into()),
+                        },
+                        _ = self.shutdown.cancelled() => break Ok(()),
+                    };
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = server.serve_unix(stream).await {
+                            warn!("rpc client failed: {err:#}");
+                        }
+                    });
+                };
+                std::fs::remove_file(path).ok();
+                result
+            }
+        }
+    }
+
+    /// Answers the requests POSTed on an HTTP connection.
+    async fn serve_http<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> anyhow::Result<()> {
+        let mut stream = BufReader::new(stream);
+        while let Some(request) = HttpRequest::read(&mut stream).await? {
+            let writer = stream.get_mut();
+            match (request.path.as_str(), &self.transmission) {
+                ("/" | "/rpc", _) if request.method == "POST" => {
+                    if let Some(reason) = refuse_from_browser(&request) {
+                        write_http_response(writer, "403 Forbidden", &[], reason.as_bytes())
+                            .await?;
+                        break;
+                    }
+                    let response = serde_json::to_vec(&self.handle(&request.body).await)?;
+                    let headers = [("Content-Type", "application/json")];
+                    write_http_response(writer, "200 OK", &headers, &response).await?;
+                }
+                ("/" | "/rpc", _) => {
+                    let headers = [("Allow", "POST")];
+                    write_http_response(writer, "405 Method Not Allowed", &headers, b"").await?;
+                }
+                (TRANSMISSION_RPC_PATH, Some(transmission)) => {
+                    transmission.serve(&request, writer).await?;
+                }
+                _ => write_http_response(writer, "404 Not Found", &[], b"").await?,
+            }
+            if !request.keep_alive() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the requests sent one per line on a Unix socket connection.
+    async fn serve_unix(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut response = serde_json::to_vec(&self.handle(line.as_bytes()).await)?;
+            response.push(b'\n');
+            writer.write_all(&response).await?;
+        }
+        Ok(())
+    }
+
+    /// Parses and answers a single request.
+    async fn handle(&self, body: &[u8]) -> Response {
+        let (id, outcome) = match serde_json::from_slice::<Value>(body) {
+            Err(err) => (Value::Null, Err(RpcError::new(PARSE_ERROR, err))),
+            Ok(value) => match serde_json::from_value::<Request>(value) {
+                Err(err) => (Value::Null, Err(RpcError::new(INVALID_REQUEST, err))),
+                Ok(request) => (request.id, self.call(&request.method, request.params).await),
+            },
+        };
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Response {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let session = &self.session;
+        match method {
+            "add" => {
+                let params: AddParams = parse_params(params)?;
+                let info_hash = match (params.torrent, params.magnet) {
+                    (Some(path), None) => {
+                        let torrent = Torrent::new(path)?;
+                        let priorities = select_files(&torrent, &params.only, &params.skip)?;
+                        match params.path {
+                            Some(path) => session.add_at(torrent, path, priorities)?,
+                            None => session.add(torrent, priorities)?,
+                        }
+                    }
+                    (None, Some(magnet)) => {
+                        if !params.only.is_empty() || !params.skip.is_empty() {
+                            return Err(RpcError::new(
+                                INVALID_PARAMS,
+                                "files of a magnet link are selected once its metainfo arrives",
+                            ));
+                        }
+                        let magnet: Magnet = magnet.parse()?;
+                        session.add_magnet(magnet, params.path)?
+                    }
+                    _ => {
+                        return Err(RpcError::new(
+                            INVALID_PARAMS,
+                            "either torrent or magnet is required",
+                        ))
+                    }
+                };
+                Ok(json!({ "info_hash": hex::encode(info_hash) }))
+            }
+            "list" => Ok(Value::Array(
+                session.list().iter().map(status_json).collect(),
+            )),
+            "status" => {
+                let params: TorrentParams = parse_params(params)?;
+                Ok(status_json(&session.status(&params.info_hash()?)?))
+            }
+            "pause" => {
+                let params: TorrentParams = parse_params(params)?;
+                session.pause(&params.info_hash()?).await?;
+                Ok(Value::Null)
+            }
+            "resume" => {
+                let params: TorrentParams = parse_params(params)?;
+                session.resume(&params.info_hash()?)?;
+                Ok(Value::Null)
+            }
+            "remove" => {
+                let params: TorrentParams = parse_params(params)?;
+                session.remove(&params.info_hash()?).await?;
+                Ok(Value::Null)
+            }
+            "files" => {
+                let params: TorrentParams = parse_params(params)?;
+                let info_hash = params.info_hash()?;
+                let Some(torrent) = session.torrent(&info_hash)? else {
+                    return Ok(Value::Array(Vec::new()));
+                };
+                let priorities = session.file_priorities(&info_hash)?;
+                let files = torrent
+                    .files()
+                    .iter()
+                    .zip(priorities)
+                    .filter(|(file, _)| !file.pad)
+                    .enumerate()
+                    .map(|(index, (file, priority))| {
+                        json!({
+                            "index": index,
+                            "path": file.path.join("/"),
+                            "length": file.length,
+                            "priority": priority.to_string(),
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(files))
+            }
+            "set_priority" => {
+                let params: PriorityParams = parse_params(params)?;
+                let info_hash = params.info_hash()?;
+                let priority: FilePriority = params
+                    .priority
+                    .parse()
+                    .map_err(|err| RpcError::new(INVALID_PARAMS, err))?;
+                let torrent = session
+                    .torrent(&info_hash)?
+                    .context("the torrent's metainfo isn't known yet")?;
+                let matched = match_files(&torrent, &params.files)?;
+                let mut priorities = session.file_priorities(&info_hash)?;
+                for (current, matched) in priorities.iter_mut().zip(matched) {
+                    if matched {
+                        *current = priority;
+                    }
+                }
+                session.set_file_priorities(&info_hash, priorities)?;
+                Ok(Value::Null)
+            }
+            "limits" => Ok(limits_json(session)),
+            "set_limits" => {
+                let params: LimitParams = parse_params(params)?;
+                if let Some(max_connections) = params.max_connections {
+                    session.set_max_connections(max_connections);
+                }
+                let mut bandwidth = session.bandwidth();
+                let rates = [
+                    (params.download_rate, &mut bandwidth.global.download),
+                    (params.upload_rate, &mut bandwidth.global.upload),
+                    (params.peer_download_rate, &mut bandwidth.per_peer.download),
+                    (params.peer_upload_rate, &mut bandwidth.per_peer.upload),
+                    (params.alt_download_rate, &mut bandwidth.alternate.download),
+                    (params.alt_upload_rate, &mut bandwidth.alternate.upload),
+                ];
+                for (rate, current) in rates {
+                    if let Some(rate) = rate {
+                        *current = rate;
+                    }
+                }
+                // an empty schedule turns the alternate rates off.
+                match params.alt_schedule.as_deref() {
+                    Some("") => bandwidth.schedule = None,
+                    Some(schedule) => {
+                        bandwidth.schedule = Some(
+                            schedule
+                                .parse()
+                                .map_err(|err| RpcError::new(INVALID_PARAMS, err))?,
+                        )
+                    }
+                    None => {}
+                }
+                session.set_bandwidth(bandwidth);
+                Ok(limits_json(session))
+            }
+            "torrent_limits" => {
+                let params: TorrentParams = parse_params(params)?;
+                let rates = session.torrent_rates(&params.info_hash()?)?;
+                Ok(json!({ "download_rate": rates.download, "upload_rate": rates.upload }))
+            }
+            "set_torrent_limits" => {
+                let params: TorrentLimitParams = parse_params(params)?;
+                let info_hash = parse_info_hash(&params.info_hash)?;
+                let mut rates = session.torrent_rates(&info_hash)?;
+                rates.download = params.download_rate.unwrap_or(rates.download);
+                rates.upload = params.upload_rate.unwrap_or(rates.upload);
+                session.set_torrent_rates(&info_hash, rates)?;
+                Ok(json!({ "download_rate": rates.download, "upload_rate": rates.upload }))
+            }
+            "banned" => {
+                let banned = session
+                    .banned_peers()
+                    .into_iter()
+                    .map(|(ip, remaining)| {
+                        json!({ "ip": ip.to_string(), "remaining_secs": remaining.as_secs() })
+                    })
+                    .collect();
+                Ok(Value::Array(banned))
+            }
+            "shutdown" => {
+                self.shutdown.cancel();
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {method}"),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AddParams {
+    /// Path to a .torrent file, as seen by the daemon.
+    torrent: Option<PathBuf>,
+    magnet: Option<String>,
+    /// Where to download to, the session's download directory when not given.
+    path: Option<PathBuf>,
+    #[serde(default)]
+    only: Vec<String>,
+    #[serde(default)]
+    skip: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentParams {
+    info_hash: String,
+}
+
+impl TorrentParams {
+    fn info_hash(&self) -> Result<[u8; 20], RpcError> {
+        parse_info_hash(&self.info_hash)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PriorityParams {
+    info_hash: String,
+    /// File indexes and globs, as for selecting files when adding.
+    files: Vec<String>,
+    priority: String,
+}
+
+impl PriorityParams {
+    fn info_hash(&self) -> Result<[u8; 20], RpcError> {
+        parse_info_hash(&self.info_hash)
+    }
+}
+
+/// Rates are in bytes per second, zero for no limit.
+#[derive(Debug, Deserialize)]
+struct LimitParams {
+    max_connections: Option<usize>,
+    download_rate: Option<usize>,
+    upload_rate: Option<usize>,
+    peer_download_rate: Option<usize>,
+    peer_upload_rate: Option<usize>,
+    alt_download_rate: Option<usize>,
+    alt_upload_rate: Option<usize>,
+    /// When the alternate rates apply, like `mon-fri 08:00-18:00`.
+    alt_schedule: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentLimitParams {
+    info_hash: String,
+    download_rate: Option<usize>,
+    upload_rate: Option<usize>,
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err))
+}
+
+fn parse_info_hash(info_hash: &str) -> Result<[u8; 20], RpcError> {
+    hex::decode(info_hash)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "info_hash must be 40 hex characters"))
+}
+
+fn status_json(status: &TorrentStatus) -> Value {
+    json!({
+        "info_hash": hex::encode(status.info_hash),
+        "name": status.name,
+        "path": status.path,
+        "state": status.state.to_string(),
+        "pieces_have": status.pieces_have,
+        "num_pieces": status.num_pieces,
+        "num_peers": status.num_peers,
+        "download_rate": status.download_rate,
+        "upload_rate": status.upload_rate,
+        "eta_secs": status.eta_secs,
+    })
+}
+
+fn limits_json(session: &Session) -> Value {
+    let bandwidth = session.bandwidth();
+    json!({
+        "max_connections": session.max_connections(),
+        "max_peers_per_torrent": session.options().max_peers_per_torrent,
+        "max_half_open": session.options().max_half_open,
+        "download_rate": bandwidth.global.download,
+        "upload_rate": bandwidth.global.upload,
+        "peer_download_rate": bandwidth.per_peer.download,
+        "peer_upload_rate": bandwidth.per_peer.upload,
+        "alt_download_rate": bandwidth.alternate.download,
+        "alt_upload_rate": bandwidth.alternate.upload,
+        "alt_schedule": bandwidth.schedule.as_ref().map(ToString::to_string),
+        "alt_active": bandwidth.alternate_active(),
+    })
+}
+
+/// Why a request that may come from a web page is refused, `None` for those of our clients.
+/// Any page open in a browser can post forms and plain text to a local port, but only pages
+/// of the same origin can post JSON, and a page reaching us through a name it rebound to our
+/// address still sends that name as the host.
+fn refuse_from_browser(request: &HttpRequest) -> Option<&'static str> {
+    let is_json = request.header("Content-Type").is_some_and(|value| {
+        let media_type = value.split(';').next().unwrap_or_default();
+        media_type.trim().eq_ignore_ascii_case("application/json")
+    });
+    if !is_json {
+        return Some("requests must be application/json");
+    }
+    let Some(host) = request.header("Host").filter(|host| is_address(host)) else {
+        return Some("requests must be sent to an ip address or localhost");
+    };
+    match request.header("Origin") {
+        Some(origin) if origin.strip_prefix("http://") != Some(host) => {
+            Some("requests from other origins aren't allowed")
+        }
+        _ => None,
+    }
+}
+
+/// Whether the host of a request, with or without a port, is an ip address or localhost.
+fn is_address(host: &str) -> bool {
+    let name = host
+        .rsplit_once(':')
+        .filter(|(_, port)| port.parse::<u16>().is_ok())
+        .map_or(host, |(name, _)| name);
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok()
+        || host.parse::<IpAddr>().is_ok()
+}
+
+/// The parts of an HTTP/1.1 request the endpoints look at.
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Reads the next request on the connection, `None` once the client closed it.
+    async fn read<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Self>> {
+        let mut line = String::new();
+        if read_header_line(reader, &mut line).await? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            bail!("invalid http request line");
+        };
+        let (method, path) = (method.to_string(), path.to_string());
+
+        let mut headers = Vec::new();
+        loop {
+            if read_header_line(reader, &mut line).await? == 0 {
+                bail!("connection closed within the http headers");
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                bail!("more than {MAX_HEADERS} http headers");
+            }
+            let (name, value) = header.split_once(':').context("invalid http header")?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut request = Self {
+            method,
+            path,
+            headers,
+            body: Vec::new(),
+        };
+        let length: usize = match request.header("Content-Length") {
+            Some(length) => length.parse().context("invalid content length")?,
+            None => 0,
+        };
+        if length > MAX_BODY {
+            bail!("request body of {length} bytes is too large");
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).await?;
+        Ok(Some(request))
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn keep_alive(&self) -> bool {
+        !self
+            .header("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+    }
+}
+
+/// Reads the next line of a request's head into `line`, refusing one longer than
+/// `MAX_HEADER_LINE`, and returns how many bytes it read.
+async fn read_header_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+) -> anyhow::Result<usize> {
+    line.clear();
+    let read = reader
+        .take(MAX_HEADER_LINE as u64 + 1)
+        .read_line(line)
+        .await?;
+    if read > MAX_HEADER_LINE {
+        bail!("http header line longer than {MAX_HEADER_LINE} bytes");
+    }
+    Ok(read)
+}
+
+pub(crate) async fn write_http_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let mut response = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\n", body.len());
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    writer.write_all(&response).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Sends a request to the daemon at `endpoint`, returning the result or failing with its error.
+pub async fn call(endpoint: &RpcEndpoint, method: &str, params: Value) -> anyhow::Result<Value> {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let response: Response = match endpoint {
+        RpcEndpoint::Http(addr) => reqwest::Client::new()
+            .post(format!("http://{addr}/"))
+            .json(&request)
+            .send()
+            .await
+            .with_context(|| format!("connect to daemon at {addr} fail"))?
+            .json()
+            .await
+            .context("decode rpc response fail")?,
+        RpcEndpoint::Unix(path) => {
+            let stream = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("connect to daemon at {} fail", path.display()))?;
+            let (reader, mut writer) = stream.into_split();
+            let mut request = serde_json::to_vec(&request)?;
+            request.push(b'\n');
+            writer.write_all(&request).await?;
+            let mut line = String::new();
+            BufReader::new(reader).read_line(&mut line).await?;
+            serde_json::from_str(&line).context("decode rpc response fail")?
+        }
+    };
+    match (response.result, response.error) {
+        (_, Some(error)) => bail!("{} ({})", error.message, error.code),
+        (result, None) => Ok(result.unwrap_or(Value::Null)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::{
+        session::{testing, TorrentState},
+        torrent::testing as torrents,
+    };
+
+    async fn server(download_dir: &Path) -> RpcServer {
+        RpcServer::new(
+            testing::session(download_dir).await,
+            CancellationToken::new(),
+        )
+    }
+
+    /// Answers `method` with `params`, as a result or an error code.
+    async fn call(server: &RpcServer, method: &str, params: Value) -> Result<Value, i64> {
+        let request = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+        let response = server.handle(request.to_string().as_bytes()).await;
+        assert_eq!(response.id, 7);
+        match response.error {
+            Some(error) => Err(error.code),
+            None => Ok(response.result.unwrap()),
+        }
+    }
+
+    #[test]
+    fn endpoints_parse_back() {
+        for endpoint in ["127.0.0.1:6880", "[::1]:80", "unix:/run/bt.sock"] {
+            let parsed: RpcEndpoint = endpoint.parse().unwrap();
+            assert_eq!(parsed.to_string(), endpoint);
+        }
+        assert!("localhost".parse::<RpcEndpoint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_their_error_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path()).await;
+        let code = |response: Response| response.error.unwrap().code;
+        assert_eq!(code(server.handle(b"{").await), PARSE_ERROR);
+        assert_eq!(code(server.handle(b"{\"id\": 1}").await), INVALID_REQUEST);
+        assert_eq!(
+            call(&server, "frobnicate", Value::Null).await,
+            Err(METHOD_NOT_FOUND)
+        );
+        assert_eq!(call(&server, "add", json!({})).await, Err(INVALID_PARAMS));
+        let params = json!({ "info_hash": "abc" });
+        assert_eq!(call(&server, "status", params).await, Err(INVALID_PARAMS));
+        let params = json!({ "info_hash": hex::encode([1; 20]) });
+        assert_eq!(call(&server, "status", params).await, Err(SESSION_ERROR));
+        let params = json!({ "magnet": format!("magnet:?xt=urn:btih:{}", hex::encode([1; 20])), "only": ["0"] });
+        assert_eq!(call(&server, "add", params).await, Err(INVALID_PARAMS));
+    }
+
+    #[tokio::test]
+    async fn torrents_are_added_steered_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path()).await;
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4]), ("b.txt", &[2; 4])], 4);
+        let file = dir.path().join("dir.torrent");
+        std::fs::write(&file, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+
+        let params = json!({ "torrent": file, "skip": ["*.txt"] });
+        let added = call(&server, "add", params).await.unwrap();
+        let info_hash = hex::encode(torrent.info_hash_bytes());
+        assert_eq!(added, json!({ "info_hash": info_hash }));
+        let torrent = json!({ "info_hash": info_hash });
+
+        let list = call(&server, "list", Value::Null).await.unwrap();
+        assert_eq!(list[0]["name"], "dir");
+        assert_eq!(list[0]["path"], json!(dir.path().join("dir")));
+        assert_eq!(list[0]["num_pieces"], 2);
+        // the skipped file is neither created nor downloaded.
+        let info_hash_bytes = parse_info_hash(&info_hash).unwrap();
+        testing::checked(&server.session, &info_hash_bytes).await;
+        assert!(dir.path().join("dir/a").exists());
+        assert!(!dir.path().join("dir/b.txt").exists());
+        assert_eq!(
+            testing::pieces_wanted(&server.session, &info_hash_bytes).await,
+            1
+        );
+
+        let priorities = |files: Value| -> Vec<String> {
+            files
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|file| format!("{} {}", file["path"], file["priority"]))
+                .collect()
+        };
+        let files = call(&server, "files", torrent.clone()).await.unwrap();
+        assert_eq!(
+            priorities(files),
+            ["\"a\" \"normal\"", "\"b.txt\" \"skip\""]
+        );
+        let params = json!({ "info_hash": info_hash, "files": ["1"], "priority": "high" });
+        call(&server, "set_priority", params).await.unwrap();
+        let files = call(&server, "files", torrent.clone()).await.unwrap();
+        assert_eq!(
+            priorities(files),
+            ["\"a\" \"normal\"", "\"b.txt\" \"high\""]
+        );
+        let params = json!({ "info_hash": info_hash, "files": ["0"], "priority": "urgent" });
+        assert_eq!(
+            call(&server, "set_priority", params).await,
+            Err(INVALID_PARAMS)
+        );
+
+        call(&server, "pause", torrent.clone()).await.unwrap();
+        let status = call(&server, "status", torrent.clone()).await.unwrap();
+        assert_eq!(status["state"], "paused");
+        call(&server, "resume", torrent.clone()).await.unwrap();
+        let status = testing::checked(&server.session, &info_hash_bytes).await;
+        assert_eq!(status.state, TorrentState::Downloading);
+
+        call(&server, "remove", torrent.clone()).await.unwrap();
+        assert_eq!(call(&server, "list", Value::Null).await.unwrap(), json!([]));
+        assert_eq!(call(&server, "status", torrent).await, Err(SESSION_ERROR));
+    }
+
+    #[tokio::test]
+    async fn limits_are_set_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path()).await;
+        let params = json!({
+            "max_connections": 20,
+            "download_rate": 1000,
+            "alt_upload_rate": 10,
+            "alt_schedule": "mon-fri 08:00-18:00",
+        });
+        let limits = call(&server, "set_limits", params).await.unwrap();
+        assert_eq!(limits["max_connections"], 20);
+        assert_eq!(limits["download_rate"], 1000);
+        assert_eq!(limits["upload_rate"], 0);
+        assert_eq!(limits["alt_upload_rate"], 10);
+        assert_eq!(limits["alt_schedule"], "mon,tue,wed,thu,fri 08:00-18:00");
+        assert_eq!(call(&server, "limits", Value::Null).await.unwrap(), limits);
+
+        let limits = call(&server, "set_limits", json!({ "alt_schedule": "" }))
+            .await
+            .unwrap();
+        assert_eq!(limits["alt_schedule"], Value::Null);
+        let params = json!({ "alt_schedule": "someday" });
+        assert_eq!(
+            call(&server, "set_limits", params).await,
+            Err(INVALID_PARAMS)
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_posted_over_http() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path()).await;
+        let (client, stream) = duplex(1 << 16);
+        let serving = tokio::spawn(async move { server.serve_http(stream).await });
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"list"}"#;
+        let requests = format!(
+            "POST /rpc HTTP/1.1\r\nHost: 127.0.0.1:6880\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{body}\
+             GET / HTTP/1.1\r\n\r\n\
+             POST /elsewhere HTTP/1.1\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        let (mut reader, mut writer) = tokio::io::split(client);
+        writer.write_all(requests.as_bytes()).await.unwrap();
+        let mut responses = String::new();
+        reader.read_to_string(&mut responses).await.unwrap();
+        serving.await.unwrap().unwrap();
+
+        let expected = r#"{"jsonrpc":"2.0","id":1,"result":[]}"#;
+        assert!(responses.starts_with("HTTP/1.1 200 OK\r\n"), "{responses}");
+        assert!(responses.contains(&format!("Content-Type: application/json\r\n\r\n{expected}")));
+        assert!(responses.contains("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(responses.ends_with("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn oversized_request_heads_are_refused() {
+        let read = |request: String| async move {
+            HttpRequest::read(&mut request.as_bytes())
+                .await
+                .map(|request| request.is_some())
+        };
+        let long = "a".repeat(MAX_HEADER_LINE);
+        let many = "Accept: */*\r\n".repeat(MAX_HEADERS);
+
+        assert!(read(format!("GET /{long} HTTP/1.1\r\n\r\n")).await.is_err());
+        assert!(read(format!("GET / HTTP/1.1\r\nX-Long: {long}\r\n\r\n"))
+            .await
+            .is_err());
+        assert!(read(format!("GET / HTTP/1.1\r\n{many}X-More: 1\r\n\r\n"))
+            .await
+            .is_err());
+        assert!(read(format!("GET / HTTP/1.1\r\n{many}\r\n")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn only_stale_sockets_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Arc::new(server(dir.path()).await);
+        let path = dir.path().join("rpc.sock");
+        let endpoint = RpcEndpoint::Unix(path.clone());
+
+        std::fs::write(&path, "precious").unwrap();
+        let err = server.clone().serve(&endpoint).await.unwrap_err();
+        assert!(err.to_string().contains("isn't a socket"), "{err:#}");
+        assert_eq!(std::fs::read(&path).unwrap(), b"precious");
+
+        std::fs::remove_file(&path).unwrap();
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(&endpoint).await }
+        });
+        // the stale socket was replaced by one that answers.
+        let stream = loop {
+            if let Ok(stream) = UnixStream::connect(&path).await {
+                break stream;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        drop(stream);
+        server.shutdown.cancel();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn requests_web_pages_can_send_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Arc::new(server(dir.path()).await);
+        let shutdown = server.shutdown.clone();
+        let post = |headers: &str| {
+            let body = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+            format!(
+                "POST / HTTP/1.1\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+        let answer = |request: String| {
+            let server = server.clone();
+            async move {
+                let (client, stream) = duplex(1 << 16);
+                let serving = tokio::spawn(async move { server.serve_http(stream).await });
+                let (mut reader, mut writer) = tokio::io::split(client);
+                writer.write_all(request.as_bytes()).await.unwrap();
+                writer.shutdown().await.unwrap();
+                let mut response = String::new();
+                reader.read_to_string(&mut response).await.unwrap();
+                serving.await.unwrap().unwrap();
+                response
+            }
+        };
+
+        for headers in [
+            // a form or plain text, which pages post anywhere without asking.
+            "Host: 127.0.0.1:6880\r\nContent-Type: text/plain\r\n",
+            "Host: 127.0.0.1:6880\r\n",
+            // a page of another origin.
+            "Host: 127.0.0.1:6880\r\nContent-Type: application/json\r\nOrigin: http://evil.example\r\n",
+            // a page whose name was rebound to our address.
+            "Host: evil.example:6880\r\nContent-Type: application/json\r\n",
+            "Content-Type: application/json\r\n",
+        ] {
+            let response = answer(post(headers)).await;
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{headers}: {response}");
+            assert!(!shutdown.is_cancelled(), "{headers}");
+        }
+
+        for host in ["localhost:6880", "[::1]:6880", "127.0.0.1"] {
+            assert!(is_address(host), "{host}");
+        }
+        let headers = "Host: localhost:6880\r\nContent-Type: application/json; charset=utf-8\r\n\
+                       Origin: http://localhost:6880\r\n";
+        let response = answer(post(headers)).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(shutdown.is_cancelled());
+    }
+}
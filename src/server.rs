@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use tokio::{net::TcpListener, sync::mpsc, time::timeout};
+
+use crate::debug;
+use crate::{
+    handshake::Handshake,
+    ipfilter::IpFilter,
+    mse::{self, EncryptionPolicy},
+    peer::PeerConnection,
+    torrent::{generate_peer_id, Torrent},
+    transport::PeerStream,
+    utp::{UtpSocket, UtpStream},
+};
+
+/// The port we listen on and advertise to the tracker.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// How long an incoming peer has to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+struct ActiveTorrent {
+    num_pieces: usize,
+    /// The torrent has v2 metadata, which we advertise in our handshake.
+    v2: bool,
+    /// Where accepted peers are handed over to the torrent's swarm.
+    peers: mpsc::UnboundedSender<PeerConnection>,
+}
+
+/// The torrents we accept incoming connections for, keyed by info hash.
+#[derive(Clone, Default)]
+pub struct ActiveTorrents {
+    torrents: Arc<Mutex<HashMap<[u8; 20], ActiveTorrent>>>,
+}
+
+impl ActiveTorrents {
+    /// Starts accepting peers for `torrent`, they are delivered through the returned receiver.
+    /// Peers may use either info hash of a hybrid torrent.
+    pub fn register(&self, torrent: &Torrent) -> mpsc::UnboundedReceiver<PeerConnection> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let active = ActiveTorrent {
+            num_pieces: torrent.num_pieces(),
+            v2: torrent.has_v2(),
+            peers: sender,
+        };
+        let mut torrents = self.torrents.lock().unwrap();
+        for info_hash in torrent.info_hashes() {
+            torrents.insert(info_hash, active.clone());
+        }
+        receiver
+    }
+
+    /// Stops accepting peers for the torrent with `info_hash`, under any of its info hashes.
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(removed) = torrents.remove(info_hash) {
+            torrents.retain(|_, active| !active.peers.same_channel(&removed.peers));
+        }
+    }
+
+    fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.torrents.lock().unwrap().keys().copied().collect()
+    }
+
+    fn lookup(&self, info_hash: &[u8; 20]) -> Option<ActiveTorrent> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+}
+
+/// Accepts incoming peer connections for the active torrents.
+pub struct Listener {
+    listener: TcpListener,
+    torrents: ActiveTorrents,
+    encryption: EncryptionPolicy,
+
+    /// The addresses we don't accept connections from.
+    ip_filter: Arc<IpFilter>,
+
+    /// Accepts uTP connections as well, on the same port.
+    utp: Option<Arc<UtpSocket>>,
+
+    /// The peer id we answer handshakes with, peers sending it are ourselves.
+    peer_id: [u8; 20],
+}
+
+impl Listener {
+    pub async fn bind(port: u16, torrents: ActiveTorrents) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .await
+            .with_context(|| format!("listening on port {port} failed"))?;
+        Ok(Self {
+            listener,
+            torrents,
+            encryption: EncryptionPolicy::default(),
+            ip_filter: Arc::default(),
+            utp: None,
+            peer_id: generate_peer_id()?,
+        })
+    }
+
+    /// Which connections we accept, encrypted ones, plaintext ones or both.
+    pub fn with_encryption(mut self, encryption: EncryptionPolicy) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Drops the connections from addresses `ip_filter` blocks, before the handshake.
+    pub fn with_ip_filter(mut self, ip_filter: Arc<IpFilter>) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    pub fn with_utp(mut self, utp: Arc<UtpSocket>) -> Self {
+        self.utp = Some(utp);
+        self
+    }
+
+    /// Answers handshakes with `peer_id`, the one the connections we open use too.
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts connections until the listener fails, each one is handled in its own task.
+    pub async fn run(self) -> anyhow::Result<()> {
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (stream, addr) = accepted?;
+                    (PeerStream::from(stream), addr)
+                }
+                Some(stream) = accept_utp(&self.utp) => {
+                    let addr = stream.peer_addr();
+                    (PeerStream::from(stream), addr)
+                }
+            };
+            if self.ip_filter.is_blocked(addr.ip()) {
+                continue;
+            }
+            let torrents = self.torrents.clone();
+            let encryption = self.encryption;
+            let peer_id = self.peer_id;
+            tokio::spawn(async move {
+                if let Err(err) = accept_peer(stream, addr, torrents, encryption, peer_id).await {
+                    debug!("incoming peer {addr} rejected: {err:#}");
+                }
+            });
+        }
+    }
+}
+
+async fn accept_utp(utp: &Option<Arc<UtpSocket>>) -> Option<UtpStream> {
+    match utp {
+        Some(utp) => utp.accept().await.ok(),
+        None => std::future::pending().await,
+    }
+}
+
+/// Negotiates encryption with an incoming peer and reads its handshake,
+/// answers it if we serve the torrent and hands the connection over to the torrent's swarm.
+/// Connections from ourselves, carrying our `peer_id`, are dropped.
+async fn accept_peer(
+    stream: PeerStream,
+    addr: SocketAddr,
+    torrents: ActiveTorrents,
+    encryption: EncryptionPolicy,
+    peer_id: [u8; 20],
+) -> anyhow::Result<()> {
+    let (mut stream, handshake) = timeout(HANDSHAKE_TIMEOUT, async {
+        let mut stream = mse::accept(stream, &torrents.info_hashes(), encryption).await?;
+        let handshake = Handshake::read(&mut stream).await?;
+        anyhow::Ok((stream, handshake))
+    })
+    .await
+    .context("handshake timed out")??;
+
+    let Some(active) = torrents.lookup(&handshake.info_hash) else {
+        bail!("unknown info hash {}", hex::encode(handshake.info_hash));
+    };
+    if handshake.peer_id == peer_id {
+        bail!("connected to ourselves");
+    }
+
+    Handshake::new(handshake.info_hash, peer_id)
+        .with_v2(active.v2)
+        .write(&mut stream)
+        .await?;
+    let peer = PeerConnection::from_stream(stream, addr, &handshake, active.num_pieces);
+
+    if active.peers.send(peer).is_err() {
+        bail!("torrent is no longer active");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::AsyncReadExt,
+        net::TcpStream,
+        time::{sleep, timeout},
+    };
+
+    use super::*;
+    use crate::torrent::testing;
+
+    async fn listen(peer_id: [u8; 20]) -> (SocketAddr, ActiveTorrents) {
+        let torrents = ActiveTorrents::default();
+        let listener = Listener::bind(0, torrents.clone())
+            .await
+            .unwrap()
+            .with_encryption(EncryptionPolicy::Disabled)
+            .with_peer_id(peer_id);
+        let addr = SocketAddr::from(([127, 0, 0, 1], listener.local_addr().unwrap().port()));
+        tokio::spawn(listener.run());
+        (addr, torrents)
+    }
+
+    #[tokio::test]
+    async fn accepted_peers_are_handed_to_the_torrent() {
+        let torrent = testing::torrent("a", &[("a", &[1; 10])], 4);
+        let (addr, torrents) = listen([9; 20]).await;
+        let mut peers = torrents.register(&torrent);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        Handshake::new(torrent.info_hash_bytes(), [1; 20])
+            .write(&mut stream)
+            .await
+            .unwrap();
+        let answer = Handshake::read(&mut stream).await.unwrap();
+        assert_eq!(answer.info_hash, torrent.info_hash_bytes());
+        assert_eq!(answer.peer_id, [9; 20]);
+
+        let peer = timeout(Duration::from_secs(5), peers.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.peer_id(), [1; 20]);
+        assert_eq!(peer.bitfield().len(), torrent.num_pieces());
+    }
+
+    /// Sends a handshake and expects the listener to hang up without answering.
+    async fn assert_rejected(addr: SocketAddr, handshake: Handshake) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        handshake.write(&mut stream).await.unwrap();
+        let mut buffer = [0; 68];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn unknown_info_hashes_are_rejected() {
+        let torrent = testing::torrent("a", &[("a", &[1; 10])], 4);
+        let (addr, torrents) = listen([9; 20]).await;
+        let _peers = torrents.register(&torrent);
+        assert_rejected(addr, Handshake::new([7; 20], [1; 20])).await;
+    }
+
+    #[tokio::test]
+    async fn connections_from_ourselves_are_dropped() {
+        let torrent = testing::torrent("a", &[("a", &[1; 10])], 4);
+        let (addr, torrents) = listen([9; 20]).await;
+        let mut peers = torrents.register(&torrent);
+        assert_rejected(addr, Handshake::new(torrent.info_hash_bytes(), [9; 20])).await;
+        sleep(Duration::from_millis(50)).await;
+        assert!(peers.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unregistered_torrents_are_rejected_under_every_info_hash() {
+        let torrent = testing::torrent("a", &[("a", &[1; 10])], 4);
+        let (addr, torrents) = listen([9; 20]).await;
+        let _peers = torrents.register(&torrent);
+        torrents.unregister(&torrent.info_hash_bytes());
+        assert!(torrents.info_hashes().is_empty());
+        assert_rejected(addr, Handshake::new(torrent.info_hash_bytes(), [1; 20])).await;
+    }
+}
@@ -0,0 +1,1220 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::warn;
+use crate::{
+    bitfield::Bitfield,
+    dht::{Dht, DEFAULT_BOOTSTRAP},
+    ipfilter::IpFilter,
+    lsd::Lsd,
+    magnet::Magnet,
+    mse::EncryptionPolicy,
+    peer_list::PeerList,
+    priority::FilePriority,
+    proxy::Proxy,
+    random,
+    ratelimit::{Bandwidth, RateLimits, Rates, Throttle},
+    server::{ActiveTorrents, Listener, DEFAULT_PORT},
+    storage::{is_file_name, DiskPool, Storage, DEFAULT_DISK_JOBS},
+    swarm::{Swarm, SwarmHandle, DEFAULT_MAX_HALF_OPEN, DEFAULT_MAX_PEERS},
+    torrent::{generate_peer_id, Torrent},
+    transport::{TransportPreference, Transports},
+    utp::UtpSocket,
+};
+
+/// The most peer connections over all torrents, unless configured otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+/// How often we look whether the alternate rate limits start or stop applying.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a torrent nobody is sharing, or a magnet link nobody has the metadata of,
+/// waits before looking for peers again.
+const RETRY_DOWNLOAD: Duration = Duration::from_secs(30);
+
+/// How a session shares its port, connections and disk between its torrents.
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+    /// The port every torrent accepts peers on.
+    pub port: u16,
+    /// Where the torrents added without a path are downloaded to.
+    pub download_dir: PathBuf,
+    /// The most peer connections over all torrents.
+    pub max_connections: usize,
+    /// The most peer connections of each torrent.
+    pub max_peers_per_torrent: usize,
+    /// The most connections being opened at the same time over all torrents.
+    pub max_half_open: usize,
+    /// How many torrents check their data on disk at the same time.
+    pub max_concurrent_checks: usize,
+    /// How many reads and writes of piece data run at the same time over all torrents.
+    pub max_disk_jobs: usize,
+    pub encryption: EncryptionPolicy,
+    pub transport: TransportPreference,
+    /// Whether torrents look for peers on the DHT.
+    pub dht: bool,
+    /// Whether torrents look for peers on the local network.
+    pub lsd: bool,
+    /// Where to keep the DHT nodes between runs.
+    pub dht_cache: Option<PathBuf>,
+    /// DHT nodes to join through as host:port, instead of the well known ones.
+    pub bootstrap: Vec<String>,
+    /// How fast the torrents may transfer.
+    pub bandwidth: Bandwidth,
+    /// The addresses no torrent connects to nor accepts connections from.
+    pub ip_filter: Arc<IpFilter>,
+    /// What the connections to peers, trackers and web seeds go through.
+    pub proxy: Option<Arc<Proxy>>,
+    /// Only go through the proxy: no incoming connections, uTP, DHT nor local discovery,
+    /// which would reach peers directly.
+    pub force_proxy: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            download_dir: PathBuf::from("."),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_peers_per_torrent: DEFAULT_MAX_PEERS,
+            max_half_open: DEFAULT_MAX_HALF_OPEN,
+            max_concurrent_checks: 1,
+            max_disk_jobs: DEFAULT_DISK_JOBS,
+            encryption: EncryptionPolicy::default(),
+            transport: TransportPreference::default(),
+            dht: true,
+            lsd: true,
+            dht_cache: None,
+            bootstrap: Vec::new(),
+            bandwidth: Bandwidth::default(),
+            ip_filter: Arc::default(),
+            proxy: None,
+            force_proxy: false,
+        }
+    }
+}
+
+/// What a torrent of the session is doing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Looking for a peer to get the metainfo of a magnet link from.
+    FetchingMetadata,
+    /// Waiting for its turn to check the data on disk, or checking it.
+    Checking,
+    Downloading,
+    Seeding,
+    Paused,
+    /// Stopped by an error, resuming retries.
+    Failed(String),
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FetchingMetadata => f.write_str("fetching metadata"),
+            Self::Checking => f.write_str("checking"),
+            Self::Downloading => f.write_str("downloading"),
+            Self::Seeding => f.write_str("seeding"),
+            Self::Paused => f.write_str("paused"),
+            Self::Failed(err) => write!(f, "failed: {err}"),
+        }
+    }
+}
+
+/// A snapshot of a torrent of the session.
+#[derive(Clone, Debug)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether the path was given when adding the torrent, rather than made from its name.
+    pub path_given: bool,
+    pub state: TorrentState,
+    pub pieces_have: usize,
+    /// The size of the pieces we have.
+    pub bytes_have: usize,
+    /// Zero until we have the metainfo of a magnet link.
+    pub num_pieces: usize,
+    pub num_peers: usize,
+    /// Bytes per second, zero while not running.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// Seconds until the wanted pieces are downloaded, none when it can't be told.
+    pub eta_secs: Option<u64>,
+}
+
+/// Downloads and seeds many torrents in one process. They accept peers on the same port,
+/// share the connection limit, the DHT node and local service discovery,
+/// and take turns checking their data on disk.
+pub struct Session {
+    resources: Arc<Resources>,
+    torrents: Mutex<HashMap<[u8; 20], ManagedTorrent>>,
+    /// None when forced through the proxy.
+    listener: Option<JoinHandle<anyhow::Result<()>>>,
+    /// The size of the connection limit, which permits are added to or taken from.
+    max_connections: Arc<Mutex<ConnectionLimit>>,
+}
+
+/// The size of the connection limit, and the permits lowering it still has to take back
+/// from the connections holding them.
+#[derive(Default)]
+struct ConnectionLimit {
+    max: usize,
+    owed: usize,
+    /// Whether a task takes the owed permits as they are released.
+    collecting: bool,
+}
+
+/// What the torrents of a session share.
+struct Resources {
+    options: SessionOptions,
+    /// The port the listener is bound to, the configured one when not listening.
+    port: u16,
+    active: ActiveTorrents,
+    connection_limit: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+    /// The peers that failed or misbehaved in any torrent.
+    peer_list: Arc<PeerList>,
+    /// Every torrent identifies us with it.
+    peer_id: [u8; 20],
+    checks: Semaphore,
+    /// Where every torrent reads and writes its pieces.
+    disk: DiskPool,
+    utp: Option<Arc<UtpSocket>>,
+    dht: Option<Arc<Dht>>,
+    lsd: Option<Arc<Lsd>>,
+    /// Stops every torrent when the session shuts down.
+    shutdown: CancellationToken,
+    bandwidth: Mutex<Bandwidth>,
+    /// Shared by every connection, at the global or alternate rates.
+    rate_limits: RateLimits,
+    /// Each connection gets limits of its own at these rates.
+    peer_limits: RateLimits,
+}
+
+struct ManagedTorrent {
+    /// Where the metainfo comes from when it was added as a magnet link.
+    magnet: Option<Arc<Magnet>>,
+    /// Where the data goes, the download directory when not given.
+    path: Option<PathBuf>,
+    /// Shared by the torrent's connections.
+    rate_limits: RateLimits,
+    progress: Arc<Mutex<Progress>>,
+    /// Stops the torrent's task, to pause or remove it.
+    stop: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+/// How a torrent's task shares it, as configured when the task starts.
+struct TorrentSettings {
+    rate_limits: RateLimits,
+}
+
+/// What a torrent's task reports back to the session, and the file priorities
+/// the session may change while the task runs.
+struct Progress {
+    state: TorrentState,
+    /// The metainfo, fetched from the peers for magnet links.
+    torrent: Option<Arc<Torrent>>,
+    /// How much we want each file, empty until we have the metainfo.
+    priorities: Vec<FilePriority>,
+    /// The pieces we have, known once the data is checked.
+    have: Option<Bitfield>,
+    /// The running swarm, while downloading or seeding.
+    swarm: Option<SwarmHandle>,
+}
+
+impl Session {
+    /// Starts listening for peers and joins the DHT, before any torrent is added.
+    pub async fn start(options: SessionOptions) -> anyhow::Result<Self> {
+        random::init().context("no random bytes from the operating system")?;
+        if options.force_proxy && options.proxy.is_none() {
+            bail!("forcing connections through the proxy needs a proxy");
+        }
+        let direct = !options.force_proxy;
+
+        let active = ActiveTorrents::default();
+        let peer_id = generate_peer_id()?;
+        let mut listener = if direct {
+            let listener = Listener::bind(options.port, active.clone())
+                .await?
+                .with_peer_id(peer_id)
+                .with_encryption(options.encryption)
+                .with_ip_filter(options.ip_filter.clone());
+            Some(listener)
+        } else {
+            None
+        };
+        let port = match &listener {
+            Some(listener) => listener.local_addr()?.port(),
+            None => options.port,
+        };
+        let utp = if options.transport == TransportPreference::TcpOnly || !direct {
+            None
+        } else {
+            match UtpSocket::bind(port).await {
+                Ok(utp) => Some(utp),
+                Err(err) => {
+                    warn!("not using utp: {err:#}");
+                    None
+                }
+            }
+        };
+        if let Some(utp) = &utp {
+            listener = listener.map(|listener| listener.with_utp(utp.clone()));
+        }
+
+        // the utp socket takes the udp port, peers learn the dht port from the port message.
+        let dht = if options.dht && direct {
+            let dht_port = if utp.is_some() { 0 } else { port };
+            match Dht::bind(dht_port, options.dht_cache.clone()).await {
+                Ok(dht) => {
+                    dht.set_ip_filter(options.ip_filter.clone());
+                    let mut bootstrap = options.bootstrap.clone();
+                    if bootstrap.is_empty() {
+                        bootstrap.extend(DEFAULT_BOOTSTRAP.iter().map(|node| node.to_string()));
+                    }
+                    let node = dht.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = node.bootstrap(&bootstrap).await {
+                            warn!("dht bootstrap failed: {err:#}");
+                        }
+                    });
+                    Some(dht)
+                }
+                Err(err) => {
+                    warn!("not using the dht: {err:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let lsd = if options.lsd && direct {
+            match Lsd::bind().await {
+                Ok(lsd) => Some(lsd),
+                Err(err) => {
+                    warn!("not using local service discovery: {err:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let max_connections = options.max_connections;
+        let bandwidth = options.bandwidth.clone();
+        let resources = Arc::new(Resources {
+            rate_limits: RateLimits::new(bandwidth.current()),
+            peer_limits: RateLimits::new(bandwidth.per_peer),
+            bandwidth: Mutex::new(bandwidth),
+            port,
+            active,
+            connection_limit: Arc::new(Semaphore::new(options.max_connections)),
+            half_open: Arc::new(Semaphore::new(options.max_half_open.max(1))),
+            peer_list: Arc::default(),
+            peer_id,
+            checks: Semaphore::new(options.max_concurrent_checks.max(1)),
+            disk: DiskPool::new(options.max_disk_jobs),
+            utp,
+            dht,
+            lsd,
+            shutdown: CancellationToken::new(),
+            options,
+        });
+        tokio::spawn(follow_schedule(resources.clone()));
+        Ok(Self {
+            resources,
+            torrents: Mutex::default(),
+            listener: listener.map(|listener| tokio::spawn(listener.run())),
+            max_connections: Arc::new(Mutex::new(ConnectionLimit {
+                max: max_connections,
+                ..ConnectionLimit::default()
+            })),
+        })
+    }
+
+    /// The port the session accepts peers on.
+    pub fn port(&self) -> u16 {
+        self.resources.port
+    }
+
+    /// What the session was started with.
+    pub fn options(&self) -> &SessionOptions {
+        &self.resources.options
+    }
+
+    /// Adds `torrent` and starts downloading it into the download directory,
+    /// under the torrent's name. `priorities` are those of its files, padding files included,
+    /// every file is wanted when empty. Returns its info hash.
+    pub fn add(&self, torrent: Torrent, priorities: Vec<FilePriority>) -> anyhow::Result<[u8; 20]> {
+        self.insert(
+            torrent.info_hash_bytes(),
+            Some((torrent, priorities)),
+            None,
+            None,
+        )
+    }
+
+    /// Adds `torrent` and starts downloading it into `path`, keeping the pieces already there.
+    /// `priorities` are as for [`Session::add`]. Returns its info hash.
+    pub fn add_at(
+        &self,
+        torrent: Torrent,
+        path: impl Into<PathBuf>,
+        priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<[u8; 20]> {
+        self.insert(
+            torrent.info_hash_bytes(),
+            Some((torrent, priorities)),
+            None,
+            Some(path.into()),
+        )
+    }
+
+    /// Adds the torrent of a magnet link, whose metainfo is fetched from the peers first.
+    /// It is downloaded into `path`, or the download directory under the torrent's name.
+    /// Returns its info hash.
+    pub fn add_magnet(&self, magnet: Magnet, path: Option<PathBuf>) -> anyhow::Result<[u8; 20]> {
+        self.insert(magnet.info_hash, None, Some(magnet), path)
+    }
+
+    fn insert(
+        &self,
+        info_hash: [u8; 20],
+        torrent: Option<(Torrent, Vec<FilePriority>)>,
+        magnet: Option<Magnet>,
+        path: Option<PathBuf>,
+    ) -> anyhow::Result<[u8; 20]> {
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.contains_key(&info_hash) {
+            bail!("torrent {} is already added", hex::encode(info_hash));
+        }
+        let (torrent, priorities) = match torrent {
+            Some((torrent, priorities)) if priorities.is_empty() => {
+                let priorities = vec![FilePriority::Normal; torrent.files().len()];
+                (Some(Arc::new(torrent)), priorities)
+            }
+            Some((torrent, priorities)) => {
+                check_priorities(&torrent, &priorities)?;
+                (Some(Arc::new(torrent)), priorities)
+            }
+            None => (None, Vec::new()),
+        };
+        let mut managed = ManagedTorrent {
+            magnet: magnet.map(Arc::new),
+            path,
+            rate_limits: RateLimits::default(),
+            progress: Arc::new(Mutex::new(Progress {
+                state: TorrentState::Checking,
+                torrent,
+                priorities,
+                have: None,
+                swarm: None,
+            })),
+            stop: CancellationToken::new(),
+            task: None,
+        };
+        managed.start(&self.resources);
+        torrents.insert(info_hash, managed);
+        Ok(info_hash)
+    }
+
+    /// The metainfo of the torrent, unless it is a magnet link still fetching it.
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> anyhow::Result<Option<Arc<Torrent>>> {
+        let torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get(info_hash).context("no such torrent")?;
+        let torrent = managed.progress.lock().unwrap().torrent.clone();
+        Ok(torrent)
+    }
+
+    /// Stops the torrent and forgets about it, its data is left on disk.
+    pub async fn remove(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let mut managed = self
+            .torrents
+            .lock()
+            .unwrap()
+            .remove(info_hash)
+            .context("no such torrent")?;
+        if let Some(task) = managed.stop() {
+            task.await.ok();
+        }
+        Ok(())
+    }
+
+    /// Stops the torrent, closing its connections, until it is resumed.
+    pub async fn pause(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let (task, progress) = {
+            let mut torrents = self.torrents.lock().unwrap();
+            let managed = torrents.get_mut(info_hash).context("no such torrent")?;
+            (managed.stop(), managed.progress.clone())
+        };
+        if let Some(task) = task {
+            task.await.ok();
+        }
+        progress.lock().unwrap().state = TorrentState::Paused;
+        Ok(())
+    }
+
+    /// Starts a paused or failed torrent again. Data checked before isn't checked again.
+    pub fn resume(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get_mut(info_hash).context("no such torrent")?;
+        if managed
+            .task
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+        {
+            return Ok(());
+        }
+        managed.start(&self.resources);
+        Ok(())
+    }
+
+    /// Connects the torrent to the peer at `addr`, if it is running.
+    pub fn add_peer(&self, info_hash: &[u8; 20], addr: SocketAddr) -> anyhow::Result<()> {
+        let torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get(info_hash).context("no such torrent")?;
+        if let Some(swarm) = &managed.progress.lock().unwrap().swarm {
+            swarm.add_peer(addr);
+        }
+        Ok(())
+    }
+
+    /// How much we want each file of the torrent, padding files included.
+    /// Empty until we have the metainfo.
+    pub fn file_priorities(&self, info_hash: &[u8; 20]) -> anyhow::Result<Vec<FilePriority>> {
+        let torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get(info_hash).context("no such torrent")?;
+        let priorities = managed.progress.lock().unwrap().priorities.clone();
+        Ok(priorities)
+    }
+
+    /// Changes how much we want each file of the torrent, padding files included.
+    /// A torrent still being checked starts downloading with them.
+    pub fn set_file_priorities(
+        &self,
+        info_hash: &[u8; 20],
+        priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<()> {
+        let torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get(info_hash).context("no such torrent")?;
+        let mut progress = managed.progress.lock().unwrap();
+        let torrent = progress
+            .torrent
+            .as_ref()
+            .context("the torrent's metainfo isn't known yet")?;
+        check_priorities(torrent, &priorities)?;
+        if let Some(swarm) = &progress.swarm {
+            swarm.set_file_priorities(&priorities);
+        }
+        progress.priorities = priorities;
+        Ok(())
+    }
+
+    /// The status of the torrent.
+    pub fn status(&self, info_hash: &[u8; 20]) -> anyhow::Result<TorrentStatus> {
+        let torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get(info_hash).context("no such torrent")?;
+        Ok(managed.status(*info_hash, &self.resources))
+    }
+
+    /// The status of every torrent.
+    pub fn list(&self) -> Vec<TorrentStatus> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(info_hash, managed)| managed.status(*info_hash, &self.resources))
+            .collect()
+    }
+
+    /// How fast the torrents may transfer.
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.resources.bandwidth.lock().unwrap().clone()
+    }
+
+    /// Changes how fast the torrents may transfer, the open connections follow right away.
+    pub fn set_bandwidth(&self, bandwidth: Bandwidth) {
+        *self.resources.bandwidth.lock().unwrap() = bandwidth;
+        self.resources.apply_bandwidth();
+    }
+
+    /// How fast the torrent may transfer, on top of the limits over all torrents.
+    pub fn torrent_rates(&self, info_hash: &[u8; 20]) -> anyhow::Result<Rates> {
+        let torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get(info_hash).context("no such torrent")?;
+        Ok(managed.rate_limits.rates())
+    }
+
+    /// Changes how fast the torrent may transfer.
+    pub fn set_torrent_rates(&self, info_hash: &[u8; 20], rates: Rates) -> anyhow::Result<()> {
+        let torrents = self.torrents.lock().unwrap();
+        let managed = torrents.get(info_hash).context("no such torrent")?;
+        managed.rate_limits.set_rates(rates);
+        Ok(())
+    }
+
+    /// The most peer connections over all torrents.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections.lock().unwrap().max
+    }
+
+    /// Changes the most peer connections over all torrents. When lowering it,
+    /// connections beyond the new limit are kept until they close.
+    pub fn set_max_connections(&self, max_connections: usize) {
+        let mut limit = self.max_connections.lock().unwrap();
+        if max_connections > limit.max {
+            // the permits still owed from lowering it are settled first.
+            let raised = max_connections - limit.max;
+            let settled = raised.min(limit.owed);
+            limit.owed -= settled;
+            self.resources
+                .connection_limit
+                .add_permits(raised - settled);
+        } else {
+            limit.owed += limit.max - max_connections;
+            if limit.owed > 0 && !limit.collecting {
+                limit.collecting = true;
+                tokio::spawn(collect_permits(
+                    self.max_connections.clone(),
+                    self.resources.connection_limit.clone(),
+                ));
+            }
+        }
+        limit.max = max_connections;
+    }
+
+    /// The peers banned for breaking the protocol or sending bad data,
+    /// with how long they stay banned.
+    pub fn banned_peers(&self) -> Vec<(IpAddr, Duration)> {
+        self.resources.peer_list.banned()
+    }
+
+    /// Stops every torrent and the listener, then saves the DHT nodes.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        self.resources.shutdown.cancel();
+        let tasks: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter_map(|managed| managed.task.take())
+            .collect();
+        for task in tasks {
+            task.await.ok();
+        }
+        if let Some(listener) = &self.listener {
+            listener.abort();
+        }
+        if let Some(dht) = &self.resources.dht {
+            dht.save()?;
+        }
+        Ok(())
+    }
+}
+
+impl ManagedTorrent {
+    /// Spawns the task downloading then seeding the torrent.
+    fn start(&mut self, resources: &Arc<Resources>) {
+        self.stop = resources.shutdown.child_token();
+        self.task = Some(tokio::spawn(run(
+            resources.clone(),
+            self.magnet.clone(),
+            self.path.clone(),
+            TorrentSettings {
+                rate_limits: self.rate_limits.clone(),
+            },
+            self.progress.clone(),
+            self.stop.clone(),
+        )));
+    }
+
+    /// Stops the torrent's task, returning it to wait for it to finish.
+    fn stop(&mut self) -> Option<JoinHandle<()>> {
+        self.stop.cancel();
+        self.task.take()
+    }
+
+    fn status(&self, info_hash: [u8; 20], resources: &Resources) -> TorrentStatus {
+        let progress = self.progress.lock().unwrap();
+        let have = match &progress.swarm {
+            Some(swarm) => Some(swarm.have()),
+            None => progress.have.clone(),
+        };
+        let name = match (&progress.torrent, &self.magnet) {
+            (Some(torrent), _) => torrent.info.name.clone(),
+            (None, Some(magnet)) => magnet.display_name(),
+            (None, None) => hex::encode(info_hash),
+        };
+        let bytes_have = match (&have, &progress.torrent) {
+            (Some(have), Some(torrent)) => have.iter().map(|index| torrent.piece_size(index)).sum(),
+            _ => 0,
+        };
+        let rates = progress
+            .swarm
+            .as_ref()
+            .map(|swarm| swarm.progress().borrow().clone())
+            .unwrap_or_default();
+        TorrentStatus {
+            info_hash,
+            // torrents with an invalid name fail before anything is written.
+            path: resources
+                .path_for(self.path.as_ref(), &name)
+                .unwrap_or_else(|_| resources.options.download_dir.clone()),
+            path_given: self.path.is_some(),
+            name,
+            state: progress.state.clone(),
+            pieces_have: have.map_or(0, |have| have.count()),
+            bytes_have,
+            num_pieces: progress
+                .torrent
+                .as_ref()
+                .map_or(0, |torrent| torrent.num_pieces()),
+            num_peers: progress.swarm.as_ref().map_or(0, |swarm| swarm.num_peers()),
+            download_rate: rates.download_rate,
+            upload_rate: rates.upload_rate,
+            eta_secs: rates.eta_secs,
+        }
+    }
+}
+
+impl Resources {
+    /// Sets the rate limits from the bandwidth settings.
+    fn apply_bandwidth(&self) {
+        let bandwidth = self.bandwidth.lock().unwrap();
+        self.rate_limits.set_rates(bandwidth.current());
+        self.peer_limits.set_rates(bandwidth.per_peer);
+    }
+
+    /// Where the torrent called `name` is downloaded to, unless given a `path`.
+    /// The name comes from the metainfo, it must be a single file name
+    /// so the torrent stays within the download directory.
+    fn path_for(&self, path: Option<&PathBuf>, name: &str) -> anyhow::Result<PathBuf> {
+        if let Some(path) = path {
+            return Ok(path.clone());
+        }
+        if !is_file_name(name) {
+            bail!("invalid torrent name {name:?}");
+        }
+        Ok(self.options.download_dir.join(name))
+    }
+}
+
+/// Switches between the global and alternate rate limits as the schedule says,
+/// until the session shuts down.
+async fn follow_schedule(resources: Arc<Resources>) {
+    loop {
+        resources.apply_bandwidth();
+        tokio::select! {
+            _ = tokio::time::sleep(SCHEDULE_INTERVAL) => {}
+            _ = resources.shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Takes the permits owed to `limit` from `semaphore` as they are available or released by
+/// the connections holding them, until none is owed.
+async fn collect_permits(limit: Arc<Mutex<ConnectionLimit>>, semaphore: Arc<Semaphore>) {
+    while let Ok(permit) = semaphore.acquire().await {
+        let mut limit = limit.lock().unwrap();
+        // raising the limit again may have settled what was owed.
+        if limit.owed > 0 {
+            permit.forget();
+            limit.owed -= 1;
+        }
+        if limit.owed == 0 {
+            limit.collecting = false;
+            return;
+        }
+    }
+}
+
+/// Fails unless there is a priority for every file of `torrent`, padding files included.
+fn check_priorities(torrent: &Torrent, priorities: &[FilePriority]) -> anyhow::Result<()> {
+    if priorities.len() != torrent.files().len() {
+        bail!(
+            "the torrent has {} files, got {} priorities",
+            torrent.files().len(),
+            priorities.len()
+        );
+    }
+    Ok(())
+}
+
+/// Fetches the metainfo of a magnet link, then checks, downloads and seeds a torrent
+/// until it is stopped, reporting how it goes in `progress`.
+async fn run(
+    resources: Arc<Resources>,
+    magnet: Option<Arc<Magnet>>,
+    path: Option<PathBuf>,
+    settings: TorrentSettings,
+    progress: Arc<Mutex<Progress>>,
+    stop: CancellationToken,
+) {
+    let known = progress.lock().unwrap().torrent.clone();
+    let peers = magnet
+        .as_ref()
+        .map(|magnet| magnet.peers.clone())
+        .unwrap_or_default();
+    let torrent = match (known, magnet) {
+        (Some(torrent), _) => torrent,
+        (None, Some(magnet)) => match fetch_torrent(&resources, &magnet, &progress, &stop).await {
+            Some(torrent) => torrent,
+            None => return,
+        },
+        (None, None) => unreachable!("torrents are added with their metainfo or a magnet link"),
+    };
+    let shared = async {
+        let path = resources.path_for(path.as_ref(), &torrent.info.name)?;
+        share(
+            &resources, &torrent, path, &settings, &peers, &progress, &stop,
+        )
+        .await
+    };
+    if let Err(err) = shared.await {
+        warn!("torrent {} failed: {err:#}", torrent.info.name);
+        progress.lock().unwrap().state = TorrentState::Failed(format!("{err:#}"));
+    }
+}
+
+/// Fetches the metainfo of `magnet` from the peers, trying again until some peer has it.
+/// Returns `None` once stopped.
+async fn fetch_torrent(
+    resources: &Resources,
+    magnet: &Magnet,
+    progress: &Mutex<Progress>,
+    stop: &CancellationToken,
+) -> Option<Arc<Torrent>> {
+    progress.lock().unwrap().state = TorrentState::FetchingMetadata;
+    let transports = Transports {
+        utp: resources.utp.clone(),
+        preference: resources.options.transport,
+        proxy: resources.options.proxy.clone(),
+        peer_id: resources.peer_id,
+    };
+    loop {
+        let fetch = magnet.fetch_torrent(
+            resources.port,
+            resources.dht.as_ref(),
+            &transports,
+            resources.options.encryption,
+            &resources.options.ip_filter,
+        );
+        let err = tokio::select! {
+            fetched = fetch => match fetched {
+                Ok(torrent) => {
+                    let torrent = Arc::new(torrent);
+                    let mut progress = progress.lock().unwrap();
+                    progress.priorities = vec![FilePriority::Normal; torrent.files().len()];
+                    progress.torrent = Some(torrent.clone());
+                    return Some(torrent);
+                }
+                Err(err) => err,
+            },
+            _ = stop.cancelled() => return None,
+        };
+        warn!("{err:#}, retrying in {RETRY_DOWNLOAD:?}");
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_DOWNLOAD) => {}
+            _ = stop.cancelled() => return None,
+        }
+    }
+}
+
+async fn share(
+    resources: &Resources,
+    torrent: &Arc<Torrent>,
+    path: PathBuf,
+    settings: &TorrentSettings,
+    peers: &[SocketAddr],
+    progress: &Mutex<Progress>,
+    stop: &CancellationToken,
+) -> anyhow::Result<()> {
+    let checked = progress.lock().unwrap().have.clone();
+    let (storage, have) = {
+        progress.lock().unwrap().state = TorrentState::Checking;
+        let _turn = tokio::select! {
+            turn = resources.checks.acquire() => turn?,
+            _ = stop.cancelled() => return Ok(()),
+        };
+        let torrent = torrent.clone();
+        let priorities = progress.lock().unwrap().priorities.clone();
+        tokio::task::spawn_blocking(move || {
+            let storage = Storage::create_selected(&path, &torrent, &priorities)?;
+            let have = match checked {
+                Some(have) => have,
+                None => storage.verify(&torrent)?,
+            };
+            anyhow::Ok((storage, have))
+        })
+        .await??
+    };
+    if stop.is_cancelled() {
+        return Ok(());
+    }
+
+    let info_hash = torrent.info_hash_bytes();
+    let inbound = resources.active.register(torrent);
+    let wanted = Bitfield::full(torrent.num_pieces());
+    let mut swarm = Swarm::new(torrent.clone(), storage, have, wanted)?
+        .with_inbound(inbound)
+        .with_port(resources.port)
+        .with_peer_id(resources.peer_id)
+        .with_encryption(resources.options.encryption)
+        .with_connection_limit(resources.connection_limit.clone())
+        .with_max_peers(resources.options.max_peers_per_torrent)
+        .with_half_open_limit(resources.half_open.clone())
+        .with_disk_pool(resources.disk.clone())
+        .with_peer_list(resources.peer_list.clone())
+        .with_ip_filter(resources.options.ip_filter.clone())
+        .with_rate_limits(
+            Throttle::default()
+                .with_limits(resources.rate_limits.clone())
+                .with_limits(settings.rate_limits.clone()),
+            resources.peer_limits.clone(),
+        )
+        .with_shutdown(stop.clone());
+    if let Some(utp) = &resources.utp {
+        swarm = swarm.with_utp(utp.clone(), resources.options.transport);
+    }
+    if let Some(proxy) = &resources.options.proxy {
+        swarm = swarm.with_proxy(proxy.clone());
+    }
+    if let Some(dht) = &resources.dht {
+        swarm = swarm.with_dht(dht.clone());
+    }
+    if let Some(lsd) = &resources.lsd {
+        swarm = swarm.with_lsd(lsd.clone());
+    }
+    for &addr in peers {
+        swarm.add_peer(addr);
+    }
+    {
+        // under the lock, so priorities changed while checking aren't missed.
+        let mut progress = progress.lock().unwrap();
+        swarm.set_file_priorities(&progress.priorities);
+        progress.swarm = Some(swarm.handle());
+    }
+
+    let result = seed_after_download(&mut swarm, progress, stop).await;
+
+    resources.active.unregister(&info_hash);
+    if let Some(lsd) = &resources.lsd {
+        lsd.unregister(&info_hash);
+    }
+    let mut progress = progress.lock().unwrap();
+    progress.have = Some(swarm.have());
+    progress.swarm = None;
+    result
+}
+
+async fn seed_after_download(
+    swarm: &mut Swarm,
+    progress: &Mutex<Progress>,
+    stop: &CancellationToken,
+) -> anyhow::Result<()> {
+    progress.lock().unwrap().state = TorrentState::Downloading;
+    // a torrent nobody shares for a while keeps waiting for peers.
+    while let Err(err) = swarm.download().await {
+        warn!("{err:#}, retrying in {RETRY_DOWNLOAD:?}");
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_DOWNLOAD) => {}
+            _ = stop.cancelled() => return Ok(()),
+        }
+    }
+    if stop.is_cancelled() {
+        return Ok(());
+    }
+    progress.lock().unwrap().state = TorrentState::Seeding;
+    swarm.seed().await
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use super::{Session, SessionOptions, TorrentState, TorrentStatus};
+    use crate::transport::TransportPreference;
+
+    /// A session downloading to `download_dir`, on a free port and without any discovery.
+    pub async fn session(download_dir: &Path) -> Arc<Session> {
+        let session = Session::start(SessionOptions {
+            port: 0,
+            download_dir: download_dir.to_path_buf(),
+            transport: TransportPreference::TcpOnly,
+            dht: false,
+            lsd: false,
+            ..SessionOptions::default()
+        })
+        .await
+        .unwrap();
+        Arc::new(session)
+    }
+
+    /// Waits until the torrent `info_hash` of `session` is started and done checking its data.
+    pub async fn checked(session: &Session, info_hash: &[u8; 20]) -> TorrentStatus {
+        for _ in 0..200 {
+            let status = session.status(info_hash).unwrap();
+            if !matches!(
+                status.state,
+                TorrentState::Checking | TorrentState::FetchingMetadata | TorrentState::Paused
+            ) {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the torrent is still checking");
+    }
+
+    /// The pieces the running swarm of the torrent `info_hash` wants, once it said so.
+    /// Only for torrents wanting some pieces.
+    pub async fn pieces_wanted(session: &Session, info_hash: &[u8; 20]) -> usize {
+        let mut progress = {
+            let torrents = session.torrents.lock().unwrap();
+            let progress = torrents[info_hash].progress.lock().unwrap();
+            progress
+                .swarm
+                .as_ref()
+                .expect("the torrent runs")
+                .progress()
+        };
+        let progress = progress
+            .wait_for(|progress| progress.pieces_wanted > 0)
+            .await
+            .unwrap();
+        progress.pieces_wanted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, time::timeout};
+
+    use super::*;
+    use crate::torrent::testing as torrents;
+
+    #[tokio::test]
+    async fn torrents_named_like_paths_fail_instead_of_escaping() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = testing::session(dir.path()).await;
+        for name in ["..", ".", "a/b", "a\\b", ""] {
+            let info_hash = session
+                .add(torrents::torrent(name, &[("a", &[1; 4])], 4), Vec::new())
+                .unwrap();
+            let status = testing::checked(&session, &info_hash).await;
+            assert_eq!(
+                status.state,
+                TorrentState::Failed(format!("invalid torrent name {name:?}"))
+            );
+            assert_eq!(status.path, dir.path());
+        }
+
+        // a path given when adding is used as is.
+        let path = dir.path().join("elsewhere");
+        let info_hash = session
+            .add_at(
+                torrents::torrent("..", &[("a", &[2; 4])], 4),
+                &path,
+                Vec::new(),
+            )
+            .unwrap();
+        assert_eq!(testing::checked(&session, &info_hash).await.path, path);
+        assert!(path.exists());
+        session.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn torrents_share_the_connection_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::start(SessionOptions {
+            port: 0,
+            download_dir: dir.path().to_path_buf(),
+            transport: TransportPreference::TcpOnly,
+            dht: false,
+            lsd: false,
+            max_connections: 1,
+            ..SessionOptions::default()
+        })
+        .await
+        .unwrap();
+        // a peer that takes connections and never answers, so they stay open.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let (accepted, mut connections) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.send(stream).ok();
+            }
+        });
+        let add = |name: &str| {
+            let torrent = torrents::torrent(name, &[("a", &[1; 4])], 4);
+            session.add(torrent, Vec::new()).unwrap()
+        };
+        let (first, second) = (add("first"), add("second"));
+        testing::checked(&session, &first).await;
+        testing::checked(&session, &second).await;
+        // connections may be slow to come on a loaded machine, the one that must not come is
+        // only waited for briefly.
+        let (wait, unexpected) = (Duration::from_secs(5), Duration::from_millis(300));
+
+        // the connections are kept, closing them would make the torrents connect again.
+        session.add_peer(&first, peer).unwrap();
+        let _taken = timeout(wait, connections.recv()).await.unwrap().unwrap();
+        session.add_peer(&second, peer).unwrap();
+        assert!(timeout(unexpected, connections.recv()).await.is_err());
+        assert_eq!(session.status(&second).unwrap().num_peers, 0);
+
+        session.set_max_connections(2);
+        session.add_peer(&second, peer).unwrap();
+        let _raised = timeout(wait, connections.recv()).await.unwrap().unwrap();
+        assert_eq!(session.status(&second).unwrap().num_peers, 1);
+        session.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lowering_and_raising_the_connection_limit_keeps_its_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::start(SessionOptions {
+            port: 0,
+            download_dir: dir.path().to_path_buf(),
+            transport: TransportPreference::TcpOnly,
+            dht: false,
+            lsd: false,
+            max_connections: 4,
+            ..SessionOptions::default()
+        })
+        .await
+        .unwrap();
+        let semaphore = session.resources.connection_limit.clone();
+        let held = semaphore.clone().acquire_many_owned(3).await.unwrap();
+        let settled = |available: usize| {
+            let semaphore = &semaphore;
+            async move {
+                for _ in 0..200 {
+                    if semaphore.available_permits() == available {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("{} permits, not {available}", semaphore.available_permits());
+            }
+        };
+
+        // the one free permit goes at once, the two owed ones wait for the connections.
+        session.set_max_connections(1);
+        settled(0).await;
+        session.set_max_connections(5);
+        settled(2).await;
+        drop(held);
+        settled(5).await;
+
+        // lowered and raised before the connections close, nothing stays taken.
+        let held = semaphore.clone().acquire_many_owned(5).await.unwrap();
+        for _ in 0..3 {
+            session.set_max_connections(2);
+            tokio::task::yield_now().await;
+            session.set_max_connections(5);
+        }
+        drop(held);
+        settled(5).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(semaphore.available_permits(), 5);
+        assert_eq!(session.max_connections(), 5);
+        session.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumed_torrents_are_not_checked_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = testing::session(dir.path()).await;
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4]), ("b", &[2; 4])], 4);
+        let info_hash = torrent.info_hash_bytes();
+        session.add(torrent.clone(), Vec::new()).unwrap();
+        assert_eq!(testing::checked(&session, &info_hash).await.pieces_have, 0);
+
+        // the data appears while paused, resuming goes on with what was checked before.
+        session.pause(&info_hash).await.unwrap();
+        std::fs::write(dir.path().join("dir/a"), [1; 4]).unwrap();
+        session.resume(&info_hash).unwrap();
+        let status = testing::checked(&session, &info_hash).await;
+        assert_eq!(
+            (status.state, status.pieces_have),
+            (TorrentState::Downloading, 0)
+        );
+
+        // adding the torrent again checks it.
+        session.remove(&info_hash).await.unwrap();
+        session.add(torrent, Vec::new()).unwrap();
+        assert_eq!(testing::checked(&session, &info_hash).await.pieces_have, 1);
+        session.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn skipped_files_are_neither_created_nor_wanted() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = testing::session(dir.path()).await;
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4]), ("b", &[2; 4])], 4);
+        let info_hash = torrent.info_hash_bytes();
+        let priorities = vec![FilePriority::Normal, FilePriority::Skip];
+        assert!(session
+            .add(torrent.clone(), vec![FilePriority::Normal])
+            .is_err());
+        session.add(torrent, priorities.clone()).unwrap();
+
+        assert_eq!(
+            testing::checked(&session, &info_hash).await.state,
+            TorrentState::Downloading
+        );
+        assert_eq!(session.file_priorities(&info_hash).unwrap(), priorities);
+        assert!(dir.path().join("dir/a").exists());
+        assert!(!dir.path().join("dir/b").exists());
+        assert_eq!(testing::pieces_wanted(&session, &info_hash).await, 1);
+        session.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn priorities_set_while_checking_are_downloaded_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = testing::session(dir.path()).await;
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4]), ("b", &[2; 4])], 4);
+        let info_hash = torrent.info_hash_bytes();
+
+        // the torrent waits for its turn to check while we hold every turn.
+        let checks = session.resources.options.max_concurrent_checks.max(1);
+        let turns = session
+            .resources
+            .checks
+            .acquire_many(checks as u32)
+            .await
+            .unwrap();
+        session.add(torrent, Vec::new()).unwrap();
+        assert_eq!(
+            session.status(&info_hash).unwrap().state,
+            TorrentState::Checking
+        );
+        let priorities = vec![FilePriority::Skip, FilePriority::High];
+        session
+            .set_file_priorities(&info_hash, priorities.clone())
+            .unwrap();
+        drop(turns);
+
+        testing::checked(&session, &info_hash).await;
+        assert!(!dir.path().join("dir/a").exists());
+        assert!(dir.path().join("dir/b").exists());
+        assert_eq!(testing::pieces_wanted(&session, &info_hash).await, 1);
+        assert_eq!(session.file_priorities(&info_hash).unwrap(), priorities);
+        session.shutdown().await.unwrap();
+    }
+}
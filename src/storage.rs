@@ -0,0 +1,408 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context};
+use tokio::sync::Semaphore;
+
+use crate::{
+    bitfield::Bitfield,
+    peer::Block,
+    priority::FilePriority,
+    torrent::{file_slices, FileEntry, Torrent},
+};
+
+/// The data of a torrent on disk, addressed by piece.
+/// The files of a multi file torrent are stored in a directory,
+/// pieces spanning several files are split between them. Padding isn't stored.
+pub struct Storage {
+    files: Vec<FileEntry>,
+    handles: Vec<Mutex<FileHandle>>,
+    piece_length: usize,
+    length: usize,
+}
+
+/// How many disk jobs run at the same time, unless configured otherwise.
+pub const DEFAULT_DISK_JOBS: usize = 4;
+
+/// Runs the reads and writes of storages on blocking threads, a bounded number at a time,
+/// so slow disks neither stall the connections nor take every blocking thread.
+#[derive(Clone)]
+pub struct DiskPool {
+    jobs: Arc<Semaphore>,
+}
+
+impl DiskPool {
+    pub fn new(max_jobs: usize) -> Self {
+        Self {
+            jobs: Arc::new(Semaphore::new(max_jobs.max(1))),
+        }
+    }
+
+    /// Reads the bytes of `block` from `storage`, see [`Storage::read_block`].
+    pub async fn read_block(
+        &self,
+        storage: &Arc<Storage>,
+        block: Block,
+    ) -> anyhow::Result<Vec<u8>> {
+        let storage = storage.clone();
+        self.run(move || storage.read_block(block)).await
+    }
+
+    /// Writes the verified `data` of the piece at `piece_index` to `storage`.
+    pub async fn write_piece(
+        &self,
+        storage: &Arc<Storage>,
+        piece_index: usize,
+        data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let storage = storage.clone();
+        self.run(move || storage.write_piece(piece_index, &data))
+            .await
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let _turn = self.jobs.acquire().await?;
+        tokio::task::spawn_blocking(job).await?
+    }
+}
+
+impl Default for DiskPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_DISK_JOBS)
+    }
+}
+
+/// Where the data of a file of the torrent is.
+enum FileHandle {
+    Open(File),
+    /// Padding, which reads as zeros.
+    Pad,
+    /// A skipped file that doesn't exist yet, it is created at the path
+    /// once a piece it shares with a wanted file is written. Only the data of such pieces
+    /// is written to it, a piece shared at its end leaves a hole before it on the
+    /// filesystems that support sparse files.
+    Deferred(PathBuf),
+}
+
+impl Storage {
+    /// Opens existing data for `torrent` at `path`, for seeding.
+    pub fn open(path: impl AsRef<Path>, torrent: &Torrent) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        for entry in torrent.files().iter().cloned() {
+            if entry.pad {
+                files.push((entry, FileHandle::Pad));
+                continue;
+            }
+            let path = file_path(path, torrent, &entry)?;
+            let file = File::open(&path).with_context(|| format!("opening {path:?} failed"))?;
+            files.push((entry, FileHandle::Open(file)));
+        }
+        Ok(Self::with_files(files, torrent))
+    }
+
+    /// Opens or creates the files at `path` to download `torrent` into,
+    /// `path` is the file of a single file torrent or the directory of a multi file one.
+    pub fn create(path: impl AsRef<Path>, torrent: &Torrent) -> anyhow::Result<Self> {
+        Self::create_selected(path, torrent, &[])
+    }
+
+    /// Like [`Storage::create`], but the files whose priority is skip aren't created
+    /// unless they already exist, `priorities` are those of the files of `torrent`.
+    pub fn create_selected(
+        path: impl AsRef<Path>,
+        torrent: &Torrent,
+        priorities: &[FilePriority],
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        for (index, entry) in torrent.files().iter().cloned().enumerate() {
+            if entry.pad {
+                files.push((entry, FileHandle::Pad));
+                continue;
+            }
+            let path = file_path(path, torrent, &entry)?;
+            let skipped = priorities.get(index) == Some(&FilePriority::Skip);
+            let handle = match (skipped, path.exists()) {
+                (true, false) => FileHandle::Deferred(path),
+                // skipped files aren't grown to their length.
+                (true, true) => FileHandle::Open(open_file(&path)?),
+                (false, _) => FileHandle::Open(create_file(&path, entry.length)?),
+            };
+            files.push((entry, handle));
+        }
+        Ok(Self::with_files(files, torrent))
+    }
+
+    /// Downloads `torrent` into temporary files that are removed once dropped.
+    pub fn temporary(torrent: &Torrent) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        for entry in torrent.files().iter().cloned() {
+            if entry.pad {
+                files.push((entry, FileHandle::Pad));
+                continue;
+            }
+            let file = tempfile::tempfile()?;
+            file.set_len(entry.length as u64)?;
+            files.push((entry, FileHandle::Open(file)));
+        }
+        Ok(Self::with_files(files, torrent))
+    }
+
+    fn with_files(files: Vec<(FileEntry, FileHandle)>, torrent: &Torrent) -> Self {
+        let (files, handles): (Vec<_>, _) = files
+            .into_iter()
+            .map(|(entry, handle)| (entry, Mutex::new(handle)))
+            .unzip();
+        // the end of the last file, padding included.
+        let length = files.last().map_or(0, |file| file.offset + file.length);
+        Self {
+            files,
+            handles,
+            piece_length: torrent.info.piece_length,
+            length,
+        }
+    }
+
+    /// Reads the bytes of `block`, which must lie within the torrent.
+    pub fn read_block(&self, block: Block) -> anyhow::Result<Vec<u8>> {
+        let offset = block.piece as usize * self.piece_length + block.begin as usize;
+        if block.begin as usize + block.length as usize > self.piece_length
+            || offset + block.length as usize > self.length
+        {
+            bail!("block {block:?} is out of range");
+        }
+
+        let mut data = vec![0u8; block.length as usize];
+        let mut read = 0;
+        for slice in file_slices(&self.files, offset, data.len()) {
+            // padding, files that don't exist yet and what lies past the end
+            // of shorter files read as zeros.
+            if let FileHandle::Open(file) = &mut *self.handles[slice.file].lock().unwrap() {
+                file.seek(SeekFrom::Start(slice.offset as u64))?;
+                let mut buf = &mut data[read..read + slice.length];
+                while !buf.is_empty() {
+                    match file.read(buf)? {
+                        0 => break,
+                        n => buf = &mut buf[n..],
+                    }
+                }
+            }
+            read += slice.length;
+        }
+        Ok(data)
+    }
+
+    /// Reads the whole piece at `piece_index` whose size is `piece_size`.
+    pub fn read_piece(&self, piece_index: usize, piece_size: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_block(Block {
+            piece: piece_index as u32,
+            begin: 0,
+            length: piece_size as u32,
+        })
+    }
+
+    /// Writes the verified data of the piece at `piece_index`.
+    pub fn write_piece(&self, piece_index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let mut written = 0;
+        for slice in file_slices(&self.files, piece_index * self.piece_length, data.len()) {
+            let mut handle = self.handles[slice.file].lock().unwrap();
+            if let FileHandle::Deferred(path) = &*handle {
+                *handle = FileHandle::Open(open_file(path)?);
+            }
+            if let FileHandle::Open(file) = &mut *handle {
+                file.seek(SeekFrom::Start(slice.offset as u64))?;
+                file.write_all(&data[written..written + slice.length])?;
+            }
+            written += slice.length;
+        }
+        Ok(())
+    }
+
+    /// Hash checks the data on disk and returns the pieces that are complete.
+    pub fn verify(&self, torrent: &Torrent) -> anyhow::Result<Bitfield> {
+        let mut have = Bitfield::new(torrent.num_pieces());
+        for index in 0..torrent.num_pieces() {
+            // missing data reads as zeros, failing the check.
+            let data = self.read_piece(index, torrent.piece_size(index))?;
+            if torrent.verify_piece(index, &data) {
+                have.set(index);
+            }
+        }
+        Ok(have)
+    }
+}
+
+/// Opens or creates the file at `path` and its directory, with the file's `length`.
+fn create_file(path: &Path, length: usize) -> anyhow::Result<File> {
+    let file = open_file(path)?;
+    file.set_len(length as u64)?;
+    Ok(file)
+}
+
+/// Opens or creates the file at `path` and its directory, keeping its length.
+fn open_file(path: &Path) -> anyhow::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("creating {parent:?} failed"))?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("creating {path:?} failed"))
+}
+
+/// Where the file `entry` of `torrent` is stored, `path` is the file of a single file torrent
+/// or the directory of a multi file one. Paths escaping the directory are refused.
+fn file_path(path: &Path, torrent: &Torrent, entry: &FileEntry) -> anyhow::Result<PathBuf> {
+    if !torrent.is_multi_file() {
+        return Ok(path.to_path_buf());
+    }
+
+    let mut file_path = path.to_path_buf();
+    for component in &entry.path {
+        if !is_file_name(component) {
+            bail!("invalid file path {:?} in torrent", entry.path);
+        }
+        file_path.push(component);
+    }
+    if entry.path.is_empty() {
+        bail!("empty file path in torrent");
+    }
+    Ok(file_path)
+}
+
+/// Whether `name` from a metainfo is a single path component, one that can't lead
+/// outside of the directory it is joined to.
+pub(crate) fn is_file_name(name: &str) -> bool {
+    !(name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::torrent::testing;
+
+    fn block(piece: u32, begin: u32, length: u32) -> Block {
+        Block {
+            piece,
+            begin,
+            length,
+        }
+    }
+
+    #[test]
+    fn pieces_spanning_files_are_split_between_them() {
+        let torrent = testing::torrent("dir", &[("a", &[1; 6]), ("sub/b", &[2; 6])], 4);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(dir.path(), &torrent).unwrap();
+        assert_eq!(storage.verify(&torrent).unwrap().count(), 0);
+
+        storage.write_piece(1, &[1, 1, 2, 2]).unwrap();
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), [0, 0, 0, 0, 1, 1]);
+        assert_eq!(fs::read(dir.path().join("sub/b")).unwrap()[..2], [2, 2]);
+        assert_eq!(storage.read_block(block(1, 1, 2)).unwrap(), [1, 2]);
+        assert_eq!(
+            storage.verify(&torrent).unwrap().iter().collect::<Vec<_>>(),
+            [1]
+        );
+    }
+
+    #[test]
+    fn blocks_out_of_range_are_refused() {
+        let torrent = testing::torrent("a", &[("a", &[1; 10])], 4);
+        let storage = Storage::temporary(&torrent).unwrap();
+        assert!(storage.read_block(block(0, 2, 4)).is_err());
+        assert!(storage.read_block(block(2, 0, 4)).is_err());
+        assert!(storage.read_block(block(2, 0, 2)).is_ok());
+    }
+
+    #[test]
+    fn seeding_reads_existing_data() {
+        let torrent = testing::torrent("a", &[("a", &[5; 10])], 4);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), [5; 10]).unwrap();
+        let storage = Storage::open(dir.path().join("a"), &torrent).unwrap();
+        assert!(storage.verify(&torrent).unwrap().is_complete());
+        assert_eq!(storage.read_piece(2, 2).unwrap(), [5, 5]);
+    }
+
+    #[test]
+    fn paths_escaping_the_directory_are_refused() {
+        let torrent = testing::torrent("dir", &[("../a", &[1; 4]), ("b", &[2; 4])], 4);
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Storage::create(dir.path().join("dir"), &torrent).is_err());
+    }
+
+    #[test]
+    fn skipped_files_are_created_once_written() {
+        let torrent = testing::torrent("dir", &[("a", &[1; 6]), ("b", &[2; 6])], 4);
+        let dir = tempfile::tempdir().unwrap();
+        let priorities = [FilePriority::Normal, FilePriority::Skip];
+        let storage = Storage::create_selected(dir.path(), &torrent, &priorities).unwrap();
+        assert!(!dir.path().join("b").exists());
+        // skipped files read as zeros until then.
+        assert_eq!(storage.read_piece(2, 4).unwrap(), [0; 4]);
+
+        // only with the data of the pieces it shares.
+        storage.write_piece(1, &[1, 1, 2, 2]).unwrap();
+        assert_eq!(fs::read(dir.path().join("b")).unwrap(), [2, 2]);
+        assert_eq!(storage.read_piece(2, 4).unwrap(), [0; 4]);
+        assert_eq!(
+            storage.verify(&torrent).unwrap().iter().collect::<Vec<_>>(),
+            [1]
+        );
+
+        // nor when it is there already.
+        drop(storage);
+        let storage = Storage::create_selected(dir.path(), &torrent, &priorities).unwrap();
+        assert_eq!(fs::read(dir.path().join("b")).unwrap(), [2, 2]);
+        assert_eq!(storage.read_piece(1, 4).unwrap(), [1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn skipped_files_sharing_their_end_only_hold_that() {
+        let torrent = testing::torrent("dir", &[("a", &[1; 6]), ("b", &[2; 6])], 4);
+        let dir = tempfile::tempdir().unwrap();
+        let priorities = [FilePriority::Skip, FilePriority::Normal];
+        let storage = Storage::create_selected(dir.path(), &torrent, &priorities).unwrap();
+        storage.write_piece(1, &[1, 1, 2, 2]).unwrap();
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), [0, 0, 0, 0, 1, 1]);
+        assert_eq!(storage.read_piece(0, 4).unwrap(), [0; 4]);
+    }
+
+    #[tokio::test]
+    async fn disk_jobs_wait_for_their_turn() {
+        let torrent = testing::torrent("a", &[("a", &[1; 8])], 4);
+        let storage = Arc::new(Storage::temporary(&torrent).unwrap());
+        let disk = DiskPool::new(1);
+
+        let turn = disk.jobs.clone().acquire_owned().await.unwrap();
+        let write = tokio::spawn({
+            let (disk, storage) = (disk.clone(), storage.clone());
+            async move { disk.write_piece(&storage, 1, vec![3; 4]).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!write.is_finished());
+        assert_eq!(storage.read_piece(1, 4).unwrap(), [0; 4]);
+
+        drop(turn);
+        write.await.unwrap().unwrap();
+        assert_eq!(
+            disk.read_block(&storage, block(1, 1, 2)).await.unwrap(),
+            [3, 3]
+        );
+        assert!(disk.read_block(&storage, block(2, 0, 4)).await.is_err());
+    }
+}
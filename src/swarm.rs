@@ -0,0 +1,1580 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use tokio::{
+    sync::{
+        broadcast, mpsc,
+        oneshot::{self, error::RecvError},
+        watch, OwnedSemaphorePermit, Semaphore,
+    },
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    bitfield::Bitfield,
+    choker::{Choker, PeerStats, RECHOKE_INTERVAL},
+    dht::Dht,
+    extension::{ExtensionHandshake, HANDSHAKE_ID, UT_PEX, UT_PEX_ID},
+    fast::allowed_fast_set,
+    ipfilter::IpFilter,
+    lsd::{Lsd, LSD_ANNOUNCE_INTERVAL},
+    mse::EncryptionPolicy,
+    peer::{PeerConnection, ProtocolViolation},
+    peer_list::PeerList,
+    peer_message::{Message, MessageTag},
+    pex::{PexMessage, PexState, FLAG_REACHABLE, FLAG_SEED},
+    picker::PiecePicker,
+    priority::{piece_priorities, FilePriority},
+    progress::{Progress, RateMeter},
+    proxy::Proxy,
+    ratelimit::{RateLimits, Throttle},
+    server::DEFAULT_PORT,
+    storage::{DiskPool, Storage},
+    torrent::Torrent,
+    tracker::{Announce, Event, TrackerResponse, TrackerTiers},
+    transport::{TransportPreference, Transports},
+    utp::UtpSocket,
+    webseed::WebSeed,
+};
+use crate::{debug, warn};
+
+/// The most peers a swarm connects to, unless configured otherwise.
+pub const DEFAULT_MAX_PEERS: usize = 50;
+
+/// The most connections being opened at the same time, unless configured otherwise.
+/// Half-open connections take up resources in routers and firewalls.
+pub const DEFAULT_MAX_HALF_OPEN: usize = 8;
+
+/// How long we wait for any message while we have outstanding requests.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we wait before announcing again when the tracker can't be reached.
+const RETRY_ANNOUNCE: Duration = Duration::from_secs(60);
+
+/// How long we wait for the trackers to hear that we leave, before leaving anyway.
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often we announce ourselves on the DHT, and look for peers there.
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How often the progress is published with fresh rates.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A download without any connected peer or completed piece for this long is given up.
+const STALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a web seed rests after a failed request.
+const WEB_SEED_RETRY: Duration = Duration::from_secs(10);
+
+/// A web seed failing this many times in a row is given up.
+const MAX_WEB_SEED_FAILURES: usize = 5;
+
+/// How long a web seed without a piece to fetch waits before looking again,
+/// unless a piece completes first.
+const WEB_SEED_IDLE: Duration = Duration::from_secs(1);
+
+/// How a swarm connects to peers and what it shares with other swarms,
+/// set by the `with_` methods before it starts.
+#[derive(Clone)]
+struct Options {
+    /// The port we tell the tracker and the peers we listen on.
+    port: u16,
+
+    /// Our DHT node, to find peers without the tracker.
+    dht: Option<Arc<Dht>>,
+
+    /// Local service discovery, to find peers on the same network.
+    lsd: Option<Arc<Lsd>>,
+
+    /// Whether the connections we open are encrypted.
+    encryption: EncryptionPolicy,
+
+    /// How we connect to peers, over TCP or uTP.
+    transports: Transports,
+
+    /// Each connection holds a permit, swarms sharing it share the limit.
+    connection_limit: Arc<Semaphore>,
+
+    /// The most peers of this swarm.
+    max_peers: usize,
+
+    /// Each connection being opened holds a permit until the handshakes are done.
+    half_open: Arc<Semaphore>,
+
+    /// Where the reads and writes of the storage run.
+    disk: DiskPool,
+
+    /// The peers that failed or misbehaved.
+    peer_list: Arc<PeerList>,
+
+    /// The addresses we don't connect to.
+    ip_filter: Arc<IpFilter>,
+
+    /// The rate limits every connection counts against.
+    throttle: Throttle,
+
+    /// Each connection gets limits of its own at these rates.
+    peer_limits: RateLimits,
+
+    /// Stops the swarm and closes its connections.
+    shutdown: CancellationToken,
+}
+
+impl Options {
+    fn new() -> io::Result<Self> {
+        Ok(Self {
+            port: DEFAULT_PORT,
+            dht: None,
+            lsd: None,
+            encryption: EncryptionPolicy::default(),
+            transports: Transports::new()?,
+            connection_limit: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            max_peers: DEFAULT_MAX_PEERS,
+            half_open: Arc::new(Semaphore::new(DEFAULT_MAX_HALF_OPEN)),
+            disk: DiskPool::default(),
+            peer_list: Arc::default(),
+            ip_filter: Arc::default(),
+            throttle: Throttle::default(),
+            peer_limits: RateLimits::default(),
+            shutdown: CancellationToken::new(),
+        })
+    }
+}
+
+/// What a swarm has from its creation on, which its handles follow and steer.
+struct State {
+    torrent: Arc<Torrent>,
+    storage: Arc<Storage>,
+    picker: Mutex<PiecePicker>,
+
+    /// The peers we are connected to, so we don't connect twice.
+    connected: Mutex<HashSet<SocketAddr>>,
+
+    /// Peers learned from other peers, the swarm connects to them.
+    discovered: mpsc::UnboundedSender<SocketAddr>,
+
+    /// The latest progress, for whoever follows the swarm.
+    progress: watch::Sender<Progress>,
+}
+
+impl State {
+    fn have(&self) -> Bitfield {
+        self.picker.lock().unwrap().have().clone()
+    }
+
+    fn num_peers(&self) -> usize {
+        self.connected.lock().unwrap().len()
+    }
+
+    fn set_file_priorities(&self, priorities: &[FilePriority]) {
+        let pieces = piece_priorities(&self.torrent, priorities);
+        self.picker.lock().unwrap().set_priorities(pieces);
+    }
+}
+
+/// State shared between the swarm and the tasks driving each connection, once it started.
+struct Shared {
+    state: Arc<State>,
+    options: Options,
+
+    /// The bencoded info dictionary, for peers that only have a magnet link.
+    info: Vec<u8>,
+    choker: Choker,
+
+    /// Every piece we complete is announced to the connections, so they send have messages.
+    haves: broadcast::Sender<usize>,
+
+    /// Tells the swarm a piece was completed.
+    completed: mpsc::UnboundedSender<usize>,
+
+    /// The address each connected peer listens on and its flags,
+    /// what we tell the other peers about through peer exchange.
+    listening: Mutex<HashMap<SocketAddr, (SocketAddr, u8)>>,
+
+    /// Piece data received from peers and web seeds, and sent to peers.
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+}
+
+/// The peers we exchange pieces with for a single torrent.
+/// Peers come from the tracker and, when listening, from incoming connections.
+/// Each connection runs in its own task, downloading the pieces the picker gives it
+/// and uploading to the peer when the choker unchokes it.
+pub struct Swarm {
+    state: Arc<State>,
+
+    /// Taken in when the swarm first starts, later changes don't reach it.
+    options: Options,
+
+    /// Built when the swarm first starts, downloading and seeding go on with it.
+    shared: Option<Arc<Shared>>,
+
+    /// Peers that connected to us, handed over by the listener.
+    inbound: Option<mpsc::UnboundedReceiver<PeerConnection>>,
+
+    /// The connections tell the swarm about the pieces they complete.
+    completed: (mpsc::UnboundedSender<usize>, mpsc::UnboundedReceiver<usize>),
+
+    discovered: mpsc::UnboundedReceiver<SocketAddr>,
+}
+
+impl Swarm {
+    /// Creates a swarm for `torrent` whose data is in `storage`.
+    /// `have` are the pieces already in storage, only the `wanted` pieces are downloaded.
+    /// Fails when the operating system has no random bytes for its peer id.
+    pub fn new(
+        torrent: Arc<Torrent>,
+        storage: Storage,
+        have: Bitfield,
+        wanted: Bitfield,
+    ) -> io::Result<Self> {
+        let (discovered_sender, discovered) = mpsc::unbounded_channel();
+        Ok(Self {
+            state: Arc::new(State {
+                torrent,
+                storage: Arc::new(storage),
+                picker: Mutex::new(PiecePicker::new(have, wanted)),
+                connected: Mutex::default(),
+                discovered: discovered_sender,
+                progress: watch::channel(Progress::default()).0,
+            }),
+            options: Options::new()?,
+            shared: None,
+            inbound: None,
+            completed: mpsc::unbounded_channel(),
+            discovered,
+        })
+    }
+
+    /// Attaches the peers accepted by the listener for this torrent to the swarm.
+    pub fn with_inbound(mut self, inbound: mpsc::UnboundedReceiver<PeerConnection>) -> Self {
+        self.inbound = Some(inbound);
+        self
+    }
+
+    /// The port the listener is bound to, which we announce to the tracker and the peers.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.options.port = port;
+        self
+    }
+
+    /// Looks for peers on the DHT as well, and tells the peers about our node.
+    /// Private torrents don't use the DHT.
+    pub fn with_dht(mut self, dht: Arc<Dht>) -> Self {
+        if !self.state.torrent.is_private() {
+            self.options.dht = Some(dht);
+        }
+        self
+    }
+
+    /// Announces the torrent on the local network, and connects to the peers announcing it there.
+    /// Private torrents aren't announced.
+    pub fn with_lsd(mut self, lsd: Arc<Lsd>) -> Self {
+        if self.state.torrent.is_private() {
+            return self;
+        }
+        lsd.register(
+            self.state.torrent.info_hash_bytes(),
+            self.state.discovered.clone(),
+        );
+        self.options.lsd = Some(lsd);
+        self
+    }
+
+    /// Whether the connections we open are encrypted, the listener decides for incoming ones.
+    pub fn with_encryption(mut self, encryption: EncryptionPolicy) -> Self {
+        self.options.encryption = encryption;
+        self
+    }
+
+    /// Connects to peers over uTP as well, in the order of `preference`.
+    pub fn with_utp(mut self, utp: Arc<UtpSocket>, preference: TransportPreference) -> Self {
+        self.options.transports.utp = Some(utp);
+        self.options.transports.preference = preference;
+        self
+    }
+
+    /// Identifies us with `peer_id` to the peers and the tracker,
+    /// the one the listener answers handshakes with.
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.options.transports.peer_id = peer_id;
+        self
+    }
+
+    /// Connects to peers, trackers and web seeds through `proxy`.
+    pub fn with_proxy(mut self, proxy: Arc<Proxy>) -> Self {
+        self.options.transports.proxy = Some(proxy);
+        self
+    }
+
+    /// The pieces we have downloaded so far.
+    pub fn have(&self) -> Bitfield {
+        self.state.have()
+    }
+
+    /// Limits the number of connections, `limit` may be shared with other swarms
+    /// and each connection holds one of its permits.
+    pub fn with_connection_limit(mut self, limit: Arc<Semaphore>) -> Self {
+        self.options.connection_limit = limit;
+        self
+    }
+
+    /// Limits the number of peers of this swarm.
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.options.max_peers = max_peers;
+        self
+    }
+
+    /// Limits the number of connections being opened at the same time, `limit` may be
+    /// shared with other swarms and each connection holds one of its permits while opening.
+    pub fn with_half_open_limit(mut self, limit: Arc<Semaphore>) -> Self {
+        self.options.half_open = limit;
+        self
+    }
+
+    /// Reads and writes the storage through `disk`, which may be shared with other swarms.
+    pub fn with_disk_pool(mut self, disk: DiskPool) -> Self {
+        self.options.disk = disk;
+        self
+    }
+
+    /// Remembers the peers that failed or misbehaved in `peer_list`,
+    /// which may be shared with other swarms.
+    pub fn with_peer_list(mut self, peer_list: Arc<PeerList>) -> Self {
+        self.options.peer_list = peer_list;
+        self
+    }
+
+    /// Doesn't connect to the peers `ip_filter` blocks, wherever we learn about them.
+    pub fn with_ip_filter(mut self, ip_filter: Arc<IpFilter>) -> Self {
+        self.options.ip_filter = ip_filter;
+        self
+    }
+
+    /// Limits the traffic of every connection by `throttle`, whose limits may be shared
+    /// with other swarms, and of each connection by limits of its own at `peer_limits`' rates.
+    pub fn with_rate_limits(mut self, throttle: Throttle, peer_limits: RateLimits) -> Self {
+        self.options.throttle = throttle;
+        self.options.peer_limits = peer_limits;
+        self
+    }
+
+    /// Stops the swarm once `shutdown` is cancelled, closing its connections.
+    /// Downloading and seeding return then.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.options.shutdown = shutdown;
+        self
+    }
+
+    /// A handle to follow and steer the swarm while it runs.
+    pub fn handle(&self) -> SwarmHandle {
+        SwarmHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Changes how much we want each file, `priorities` are those of the files of the torrent.
+    /// Only the pieces overlapping files that aren't skipped are downloaded,
+    /// those of higher priority files first.
+    pub fn set_file_priorities(&self, priorities: &[FilePriority]) {
+        self.state.set_file_priorities(priorities);
+    }
+
+    /// Follows the progress of the download, updated as pieces complete
+    /// and every second with the rates.
+    pub fn progress(&self) -> watch::Receiver<Progress> {
+        self.state.progress.subscribe()
+    }
+
+    pub fn dht(&self) -> Option<&Arc<Dht>> {
+        self.options.dht.as_ref()
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.state.storage
+    }
+
+    /// Number of peers we are connected to.
+    pub fn num_peers(&self) -> usize {
+        self.state.num_peers()
+    }
+
+    /// Has the swarm connect to `addr` once it runs, unless we are already connected to it,
+    /// have as many connections as we may, it is filtered out, or it recently failed
+    /// or misbehaved.
+    pub fn add_peer(&self, addr: SocketAddr) {
+        self.state.discovered.send(addr).ok();
+    }
+
+    /// The state the connections share, built from the options when the swarm first starts.
+    fn start(&mut self) -> Arc<Shared> {
+        if let Some(shared) = &self.shared {
+            return shared.clone();
+        }
+        let shared = Arc::new(Shared {
+            info: self.state.torrent.info_bytes().into_owned(),
+            state: self.state.clone(),
+            options: self.options.clone(),
+            choker: Choker::default(),
+            haves: broadcast::channel(64).0,
+            completed: self.completed.0.clone(),
+            listening: Mutex::default(),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+        });
+        self.shared = Some(shared.clone());
+        shared
+    }
+
+    /// Runs until every wanted piece is downloaded.
+    pub async fn download(&mut self) -> anyhow::Result<()> {
+        self.run(true).await
+    }
+
+    /// Serves the pieces we have until the process is stopped.
+    pub async fn seed(&mut self) -> anyhow::Result<()> {
+        self.run(false).await
+    }
+
+    async fn run(&mut self, until_complete: bool) -> anyhow::Result<()> {
+        let shared = self.start();
+        let mut trackers = TrackerTiers::new(shared.state.torrent.trackers())?;
+        let mut event = Event::Started;
+        let mut pending = None;
+        let result = self
+            .transfer(
+                &shared,
+                until_complete,
+                &mut trackers,
+                &mut event,
+                &mut pending,
+            )
+            .await;
+
+        // a finished download waits for the announce in flight, which decides whether the
+        // trackers heard that we came, shutdown doesn't wait for trackers that may never answer.
+        let deadline = Instant::now() + STOPPED_ANNOUNCE_TIMEOUT;
+        if let Some(mut pending) = pending {
+            let answer = match shared.options.shutdown.is_cancelled() {
+                true => None,
+                false => tokio::time::timeout_at(deadline, &mut pending.answered)
+                    .await
+                    .ok()
+                    .and_then(Result::ok),
+            };
+            match answer {
+                Some(answer) => {
+                    if let Err(err) = answered(&mut trackers, &mut event, pending.event, answer) {
+                        warn!("announce failed: {err:#}");
+                    }
+                }
+                None if pending.event == Event::Started => event = Event::Started,
+                None => {}
+            }
+        }
+
+        // the trackers only hear that we leave when they heard that we came.
+        if event != Event::Started && !trackers.is_empty() {
+            let stopping = async {
+                if event == Event::Completed {
+                    shared.announce(&mut trackers, Event::Completed).await?;
+                }
+                shared.announce(&mut trackers, Event::Stopped).await
+            };
+            match tokio::time::timeout_at(deadline, stopping).await {
+                Ok(Err(err)) => warn!("announce failed: {err:#}"),
+                Err(_) => warn!("announce failed: timed out"),
+                Ok(Ok(_)) => {}
+            }
+        }
+        result
+    }
+
+    /// Connects to peers and trades pieces with them until shutdown, or until every wanted piece
+    /// is downloaded when `until_complete`. `event` is what the trackers have yet to hear from us,
+    /// `pending` the announce still in flight when we return.
+    async fn transfer(
+        &mut self,
+        shared: &Arc<Shared>,
+        until_complete: bool,
+        trackers: &mut TrackerTiers,
+        event: &mut Event,
+        pending: &mut Option<PendingAnnounce>,
+    ) -> anyhow::Result<()> {
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+        let mut next_announce = Instant::now();
+        let mut next_dht_announce = Instant::now();
+        let mut next_lsd_announce = Instant::now();
+        let mut last_active = Instant::now();
+        let mut progress_tick = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut last_progress = Instant::now();
+        let (mut download_rate, mut upload_rate) = (RateMeter::default(), RateMeter::default());
+        // trackerless torrents only find peers through the dht and peer exchange.
+        let has_tracker = !trackers.is_empty();
+        let mut was_complete = shared.state.picker.lock().unwrap().is_complete();
+
+        if !was_complete {
+            for url in &shared.state.torrent.url_list {
+                match WebSeed::new(url.as_str()) {
+                    Ok(mut seed) => {
+                        if let Some(proxy) = &shared.options.transports.proxy {
+                            seed = seed.with_proxy(proxy.clone());
+                        }
+                        tokio::spawn(shared.clone().drive_web_seed(seed));
+                    }
+                    Err(err) => warn!("not using web seed {url}: {err:#}"),
+                }
+            }
+        }
+
+        shared.publish_progress(None);
+        loop {
+            let complete = shared.state.picker.lock().unwrap().is_complete();
+            // only downloads that finish while the trackers know us, or are about to, are completed ones.
+            if complete && !was_complete && (*event == Event::None || pending.is_some()) {
+                *event = Event::Completed;
+            }
+            was_complete = complete;
+            if until_complete && complete {
+                shared.publish_progress(None);
+                return Ok(());
+            }
+
+            tokio::select! {
+                _ = shared.options.shutdown.cancelled() => return Ok(()),
+                Some(peer) = recv_inbound(&mut self.inbound) => shared.attach(peer),
+                Some(_) = self.completed.1.recv() => {
+                    last_active = Instant::now();
+                    shared.publish_progress(None);
+                }
+                Some(addr) = self.discovered.recv() => shared.add_peer(addr),
+                _ = progress_tick.tick() => {
+                    let elapsed = last_progress.elapsed();
+                    last_progress = Instant::now();
+                    let rates = (
+                        download_rate.sample(shared.downloaded.load(Ordering::Relaxed), elapsed),
+                        upload_rate.sample(shared.uploaded.load(Ordering::Relaxed), elapsed),
+                    );
+                    shared.publish_progress(Some(rates));
+                }
+                _ = rechoke.tick() => {
+                    shared.choker.rechoke(complete);
+
+                    if shared.state.num_peers() > 0 {
+                        last_active = Instant::now();
+                    } else if until_complete && last_active.elapsed() > STALL_TIMEOUT {
+                        bail!("no peer to download from");
+                    }
+                }
+                _ = tokio::time::sleep_until(next_dht_announce), if shared.options.dht.is_some() => {
+                    next_dht_announce = Instant::now() + DHT_ANNOUNCE_INTERVAL;
+                    shared.announce_dht();
+                }
+                _ = tokio::time::sleep_until(next_lsd_announce), if shared.options.lsd.is_some() => {
+                    next_lsd_announce = Instant::now() + LSD_ANNOUNCE_INTERVAL;
+                    if let Some(lsd) = &shared.options.lsd {
+                        let info_hash = shared.state.torrent.info_hash_bytes();
+                        if let Err(err) = lsd.announce(&[info_hash], shared.options.port).await {
+                            warn!("{err:#}");
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(next_announce), if has_tracker && pending.is_none() => {
+                    *pending = Some(shared.announce_in_background(trackers, *event));
+                }
+                Ok(answer) = recv_answer(pending) => {
+                    let sent = pending.take().expect("an announce is in flight").event;
+                    match answered(trackers, event, sent, answer) {
+                        Ok(response) => {
+                            next_announce = Instant::now() + response.interval();
+                            for peer in response.all_peers() {
+                                shared.add_peer(peer.addr().into());
+                            }
+                        }
+                        Err(err) => {
+                            warn!("announce failed: {err:#}");
+                            next_announce = Instant::now() + RETRY_ANNOUNCE;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The trackers, in the order they answered, and their response to an announce.
+type Announced = (TrackerTiers, anyhow::Result<TrackerResponse>);
+
+/// An announce running in the background, aborted once dropped.
+struct PendingAnnounce {
+    /// What the announce tells the trackers.
+    event: Event,
+    answered: oneshot::Receiver<Announced>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for PendingAnnounce {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn recv_answer(pending: &mut Option<PendingAnnounce>) -> Result<Announced, RecvError> {
+    match pending {
+        Some(pending) => (&mut pending.answered).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Takes in the `answer` to an announce of `sent`: the trackers in their new order and,
+/// once they heard `sent`, the `event` they have yet to hear. A download may have
+/// completed while the announce was in flight, a started one that failed is sent again.
+fn answered(
+    trackers: &mut TrackerTiers,
+    event: &mut Event,
+    sent: Event,
+    (answered, response): Announced,
+) -> anyhow::Result<TrackerResponse> {
+    *trackers = answered;
+    match &response {
+        Ok(_) if *event == sent => *event = Event::None,
+        Err(_) if sent == Event::Started => *event = Event::Started,
+        _ => {}
+    }
+    response
+}
+
+async fn recv_inbound(
+    inbound: &mut Option<mpsc::UnboundedReceiver<PeerConnection>>,
+) -> Option<PeerConnection> {
+    match inbound {
+        Some(inbound) => inbound.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A handle to a running swarm, to follow its progress and steer it.
+#[derive(Clone)]
+pub struct SwarmHandle {
+    state: Arc<State>,
+}
+
+impl SwarmHandle {
+    /// The pieces we have.
+    pub fn have(&self) -> Bitfield {
+        self.state.have()
+    }
+
+    /// Number of peers we are connected to.
+    pub fn num_peers(&self) -> usize {
+        self.state.num_peers()
+    }
+
+    /// Has the swarm connect to `addr`, see [`Swarm::add_peer`].
+    pub fn add_peer(&self, addr: SocketAddr) {
+        self.state.discovered.send(addr).ok();
+    }
+
+    /// Changes how much we want each file, see [`Swarm::set_file_priorities`].
+    pub fn set_file_priorities(&self, priorities: &[FilePriority]) {
+        self.state.set_file_priorities(priorities);
+    }
+
+    /// Follows the progress of the download, see [`Swarm::progress`].
+    pub fn progress(&self) -> watch::Receiver<Progress> {
+        self.state.progress.subscribe()
+    }
+}
+
+impl Shared {
+    /// Connects to `addr` in the background, unless we are already connected to it,
+    /// have as many connections as we may, it is filtered out, or it recently failed
+    /// or misbehaved.
+    fn add_peer(self: &Arc<Self>, addr: SocketAddr) {
+        if self.options.ip_filter.is_blocked(addr.ip()) || !self.options.peer_list.may_connect(addr)
+        {
+            return;
+        }
+        let Some(permit) = self.reserve(addr) else {
+            return;
+        };
+
+        let shared = self.clone();
+        tokio::spawn(async move {
+            let connecting = async {
+                let _opening = shared.options.half_open.acquire().await?;
+                PeerConnection::connect(
+                    &shared.state.torrent,
+                    addr,
+                    &shared.options.transports,
+                    shared.options.encryption,
+                )
+                .await
+            };
+            let connected = tokio::select! {
+                connected = connecting => connected,
+                _ = shared.options.shutdown.cancelled() => return,
+            };
+            match connected {
+                Ok(peer) => {
+                    shared.options.peer_list.connected(addr);
+                    shared.clone().drive(peer).await;
+                }
+                Err(err) => {
+                    debug!("failed to connect to {addr}: {err:#}");
+                    shared.options.peer_list.connect_failed(addr);
+                    shared.state.connected.lock().unwrap().remove(&addr);
+                }
+            }
+            drop(permit);
+        });
+    }
+
+    /// Starts exchanging pieces with a peer that connected to us, unless we have
+    /// as many connections as we may or it is banned.
+    fn attach(self: &Arc<Self>, peer: PeerConnection) {
+        if self.options.peer_list.is_banned(peer.addr().ip()) {
+            return;
+        }
+        let Some(permit) = self.reserve(peer.addr()) else {
+            return;
+        };
+        debug!("peer {} connected to us", peer.addr());
+        let shared = self.clone();
+        tokio::spawn(async move {
+            shared.drive(peer).await;
+            drop(permit);
+        });
+    }
+
+    /// Announces the torrent on the DHT in the background, connecting to the peers it finds.
+    fn announce_dht(self: &Arc<Self>) {
+        let Some(dht) = self.options.dht.clone() else {
+            return;
+        };
+        let shared = self.clone();
+        tokio::spawn(async move {
+            let peers = dht
+                .announce(shared.state.torrent.info_hash_bytes(), shared.options.port)
+                .await;
+            for peer in peers {
+                shared.state.discovered.send(peer).ok();
+            }
+        });
+    }
+
+    /// What we tell the trackers with `event` about our transfer so far.
+    fn announcement(&self, event: Event) -> Announce {
+        let left = self
+            .state
+            .picker
+            .lock()
+            .unwrap()
+            .left(|index| self.state.torrent.piece_size(index));
+        Announce {
+            info_hash: self.state.torrent.info_hash_bytes(),
+            peer_id: self.options.transports.peer_id,
+            port: self.options.port,
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left,
+            event,
+        }
+    }
+
+    /// Announces `event` to the trackers with our transfer so far.
+    async fn announce(
+        &self,
+        trackers: &mut TrackerTiers,
+        event: Event,
+    ) -> anyhow::Result<TrackerResponse> {
+        let proxy = self.options.transports.proxy.as_deref();
+        trackers.announce(&self.announcement(event), proxy).await
+    }
+
+    /// Announces `event` to the trackers in the background, so a tracker that doesn't answer
+    /// doesn't hold up the swarm.
+    fn announce_in_background(&self, trackers: &TrackerTiers, event: Event) -> PendingAnnounce {
+        let mut trackers = trackers.clone();
+        let announce = self.announcement(event);
+        let proxy = self.options.transports.proxy.clone();
+        let (answer, answered) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let response = trackers.announce(&announce, proxy.as_deref()).await;
+            answer.send((trackers, response)).ok();
+        });
+        PendingAnnounce {
+            event,
+            answered,
+            task,
+        }
+    }
+
+    /// Counts `addr` as connected and takes a connection permit for it, unless we are
+    /// already connected to it or have as many connections as we may.
+    fn reserve(&self, addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        let mut connected = self.state.connected.lock().unwrap();
+        if connected.len() >= self.options.max_peers || connected.contains(&addr) {
+            return None;
+        }
+        let permit = self
+            .options
+            .connection_limit
+            .clone()
+            .try_acquire_owned()
+            .ok()?;
+        connected.insert(addr);
+        Some(permit)
+    }
+
+    /// Publishes the pieces we have and the peers, with new download and upload rates
+    /// or the ones published last.
+    fn publish_progress(&self, rates: Option<(u64, u64)>) {
+        let (pieces_done, pieces_wanted, bytes_done, bytes_wanted) = {
+            let picker = self.state.picker.lock().unwrap();
+            let (pieces_done, pieces_wanted) = picker.wanted_count();
+            let (bytes_done, bytes_wanted) =
+                picker.wanted_size(|index| self.state.torrent.piece_size(index));
+            (pieces_done, pieces_wanted, bytes_done, bytes_wanted)
+        };
+        let peers = self.state.num_peers();
+        self.state.progress.send_modify(|progress| {
+            progress.pieces_done = pieces_done;
+            progress.pieces_wanted = pieces_wanted;
+            progress.bytes_done = bytes_done;
+            progress.bytes_wanted = bytes_wanted;
+            progress.downloaded = self.downloaded.load(Ordering::Relaxed);
+            progress.uploaded = self.uploaded.load(Ordering::Relaxed);
+            if let Some((download_rate, upload_rate)) = rates {
+                progress.download_rate = download_rate;
+                progress.upload_rate = upload_rate;
+            }
+            progress.peers = peers;
+            progress.estimate();
+        });
+    }
+
+    /// Exchanges pieces with the peer until the connection fails or the swarm
+    /// is shut down, then forgets about it.
+    async fn drive(self: Arc<Self>, mut peer: PeerConnection) {
+        let addr = peer.addr();
+        let throttle = self
+            .options
+            .throttle
+            .clone()
+            .with_limits(self.options.peer_limits.follower());
+        peer.set_throttle(throttle.clone());
+        let stats = self.choker.register(addr);
+
+        tokio::select! {
+            result = self.exchange(&mut peer, &stats, &throttle) => {
+                if let Err(err) = result {
+                    debug!("disconnected from {addr}: {err:#}");
+                    if err.downcast_ref::<ProtocolViolation>().is_some() {
+                        self.options.peer_list.ban(addr, &format!("{err:#}"));
+                    }
+                }
+            }
+            _ = self.options.shutdown.cancelled() => {}
+        }
+
+        self.choker.unregister(&addr);
+        self.listening.lock().unwrap().remove(&addr);
+        {
+            let mut picker = self.state.picker.lock().unwrap();
+            picker.remove_peer(peer.bitfield());
+            if let Some(index) = peer.current_piece() {
+                picker.release(index as usize);
+            }
+        }
+        self.state.connected.lock().unwrap().remove(&addr);
+    }
+
+    /// `throttle` is the one of the connection, blocks are served once it allows.
+    async fn exchange(
+        &self,
+        peer: &mut PeerConnection,
+        stats: &PeerStats,
+        throttle: &Throttle,
+    ) -> anyhow::Result<()> {
+        let mut unchoke = stats.decisions();
+        let mut haves = self.haves.subscribe();
+        let mut pex = PexState::default();
+
+        // the pieces the peer heard we have, from the bitfield and have messages.
+        let mut announced = self.state.picker.lock().unwrap().have().clone();
+        peer.send_bitfield(&announced).await?;
+        let mut extensions =
+            ExtensionHandshake::new(self.options.port).with_metadata_size(self.info.len());
+        if self.state.torrent.is_private() {
+            extensions = extensions.without_pex();
+        }
+        peer.send_extension_handshake(&extensions).await?;
+        if let Some(dht) = &self.options.dht {
+            peer.send_port(dht.port()).await?;
+        }
+        peer.receive_bitfield().await?;
+        self.state.picker.lock().unwrap().add_peer(peer.bitfield());
+        self.advertise(peer);
+
+        // v2 torrents from magnet links miss the piece layers to verify the pieces with.
+        if peer.supports_v2() {
+            for request in self.state.torrent.piece_layer_requests() {
+                peer.send_hash_request(&request).await?;
+            }
+        }
+
+        // let the peer get started with a few pieces before it is unchoked.
+        if let (true, SocketAddr::V4(addr)) = (peer.supports_fast(), peer.addr()) {
+            let allowed = allowed_fast_set(
+                *addr.ip(),
+                &self.state.torrent.info_hash_bytes(),
+                self.state.torrent.num_pieces(),
+            );
+            peer.send_allowed_fast(&allowed).await?;
+        }
+
+        loop {
+            let (interested, pick) = {
+                let mut picker = self.state.picker.lock().unwrap();
+                let interested =
+                    peer.current_piece().is_some() || picker.is_interesting(peer.bitfield());
+                let available = self.verifiable(peer.bitfield());
+                let pick = if !interested || peer.current_piece().is_some() {
+                    None
+                } else if peer.state().peer_choking {
+                    // only the pieces the peer allowed can be requested while choked.
+                    let allowed = peer.allowed_fast().iter().map(|&index| index as usize);
+                    picker.pick_among(&available, allowed)
+                } else {
+                    let suggested = peer.suggested().map(|index| index as usize);
+                    picker
+                        .pick_among(&available, suggested)
+                        .or_else(|| picker.pick(&available))
+                };
+                (interested, pick)
+            };
+            if let Some(index) = pick {
+                peer.start_piece(index as u32, self.state.torrent.piece_size(index));
+            }
+            peer.set_interested(interested).await?;
+            if stats.set_interest(peer.state().peer_interested, interested) {
+                self.choker.peer_interested(&peer.addr());
+            }
+            peer.request_blocks().await?;
+            if peer.has_hash_requests() {
+                let have = self.state.picker.lock().unwrap().have().clone();
+                peer.serve_hash_requests(&self.state.torrent, &self.state.storage, &have)
+                    .await?;
+            }
+            if peer.has_metadata_requests() {
+                peer.serve_metadata_requests(&self.info).await?;
+            }
+
+            let serving = peer.has_requests();
+            let outstanding = peer.has_outstanding_requests();
+            // peer exchange starts once the peer told us it supports it.
+            let pex_supported =
+                peer.extension_id(UT_PEX).is_some() && !self.state.torrent.is_private();
+            let next_pex = Instant::from_std(pex.next_send());
+            tokio::select! {
+                message = async {
+                    if outstanding {
+                        peer.next_message_within(REQUEST_TIMEOUT).await
+                    } else {
+                        peer.next_message().await
+                    }
+                } => {
+                    self.handle(peer, stats, &mut pex, &message?).await?;
+                }
+                // one block at a time, so choking decisions and haves aren't held up by uploads.
+                _ = throttle.ready_to_send(), if serving => {
+                    // handle the messages that already arrived first,
+                    // a cancel may be waiting for the block we are about to send.
+                    while let Some(message) = peer.try_next_message()? {
+                        self.handle(peer, stats, &mut pex, &message).await?;
+                    }
+                    let have = self.state.picker.lock().unwrap().have().clone();
+                    let sent = peer.serve_request(&self.state.storage, &self.options.disk, &have).await?;
+                    stats.record_upload(sent);
+                    self.uploaded.fetch_add(sent as u64, Ordering::Relaxed);
+                }
+                Ok(()) = unchoke.changed() => {
+                    let unchoke = *unchoke.borrow();
+                    peer.set_choking(!unchoke).await?;
+                }
+                _ = tokio::time::sleep_until(next_pex), if pex_supported => {
+                    self.send_pex(peer, &mut pex).await?;
+                }
+                index = haves.recv() => {
+                    let pieces = match index {
+                        Ok(index) => vec![index],
+                        // we fell behind, the peer hears about every piece it missed.
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            let have = self.state.picker.lock().unwrap().have().clone();
+                            have.iter().filter(|&index| !announced.get(index)).collect()
+                        }
+                        Err(broadcast::error::RecvError::Closed) => Vec::new(),
+                    };
+                    for index in pieces {
+                        if announced.get(index) {
+                            continue;
+                        }
+                        announced.set(index);
+                        // someone else finished the piece first during the end game.
+                        if peer.current_piece() == Some(index as u32) {
+                            peer.cancel_piece().await?;
+                        }
+                        peer.send_have(index).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The pieces of `bitfield` we can verify once downloaded, the others wait for their piece layer.
+    fn verifiable<'a>(&self, bitfield: &'a Bitfield) -> Cow<'a, Bitfield> {
+        if self.state.torrent.has_piece_layers() {
+            return Cow::Borrowed(bitfield);
+        }
+        let mut verifiable = bitfield.clone();
+        for index in bitfield.iter() {
+            if !self.state.torrent.can_verify(index) {
+                verifiable.clear(index);
+            }
+        }
+        Cow::Owned(verifiable)
+    }
+
+    /// Tells the peer about the peers we connected to and disconnected from since last time.
+    async fn send_pex(&self, peer: &mut PeerConnection, pex: &mut PexState) -> anyhow::Result<()> {
+        let Some(id) = peer.extension_id(UT_PEX) else {
+            return Ok(());
+        };
+        let message = {
+            let listening = self.listening.lock().unwrap();
+            pex.update(&listening, peer.addr())
+        };
+        if let Some(message) = message {
+            peer.send_extended(id, &message.to_bytes()?).await?;
+        }
+        Ok(())
+    }
+
+    /// Records where the peer can be reached, for the other peers to learn through peer exchange.
+    fn advertise(&self, peer: &PeerConnection) {
+        let Some(listen_addr) = peer.listen_addr() else {
+            return;
+        };
+        let mut flags = 0;
+        if peer.is_outbound() {
+            flags |= FLAG_REACHABLE;
+        }
+        if peer.bitfield().is_complete() {
+            flags |= FLAG_SEED;
+        }
+        self.listening
+            .lock()
+            .unwrap()
+            .insert(peer.addr(), (listen_addr, flags));
+    }
+
+    /// Keeps the picker and the choker up to date with a message from the peer,
+    /// and stores the piece it completes.
+    async fn handle(
+        &self,
+        peer: &mut PeerConnection,
+        stats: &PeerStats,
+        pex: &mut PexState,
+        message: &Message,
+    ) -> anyhow::Result<()> {
+        match message.tag {
+            MessageTag::Bitfield | MessageTag::HaveAll => {
+                self.state.picker.lock().unwrap().add_peer(peer.bitfield());
+                self.advertise(peer);
+            }
+            MessageTag::Have => {
+                let index = u32::from_be_bytes(message.payload[..4].try_into()?);
+                if peer.announced_new_piece() {
+                    self.state.picker.lock().unwrap().add_have(index as usize);
+                }
+                if peer.bitfield().is_complete() {
+                    self.advertise(peer);
+                }
+            }
+            MessageTag::Port => {
+                if let (Some(dht), Some(port)) = (&self.options.dht, peer.dht_port()) {
+                    dht.add_node(SocketAddr::new(peer.addr().ip(), port));
+                }
+            }
+            MessageTag::Extended => match message.payload.first() {
+                // the extension handshake may tell us the port the peer listens on.
+                Some(&HANDSHAKE_ID) => self.advertise(peer),
+                // we didn't offer peer exchange for private torrents.
+                Some(&UT_PEX_ID) if !self.state.torrent.is_private() && pex.accept() => {
+                    let message = PexMessage::from_bytes(&message.payload[1..])?;
+                    for (addr, _) in message.added() {
+                        self.state.discovered.send(addr).ok();
+                    }
+                }
+                _ => {}
+            },
+            MessageTag::Piece => {
+                let block = message.payload.len().saturating_sub(8);
+                stats.record_download(block);
+                self.downloaded.fetch_add(block as u64, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        while let Some((request, hashes)) = peer.take_hashes() {
+            self.state
+                .torrent
+                .add_piece_hashes(&request, &hashes)
+                .context(ProtocolViolation)?;
+        }
+
+        let Some((index, data)) = peer.take_completed_piece() else {
+            return Ok(());
+        };
+        // we download each piece from a single peer, so we know who sent the bad data.
+        if let Err(err) = self.verify_piece(index as usize, &data) {
+            self.options.peer_list.ban(peer.addr(), &format!("{err:#}"));
+            return Err(err);
+        }
+        self.write_piece(index as usize, data).await
+    }
+
+    /// Verifies a downloaded piece and writes it to storage,
+    /// telling the connections and the swarm it is done.
+    async fn store_piece(&self, index: usize, data: Vec<u8>) -> anyhow::Result<()> {
+        self.verify_piece(index, &data)?;
+        self.write_piece(index, data).await
+    }
+
+    /// Checks a downloaded piece against its hash, a bad piece is picked again.
+    fn verify_piece(&self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        if !self.state.torrent.verify_piece(index, data) {
+            self.state.picker.lock().unwrap().release(index);
+            bail!("piece {index} failed the hash check");
+        }
+        Ok(())
+    }
+
+    /// Writes a verified piece to storage, telling the connections and the swarm it is done.
+    async fn write_piece(&self, index: usize, data: Vec<u8>) -> anyhow::Result<()> {
+        self.options
+            .disk
+            .write_piece(&self.state.storage, index, data)
+            .await?;
+        if self.state.picker.lock().unwrap().complete(index) {
+            self.haves.send(index).ok();
+            self.completed.send(index).ok();
+        }
+        Ok(())
+    }
+
+    /// Downloads the pieces the picker gives it from a web seed, which has every piece,
+    /// until we have every wanted piece, the seed keeps failing or it ignores range requests.
+    async fn drive_web_seed(self: Arc<Self>, seed: WebSeed) {
+        let everything = Bitfield::full(self.state.torrent.num_pieces());
+        self.state.picker.lock().unwrap().add_peer(&everything);
+
+        tokio::select! {
+            _ = self.fetch_from_web_seed(&seed, &everything) => {}
+            _ = self.options.shutdown.cancelled() => {}
+        }
+        self.state.picker.lock().unwrap().remove_peer(&everything);
+    }
+
+    async fn fetch_from_web_seed(&self, seed: &WebSeed, everything: &Bitfield) {
+        let mut failures = 0;
+        let mut completed = self.haves.subscribe();
+        loop {
+            let available = self.verifiable(everything);
+            let pick = {
+                let mut picker = self.state.picker.lock().unwrap();
+                if picker.is_complete() {
+                    break;
+                }
+                picker.pick(&available)
+            };
+            // peers may be downloading the rest, which they may drop, pieces may be waiting
+            // for their piece layer, or be wanted later.
+            let Some(index) = pick else {
+                tokio::select! {
+                    _ = completed.recv() => {}
+                    _ = tokio::time::sleep(WEB_SEED_IDLE) => {}
+                }
+                continue;
+            };
+            self.options.throttle.ready_to_receive().await;
+            let result = match seed.fetch_piece(&self.state.torrent, index).await {
+                Ok(data) => {
+                    self.options.throttle.received(data.len());
+                    self.downloaded
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    self.store_piece(index, data).await
+                }
+                Err(err) => {
+                    self.state.picker.lock().unwrap().release(index);
+                    Err(err)
+                }
+            };
+            if let Err(err) = result {
+                warn!("web seed {} failed: {err:#}", seed.url());
+                if seed.is_range_less() {
+                    break;
+                }
+                failures += 1;
+                if failures >= MAX_WEB_SEED_FAILURES {
+                    break;
+                }
+                tokio::time::sleep(WEB_SEED_RETRY).await;
+            } else {
+                failures = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::{
+        handshake::Handshake, merkle, mse::CryptoStream, peer_message::MessageFramer, ratelimit,
+        torrent::testing, tracker, transport::PeerStream, webseed, BLOCK_MAX,
+    };
+
+    /// A swarm of a one piece torrent announced to `url`, which has the piece when `seeding`.
+    fn swarm(url: String, seeding: bool) -> Swarm {
+        let mut torrent = testing::torrent("a", &[("a", &[1; 100])], 16_384);
+        torrent.announce = url;
+        let storage = Storage::temporary(&torrent).unwrap();
+        let have = match seeding {
+            true => Bitfield::full(1),
+            false => Bitfield::new(1),
+        };
+        Swarm::new(Arc::new(torrent), storage, have, Bitfield::full(1)).unwrap()
+    }
+
+    /// A connection to a peer without any extension, along with the peer's end of it.
+    async fn connection(num_pieces: usize) -> (PeerConnection, Framed<TcpStream, MessageFramer>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (ours, theirs) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut handshake = Handshake::new([0; 20], [1; 20]);
+        handshake.reserved = [0; 8];
+        let stream = CryptoStream::plaintext(PeerStream::from(ours.unwrap()));
+        let peer = PeerConnection::from_stream(stream, addr, &handshake, num_pieces);
+        (peer, Framed::new(theirs.unwrap().0, MessageFramer))
+    }
+
+    /// The next message the connection sent the peer.
+    async fn receive(remote: &mut Framed<TcpStream, MessageFramer>) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), remote.next())
+            .await
+            .expect("a message arrives")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn peers_hear_of_every_piece_even_when_falling_behind() {
+        let torrent = testing::torrent("a", &[("a", &[1; 100 * 16])], 16);
+        let storage = Storage::temporary(&torrent).unwrap();
+        let mut swarm = Swarm::new(
+            Arc::new(torrent),
+            storage,
+            Bitfield::new(100),
+            Bitfield::full(100),
+        )
+        .unwrap();
+        let shared = swarm.start();
+        let (peer, mut remote) = connection(100).await;
+        tokio::spawn(shared.clone().drive(peer));
+        // the connection waits for the bitfield of the peer.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // more pieces complete than the connection is told about at once.
+        for index in 0..100 {
+            shared.state.picker.lock().unwrap().complete(index);
+            shared.haves.send(index).unwrap();
+        }
+        let bitfield = Message {
+            tag: MessageTag::Bitfield,
+            payload: vec![0; 13],
+        };
+        remote.send(bitfield).await.unwrap();
+
+        let mut heard = Bitfield::new(100);
+        while heard.count() < 100 {
+            let message = receive(&mut remote).await;
+            if message.tag == MessageTag::Have {
+                let index = u32::from_be_bytes(message.payload[..4].try_into().unwrap());
+                assert!(!heard.get(index as usize), "piece {index} announced twice");
+                heard.set(index as usize);
+            }
+        }
+        shared.options.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn chokes_go_out_while_serving_throttled_requests() {
+        let torrent = testing::torrent("a", &[("a", &[1; 8 * BLOCK_MAX])], BLOCK_MAX);
+        let storage = Storage::temporary(&torrent).unwrap();
+        // a block a second.
+        let upload = RateLimits::new(ratelimit::Rates {
+            download: 0,
+            upload: BLOCK_MAX,
+        });
+        let mut swarm = Swarm::new(
+            Arc::new(torrent),
+            storage,
+            Bitfield::full(8),
+            Bitfield::full(8),
+        )
+        .unwrap()
+        .with_rate_limits(
+            Throttle::default().with_limits(upload),
+            RateLimits::default(),
+        );
+        let shared = swarm.start();
+        let (peer, mut remote) = connection(8).await;
+        tokio::spawn(shared.clone().drive(peer));
+
+        assert_eq!(receive(&mut remote).await.tag, MessageTag::Bitfield);
+        let interested = Message {
+            tag: MessageTag::Interested,
+            payload: Vec::new(),
+        };
+        remote.send(interested).await.unwrap();
+        // a free slot unchokes the peer right away.
+        assert_eq!(receive(&mut remote).await.tag, MessageTag::Unchoke);
+        for index in 0..8 {
+            let request = Message::new_request(index, 0, BLOCK_MAX as u32);
+            remote.send(request).await.unwrap();
+        }
+        assert_eq!(receive(&mut remote).await.tag, MessageTag::Piece);
+
+        // the peer loses its slot while most of its requests wait for the throttle.
+        let not_interested = Message {
+            tag: MessageTag::NotInterested,
+            payload: Vec::new(),
+        };
+        remote.send(not_interested).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shared.choker.rechoke(true);
+
+        let mut pieces = 0;
+        loop {
+            match receive(&mut remote).await.tag {
+                MessageTag::Piece => pieces += 1,
+                MessageTag::Choke => break,
+                tag => panic!("unexpected {tag:?}"),
+            }
+        }
+        assert!(pieces <= 1, "{pieces} blocks were served before the choke");
+        shared.options.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn web_seeds_wait_for_pieces_they_cant_verify_yet() {
+        let data: Vec<u8> = (0..4 * BLOCK_MAX + 100).map(|i| i as u8).collect();
+        let full = testing::torrent_v2("big", &[("big", &data)], BLOCK_MAX);
+        let url = webseed::testing::serve(HashMap::from([("/big", data.clone())]), true).await;
+        // a magnet link only brings the info dictionary, without the piece layer.
+        let torrent = Torrent::from_info_bytes(&full.info_bytes()).unwrap();
+        let storage = Storage::temporary(&torrent).unwrap();
+        let mut swarm = Swarm::new(
+            Arc::new(torrent),
+            storage,
+            Bitfield::new(5),
+            Bitfield::full(5),
+        )
+        .unwrap();
+        let shared = swarm.start();
+        let mut completed = shared.haves.subscribe();
+        tokio::spawn(shared.clone().drive_web_seed(WebSeed::new(url).unwrap()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // a peer sends the piece layer.
+        let request = shared.state.torrent.piece_layer_requests()[0];
+        let have = Bitfield::new(5);
+        let hashes = merkle::answer(&full, &shared.state.storage, &have, &request)
+            .unwrap()
+            .unwrap();
+        shared
+            .state
+            .torrent
+            .add_piece_hashes(&request, &hashes)
+            .unwrap();
+        for _ in 0..5 {
+            receive_have(&mut completed).await;
+        }
+        assert!(shared.state.picker.lock().unwrap().is_complete());
+    }
+
+    async fn receive_have(completed: &mut broadcast::Receiver<usize>) -> usize {
+        tokio::time::timeout(Duration::from_secs(5), completed.recv())
+            .await
+            .expect("a piece completes")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn peers_requesting_pieces_we_dont_have_are_banned() {
+        let (url, _requests) = tracker::testing::serve(&[]).await;
+        let mut swarm = swarm(url, false);
+        let shared = swarm.start();
+        let (peer, mut remote) = connection(1).await;
+        let ip = peer.addr().ip();
+        let driving = tokio::spawn(shared.clone().drive(peer));
+
+        remote
+            .send(Message::new_piece_index(MessageTag::Have, 0))
+            .await
+            .unwrap();
+        let interested = Message {
+            tag: MessageTag::Interested,
+            payload: Vec::new(),
+        };
+        remote.send(interested).await.unwrap();
+        // a free slot unchokes the peer right away.
+        while receive(&mut remote).await.tag != MessageTag::Unchoke {}
+        remote.send(Message::new_request(0, 0, 100)).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), driving)
+            .await
+            .unwrap()
+            .unwrap();
+        let banned = shared.options.peer_list.banned();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].0, ip);
+    }
+
+    #[tokio::test]
+    async fn trackers_hear_downloads_start_complete_and_stop() {
+        let (url, mut requests) = tracker::testing::serve(&[]).await;
+        let mut swarm = swarm(url, false);
+        let shared = swarm.start();
+        let download = tokio::spawn(async move { swarm.download().await });
+
+        let started = requests.recv().await.unwrap();
+        assert!(started.contains("&downloaded=0&left=100&"), "{started}");
+        assert!(started.ends_with("&event=started"));
+
+        // the piece arrives from a peer.
+        shared.downloaded.fetch_add(100, Ordering::Relaxed);
+        shared.state.picker.lock().unwrap().complete(0);
+        shared.completed.send(0).unwrap();
+        download.await.unwrap().unwrap();
+
+        let completed = requests.recv().await.unwrap();
+        assert!(completed.contains("&downloaded=100&left=0&"), "{completed}");
+        assert!(completed.ends_with("&event=completed"));
+        assert!(requests.recv().await.unwrap().ends_with("&event=stopped"));
+    }
+
+    #[tokio::test]
+    async fn progress_is_published_as_pieces_complete() {
+        let (url, _requests) = tracker::testing::serve(&[]).await;
+        let mut swarm = swarm(url, false);
+        let shared = swarm.start();
+        let mut progress = swarm.progress();
+        let download = tokio::spawn(async move { swarm.download().await });
+
+        progress.changed().await.unwrap();
+        let started = progress.borrow_and_update().clone();
+        assert_eq!((started.pieces_done, started.pieces_wanted), (0, 1));
+        assert_eq!((started.bytes_done, started.bytes_wanted), (0, 100));
+        assert_eq!((started.peers, started.eta_secs), (0, None));
+
+        shared.downloaded.fetch_add(100, Ordering::Relaxed);
+        shared.state.picker.lock().unwrap().complete(0);
+        shared.completed.send(0).unwrap();
+        download.await.unwrap().unwrap();
+
+        let done = progress.borrow().clone();
+        assert!(done.is_complete());
+        assert_eq!((done.bytes_done, done.downloaded), (100, 100));
+        assert_eq!(done.eta(), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn seeds_announce_stopped_on_shutdown_only() {
+        // the swarm connects to the peer once the tracker's answer reached it.
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut compact = vec![127, 0, 0, 1];
+        compact.extend(peer.local_addr().unwrap().port().to_be_bytes());
+        let (url, mut requests) = tracker::testing::serve(&compact).await;
+        let shutdown = CancellationToken::new();
+        let mut swarm = swarm(url, true).with_shutdown(shutdown.clone());
+        let seed = tokio::spawn(async move { swarm.seed().await });
+
+        let started = requests.recv().await.unwrap();
+        assert!(started.contains("&left=0&"), "{started}");
+        assert!(started.ends_with("&event=started"));
+        let _connection = peer.accept().await.unwrap();
+
+        shutdown.cancel();
+        seed.await.unwrap().unwrap();
+        // seeds never completed a download.
+        assert!(requests.recv().await.unwrap().ends_with("&event=stopped"));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn swarms_are_configured_after_taking_a_handle() {
+        let (url, mut requests) = tracker::testing::serve(&[]).await;
+        let shutdown = CancellationToken::new();
+        let swarm = swarm(url, true);
+        let handle = swarm.handle();
+        let mut swarm = swarm
+            .with_port(7000)
+            .with_max_peers(3)
+            .with_shutdown(shutdown.clone());
+        let seed = tokio::spawn(async move { swarm.seed().await });
+
+        let started = requests.recv().await.unwrap();
+        assert!(started.contains("&port=7000&"), "{started}");
+        assert_eq!(handle.have(), Bitfield::full(1));
+        shutdown.cancel();
+        seed.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn trackers_that_never_answer_dont_hold_up_shutdown() {
+        let (url, mut connections) = tracker::testing::silent().await;
+        let shutdown = CancellationToken::new();
+        let mut swarm = swarm(url, true).with_shutdown(shutdown.clone());
+        let mut progress = swarm.progress();
+        let seed = tokio::spawn(async move { swarm.seed().await });
+
+        connections.recv().await.unwrap();
+        // the swarm keeps publishing its progress while the announce is in flight.
+        progress.changed().await.unwrap();
+        progress.changed().await.unwrap();
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), seed)
+            .await
+            .expect("the swarm stops while the announce is in flight")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,27 +1,168 @@
-use std::{fs, net::SocketAddrV4, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    net::{SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
 
-use anyhow::Context;
-use futures_util::{SinkExt, StreamExt};
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWrite;
 
+use crate::debug;
 use crate::{
-    peer_message::{Message, MessageFramer, MessageTag},
-    tracker::{Peer, TrackerResponse},
+    bitfield::Bitfield,
+    handshake::Handshake,
+    merkle::{self, Hash, HashRequest},
+    metadata,
+    proxy::Proxy,
+    random,
+    server::DEFAULT_PORT,
+    sha256::Sha256,
+    storage::Storage,
+    swarm::Swarm,
+    tracker::{Announce, Event, Peer, TrackerResponse, TrackerTiers},
     BLOCK_MAX,
 };
 
 /// Metainfo files (also known as .torrent files).
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Torrent {
-    /// The URL of the tracker.
+    /// The URL of the tracker, empty for trackerless torrents.
+    #[serde(default)]
     pub announce: String,
+    /// Tiers of tracker URLs (BEP 12), tried tier by tier. Replaces announce when it holds
+    /// a tracker.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+    /// Free-form text from the author.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "lossy_string"
+    )]
+    pub comment: Option<String>,
+    /// The program that made the file.
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "lossy_string"
+    )]
+    pub created_by: Option<String>,
+    /// When the file was made, in seconds since the Unix epoch.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
     /// Info This maps to a dictionary.
     pub info: Info,
+    /// DHT nodes to join through, host and port, for trackerless torrents (BEP 5).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<(String, u16)>,
+    /// Web seeds (BEP 19), HTTP or FTP urls serving the same data as the peers.
+    /// Either a single url or a list of them.
+    #[serde(
+        rename = "url-list",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many"
+    )]
+    pub url_list: Vec<String>,
+    /// The piece layers of the merkle trees of v2 torrents (BEP 52), keyed by pieces root.
+    /// Only files larger than a piece have one, the concatenated hashes of their pieces.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+
+    /// The bencoded info dictionary as it was in the metainfo, unknown keys included.
+    /// The info hash is the hash of these bytes.
+    #[serde(skip)]
+    info_bytes: Vec<u8>,
+
+    /// The files laid out in the pieces, computed once when the metainfo is decoded.
+    #[serde(skip)]
+    layout: OnceLock<Vec<FileEntry>>,
+
+    /// The piece layers missing from the metainfo, as peers send them with hash requests,
+    /// keyed by pieces root. Torrents from magnet links have none of them to start with.
+    #[serde(skip)]
+    fetched_layers: Arc<Mutex<BTreeMap<Hash, Vec<Option<Hash>>>>>,
+
+    /// Set once every piece layer is known, they are never forgotten.
+    #[serde(skip)]
+    has_piece_layers: Arc<AtomicBool>,
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) if url.is_empty() => Vec::new(),
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
+}
+
+/// Reads text that isn't always UTF-8 in the wild, replacing the invalid bytes
+/// rather than rejecting the whole file.
+fn lossy_string<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let bytes = Option::<ByteBuf>::deserialize(deserializer)?;
+    Ok(bytes.map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+/// The value of the info key of the bencoded metainfo `bytes`.
+fn info_slice(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+    let mut at = 1;
+    while *bytes.get(at)? != b'e' {
+        let key_length = metadata::bencode_length(&bytes[at..])?;
+        let key = &bytes[at..at + key_length];
+        at += key_length;
+        let value_length = metadata::bencode_length(&bytes[at..])?;
+        if key == b"4:info" {
+            return Some(&bytes[at..at + value_length]);
+        }
+        at += value_length;
+    }
+    None
+}
+
+/// The start of our peer ids, the client and its version in the Azureus style.
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-CB0001-";
+
+/// A new peer id, our prefix followed by random bytes. Each session picks its own,
+/// which tells connections to ourselves apart from connections to other peers.
+pub fn generate_peer_id() -> io::Result<[u8; 20]> {
+    let mut peer_id = random::bytes::<20>()?;
+    peer_id[..PEER_ID_PREFIX.len()].copy_from_slice(PEER_ID_PREFIX);
+    Ok(peer_id)
 }
 
-const PEER_ID: &[u8; 20] = b"00112233445566778899";
 #[allow(dead_code)]
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Info {
@@ -29,8 +170,14 @@ pub struct Info {
     /// It is purely advisory.
     pub name: String,
 
-    /// length - The length of the file, in bytes.
-    pub length: usize,
+    /// length - The length of the file, in bytes. Only single file torrents have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+
+    /// files - The files of a multi file torrent, in the order they are laid out in the pieces.
+    /// name is then the name of the directory they are in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileInfo>>,
 
     /// piece length is the number of bytes in each piece the file is split into.
     /// For the purposes of transfer, files are split into fixed-size pieces which are all the same length
@@ -42,34 +189,233 @@ pub struct Info {
     /// pieces is a string whose length is a multiple of 20.
     /// It is to be subdivided into strings of length 20,
     /// each of which is the SHA1 hash of the piece at the corresponding index.
-    #[serde(with = "serde_bytes")]
+    /// v2 only torrents don't have it.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
     pub pieces: Vec<u8>,
+
+    /// private - 1 if peers must only come from the trackers of the torrent (BEP 27),
+    /// the DHT, peer exchange and local service discovery are not used then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
+    /// meta version - 2 for v2 torrents (BEP 52), hybrid torrents have the v1 keys as well.
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+
+    /// file tree - The files of a v2 torrent, directories map the names of their entries to them.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<String, FileTreeNode>>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct FileInfo {
+    /// length - The length of the file, in bytes.
+    pub length: usize,
+
+    /// path - The subdirectory names, the last of which is the file name.
+    pub path: Vec<String>,
+
+    /// attr - Flags of the file, p marks the padding hybrid torrents align the files with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl FileInfo {
+    /// Whether the file is padding, which isn't stored and is all zeros.
+    pub fn is_pad(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// An entry of the file tree, a file is a dictionary with a single empty key.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File {
+        #[serde(rename = "")]
+        file: V2File,
+    },
+    Directory(BTreeMap<String, FileTreeNode>),
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct V2File {
+    pub length: usize,
+
+    /// pieces root - The root of the merkle tree of the file's 16 KiB blocks, empty files have none.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+/// A file of the torrent, the files are laid out back to back in the pieces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileEntry {
+    /// The path of the file, for a single file torrent only its name.
+    pub path: Vec<String>,
+    pub length: usize,
+    /// Where the file starts in the torrent.
+    pub offset: usize,
+    /// Padding aligning the next file to a piece, it isn't stored and is all zeros.
+    pub pad: bool,
+    /// The root of the file's merkle tree, for v2 torrents.
+    pub pieces_root: Option<Hash>,
+}
+
+/// A part of a file that a range of the torrent spans.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileSlice {
+    /// The index of the file in the torrent.
+    pub file: usize,
+    /// Where the slice starts in the file.
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Splits the `length` bytes at `offset` in the torrent into the parts of each file they span.
+pub fn file_slices(files: &[FileEntry], offset: usize, length: usize) -> Vec<FileSlice> {
+    let end = offset + length;
+    files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| {
+            file.length > 0 && file.offset < end && offset < file.offset + file.length
+        })
+        .map(|(index, file)| {
+            let start = offset.max(file.offset);
+            let stop = end.min(file.offset + file.length);
+            FileSlice {
+                file: index,
+                offset: start - file.offset,
+                length: stop - start,
+            }
+        })
+        .collect()
 }
 
 impl Torrent {
     pub fn new(path: PathBuf) -> Result<Torrent, anyhow::Error> {
         let torrent_byte = fs::read(path)?;
-        let decoded: Torrent = serde_bencode::from_bytes(&torrent_byte)?;
+        Self::from_bytes(&torrent_byte)
+    }
+
+    /// Decodes the contents of a .torrent file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Torrent, anyhow::Error> {
+        let mut decoded: Torrent = serde_bencode::from_bytes(bytes)?;
+        decoded.info_bytes = info_slice(bytes)
+            .context("metainfo without an info dictionary")?
+            .to_vec();
+        if decoded.has_v2() {
+            decoded.check_piece_layers()?;
+        }
+        decoded.files();
         Ok(decoded)
     }
 
+    /// A torrent of which we only know the bencoded info dictionary,
+    /// like the one peers send for a magnet link.
+    pub fn from_info_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let info: Info = serde_bencode::from_bytes(bytes).context("decode info dictionary fail")?;
+        if metadata::bencode_length(bytes) != Some(bytes.len()) {
+            bail!("info dictionary followed by extra bytes");
+        }
+        let torrent = Self {
+            announce: String::new(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info,
+            nodes: Vec::new(),
+            url_list: Vec::new(),
+            piece_layers: BTreeMap::new(),
+            info_bytes: bytes.to_vec(),
+            layout: OnceLock::new(),
+            fetched_layers: Arc::default(),
+            has_piece_layers: Arc::default(),
+        };
+        torrent.files();
+        Ok(torrent)
+    }
+
+    /// The bencoded info dictionary, which peers exchange for magnet links.
+    pub fn info_bytes(&self) -> Cow<'_, [u8]> {
+        if self.info_bytes.is_empty() {
+            // only torrents built in memory don't have the original bytes.
+            return Cow::Owned(
+                serde_bencode::to_bytes(&self.info).expect("it must be valid bytes"),
+            );
+        }
+        Cow::Borrowed(&self.info_bytes)
+    }
+
+    /// The tiers of trackers, from the announce list or else the single announce URL.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        match (tiers.is_empty(), self.announce.is_empty()) {
+            (false, _) => tiers,
+            (true, false) => vec![vec![self.announce.clone()]],
+            (true, true) => Vec::new(),
+        }
+    }
+
+    /// Whether the torrent only gets peers from its trackers.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// Whether the torrent has the v1 keys, with SHA1 piece hashes.
+    pub fn has_v1(&self) -> bool {
+        self.info.length.is_some() || self.info.files.is_some()
+    }
+
+    /// Whether the torrent has the v2 keys (BEP 52), with merkle trees of the files.
+    /// Hybrid torrents have both.
+    pub fn has_v2(&self) -> bool {
+        self.info.meta_version == Some(2) && self.info.file_tree.is_some()
+    }
+
     pub fn info_hash_hex(&self) -> Result<String, anyhow::Error> {
-        let bytes = serde_bencode::to_bytes(&self.info)?;
-        let mut hasher = <Sha1 as Digest>::new();
-        hasher.update(bytes);
-        let hash = hasher.finalize();
-        let hex = hex::encode(hash);
-        Ok(hex)
+        Ok(hex::encode(self.info_hash_bytes()))
     }
 
+    /// The info hash peers and trackers know the torrent by. v2 only torrents use their
+    /// SHA256 info hash truncated to 20 bytes, the others the SHA1 one.
     pub fn info_hash_bytes(&self) -> [u8; 20] {
-        let bytes = serde_bencode::to_bytes(&self.info).expect("it must be valid bytes");
-        let mut hasher = <Sha1 as Digest>::new();
-        hasher.update(&bytes);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]")
+        let bytes = self.info_bytes();
+        if self.has_v1() {
+            let mut hasher = <Sha1 as Digest>::new();
+            hasher.update(&bytes);
+            hasher.finalize().into()
+        } else {
+            Sha256::digest(&bytes)[..20].try_into().unwrap()
+        }
+    }
+
+    /// The SHA256 info hash of v2 torrents.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.has_v2().then(|| Sha256::digest(&self.info_bytes()))
+    }
+
+    /// All the info hashes peers may know the torrent by, hybrid torrents have two.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash_bytes()];
+        if let (true, Some(hash)) = (self.has_v1(), self.info_hash_v2()) {
+            hashes.push(hash[..20].try_into().unwrap());
+        }
+        hashes
     }
 
     pub fn info_hash_urlencoded(&self) -> Result<String, anyhow::Error> {
@@ -87,161 +433,745 @@ impl Torrent {
     }
 
     pub async fn discover_peers(&self) -> Result<Vec<Peer>, anyhow::Error> {
-        let endpoint = format!(
-            "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}",
-            self.announce,
-            self.info_hash_urlencoded().unwrap(),
-            "00112233445566778899",
-            6881,
-            0,
-            0,
-            self.info.length,
-            1
-        );
-        let response = reqwest::get(endpoint).await?.bytes().await?;
-        let decoded: TrackerResponse = serde_bencode::from_bytes(&response)?;
+        let response = self
+            .announce(&generate_peer_id()?, DEFAULT_PORT, self.length(), None)
+            .await?;
+        Ok(response.all_peers())
+    }
 
-        Ok(decoded.all_peers())
+    /// Tells the trackers, tier by tier, that `peer_id` is listening on `port`
+    /// and still needs `left` bytes, through `proxy` if there is one.
+    pub async fn announce(
+        &self,
+        peer_id: &[u8; 20],
+        port: u16,
+        left: usize,
+        proxy: Option<&Proxy>,
+    ) -> anyhow::Result<TrackerResponse> {
+        let announce = Announce {
+            info_hash: self.info_hash_bytes(),
+            peer_id: *peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: Event::None,
+        };
+        TrackerTiers::new(self.trackers())?
+            .announce(&announce, proxy)
+            .await
     }
 
-    async fn make_handshake(
+    pub(crate) async fn make_handshake<S: AsyncWrite + Unpin>(
         &self,
-        stream: &mut tokio::net::TcpStream,
-        peer_addr: SocketAddrV4,
+        stream: &mut S,
+        peer_addr: SocketAddr,
         peer_id: [u8; 20],
     ) -> anyhow::Result<()> {
-        eprintln!("connected to {peer_addr}");
-        let mut message = Vec::with_capacity(68);
+        debug!("connected to {peer_addr}");
+        Handshake::new(self.info_hash_bytes(), peer_id)
+            .with_v2(self.has_v2())
+            .write(stream)
+            .await
+    }
 
-        // length of the protocol string (BitTorrent protocol) which is 19 (1 byte)
-        message.push(19);
+    pub async fn peer_handshake(&self, peer_addr: SocketAddrV4) -> anyhow::Result<String> {
+        let mut stream = tokio::net::TcpStream::connect(peer_addr).await?;
+        self.make_handshake(&mut stream, peer_addr.into(), generate_peer_id()?)
+            .await?;
+        let handshake = Handshake::read(&mut stream).await?;
+        Ok(hex::encode(handshake.peer_id))
+    }
 
-        // the string BitTorrent protocol (19 bytes)
-        for byte in b"BitTorrent protocol" {
-            message.push(*byte);
+    /// The total length of the files, in bytes, without padding.
+    pub fn length(&self) -> usize {
+        match (&self.info.length, &self.info.files) {
+            (Some(length), _) => *length,
+            (None, Some(files)) => files
+                .iter()
+                .filter(|file| !file.is_pad())
+                .map(|file| file.length)
+                .sum(),
+            (None, None) => self.tree_files().iter().map(|(_, file)| file.length).sum(),
         }
+    }
 
-        // eight reserved bytes, which are all set to zero (8 bytes)
-        for byte in [0u8; 8] {
-            message.push(byte);
-        }
+    /// The files of the torrent, a single file torrent has a single file named after the torrent.
+    /// The files of v2 torrents start at a piece, padding fills the gaps.
+    pub fn files(&self) -> &[FileEntry] {
+        self.layout.get_or_init(|| self.lay_out_files())
+    }
 
-        // sha1 infohash (20 bytes) (NOT the hexadecimal representation, which is 40 bytes long)
-        for byte in self.info_hash_bytes() {
-            message.push(byte);
-        }
+    fn lay_out_files(&self) -> Vec<FileEntry> {
+        let tree_files = self.tree_files();
+        let roots: HashMap<&[String], Hash> = tree_files
+            .iter()
+            .filter_map(|(path, file)| {
+                let root = file.pieces_root.as_ref()?.as_slice().try_into().ok()?;
+                Some((path.as_slice(), root))
+            })
+            .collect();
+        let pieces_root = |path: &[String]| roots.get(path).copied();
 
-        // peer id (20 bytes)
-        for byte in peer_id {
-            message.push(byte);
+        if !self.has_v1() {
+            let mut offset = 0;
+            let mut entries = Vec::new();
+            for (path, file) in &tree_files {
+                let gap = offset % self.info.piece_length;
+                if file.length > 0 && gap > 0 {
+                    entries.push(FileEntry {
+                        path: vec![".pad".to_string()],
+                        length: self.info.piece_length - gap,
+                        offset,
+                        pad: true,
+                        pieces_root: None,
+                    });
+                    offset += self.info.piece_length - gap;
+                }
+                entries.push(FileEntry {
+                    path: path.clone(),
+                    length: file.length,
+                    offset,
+                    pad: false,
+                    pieces_root: pieces_root(path),
+                });
+                offset += file.length;
+            }
+            return entries;
         }
 
-        stream.write_all(message.as_slice()).await?;
-        Ok(())
+        let Some(files) = &self.info.files else {
+            let path = vec![self.info.name.clone()];
+            return vec![FileEntry {
+                pieces_root: pieces_root(&path),
+                path,
+                length: self.length(),
+                offset: 0,
+                pad: false,
+            }];
+        };
+
+        let mut offset = 0;
+        files
+            .iter()
+            .map(|file| {
+                let entry = FileEntry {
+                    path: file.path.clone(),
+                    length: file.length,
+                    offset,
+                    pad: file.is_pad(),
+                    pieces_root: pieces_root(&file.path),
+                };
+                offset += file.length;
+                entry
+            })
+            .collect()
     }
 
-    pub async fn peer_handshake(&self, peer_addr: SocketAddrV4) -> anyhow::Result<String> {
-        let mut stream = tokio::net::TcpStream::connect(peer_addr).await?;
-        self.make_handshake(&mut stream, peer_addr, *PEER_ID)
-            .await?;
-        let mut buffer = [0u8; 68];
-        stream.read_exact(&mut buffer).await?;
-        Ok(hex::encode(&buffer[48..]))
+    /// The files of the file tree with their paths, in the order of the tree.
+    fn tree_files(&self) -> Vec<(Vec<String>, &V2File)> {
+        fn walk<'a>(
+            directory: &'a BTreeMap<String, FileTreeNode>,
+            path: &mut Vec<String>,
+            files: &mut Vec<(Vec<String>, &'a V2File)>,
+        ) {
+            for (name, node) in directory {
+                path.push(name.clone());
+                match node {
+                    FileTreeNode::File { file } => files.push((path.clone(), file)),
+                    FileTreeNode::Directory(entries) => walk(entries, path, files),
+                }
+                path.pop();
+            }
+        }
+
+        let mut files = Vec::new();
+        if let Some(tree) = &self.info.file_tree {
+            walk(tree, &mut Vec::new(), &mut files);
+        }
+        files
     }
 
-    pub async fn download_piece(&self, piece_index: u32) -> anyhow::Result<Vec<u8>> {
-        // retrieve random peer to make a handshake with
-        // TODO: for now there is not rand crate so i will get the first peer.
-        let peers = self.discover_peers().await?;
-        let peer = peers.last().expect("there is no peer");
+    /// Whether the torrent is a directory of files rather than a single file.
+    pub fn is_multi_file(&self) -> bool {
+        if self.has_v1() {
+            return self.info.files.is_some();
+        }
+        // a single file v2 torrent's tree is only the file, named after the torrent.
+        match &self.info.file_tree {
+            Some(tree) => {
+                tree.len() != 1 || !matches!(tree.values().next(), Some(FileTreeNode::File { .. }))
+            }
+            None => false,
+        }
+    }
 
-        let mut stream = tokio::net::TcpStream::connect(peer.addr()).await?;
+    /// Where the data of the torrent ends, padding included.
+    fn layout_length(&self) -> usize {
+        match self.files().last() {
+            Some(file) => file.offset + file.length,
+            None => 0,
+        }
+    }
 
-        // make handshake and receive the first message
-        self.make_handshake(&mut stream, peer.addr(), *PEER_ID)
-            .await
-            .context("handshake failed")?;
-        let mut buffer = [0u8; 68];
-        stream.read_exact(&mut buffer).await?;
+    /// The file holding the byte at `offset` of the torrent, `None` within padding.
+    fn file_at(&self, offset: usize) -> Option<&FileEntry> {
+        let files = self.files();
+        let index = files.partition_point(|file| file.offset + file.length <= offset);
+        files
+            .get(index)
+            .filter(|file| !file.pad && file.offset <= offset)
+    }
 
-        let mut peer = tokio_util::codec::Framed::new(stream, MessageFramer);
+    /// The file whose merkle tree has `pieces_root`.
+    pub fn file_with_root(&self, pieces_root: &Hash) -> Option<&FileEntry> {
+        self.files()
+            .iter()
+            .find(|file| file.pieces_root.as_ref() == Some(pieces_root))
+    }
 
-        let bitfiel_message = peer.next().await;
-        eprintln!("Message: {:?}", bitfiel_message);
+    /// Number of pieces the torrent is split into.
+    pub fn num_pieces(&self) -> usize {
+        if !self.has_v1() {
+            let piece_length = self.info.piece_length;
+            return self.layout_length().div_ceil(piece_length);
+        }
+        // a piece hash is 20 bytes in length
+        self.info.pieces.len() / 20
+    }
 
-        // send interest message
-        peer.send(Message {
-            tag: MessageTag::Interested,
-            payload: Vec::new(),
-        })
-        .await
-        .context("send interested message fail")?;
-
-        // Wait until we receive unchoke message
-        loop {
-            if let Some(Ok(message)) = peer.next().await {
-                if message.tag == MessageTag::Unchoke {
-                    break;
-                }
-            }
+    /// The size of the piece at `piece_index`, only the last piece may be shorter.
+    /// In v2 only torrents the last piece of every file may be shorter.
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        if !self.has_v1() {
+            let start = piece_index * self.info.piece_length;
+            return self.file_at(start).map_or(0, |file| {
+                self.info
+                    .piece_length
+                    .min(file.offset + file.length - start)
+            });
         }
 
-        let mut block_index: u32 = 0;
-        let mut block_length: u32 = BLOCK_MAX as u32;
+        if piece_index + 1 < self.num_pieces() {
+            return self.info.piece_length;
+        }
 
-        let mut remaining_bytes = if piece_index < (self.info.pieces.len() / 20 - 1) as u32 {
-            // a piece hash is 20 bytes in length
+        let last_len = self.layout_length() % self.info.piece_length;
+        if last_len == 0 {
             self.info.piece_length
         } else {
-            let last_len = self.info.length % self.info.piece_length;
+            last_len
+        }
+    }
 
-            if last_len == 0 {
-                self.info.piece_length
-            } else {
-                last_len
-            }
+    /// The SHA1 hash of the piece at `piece_index`.
+    pub fn piece_hash(&self, piece_index: usize) -> &[u8] {
+        &self.info.pieces[piece_index * 20..(piece_index + 1) * 20]
+    }
+
+    /// The hashes of the pieces of the file whose tree has `pieces_root`,
+    /// only files larger than a piece have them.
+    pub fn piece_layer(&self, pieces_root: &Hash) -> Option<Vec<Hash>> {
+        let Some(layer) = self.piece_layers.get(&ByteBuf::from(pieces_root.to_vec())) else {
+            let fetched = self.fetched_layers.lock().unwrap();
+            return fetched.get(pieces_root)?.iter().copied().collect();
         };
+        if layer.len() % 32 != 0 {
+            return None;
+        }
+        Some(
+            layer
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().unwrap())
+                .collect(),
+        )
+    }
+
+    /// The hash of the piece at `index` in the file whose tree has `pieces_root`.
+    fn layer_hash(&self, pieces_root: &Hash, index: usize) -> Option<Hash> {
+        match self.piece_layers.get(&ByteBuf::from(pieces_root.to_vec())) {
+            Some(layer) => layer.get(index * 32..(index + 1) * 32)?.try_into().ok(),
+            None => *self
+                .fetched_layers
+                .lock()
+                .unwrap()
+                .get(pieces_root)?
+                .get(index)?,
+        }
+    }
 
-        let mut piece_data = Vec::new();
-        while remaining_bytes != 0 {
-            if remaining_bytes < block_length as usize {
-                block_length = remaining_bytes as u32;
+    /// The hash requests for the piece layers we don't have, at most `MAX_HASHES` hashes each.
+    /// Only files larger than a piece have a piece layer.
+    pub fn piece_layer_requests(&self) -> Vec<HashRequest> {
+        let piece_length = self.info.piece_length;
+        let piece_level = (piece_length / BLOCK_MAX).trailing_zeros();
+        let fetched = self.fetched_layers.lock().unwrap();
+        let mut requests = Vec::new();
+        for file in self.files() {
+            let Some(pieces_root) = file.pieces_root else {
+                continue;
+            };
+            if file.length <= piece_length
+                || self
+                    .piece_layers
+                    .contains_key(&ByteBuf::from(pieces_root.to_vec()))
+            {
+                continue;
+            }
+            let pieces = file.length.div_ceil(piece_length);
+            let width = pieces.next_power_of_two();
+            let length = width.min(merkle::MAX_HASHES);
+            for index in (0..pieces).step_by(length) {
+                let missing = fetched.get(&pieces_root).is_none_or(|layer| {
+                    layer[index..pieces.min(index + length)]
+                        .iter()
+                        .any(Option::is_none)
+                });
+                if missing {
+                    requests.push(HashRequest {
+                        pieces_root,
+                        base_layer: piece_level,
+                        index: index as u32,
+                        length: length as u32,
+                        // uncles all the way up to the pieces root.
+                        proof_layers: width.trailing_zeros(),
+                    });
+                }
             }
+        }
+        requests
+    }
 
-            // send request message
-            peer.send(Message::new_request(
-                piece_index as u32,
-                block_index * BLOCK_MAX as u32,
-                block_length,
-            ))
-            .await
-            .context("sending request message fail")?;
-
-            // read the next message it must be piece message containing the piece data.
-            if let Some(Ok(message)) = peer.next().await {
-                if message.tag == MessageTag::Piece {
-                    // TODO: export it to a function -> get the block of the piece message
-                    // the piece message payload structure
-                    // [0..4] -> index
-                    // [4..8] -> begin
-                    // [8..] -> block data usually 2^14 bytes long (we copy the block data only)
-                    piece_data.extend_from_slice(&message.payload[8..]);
+    /// Whether the piece layers of every file larger than a piece are known.
+    pub fn has_piece_layers(&self) -> bool {
+        if self.has_piece_layers.load(Ordering::Relaxed) {
+            return true;
+        }
+        let known = self.piece_layer_requests().is_empty();
+        if known {
+            self.has_piece_layers.store(true, Ordering::Relaxed);
+        }
+        known
+    }
+
+    /// Stores the piece hashes a peer sent for one of our `piece_layer_requests`,
+    /// once they are proven to lead to the pieces root of their file.
+    pub fn add_piece_hashes(&self, request: &HashRequest, hashes: &[Hash]) -> anyhow::Result<()> {
+        let piece_length = self.info.piece_length;
+        let piece_level = (piece_length / BLOCK_MAX).trailing_zeros();
+        let Some(file) = self.file_with_root(&request.pieces_root) else {
+            bail!("hashes for a file that isn't in the torrent");
+        };
+        if request.base_layer != piece_level || file.length <= piece_length {
+            bail!("hashes of a layer we didn't ask for");
+        }
+        let pieces = file.length.div_ceil(piece_length);
+        let height = pieces.next_power_of_two().trailing_zeros();
+        if !merkle::verify_proof(request, hashes, height) {
+            bail!("hashes don't lead to the pieces root");
+        }
+
+        let mut fetched = self.fetched_layers.lock().unwrap();
+        let layer = fetched
+            .entry(request.pieces_root)
+            .or_insert_with(|| vec![None; pieces]);
+        let index = request.index as usize;
+        for (position, hash) in (index..pieces).zip(hashes) {
+            layer[position] = Some(*hash);
+        }
+        Ok(())
+    }
+
+    /// Whether `data` is the piece at `piece_index`. Hybrid torrents check both hashes.
+    pub fn verify_piece(&self, piece_index: usize, data: &[u8]) -> bool {
+        if self.has_v1() && Sha1::digest(data).as_slice() != self.piece_hash(piece_index) {
+            return false;
+        }
+        if !self.has_v2() {
+            return true;
+        }
+        // without a piece layer only the v1 hash can tell.
+        self.verify_merkle(piece_index, data)
+            .unwrap_or(self.has_v1())
+    }
+
+    /// Whether we can tell if data is the piece at `piece_index`, v2 only torrents
+    /// from magnet links can't until peers sent the piece layer of its file.
+    pub fn can_verify(&self, piece_index: usize) -> bool {
+        if self.has_v1() || !self.has_v2() {
+            return true;
+        }
+        let piece_length = self.info.piece_length;
+        let start = piece_index * piece_length;
+        match self.file_at(start) {
+            Some(file) if file.length > piece_length => file
+                .pieces_root
+                .and_then(|root| self.layer_hash(&root, (start - file.offset) / piece_length))
+                .is_some(),
+            _ => true,
+        }
+    }
+
+    /// Checks the piece against the merkle tree of the file it is in, `None` if we can't.
+    fn verify_merkle(&self, piece_index: usize, data: &[u8]) -> Option<bool> {
+        let piece_length = self.info.piece_length;
+        let start = piece_index * piece_length;
+        let file = self.file_at(start)?;
+        let pieces_root = file.pieces_root?;
+
+        // hybrid torrents pad the last piece of a file.
+        let data = &data[..data.len().min(file.offset + file.length - start)];
+        let leaves = merkle::block_hashes(data);
+        if file.length <= piece_length {
+            let width = leaves.len().next_power_of_two();
+            return Some(merkle::root(&leaves, width, 0) == pieces_root);
+        }
+        let expected = self.layer_hash(&pieces_root, (start - file.offset) / piece_length)?;
+        Some(merkle::root(&leaves, piece_length / BLOCK_MAX, 0) == expected)
+    }
+
+    /// Checks the piece layers of the files larger than a piece against their pieces root.
+    fn check_piece_layers(&self) -> anyhow::Result<()> {
+        let piece_length = self.info.piece_length;
+        if !piece_length.is_power_of_two() || piece_length < BLOCK_MAX {
+            bail!("piece length of v2 torrents must be a power of two of at least 16 KiB");
+        }
+        let piece_level = (piece_length / BLOCK_MAX).trailing_zeros();
+        for (path, file) in self.tree_files() {
+            let pieces_root: Option<Hash> = file
+                .pieces_root
+                .as_ref()
+                .and_then(|root| root.as_slice().try_into().ok());
+            let Some(pieces_root) = pieces_root else {
+                if file.length > 0 {
+                    bail!("file {path:?} has no valid pieces root");
                 }
+                continue;
+            };
+            if file.length <= piece_length {
+                continue;
+            }
+            let pieces = file.length.div_ceil(piece_length);
+            match self.piece_layer(&pieces_root) {
+                Some(layer)
+                    if layer.len() == pieces
+                        && merkle::root(&layer, pieces.next_power_of_two(), piece_level)
+                            == pieces_root => {}
+                _ => bail!("invalid piece layer for file {path:?}"),
             }
-            remaining_bytes -= block_length as usize;
-            block_index += 1;
         }
+        Ok(())
+    }
+
+    pub async fn download_piece(&self, piece_index: u32) -> anyhow::Result<Vec<u8>> {
+        let piece_index = piece_index as usize;
+        let mut wanted = Bitfield::new(self.num_pieces());
+        wanted.set(piece_index);
 
-        Ok(piece_data)
+        let mut swarm = Swarm::new(
+            Arc::new(self.clone()),
+            Storage::temporary(self)?,
+            Bitfield::new(self.num_pieces()),
+            wanted,
+        )?;
+        swarm.download().await?;
+        swarm
+            .storage()
+            .read_piece(piece_index, self.piece_size(piece_index))
     }
 
     pub async fn download_all(&self) -> anyhow::Result<Vec<u8>> {
-        let mut file = Vec::new();
-        for i in 0..(self.info.pieces.len() / 20) {
-            let piece = self.download_piece(i as u32).await?;
-            file.extend(piece);
-        }
+        let mut swarm = Swarm::new(
+            Arc::new(self.clone()),
+            Storage::temporary(self)?,
+            Bitfield::new(self.num_pieces()),
+            Bitfield::full(self.num_pieces()),
+        )?;
+        swarm.download().await?;
 
+        let mut file = Vec::with_capacity(self.length());
+        for i in 0..self.num_pieces() {
+            file.extend(swarm.storage().read_piece(i, self.piece_size(i))?);
+        }
         Ok(file)
     }
 }
+
+/// Torrents built from data in memory, for the tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use serde::Serialize;
+    use sha1::{Digest, Sha1};
+
+    use std::collections::BTreeMap;
+
+    use serde_bytes::ByteBuf;
+
+    use super::{FileInfo, FileTreeNode, Info, Torrent, V2File};
+    use crate::{merkle, BLOCK_MAX};
+
+    #[derive(Serialize)]
+    struct Metainfo<'a> {
+        announce: &'a str,
+        info: &'a Info,
+        #[serde(rename = "piece layers", skip_serializing_if = "BTreeMap::is_empty")]
+        piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+    }
+
+    /// A v1 torrent called `name` with `files`, a single file torrent when there is one file.
+    pub fn torrent(name: &str, files: &[(&str, &[u8])], piece_length: usize) -> Torrent {
+        let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.to_vec()).collect();
+        let pieces = data
+            .chunks(piece_length)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let (length, files) = match files {
+            [(_, data)] => (Some(data.len()), None),
+            files => (
+                None,
+                Some(
+                    files
+                        .iter()
+                        .map(|(path, data)| FileInfo {
+                            length: data.len(),
+                            path: path.split('/').map(str::to_string).collect(),
+                            attr: None,
+                        })
+                        .collect(),
+                ),
+            ),
+        };
+        let info = Info {
+            name: name.to_string(),
+            length,
+            files,
+            piece_length,
+            pieces,
+            private: None,
+            meta_version: None,
+            file_tree: None,
+        };
+        let metainfo = Metainfo {
+            announce: "http://tracker.invalid/announce",
+            info: &info,
+            piece_layers: BTreeMap::new(),
+        };
+        Torrent::from_bytes(&serde_bencode::to_bytes(&metainfo).unwrap()).unwrap()
+    }
+
+    /// A v2 only torrent called `name` with `files`, a single file torrent when there is one file,
+    /// with the piece layers of the files larger than a piece.
+    pub fn torrent_v2(name: &str, files: &[(&str, &[u8])], piece_length: usize) -> Torrent {
+        let piece_level = (piece_length / BLOCK_MAX).trailing_zeros();
+        let mut tree = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        for (path, data) in files {
+            let leaves = merkle::block_hashes(data);
+            let pieces_root = if data.len() <= piece_length {
+                merkle::root(&leaves, leaves.len().next_power_of_two(), 0)
+            } else {
+                let layer: Vec<merkle::Hash> = data
+                    .chunks(piece_length)
+                    .map(|piece| {
+                        merkle::root(&merkle::block_hashes(piece), piece_length / BLOCK_MAX, 0)
+                    })
+                    .collect();
+                let root = merkle::root(&layer, layer.len().next_power_of_two(), piece_level);
+                piece_layers.insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer.concat()));
+                root
+            };
+            let file = FileTreeNode::File {
+                file: V2File {
+                    length: data.len(),
+                    pieces_root: (!data.is_empty()).then(|| ByteBuf::from(pieces_root.to_vec())),
+                },
+            };
+
+            let path = if files.len() == 1 { name } else { path };
+            let mut components: Vec<&str> = path.split('/').collect();
+            let file_name = components.pop().unwrap();
+            let mut directory = &mut tree;
+            for component in components {
+                let node = directory
+                    .entry(component.to_string())
+                    .or_insert_with(|| FileTreeNode::Directory(BTreeMap::new()));
+                let FileTreeNode::Directory(entries) = node else {
+                    panic!("{component} is a file");
+                };
+                directory = entries;
+            }
+            directory.insert(file_name.to_string(), file);
+        }
+
+        let info = Info {
+            name: name.to_string(),
+            length: None,
+            files: None,
+            piece_length,
+            pieces: Vec::new(),
+            private: None,
+            meta_version: Some(2),
+            file_tree: Some(tree),
+        };
+        let metainfo = Metainfo {
+            announce: "http://tracker.invalid/announce",
+            info: &info,
+            piece_layers,
+        };
+        Torrent::from_bytes(&serde_bencode::to_bytes(&metainfo).unwrap()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_ids_are_random_with_our_prefix() {
+        let (first, second) = (generate_peer_id().unwrap(), generate_peer_id().unwrap());
+        assert_eq!(&first[..8], PEER_ID_PREFIX);
+        assert_ne!(first, second);
+    }
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 31 % 253) as u8).collect()
+    }
+
+    #[test]
+    fn v2_files_start_at_a_piece() {
+        let (a, b) = (data(20_000), data(40_000));
+        let torrent = testing::torrent_v2("dir", &[("a", &a), ("sub/b", &b)], BLOCK_MAX);
+        let files = torrent.files();
+        // the layout is computed once.
+        assert!(std::ptr::eq(files, torrent.files()));
+        let layout: Vec<(usize, usize, bool)> = files
+            .iter()
+            .map(|file| (file.offset, file.length, file.pad))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (0, 20_000, false),
+                (20_000, 12_768, true),
+                (32_768, 40_000, false)
+            ]
+        );
+        assert_eq!(files[2].path, ["sub", "b"]);
+        assert!(torrent.is_multi_file());
+
+        assert_eq!(torrent.num_pieces(), 5);
+        let sizes: Vec<usize> = (0..5).map(|index| torrent.piece_size(index)).collect();
+        assert_eq!(sizes, [16_384, 3_616, 16_384, 16_384, 7_232]);
+        assert_eq!(torrent.length(), 60_000);
+    }
+
+    #[test]
+    fn v2_pieces_are_verified_against_the_merkle_trees() {
+        let (small, big) = (data(1_000), data(3 * BLOCK_MAX + 100));
+        let torrent = testing::torrent_v2("dir", &[("big", &big), ("small", &small)], BLOCK_MAX);
+        let pieces: Vec<&[u8]> = big.chunks(BLOCK_MAX).chain([&small[..]]).collect();
+        for (index, piece) in pieces.iter().enumerate() {
+            assert!(torrent.can_verify(index));
+            assert!(torrent.verify_piece(index, piece), "piece {index}");
+            assert!(!torrent.verify_piece(index, &piece[1..]));
+        }
+    }
+
+    #[test]
+    fn missing_piece_layers_are_fetched_from_peers() {
+        let big = data(4 * BLOCK_MAX + 100);
+        let full = testing::torrent_v2("big", &[("big", &big)], BLOCK_MAX);
+        // a magnet link only brings the info dictionary.
+        let fetched = Torrent::from_info_bytes(&full.info_bytes()).unwrap();
+        let shared = fetched.clone();
+        assert!(!fetched.has_piece_layers());
+        assert!(!fetched.has_piece_layers.load(Ordering::Relaxed));
+        assert!(!fetched.can_verify(0));
+        assert!(!fetched.verify_piece(0, &big[..BLOCK_MAX]));
+
+        let requests = fetched.piece_layer_requests();
+        assert_eq!(requests.len(), 1);
+        let request = requests[0];
+        assert_eq!((request.index, request.length), (0, 8));
+        assert_eq!(request.proof_layers, 3);
+
+        let storage = Storage::temporary(&full).unwrap();
+        let have = Bitfield::new(full.num_pieces());
+        let hashes = merkle::answer(&full, &storage, &have, &request)
+            .unwrap()
+            .unwrap();
+        let mut forged = hashes.clone();
+        forged[1][0] ^= 1;
+        assert!(fetched.add_piece_hashes(&request, &forged).is_err());
+        assert!(!fetched.can_verify(1));
+
+        fetched.add_piece_hashes(&request, &hashes).unwrap();
+        assert!(fetched.has_piece_layers());
+        // remembered, by the clones the swarm holds too.
+        assert!(shared.has_piece_layers.load(Ordering::Relaxed));
+        for (index, piece) in big.chunks(BLOCK_MAX).enumerate() {
+            assert!(fetched.verify_piece(index, piece), "piece {index}");
+        }
+        assert_eq!(
+            fetched.piece_layer(&request.pieces_root),
+            full.piece_layer(&request.pieces_root)
+        );
+    }
+
+    /// A single file metainfo whose info dictionary has the `extra` keys, sorted as bencode wants.
+    fn metainfo(extra: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend([7; 20]);
+        info.extend(extra.as_bytes());
+        info.push(b'e');
+        let mut metainfo = b"d8:announce16:http://t.invalid13:announce-listll16:http://t.invalidel14:http://u.validee4:info".to_vec();
+        metainfo.extend(&info);
+        metainfo.push(b'e');
+        (metainfo, info)
+    }
+
+    #[test]
+    fn info_hashes_cover_the_keys_we_dont_know() {
+        let (bytes, info) = metainfo("6:source3:abc");
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.info_bytes(), &info[..]);
+        assert_eq!(
+            torrent.info_hash_bytes(),
+            <[u8; 20]>::from(Sha1::digest(&info))
+        );
+        // re-encoding what we decoded would lose the source.
+        let reencoded = serde_bencode::to_bytes(&torrent.info).unwrap();
+        assert_ne!(
+            torrent.info_hash_bytes(),
+            <[u8; 20]>::from(Sha1::digest(&reencoded))
+        );
+
+        let from_info = Torrent::from_info_bytes(&info).unwrap();
+        assert_eq!(from_info.info_hash_bytes(), torrent.info_hash_bytes());
+        assert!(Torrent::from_info_bytes(&[&info[..], b"i1e"].concat()).is_err());
+    }
+
+    #[test]
+    fn private_torrents_are_flagged() {
+        let (bytes, _) = metainfo("");
+        assert!(!Torrent::from_bytes(&bytes).unwrap().is_private());
+        let (bytes, _) = metainfo("7:privatei1e");
+        assert!(Torrent::from_bytes(&bytes).unwrap().is_private());
+        let (bytes, _) = metainfo("7:privatei0e");
+        assert!(!Torrent::from_bytes(&bytes).unwrap().is_private());
+    }
+
+    #[test]
+    fn announce_list_replaces_announce() {
+        let (bytes, _) = metainfo("");
+        let mut torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            torrent.trackers(),
+            [vec!["http://t.invalid"], vec!["http://u.valid"]]
+        );
+        torrent.announce_list = vec![Vec::new()];
+        assert_eq!(torrent.trackers(), [vec!["http://t.invalid"]]);
+        torrent.announce.clear();
+        assert!(torrent.trackers().is_empty());
+    }
+}
@@ -1,9 +1,37 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
+    io,
     net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
 };
 
-use serde::Deserialize;
+use anyhow::{bail, Context};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::time::{timeout, timeout_at, Instant};
+
+use crate::{
+    debug,
+    proxy::{self, Datagrams, Proxy},
+    random,
+};
+
+/// How long a single request to a tracker may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The magic number of the connect requests of the UDP tracker protocol (BEP 15).
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_CONNECT: u32 = 0;
+const UDP_ANNOUNCE: u32 = 1;
+const UDP_SCRAPE: u32 = 2;
+const UDP_ERROR: u32 = 3;
+/// How long we wait for a UDP tracker before asking again. BEP 15 starts at 15 seconds and
+/// doubles, but a whole request only gets [`REQUEST_TIMEOUT`].
+const UDP_RETRANSMIT: Duration = Duration::from_secs(5);
+/// The largest datagram a UDP tracker may answer with.
+const MAX_DATAGRAM: usize = 65507;
 
 #[allow(dead_code)]
 #[derive(Clone, Deserialize, Debug)]
@@ -19,6 +47,11 @@ pub struct TrackerResponse {
 }
 
 impl TrackerResponse {
+    /// How long to wait before announcing again.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval as u64)
+    }
+
     pub fn all_peers(&self) -> Vec<Peer> {
         let mut peers = Vec::new();
         for chunk_6 in self.peers.chunks(6) {
@@ -26,7 +59,325 @@ impl TrackerResponse {
             let port = u16::from_be_bytes([chunk_6[4], chunk_6[5]]);
             peers.push(Peer(SocketAddrV4::new(addr, port)));
         }
-        return peers;
+        peers
+    }
+}
+
+/// Why we announce, besides the regular announces which have no event.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Event {
+    #[default]
+    None,
+    /// The first announce of the torrent.
+    Started,
+    /// The download just finished.
+    Completed,
+    /// We leave the swarm.
+    Stopped,
+}
+
+impl Event {
+    fn as_str(&self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Started => Some("started"),
+            Event::Completed => Some("completed"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+
+    /// The number of the event in UDP announces.
+    fn udp_code(&self) -> u32 {
+        match self {
+            Event::None => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
+/// What we tell the tracker about ourselves and our transfer of the torrent.
+#[derive(Clone, Copy, Debug)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    /// The port we listen on.
+    pub port: u16,
+    /// The bytes we uploaded and downloaded since the started event.
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// The bytes we still need.
+    pub left: usize,
+    pub event: Event,
+}
+
+impl Announce {
+    /// The announce url of the tracker at `url`, which may have a query of its own already.
+    fn url(&self, url: &str) -> String {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let mut endpoint = format!(
+            "{url}{separator}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            url_encode(&self.info_hash),
+            url_encode(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left,
+        );
+        if let Some(event) = self.event.as_str() {
+            endpoint.push_str("&event=");
+            endpoint.push_str(event);
+        }
+        endpoint
+    }
+
+    /// The body of a UDP announce, after the header.
+    fn udp_body(&self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&self.info_hash);
+        body.extend_from_slice(&self.peer_id);
+        body.extend_from_slice(&self.downloaded.to_be_bytes());
+        body.extend_from_slice(&(self.left as u64).to_be_bytes());
+        body.extend_from_slice(&self.uploaded.to_be_bytes());
+        body.extend_from_slice(&self.event.udp_code().to_be_bytes());
+        // the tracker takes the address the announce comes from, any number of peers will do.
+        body.extend_from_slice(&[0; 4]);
+        body.extend_from_slice(&random::bytes::<4>()?);
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        body.extend_from_slice(&self.port.to_be_bytes());
+        Ok(body)
+    }
+}
+
+/// Tells the tracker at `url` about us with `announce`, through `proxy` if there is one.
+pub async fn announce(
+    url: &str,
+    announce: &Announce,
+    proxy: Option<&Proxy>,
+) -> anyhow::Result<TrackerResponse> {
+    if url.starts_with("udp://") {
+        let answer = udp_request(url, proxy, UDP_ANNOUNCE, &announce.udp_body()?).await?;
+        // the interval, then the leechers and seeders we don't need, before the peers.
+        let interval = answer.first_chunk().context("announce answer too short")?;
+        return Ok(TrackerResponse {
+            interval: u32::from_be_bytes(*interval) as usize,
+            peers: answer.get(12..).unwrap_or_default().to_vec(),
+        });
+    }
+    let response = get(&announce.url(url), proxy).await?;
+    let decoded: TrackerResponse = serde_bencode::from_bytes(&response)?;
+
+    Ok(decoded)
+}
+
+/// What a tracker knows about the swarm of a torrent.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Peers with every piece.
+    pub complete: u64,
+    /// Peers still downloading.
+    pub incomplete: u64,
+    /// The downloads the tracker saw complete.
+    #[serde(default)]
+    pub downloaded: u64,
+}
+
+#[derive(Deserialize)]
+struct ScrapeResponse {
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// Asks the tracker announcing at `url` about the swarm of the torrent `info_hash`,
+/// through `proxy` if there is one.
+pub async fn scrape(
+    url: &str,
+    info_hash: &[u8; 20],
+    proxy: Option<&Proxy>,
+) -> anyhow::Result<ScrapeStats> {
+    if url.starts_with("udp://") {
+        let answer = udp_request(url, proxy, UDP_SCRAPE, info_hash).await?;
+        let number = |at: usize| {
+            answer
+                .get(at..at + 4)
+                .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as u64)
+                .context("scrape answer too short")
+        };
+        return Ok(ScrapeStats {
+            complete: number(0)?,
+            downloaded: number(4)?,
+            incomplete: number(8)?,
+        });
+    }
+    let scrape = scrape_url(url).context("the tracker can't be scraped")?;
+    let separator = if scrape.contains('?') { '&' } else { '?' };
+    let endpoint = format!("{scrape}{separator}info_hash={}", url_encode(info_hash));
+    let response: ScrapeResponse = serde_bencode::from_bytes(&get(&endpoint, proxy).await?)?;
+    response
+        .files
+        .into_iter()
+        .find(|(hash, _)| hash.as_slice() == info_hash)
+        .map(|(_, stats)| stats)
+        .context("the tracker doesn't know the torrent")
+}
+
+/// The scrape url of the tracker announcing at `url`, by convention the announce url
+/// with scrape for announce in its last path segment. None when it doesn't follow it.
+fn scrape_url(url: &str) -> Option<String> {
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    };
+    let (base, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    let mut scrape = format!("{base}/scrape{rest}");
+    if let Some(query) = query {
+        scrape.push('?');
+        scrape.push_str(query);
+    }
+    Some(scrape)
+}
+
+/// Gets `endpoint` of a tracker, through `proxy` if there is one.
+async fn get(endpoint: &str, proxy: Option<&Proxy>) -> anyhow::Result<Vec<u8>> {
+    let request = async {
+        match proxy {
+            Some(proxy) => {
+                let (status, body) = proxy.http_get(endpoint, &[]).await?;
+                if status != 200 {
+                    bail!("tracker answered {status}");
+                }
+                Ok(body)
+            }
+            None => Ok(reqwest::get(endpoint).await?.bytes().await?.to_vec()),
+        }
+    };
+    timeout(REQUEST_TIMEOUT, request)
+        .await
+        .context("tracker timed out")?
+}
+
+/// Asks the UDP tracker at `url` (BEP 15) for `action` with `body`, through `proxy` if there
+/// is one, returning the answer after its header. The connection id the request needs comes
+/// from a connect request first.
+async fn udp_request(
+    url: &str,
+    proxy: Option<&Proxy>,
+    action: u32,
+    body: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let request = async {
+        let parsed = Url::parse(url).with_context(|| format!("invalid tracker url {url}"))?;
+        let host = parsed.host_str().context("tracker url without host")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = parsed.port().context("udp tracker url without port")?;
+        let datagrams = proxy::datagrams(proxy, host, port).await?;
+
+        let connected = udp_exchange(&datagrams, UDP_PROTOCOL_ID, UDP_CONNECT, &[]).await?;
+        let connection_id = connected
+            .first_chunk()
+            .context("connect answer too short")?;
+        udp_exchange(&datagrams, u64::from_be_bytes(*connection_id), action, body).await
+    };
+    timeout(REQUEST_TIMEOUT, request)
+        .await
+        .context("tracker timed out")?
+}
+
+/// Sends a UDP tracker the request for `action` until it answers, returning the answer after
+/// its header. Datagrams get lost, the request goes again every [`UDP_RETRANSMIT`].
+async fn udp_exchange(
+    datagrams: &Datagrams,
+    connection_id: u64,
+    action: u32,
+    body: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let transaction: [u8; 4] = random::bytes()?;
+    let mut request = connection_id.to_be_bytes().to_vec();
+    request.extend_from_slice(&action.to_be_bytes());
+    request.extend_from_slice(&transaction);
+    request.extend_from_slice(body);
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        datagrams.send(&request).await?;
+        let deadline = Instant::now() + UDP_RETRANSMIT;
+        while let Ok(received) = timeout_at(deadline, datagrams.recv(&mut buffer)).await {
+            let answer = &buffer[..received?];
+            // late answers to earlier requests are dropped.
+            let Some((header, rest)) = answer.split_first_chunk::<8>() else {
+                continue;
+            };
+            if header[4..] != transaction {
+                continue;
+            }
+            match u32::from_be_bytes(header[..4].try_into()?) {
+                answered if answered == action => return Ok(rest.to_vec()),
+                UDP_ERROR => bail!("tracker failed: {}", String::from_utf8_lossy(rest)),
+                other => bail!("tracker answered action {other} to {action}"),
+            }
+        }
+    }
+}
+
+fn url_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("%{byte:02x}")).collect()
+}
+
+/// The trackers of a torrent in tiers (BEP 12). The tiers are tried in order and the trackers
+/// of a tier in a random order, the first that answers moves to the front of its tier.
+#[derive(Clone, Debug, Default)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> io::Result<Self> {
+        tiers.retain(|tier| !tier.is_empty());
+        for tier in &mut tiers {
+            // Fisher-Yates, so the load spreads over the trackers of a tier.
+            for i in (1..tier.len()).rev() {
+                let j = random::below(i as u64 + 1)? as usize;
+                tier.swap(i, j);
+            }
+        }
+        Ok(Self { tiers })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// The trackers in the order they are tried.
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.tiers.iter().flatten().map(String::as_str)
+    }
+
+    /// Announces to the trackers until one answers.
+    pub async fn announce(
+        &mut self,
+        announce: &Announce,
+        proxy: Option<&Proxy>,
+    ) -> anyhow::Result<TrackerResponse> {
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for index in 0..tier.len() {
+                match self::announce(&tier[index], announce, proxy).await {
+                    Ok(response) => {
+                        let url = tier.remove(index);
+                        tier.insert(0, url);
+                        return Ok(response);
+                    }
+                    Err(err) => {
+                        debug!("announce to {} failed: {err:#}", tier[index]);
+                        last_error = Some(err);
+                    }
+                }
+            }
+        }
+        match last_error {
+            Some(err) => Err(err),
+            None => bail!("the torrent has no tracker"),
+        }
     }
 }
 
@@ -44,3 +395,299 @@ impl Display for Peer {
         f.write_fmt(format_args!("{}", self.0))
     }
 }
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// A tracker answering every announce with `peers`, compact, and an interval of an hour.
+    /// The requested paths, query included, are sent to the receiver.
+    pub async fn serve(peers: &[u8]) -> (String, mpsc::UnboundedReceiver<String>) {
+        let mut body = format!("d8:intervali3600e5:peers{}:", peers.len()).into_bytes();
+        body.extend(peers);
+        body.push(b'e');
+        serve_body(body).await
+    }
+
+    /// A tracker at an announce url answering every request with `body`, like [`serve`].
+    pub async fn serve_body(body: Vec<u8>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    if stream.read(&mut byte).await.unwrap() == 0 {
+                        break;
+                    }
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                requests
+                    .send(request.split(' ').nth(1).unwrap().to_string())
+                    .ok();
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    /// A tracker at an announce url that accepts connections and never answers.
+    /// A message is sent to the receiver for every connection.
+    pub async fn silent() -> (String, mpsc::UnboundedReceiver<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (connections, accepted) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                open.push(stream);
+                connections.send(()).ok();
+            }
+        });
+        (url, accepted)
+    }
+
+    /// A UDP tracker (BEP 15) answering every announce with `peers`, compact, and an interval
+    /// of an hour, and every scrape with 5 seeders, 50 downloads and 10 leechers. The bodies
+    /// of the announces and scrapes are sent to the receiver.
+    pub async fn serve_udp(peers: &[u8]) -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let peers = peers.to_vec();
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let connection_id = 0x1234_5678_u64.to_be_bytes();
+            let mut buffer = [0; 2048];
+            loop {
+                let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = &buffer[..length];
+                let mut answer = request[8..16].to_vec();
+                match u32::from_be_bytes(request[8..12].try_into().unwrap()) {
+                    UDP_CONNECT => {
+                        assert_eq!(request[..8], UDP_PROTOCOL_ID.to_be_bytes());
+                        answer.extend(connection_id);
+                    }
+                    action => {
+                        assert_eq!(request[..8], connection_id);
+                        requests.send(request[16..].to_vec()).ok();
+                        let stats = [5u32, 50, 10];
+                        match action {
+                            UDP_ANNOUNCE => {
+                                answer.extend(3600u32.to_be_bytes());
+                                answer.extend([0; 8]);
+                                answer.extend(&peers);
+                            }
+                            UDP_SCRAPE => answer.extend(stats.map(u32::to_be_bytes).concat()),
+                            _ => unreachable!("unknown action {action}"),
+                        }
+                    }
+                }
+                socket.send_to(&answer, from).await.unwrap();
+            }
+        });
+        (url, received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn request(event: Event) -> Announce {
+        Announce {
+            info_hash: [0xab; 20],
+            peer_id: [b'-'; 20],
+            port: 6881,
+            uploaded: 12,
+            downloaded: 34,
+            left: 56,
+            event,
+        }
+    }
+
+    #[test]
+    fn announce_urls_carry_counters_and_events() {
+        let url = request(Event::None).url("http://t.invalid/announce");
+        assert!(url.starts_with("http://t.invalid/announce?info_hash=%ab%ab"));
+        assert!(url.ends_with("&port=6881&uploaded=12&downloaded=34&left=56&compact=1"));
+
+        for (event, name) in [
+            (Event::Started, "started"),
+            (Event::Completed, "completed"),
+            (Event::Stopped, "stopped"),
+        ] {
+            let url = request(event).url("http://t.invalid/announce");
+            assert!(url.ends_with(&format!("&compact=1&event={name}")));
+        }
+    }
+
+    #[test]
+    fn announce_urls_keep_their_query() {
+        let url = request(Event::None).url("http://t.invalid/announce?passkey=secret");
+        assert!(url.starts_with("http://t.invalid/announce?passkey=secret&info_hash=%ab"));
+        assert_eq!(url.matches('?').count(), 1);
+    }
+
+    #[tokio::test]
+    async fn tiers_are_tried_in_order_and_answering_trackers_move_first() {
+        // nothing listens on a port we bound and dropped.
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}/announce", listener.local_addr().unwrap())
+        };
+        let (live, mut requests) = testing::serve(&[127, 0, 0, 1, 0x1a, 0xe1]).await;
+        let (unused, mut unused_requests) = testing::serve(&[]).await;
+
+        let mut tiers = TrackerTiers::new(vec![
+            Vec::new(),
+            vec![dead.clone(), live.clone()],
+            vec![unused.clone()],
+        ])
+        .unwrap();
+        assert_eq!(tiers.urls().count(), 3);
+        let response = tiers
+            .announce(&request(Event::Started), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.all_peers()[0].addr(),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881)
+        );
+        assert_eq!(response.interval(), Duration::from_secs(3600));
+        assert!(requests.recv().await.unwrap().ends_with("&event=started"));
+        // the next tier is only tried when the ones before it fail.
+        assert!(unused_requests.try_recv().is_err());
+        assert_eq!(tiers.urls().collect::<Vec<_>>(), [&live, &dead, &unused]);
+    }
+
+    #[test]
+    fn scrape_urls_replace_announce() {
+        for (announce, scrape) in [
+            ("http://t.invalid/announce", Some("http://t.invalid/scrape")),
+            (
+                "http://t.invalid/x/announce.php",
+                Some("http://t.invalid/x/scrape.php"),
+            ),
+            (
+                "http://t.invalid/announce?passkey=a",
+                Some("http://t.invalid/scrape?passkey=a"),
+            ),
+            ("http://t.invalid/a", None),
+            ("http://t.invalid/announce/x", None),
+        ] {
+            assert_eq!(scrape_url(announce).as_deref(), scrape, "{announce}");
+        }
+    }
+
+    #[tokio::test]
+    async fn scrapes_find_the_torrent() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend([0xab; 20]);
+        body.extend(b"d8:completei5e10:downloadedi50e10:incompletei10ee20:");
+        body.extend([0xcd; 20]);
+        body.extend(b"d8:completei0e10:incompletei1eeee");
+        let (url, mut requests) = testing::serve_body(body).await;
+
+        let stats = scrape(&url, &[0xab; 20], None).await.unwrap();
+        assert_eq!(
+            stats,
+            ScrapeStats {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50
+            }
+        );
+        let path = requests.recv().await.unwrap();
+        assert_eq!(path, format!("/scrape?info_hash={}", "%ab".repeat(20)));
+        let stats = scrape(&url, &[0xcd; 20], None).await.unwrap();
+        assert_eq!((stats.complete, stats.downloaded), (0, 0));
+        let err = scrape(&url, &[0xef; 20], None).await.unwrap_err();
+        assert_eq!(err.to_string(), "the tracker doesn't know the torrent");
+        assert!(scrape("http://t.invalid/a", &[0xab; 20], None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_trackers_answer_announces_and_scrapes() {
+        let (url, mut requests) = testing::serve_udp(&[127, 0, 0, 1, 0x1a, 0xe1]).await;
+
+        let response = announce(&url, &request(Event::Started), None)
+            .await
+            .unwrap();
+        assert_eq!(response.interval(), Duration::from_secs(3600));
+        assert_eq!(response.all_peers()[0].to_string(), "127.0.0.1:6881");
+        let body = requests.recv().await.unwrap();
+        assert_eq!(body.len(), 82);
+        assert_eq!(
+            (&body[..20], &body[20..40]),
+            (&[0xab; 20][..], &[b'-'; 20][..])
+        );
+        let number = |at: usize| u64::from_be_bytes(body[at..at + 8].try_into().unwrap());
+        assert_eq!((number(40), number(48), number(56)), (34, 56, 12));
+        // started, then the port last.
+        assert_eq!(body[64..68], 2u32.to_be_bytes());
+        assert_eq!(body[80..], 6881u16.to_be_bytes());
+
+        let stats = scrape(&url, &[0xab; 20], None).await.unwrap();
+        assert_eq!(
+            stats,
+            ScrapeStats {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50
+            }
+        );
+        assert_eq!(requests.recv().await.unwrap(), [0xab; 20]);
+    }
+
+    #[tokio::test]
+    async fn udp_trackers_are_reached_through_socks5() {
+        let (url, mut requests) = testing::serve_udp(&[127, 0, 0, 1, 0x1a, 0xe1]).await;
+        let (socks, mut targets) = proxy::testing::socks5_server("user", "secret").await;
+        let proxy: Proxy = format!("socks5://user:secret@{socks}").parse().unwrap();
+
+        let response = announce(&url, &request(Event::None), Some(&proxy))
+            .await
+            .unwrap();
+        assert_eq!(response.all_peers().len(), 1);
+        assert_eq!(requests.recv().await.unwrap()[64..68], 0u32.to_be_bytes());
+        let tracker = url
+            .trim_start_matches("udp://")
+            .trim_end_matches("/announce");
+        assert_eq!(targets.recv().await.unwrap(), tracker);
+
+        // an http proxy can't relay the datagrams, they don't go around it.
+        let http: Proxy = "http://127.0.0.1:1".parse().unwrap();
+        let err = announce(&url, &request(Event::None), Some(&http))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("can't relay udp"), "{err:#}");
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn torrents_without_trackers_fail_to_announce() {
+        let mut tiers = TrackerTiers::new(vec![Vec::new()]).unwrap();
+        assert!(tiers.is_empty());
+        assert!(tiers.announce(&request(Event::None), None).await.is_err());
+    }
+}
@@ -0,0 +1,860 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::io::AsyncWrite;
+
+use crate::{
+    base64,
+    magnet::Magnet,
+    mse::EncryptionPolicy,
+    priority::FilePriority,
+    random,
+    ratelimit::Rates,
+    rpc::{write_http_response, HttpRequest},
+    session::{Session, TorrentState, TorrentStatus},
+    storage::is_file_name,
+    torrent::Torrent,
+    transport::TransportPreference,
+};
+
+/// Where Transmission clients send their requests.
+pub const TRANSMISSION_RPC_PATH: &str = "/transmission/rpc";
+
+/// Every request has to echo the session id, which a client learns from the 409 answer
+/// to its first request. It keeps web pages from sending requests with the user's browser.
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+/// The protocol version of Transmission 4.0, whose methods we answer a subset of.
+const RPC_VERSION: u32 = 17;
+const RPC_VERSION_MINIMUM: u32 = 14;
+
+const STATUS_STOPPED: u8 = 0;
+const STATUS_CHECK: u8 = 2;
+const STATUS_DOWNLOAD: u8 = 4;
+const STATUS_SEED: u8 = 6;
+
+/// Transmission's speeds are in kB/s.
+const SPEED_UNIT: usize = 1000;
+
+/// The error code for a problem on our side, like a disk that is full.
+const ERROR_LOCAL: u8 = 3;
+
+/// Answers Transmission's RPC protocol with the torrents of a session, for the tools
+/// that already speak it.
+pub struct TransmissionRpc {
+    session: Arc<Session>,
+    session_id: String,
+    ids: Mutex<TorrentIds>,
+}
+
+/// Transmission clients mostly refer to torrents by small numbers,
+/// handed out as the torrents are first seen.
+#[derive(Default)]
+struct TorrentIds {
+    ids: HashMap<[u8; 20], u64>,
+    last: u64,
+}
+
+impl TorrentIds {
+    fn id(&mut self, info_hash: [u8; 20]) -> u64 {
+        *self.ids.entry(info_hash).or_insert_with(|| {
+            self.last += 1;
+            self.last
+        })
+    }
+
+    fn info_hash(&self, id: u64) -> Option<[u8; 20]> {
+        self.ids
+            .iter()
+            .find(|(_, &known)| known == id)
+            .map(|(&info_hash, _)| info_hash)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    arguments: Value,
+    tag: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AddArguments {
+    /// A path to a .torrent file as seen by the daemon, its URL or a magnet link.
+    filename: Option<String>,
+    /// The contents of a .torrent file in base64.
+    metainfo: Option<String>,
+    download_dir: Option<PathBuf>,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    files_wanted: Vec<usize>,
+    #[serde(default)]
+    files_unwanted: Vec<usize>,
+    #[serde(default)]
+    priority_high: Vec<usize>,
+    #[serde(default)]
+    priority_low: Vec<usize>,
+    #[serde(default)]
+    priority_normal: Vec<usize>,
+}
+
+impl AddArguments {
+    fn selects_files(&self) -> bool {
+        !(self.files_wanted.is_empty()
+            && self.files_unwanted.is_empty()
+            && self.priority_high.is_empty()
+            && self.priority_low.is_empty()
+            && self.priority_normal.is_empty())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GetArguments {
+    fields: Vec<String>,
+    ids: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdsArguments {
+    ids: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RemoveArguments {
+    ids: Option<Value>,
+    #[serde(default)]
+    delete_local_data: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionGetArguments {
+    fields: Option<Vec<String>>,
+}
+
+impl TransmissionRpc {
+    pub fn new(session: Arc<Session>) -> io::Result<Self> {
+        Ok(Self {
+            session,
+            session_id: hex::encode(random::bytes::<24>()?),
+            ids: Mutex::new(TorrentIds::default()),
+        })
+    }
+
+    /// Answers a request sent to [`TRANSMISSION_RPC_PATH`].
+    pub(crate) async fn serve<W: AsyncWrite + Unpin>(
+        &self,
+        request: &HttpRequest,
+        writer: &mut W,
+    ) -> anyhow::Result<()> {
+        let session_id = [(SESSION_ID_HEADER, self.session_id.as_str())];
+        if request.method != "POST" {
+            return write_http_response(
+                writer,
+                "405 Method Not Allowed",
+                &[("Allow", "POST")],
+                b"",
+            )
+            .await;
+        }
+        if request.header(SESSION_ID_HEADER) != Some(&self.session_id) {
+            let body = format!("{SESSION_ID_HEADER}: {}", self.session_id);
+            return write_http_response(writer, "409 Conflict", &session_id, body.as_bytes()).await;
+        }
+        let response = serde_json::to_vec(&self.handle(&request.body).await)?;
+        let headers = [session_id[0], ("Content-Type", "application/json")];
+        write_http_response(writer, "200 OK", &headers, &response).await
+    }
+
+    /// Answers a request with its result, "success" unless it failed.
+    async fn handle(&self, body: &[u8]) -> Value {
+        let request: Request = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => return json!({ "result": format!("invalid request: {err}") }),
+        };
+        let (result, arguments) = match self.call(&request.method, request.arguments).await {
+            Ok(arguments) => ("success".to_string(), arguments),
+            Err(err) => (format!("{err:#}"), json!({})),
+        };
+        let mut response = json!({ "result": result, "arguments": arguments });
+        if let Some(tag) = request.tag {
+            response["tag"] = tag;
+        }
+        response
+    }
+
+    async fn call(&self, method: &str, arguments: Value) -> anyhow::Result<Value> {
+        match method {
+            "session-get" => self.session_get(parse_arguments(arguments)?),
+            "torrent-add" => self.torrent_add(parse_arguments(arguments)?).await,
+            "torrent-get" => self.torrent_get(parse_arguments(arguments)?),
+            "torrent-start" | "torrent-start-now" => {
+                let arguments: IdsArguments = parse_arguments(arguments)?;
+                for info_hash in self.resolve(arguments.ids.as_ref())? {
+                    self.session.resume(&info_hash)?;
+                }
+                Ok(json!({}))
+            }
+            "torrent-stop" => {
+                let arguments: IdsArguments = parse_arguments(arguments)?;
+                for info_hash in self.resolve(arguments.ids.as_ref())? {
+                    self.session.pause(&info_hash).await?;
+                }
+                Ok(json!({}))
+            }
+            "torrent-remove" => {
+                let arguments: RemoveArguments = parse_arguments(arguments)?;
+                for info_hash in self.resolve(arguments.ids.as_ref())? {
+                    let status = self.session.status(&info_hash)?;
+                    self.session.remove(&info_hash).await?;
+                    if arguments.delete_local_data {
+                        let download_dir = &self.session.options().download_dir;
+                        delete_data(&status.path, status.path_given, download_dir)?;
+                    }
+                    self.ids.lock().unwrap().ids.remove(&info_hash);
+                }
+                Ok(json!({}))
+            }
+            _ => bail!("method name not recognized"),
+        }
+    }
+
+    fn session_get(&self, arguments: SessionGetArguments) -> anyhow::Result<Value> {
+        let options = self.session.options();
+        let encryption = match options.encryption {
+            EncryptionPolicy::Disabled => "tolerated",
+            EncryptionPolicy::Enabled => "preferred",
+            EncryptionPolicy::Forced => "required",
+        };
+        let bandwidth = self.session.bandwidth();
+        // transmission counts speeds in kB/s and turns limits on and off separately.
+        let limit = |rate: usize| rate.div_ceil(SPEED_UNIT);
+        let mut session = json!({
+            "speed-limit-down": limit(bandwidth.global.download),
+            "speed-limit-down-enabled": bandwidth.global.download > 0,
+            "speed-limit-up": limit(bandwidth.global.upload),
+            "speed-limit-up-enabled": bandwidth.global.upload > 0,
+            "alt-speed-down": limit(bandwidth.alternate.download),
+            "alt-speed-up": limit(bandwidth.alternate.upload),
+            "alt-speed-enabled": bandwidth.alternate_active(),
+            "alt-speed-time-enabled": bandwidth.schedule.is_some(),
+            "version": format!("{} ({})", env!("CARGO_PKG_VERSION"), env!("CARGO_PKG_NAME")),
+            "rpc-version": RPC_VERSION,
+            "rpc-version-minimum": RPC_VERSION_MINIMUM,
+            "session-id": self.session_id,
+            "download-dir": options.download_dir,
+            "peer-port": self.session.port(),
+            "peer-limit-global": self.session.max_connections(),
+            "dht-enabled": options.dht,
+            "lsd-enabled": options.lsd,
+            "pex-enabled": true,
+            "utp-enabled": options.transport != TransportPreference::TcpOnly,
+            "encryption": encryption,
+        });
+        if let (Some(fields), Value::Object(session)) = (arguments.fields, &mut session) {
+            session.retain(|key, _| fields.contains(key));
+        }
+        Ok(session)
+    }
+
+    /// Downloads the file at `url`, through the proxy of the session if there is one.
+    async fn download(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let options = self.session.options();
+        match &options.proxy {
+            Some(proxy) => {
+                let (status, body) = proxy.http_get(url, &[]).await?;
+                if status != 200 {
+                    bail!("server answered {status}");
+                }
+                Ok(body)
+            }
+            None if options.force_proxy => bail!("forced through a proxy but there is none"),
+            None => {
+                let response = reqwest::get(url).await?.error_for_status()?;
+                Ok(response.bytes().await?.to_vec())
+            }
+        }
+    }
+
+    async fn torrent_add(&self, arguments: AddArguments) -> anyhow::Result<Value> {
+        let session = &self.session;
+        let (torrent, magnet) = match (&arguments.metainfo, &arguments.filename) {
+            (Some(metainfo), _) => {
+                let bytes = base64::decode(metainfo).context("metainfo isn't valid base64")?;
+                (Some(Torrent::from_bytes(&bytes)?), None)
+            }
+            (None, Some(filename)) if filename.starts_with("magnet:") => {
+                (None, Some(filename.parse::<Magnet>()?))
+            }
+            (None, Some(filename))
+                if filename.starts_with("http://") || filename.starts_with("https://") =>
+            {
+                let bytes = self
+                    .download(filename)
+                    .await
+                    .with_context(|| format!("download {filename} fail"))?;
+                (Some(Torrent::from_bytes(&bytes)?), None)
+            }
+            (None, Some(filename)) => (Some(Torrent::new(PathBuf::from(filename))?), None),
+            (None, None) => bail!("no filename or metainfo specified"),
+        };
+
+        let info_hash = match (&torrent, &magnet) {
+            (Some(torrent), _) => torrent.info_hash_bytes(),
+            (None, Some(magnet)) => magnet.info_hash,
+            (None, None) => unreachable!("a torrent or magnet link was parsed above"),
+        };
+        if let Ok(status) = session.status(&info_hash) {
+            return Ok(json!({ "torrent-duplicate": self.summary(&status) }));
+        }
+
+        match (torrent, magnet) {
+            (Some(torrent), _) => {
+                if !is_file_name(&torrent.info.name) {
+                    bail!("invalid torrent name {:?}", torrent.info.name);
+                }
+                let path = arguments
+                    .download_dir
+                    .as_ref()
+                    .map(|dir| dir.join(&torrent.info.name));
+                // the priorities go in with the torrent, so unwanted files are never started.
+                let files = file_indexes(&torrent);
+                let mut priorities = vec![FilePriority::Normal; torrent.files().len()];
+                let mut set = |indexes: &[usize], priority| {
+                    for &index in indexes {
+                        if let Some(&file) = files.get(index) {
+                            priorities[file] = priority;
+                        }
+                    }
+                };
+                set(&arguments.files_wanted, FilePriority::Normal);
+                set(&arguments.priority_low, FilePriority::Low);
+                set(&arguments.priority_normal, FilePriority::Normal);
+                set(&arguments.priority_high, FilePriority::High);
+                set(&arguments.files_unwanted, FilePriority::Skip);
+                match path {
+                    Some(path) => session.add_at(torrent, path, priorities)?,
+                    None => session.add(torrent, priorities)?,
+                };
+            }
+            (None, Some(magnet)) => {
+                if arguments.selects_files() {
+                    bail!("files of a magnet link are selected once its metainfo arrives");
+                }
+                // the display name stands in for the torrent's name, which we don't know yet.
+                if !is_file_name(&magnet.display_name()) {
+                    bail!("invalid torrent name {:?}", magnet.display_name());
+                }
+                let path = arguments
+                    .download_dir
+                    .as_ref()
+                    .map(|dir| dir.join(magnet.display_name()));
+                session.add_magnet(magnet, path)?;
+            }
+            (None, None) => unreachable!("a torrent or magnet link was parsed above"),
+        }
+        if arguments.paused {
+            session.pause(&info_hash).await?;
+        }
+        Ok(json!({ "torrent-added": self.summary(&session.status(&info_hash)?) }))
+    }
+
+    fn torrent_get(&self, arguments: GetArguments) -> anyhow::Result<Value> {
+        let recently_active = arguments
+            .ids
+            .as_ref()
+            .is_some_and(|ids| ids == "recently-active");
+        let mut torrents = Vec::new();
+        for info_hash in self.resolve(arguments.ids.as_ref())? {
+            // removed in the meantime.
+            let Ok(status) = self.session.status(&info_hash) else {
+                continue;
+            };
+            let torrent = self.session.torrent(&info_hash)?;
+            let priorities = self.session.file_priorities(&info_hash)?;
+            let rates = self.session.torrent_rates(&info_hash)?;
+            let fields = self.fields(
+                &status,
+                torrent.as_deref(),
+                &priorities,
+                rates,
+                &arguments.fields,
+            );
+            torrents.push(fields);
+        }
+        let mut response = json!({ "torrents": torrents });
+        if recently_active {
+            // we don't keep track of removed torrents.
+            response["removed"] = json!([]);
+        }
+        Ok(response)
+    }
+
+    /// The `fields` of a torrent, those we don't know are left out as Transmission does.
+    fn fields(
+        &self,
+        status: &TorrentStatus,
+        torrent: Option<&Torrent>,
+        priorities: &[FilePriority],
+        rates: Rates,
+        fields: &[String],
+    ) -> Value {
+        let files: Vec<_> = match torrent {
+            Some(torrent) => torrent
+                .files()
+                .iter()
+                .zip(priorities.iter().copied())
+                .filter(|(file, _)| !file.pad)
+                .collect(),
+            None => Vec::new(),
+        };
+        let total_size: usize = files.iter().map(|(file, _)| file.length).sum();
+        let size_when_done: usize = files
+            .iter()
+            .filter(|(_, priority)| *priority != FilePriority::Skip)
+            .map(|(file, _)| file.length)
+            .sum();
+        let left_until_done = size_when_done.saturating_sub(status.bytes_have);
+        let percent_done = match size_when_done {
+            0 => 0.0,
+            size => (size - left_until_done) as f64 / size as f64,
+        };
+        let error = match &status.state {
+            TorrentState::Failed(err) => err.as_str(),
+            _ => "",
+        };
+
+        let mut object = serde_json::Map::new();
+        for field in fields {
+            let value = match field.as_str() {
+                "id" => json!(self.ids.lock().unwrap().id(status.info_hash)),
+                "hashString" => json!(hex::encode(status.info_hash)),
+                "name" => json!(status.name),
+                "status" => json!(status_code(&status.state)),
+                "error" => json!(if error.is_empty() { 0 } else { ERROR_LOCAL }),
+                "errorString" => json!(error),
+                "downloadDir" => json!(status.path.parent()),
+                "metadataPercentComplete" => json!(if torrent.is_some() { 1.0 } else { 0.0 }),
+                "percentDone" => json!(percent_done),
+                "totalSize" => json!(total_size),
+                "sizeWhenDone" => json!(size_when_done),
+                "leftUntilDone" => json!(left_until_done),
+                "haveValid" => json!(status.bytes_have),
+                "peersConnected" => json!(status.num_peers),
+                "rateDownload" => json!(status.download_rate),
+                "rateUpload" => json!(status.upload_rate),
+                // transmission tells an unknown eta by -1.
+                "eta" => json!(status.eta_secs.map_or(-1, |eta| eta as i64)),
+                "downloadLimit" => json!(rates.download.div_ceil(SPEED_UNIT)),
+                "downloadLimited" => json!(rates.download > 0),
+                "uploadLimit" => json!(rates.upload.div_ceil(SPEED_UNIT)),
+                "uploadLimited" => json!(rates.upload > 0),
+                "pieceCount" => json!(status.num_pieces),
+                "pieceSize" => json!(torrent.map_or(0, |torrent| torrent.info.piece_length)),
+                "files" => json!(files
+                    .iter()
+                    .map(|(file, _)| {
+                        let mut path = file.path.clone();
+                        if torrent.is_some_and(Torrent::is_multi_file) {
+                            path.insert(0, status.name.clone());
+                        }
+                        json!({ "name": path.join("/"), "length": file.length })
+                    })
+                    .collect::<Vec<_>>()),
+                "wanted" => json!(files
+                    .iter()
+                    .map(|(_, priority)| *priority != FilePriority::Skip)
+                    .collect::<Vec<_>>()),
+                "priorities" => json!(files
+                    .iter()
+                    .map(|(_, priority)| match priority {
+                        FilePriority::Low => -1,
+                        FilePriority::Skip | FilePriority::Normal => 0,
+                        FilePriority::High => 1,
+                    })
+                    .collect::<Vec<_>>()),
+                _ => continue,
+            };
+            object.insert(field.clone(), value);
+        }
+        Value::Object(object)
+    }
+
+    /// What torrent-add answers about the torrent it added or already had.
+    fn summary(&self, status: &TorrentStatus) -> Value {
+        json!({
+            "id": self.ids.lock().unwrap().id(status.info_hash),
+            "name": status.name,
+            "hashString": hex::encode(status.info_hash),
+        })
+    }
+
+    /// The torrents `ids` refers to: an id, an info hash, a list of them,
+    /// or every torrent when not given.
+    fn resolve(&self, ids: Option<&Value>) -> anyhow::Result<Vec<[u8; 20]>> {
+        let mut known = self.ids.lock().unwrap();
+        let mut all: Vec<_> = self
+            .session
+            .list()
+            .iter()
+            .map(|status| (known.id(status.info_hash), status.info_hash))
+            .collect();
+        all.sort();
+
+        let ids = match ids {
+            None => return Ok(all.into_iter().map(|(_, info_hash)| info_hash).collect()),
+            // we don't keep track of activity, everything counts as recently active.
+            Some(Value::String(ids)) if ids == "recently-active" => {
+                return Ok(all.into_iter().map(|(_, info_hash)| info_hash).collect())
+            }
+            Some(Value::Array(ids)) => ids.as_slice(),
+            Some(id) => std::slice::from_ref(id),
+        };
+        let mut info_hashes = Vec::with_capacity(ids.len());
+        for id in ids {
+            let info_hash = match id {
+                Value::Number(id) => id
+                    .as_u64()
+                    .and_then(|id| known.info_hash(id))
+                    .with_context(|| format!("no torrent with id {id}"))?,
+                Value::String(info_hash) => hex::decode(info_hash)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .with_context(|| format!("invalid hash string {info_hash}"))?,
+                _ => bail!("ids must be numbers or hash strings"),
+            };
+            info_hashes.push(info_hash);
+        }
+        Ok(info_hashes)
+    }
+}
+
+fn parse_arguments<T: DeserializeOwned>(arguments: Value) -> anyhow::Result<T> {
+    // a request without arguments may leave them out.
+    let arguments = match arguments {
+        Value::Null => json!({}),
+        arguments => arguments,
+    };
+    serde_json::from_value(arguments).context("invalid arguments")
+}
+
+fn status_code(state: &TorrentState) -> u8 {
+    match state {
+        TorrentState::Paused | TorrentState::Failed(_) => STATUS_STOPPED,
+        TorrentState::Checking => STATUS_CHECK,
+        TorrentState::FetchingMetadata | TorrentState::Downloading => STATUS_DOWNLOAD,
+        TorrentState::Seeding => STATUS_SEED,
+    }
+}
+
+/// Where the files Transmission counts are in the torrent's files, skipping the padding.
+fn file_indexes(torrent: &Torrent) -> Vec<usize> {
+    torrent
+        .files()
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| !file.pad)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Deletes a torrent's downloaded file or directory at `path`. Unless the path was `given`
+/// when adding the torrent, it must lie strictly within the `download_dir` once symlinks
+/// are resolved, anything else is refused.
+fn delete_data(path: &Path, given: bool, download_dir: &Path) -> anyhow::Result<()> {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("resolve {} fail", path.display())),
+    };
+    if !given {
+        let download_dir = download_dir
+            .canonicalize()
+            .with_context(|| format!("resolve {} fail", download_dir.display()))?;
+        if path == download_dir || !path.starts_with(&download_dir) {
+            bail!(
+                "refusing to delete {} outside of the download directory",
+                path.display()
+            );
+        }
+    }
+
+    let deleted = if path.is_dir() {
+        std::fs::remove_dir_all(&path)
+    } else {
+        std::fs::remove_file(&path)
+    };
+    match deleted {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("delete {} fail", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proxy,
+        session::{testing, SessionOptions},
+        torrent::testing as torrents,
+        tracker,
+    };
+
+    async fn rpc(download_dir: &Path) -> TransmissionRpc {
+        TransmissionRpc::new(testing::session(download_dir).await).unwrap()
+    }
+
+    /// Answers `method` with `arguments`, failing with the result unless it is a success.
+    async fn call(rpc: &TransmissionRpc, method: &str, arguments: Value) -> Result<Value, String> {
+        let request = json!({ "method": method, "arguments": arguments, "tag": 5 });
+        let response = rpc.handle(request.to_string().as_bytes()).await;
+        assert_eq!(response["tag"], 5);
+        match response["result"].as_str().unwrap() {
+            "success" => Ok(response["arguments"].clone()),
+            result => Err(result.to_string()),
+        }
+    }
+
+    /// Writes `torrent` to a .torrent file in `dir`.
+    fn metainfo_file(dir: &Path, torrent: &Torrent) -> String {
+        let path = dir.join(format!(
+            "{}.torrent",
+            hex::encode(torrent.info_hash_bytes())
+        ));
+        std::fs::write(&path, serde_bencode::to_bytes(torrent).unwrap()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_session_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = rpc(dir.path()).await;
+        let body = br#"{"method":"session-get","arguments":{"fields":["rpc-version"]}}"#;
+        let request = |method: &str, session_id: Option<&str>| HttpRequest {
+            method: method.to_string(),
+            path: TRANSMISSION_RPC_PATH.to_string(),
+            headers: session_id
+                .map(|id| (SESSION_ID_HEADER.to_string(), id.to_string()))
+                .into_iter()
+                .collect(),
+            body: body.to_vec(),
+        };
+        let answer = |request: HttpRequest| {
+            let rpc = &rpc;
+            async move {
+                let mut response = Vec::new();
+                rpc.serve(&request, &mut response).await.unwrap();
+                String::from_utf8(response).unwrap()
+            }
+        };
+
+        let conflict = answer(request("POST", None)).await;
+        assert!(conflict.starts_with("HTTP/1.1 409 Conflict\r\n"));
+        assert!(conflict.contains(&format!("{SESSION_ID_HEADER}: {}\r\n", rpc.session_id)));
+        assert!(answer(request("POST", Some("stale")))
+            .await
+            .starts_with("HTTP/1.1 409"));
+        assert!(answer(request("GET", Some(&rpc.session_id)))
+            .await
+            .starts_with("HTTP/1.1 405"));
+
+        let response = answer(request("POST", Some(&rpc.session_id))).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"arguments":{"rpc-version":17},"result":"success"}"#));
+    }
+
+    #[tokio::test]
+    async fn torrents_are_added_listed_and_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = rpc(dir.path()).await;
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4]), ("b", &[2; 4])], 4);
+        let filename = metainfo_file(dir.path(), &torrent);
+        let hash_string = hex::encode(torrent.info_hash_bytes());
+
+        let arguments = json!({ "filename": filename, "files-unwanted": [1], "paused": true });
+        let added = call(&rpc, "torrent-add", arguments).await.unwrap();
+        assert_eq!(
+            added,
+            json!({ "torrent-added": { "id": 1, "name": "dir", "hashString": hash_string } })
+        );
+        let duplicate = call(&rpc, "torrent-add", json!({ "filename": filename })).await;
+        assert_eq!(duplicate.unwrap()["torrent-duplicate"]["id"], 1);
+
+        let fields = [
+            "id",
+            "name",
+            "status",
+            "totalSize",
+            "sizeWhenDone",
+            "wanted",
+            "files",
+        ];
+        let arguments = json!({ "fields": fields, "ids": [hash_string] });
+        let got = call(&rpc, "torrent-get", arguments).await.unwrap();
+        assert_eq!(
+            got["torrents"][0],
+            json!({
+                "id": 1,
+                "name": "dir",
+                "status": STATUS_STOPPED,
+                "totalSize": 8,
+                "sizeWhenDone": 4,
+                "wanted": [true, false],
+                "files": [{ "name": "dir/a", "length": 4 }, { "name": "dir/b", "length": 4 }],
+            })
+        );
+
+        call(&rpc, "torrent-start", json!({ "ids": 1 }))
+            .await
+            .unwrap();
+        let info_hash = torrent.info_hash_bytes();
+        testing::checked(&rpc.session, &info_hash).await;
+        let arguments = json!({ "fields": ["status"], "ids": "recently-active" });
+        let got = call(&rpc, "torrent-get", arguments).await.unwrap();
+        assert_eq!(
+            got,
+            json!({ "torrents": [{ "status": STATUS_DOWNLOAD }], "removed": [] })
+        );
+
+        assert_eq!(
+            call(&rpc, "torrent-stop", json!({ "ids": [2] })).await,
+            Err("no torrent with id 2".to_string())
+        );
+        assert_eq!(
+            call(&rpc, "torrent-frobnicate", Value::Null).await,
+            Err("method name not recognized".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn torrents_added_by_url_are_downloaded_through_the_proxy() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4])], 4);
+        let (url, mut requests) =
+            tracker::testing::serve_body(serde_bencode::to_bytes(&torrent).unwrap()).await;
+        let (socks, mut targets) = proxy::testing::socks5_server("user", "secret").await;
+        let session = Session::start(SessionOptions {
+            port: 0,
+            download_dir: dir.path().to_path_buf(),
+            transport: TransportPreference::TcpOnly,
+            dht: false,
+            lsd: false,
+            proxy: Some(Arc::new(
+                format!("socks5://user:secret@{socks}").parse().unwrap(),
+            )),
+            force_proxy: true,
+            ..SessionOptions::default()
+        })
+        .await
+        .unwrap();
+        let rpc = TransmissionRpc::new(Arc::new(session)).unwrap();
+
+        let arguments = json!({ "filename": url, "paused": true });
+        let added = call(&rpc, "torrent-add", arguments).await.unwrap();
+        assert_eq!(added["torrent-added"]["name"], "dir");
+        assert_eq!(requests.recv().await.unwrap(), "/announce");
+        let server = url
+            .trim_start_matches("http://")
+            .trim_end_matches("/announce");
+        assert_eq!(targets.recv().await.unwrap(), server);
+    }
+
+    #[tokio::test]
+    async fn unwanted_files_of_started_torrents_are_not_downloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = rpc(dir.path()).await;
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4]), ("b", &[2; 4])], 4);
+        let arguments = json!({
+            "filename": metainfo_file(dir.path(), &torrent),
+            "files-unwanted": [0],
+            "priority-high": [1],
+        });
+        call(&rpc, "torrent-add", arguments).await.unwrap();
+
+        let info_hash = torrent.info_hash_bytes();
+        testing::checked(&rpc.session, &info_hash).await;
+        assert!(!dir.path().join("dir/a").exists());
+        assert!(dir.path().join("dir/b").exists());
+        assert_eq!(testing::pieces_wanted(&rpc.session, &info_hash).await, 1);
+        assert_eq!(
+            rpc.session.file_priorities(&info_hash).unwrap(),
+            [FilePriority::Skip, FilePriority::High]
+        );
+    }
+
+    #[tokio::test]
+    async fn torrents_named_like_paths_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = rpc(dir.path()).await;
+        let torrent = torrents::torrent("..", &[("a", &[1; 4])], 4);
+        let arguments = json!({
+            "filename": metainfo_file(dir.path(), &torrent),
+            "download-dir": dir.path().join("sub"),
+        });
+        let refused = call(&rpc, "torrent-add", arguments).await.unwrap_err();
+        assert_eq!(refused, "invalid torrent name \"..\"");
+        assert!(rpc.session.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn removing_deletes_the_downloaded_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = rpc(dir.path()).await;
+        let torrent = torrents::torrent("dir", &[("a", &[1; 4]), ("b", &[2; 4])], 4);
+        let filename = metainfo_file(dir.path(), &torrent);
+        call(&rpc, "torrent-add", json!({ "filename": filename }))
+            .await
+            .unwrap();
+        testing::checked(&rpc.session, &torrent.info_hash_bytes()).await;
+        assert!(dir.path().join("dir/a").exists());
+
+        let arguments = json!({ "ids": [1], "delete-local-data": true });
+        call(&rpc, "torrent-remove", arguments).await.unwrap();
+        assert!(!dir.path().join("dir").exists());
+        assert!(rpc.session.list().is_empty());
+        assert!(Path::new(&filename).exists());
+    }
+
+    #[test]
+    fn only_data_within_the_download_dir_or_at_the_given_path_is_deleted() {
+        let root = tempfile::tempdir().unwrap();
+        let (downloads, elsewhere) = (root.path().join("downloads"), root.path().join("elsewhere"));
+        std::fs::create_dir_all(downloads.join("torrent")).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        std::os::unix::fs::symlink(&elsewhere, downloads.join("link")).unwrap();
+
+        for path in [
+            downloads.clone(),
+            downloads.join("."),
+            downloads.join("torrent/../.."),
+            elsewhere.clone(),
+            downloads.join("link"),
+        ] {
+            let err = delete_data(&path, false, &downloads).unwrap_err();
+            assert!(
+                err.to_string().starts_with("refusing to delete"),
+                "{path:?}"
+            );
+        }
+        assert!(elsewhere.exists());
+
+        delete_data(&downloads.join("missing"), false, &downloads).unwrap();
+        delete_data(&downloads.join("torrent"), false, &downloads).unwrap();
+        assert!(!downloads.join("torrent").exists());
+        // a path the torrent was added with is the user's choice.
+        delete_data(&elsewhere, true, &downloads).unwrap();
+        assert!(!elsewhere.exists());
+    }
+}
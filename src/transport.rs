@@ -0,0 +1,187 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    proxy::Proxy,
+    torrent::generate_peer_id,
+    utp::{UtpSocket, UtpStream},
+};
+
+/// How long each transport gets to connect before we try the next one.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to a peer over either transport.
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl From<TcpStream> for PeerStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<UtpStream> for PeerStream {
+    fn from(stream: UtpStream) -> Self {
+        Self::Utp(stream)
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Which transport we connect to peers with first, the other one is the fallback.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportPreference {
+    #[default]
+    Tcp,
+    /// uTP backs off when it sees the network getting congested,
+    /// so the transfers don't get in the way of other traffic.
+    Utp,
+    TcpOnly,
+    UtpOnly,
+}
+
+impl FromStr for TransportPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "utp" => Ok(Self::Utp),
+            "tcp-only" => Ok(Self::TcpOnly),
+            "utp-only" => Ok(Self::UtpOnly),
+            _ => bail!("transport must be tcp, utp, tcp-only or utp-only"),
+        }
+    }
+}
+
+impl fmt::Display for TransportPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Utp => "utp",
+            Self::TcpOnly => "tcp-only",
+            Self::UtpOnly => "utp-only",
+        })
+    }
+}
+
+/// The transports we connect to peers with, uTP needs a socket.
+#[derive(Clone)]
+pub struct Transports {
+    pub utp: Option<Arc<UtpSocket>>,
+    pub preference: TransportPreference,
+    /// Connections go over TCP through the proxy, uTP doesn't go through proxies.
+    pub proxy: Option<Arc<Proxy>>,
+    /// The peer id we send in our handshakes, peers sending it back are ourselves.
+    pub peer_id: [u8; 20],
+}
+
+impl Transports {
+    /// Direct TCP and uTP connections, identified by a new peer id.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            utp: None,
+            preference: TransportPreference::default(),
+            proxy: None,
+            peer_id: generate_peer_id()?,
+        })
+    }
+
+    /// Connects to `addr` with the preferred transport, falling back to the other one.
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<PeerStream> {
+        if let Some(proxy) = &self.proxy {
+            let host = addr.ip().to_string();
+            let connecting = proxy.connect(&host, addr.port());
+            return match timeout(CONNECT_TIMEOUT, connecting).await {
+                Ok(stream) => Ok(PeerStream::from(stream?)),
+                Err(_) => bail!("connecting to {addr} through {proxy} timed out"),
+            };
+        }
+        let utp_first = matches!(
+            self.preference,
+            TransportPreference::Utp | TransportPreference::UtpOnly
+        );
+        let order: &[bool] = match self.preference {
+            TransportPreference::TcpOnly | TransportPreference::UtpOnly => &[utp_first],
+            _ => &[utp_first, !utp_first],
+        };
+
+        let mut last_error = None;
+        for &utp in order {
+            let socket = match (&self.utp, utp) {
+                (Some(socket), true) => Some(socket),
+                (None, true) => continue,
+                (_, false) => None,
+            };
+            let connecting = async {
+                match socket {
+                    Some(socket) => anyhow::Ok(PeerStream::from(socket.connect(addr).await?)),
+                    None => anyhow::Ok(PeerStream::from(TcpStream::connect(addr).await?)),
+                }
+            };
+            match timeout(CONNECT_TIMEOUT, connecting).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => last_error = Some(err),
+                Err(_) => last_error = Some(anyhow!("connecting to {addr} timed out")),
+            }
+        }
+        match last_error {
+            Some(err) => Err(err),
+            None => bail!("no transport to connect to {addr} with"),
+        }
+    }
+}
@@ -0,0 +1,1153 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::poll_fn,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context as TaskContext, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::mpsc,
+};
+
+use crate::{random, warn};
+
+/// The packet types of the micro transport protocol (BEP 29).
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+
+/// The extension carrying the selective acks.
+const SELECTIVE_ACK: u8 = 1;
+
+/// The largest payload of a packet, so packets fit in an ethernet frame.
+const MAX_PAYLOAD: usize = 1400;
+
+/// LEDBAT aims to add at most this much delay to the path, backing off when it sees more.
+const TARGET_DELAY: i64 = 100_000;
+
+/// How much the congestion window grows per round trip at most, in bytes.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1 << 20;
+
+/// How much we buffer on each side of a connection.
+const SEND_BUFFER: usize = 1 << 18;
+const RECEIVE_BUFFER: usize = 1 << 20;
+
+/// Packets further ahead than this are dropped instead of kept until the gap is filled.
+const MAX_REORDER: u16 = 1024;
+
+/// The longest selective ack we send, in bytes.
+const MAX_SELECTIVE_ACK: usize = 32;
+
+/// The retransmission timeout before we measured the round trip time, and its bounds.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A packet sent this many times without an ack gives up the connection,
+/// the syn has fewer tries so connecting falls back to tcp quickly.
+const MAX_TRANSMISSIONS: u32 = 6;
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+
+/// We send a keepalive after this long without sending anything,
+/// and give up on a connection we received nothing from for much longer.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(29);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often the connections check their timers.
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The lowest delay is remembered for this long, to follow route changes.
+const BASE_DELAY_HISTORY: Duration = Duration::from_secs(60);
+
+/// Incoming connections waiting to be accepted, more are refused.
+const ACCEPT_BACKLOG: usize = 64;
+
+type SharedConnection = Arc<Mutex<Connection>>;
+
+/// A UDP socket carrying uTP connections, both the ones we open and the ones we accept.
+/// Peers expect it on the same port as the TCP listener.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+
+    /// The connections, keyed by the peer's address and the connection id it sends us.
+    connections: Mutex<HashMap<(SocketAddr, u16), SharedConnection>>,
+
+    incoming: tokio::sync::Mutex<mpsc::Receiver<SharedConnection>>,
+    incoming_sender: mpsc::Sender<SharedConnection>,
+
+    /// The epoch of our timestamps.
+    start: Instant,
+}
+
+impl UtpSocket {
+    pub async fn bind(port: u16) -> anyhow::Result<Arc<Self>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+            .await
+            .with_context(|| format!("bind utp socket on port {port} fail"))?;
+        let (incoming_sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let utp = Arc::new(Self {
+            socket: Arc::new(socket),
+            connections: Mutex::default(),
+            incoming: tokio::sync::Mutex::new(incoming),
+            incoming_sender,
+            start: Instant::now(),
+        });
+        tokio::spawn(run(utp.socket.clone(), Arc::downgrade(&utp)));
+        Ok(utp)
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Opens a connection to `addr`, returning once the peer answered our syn.
+    pub async fn connect(self: &Arc<Self>, addr: SocketAddr) -> anyhow::Result<UtpStream> {
+        let connection = {
+            let mut connections = self.connections.lock().unwrap();
+            let receive_id = loop {
+                let id = u16::from_be_bytes(random::bytes()?);
+                if !connections.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let mut connection = Connection::new(
+                self.socket.clone(),
+                addr,
+                self.start,
+                receive_id,
+                receive_id.wrapping_add(1),
+                1,
+            );
+            connection.state = State::SynSent;
+            connection.send_syn();
+            let connection = Arc::new(Mutex::new(connection));
+            connections.insert((addr, receive_id), connection.clone());
+            connection
+        };
+
+        poll_fn(|cx| {
+            let mut connection = connection.lock().unwrap();
+            match connection.state {
+                State::SynSent => {
+                    connection.connect_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Failed(kind) => Poll::Ready(Err(io::Error::from(kind))),
+                _ => Poll::Ready(Ok(())),
+            }
+        })
+        .await
+        .with_context(|| format!("utp connection to {addr} fail"))?;
+
+        Ok(UtpStream {
+            connection,
+            _socket: self.clone(),
+        })
+    }
+
+    /// Waits for a peer to connect to us.
+    pub async fn accept(self: &Arc<Self>) -> anyhow::Result<UtpStream> {
+        match self.incoming.lock().await.recv().await {
+            Some(connection) => Ok(UtpStream {
+                connection,
+                _socket: self.clone(),
+            }),
+            None => bail!("utp socket closed"),
+        }
+    }
+
+    /// Hands a packet to its connection, or starts a connection for a syn.
+    fn dispatch(&self, packet: Packet, from: SocketAddr) {
+        let now = Instant::now();
+        let mut connections = self.connections.lock().unwrap();
+
+        if packet.kind == ST_SYN {
+            // the syn carries the id the peer receives with, it sends the other packets with one more.
+            let receive_id = packet.connection_id.wrapping_add(1);
+            if let Some(connection) = connections.get(&(from, receive_id)) {
+                // our answer got lost.
+                connection.lock().unwrap().send_state();
+                return;
+            }
+            if self.incoming_sender.capacity() == 0 {
+                return;
+            }
+
+            let send_id = match random::bytes() {
+                Ok(id) => u16::from_be_bytes(id),
+                Err(err) => {
+                    warn!("dropping the utp syn of {from}: {err}");
+                    return;
+                }
+            };
+            let mut connection = Connection::new(
+                self.socket.clone(),
+                from,
+                self.start,
+                receive_id,
+                packet.connection_id,
+                send_id,
+            );
+            connection.state = State::Connected;
+            connection.ack_nr = packet.seq_nr;
+            connection.on_timestamps(&packet, now);
+            connection.send_state();
+            let connection = Arc::new(Mutex::new(connection));
+            if self.incoming_sender.try_send(connection.clone()).is_ok() {
+                connections.insert((from, receive_id), connection);
+            }
+            return;
+        }
+
+        match connections.get(&(from, packet.connection_id)) {
+            Some(connection) => connection.lock().unwrap().on_packet(packet, now),
+            None if packet.kind != ST_RESET => {
+                // tell the peer to forget about a connection we don't know.
+                let reset = Packet {
+                    kind: ST_RESET,
+                    connection_id: packet.connection_id,
+                    timestamp: micros_since(self.start),
+                    timestamp_difference: 0,
+                    window: 0,
+                    seq_nr: 0,
+                    ack_nr: packet.seq_nr,
+                    selective_ack: None,
+                    payload: Vec::new(),
+                };
+                self.socket.try_send_to(&reset.to_bytes(), from).ok();
+            }
+            None => {}
+        }
+    }
+
+    /// Runs the timers of the connections, forgetting the ones that are done.
+    fn tick(&self) {
+        let now = Instant::now();
+        self.connections.lock().unwrap().retain(|_, connection| {
+            let mut connection = connection.lock().unwrap();
+            connection.tick(now);
+            !connection.is_finished()
+        });
+    }
+}
+
+/// Reads the packets sent to the socket and runs the timers, until the socket is dropped.
+async fn run(socket: Arc<UdpSocket>, utp: Weak<UtpSocket>) {
+    let mut buffer = vec![0; 1 << 16];
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let Some(utp) = utp.upgrade() else {
+                    return;
+                };
+                if let Ok((length, from)) = received {
+                    if let Some(packet) = Packet::parse(&buffer[..length]) {
+                        utp.dispatch(packet, from);
+                    }
+                }
+            }
+            _ = tick.tick() => {
+                let Some(utp) = utp.upgrade() else {
+                    return;
+                };
+                utp.tick();
+            }
+        }
+    }
+}
+
+/// A uTP connection, read and written like a TCP stream.
+pub struct UtpStream {
+    connection: Arc<Mutex<Connection>>,
+    /// The socket delivers our packets for as long as we are around.
+    _socket: Arc<UtpSocket>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.lock().unwrap().addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        if !connection.received.is_empty() {
+            let window_was_closed = connection.window() < MAX_PAYLOAD;
+            let length = connection.received.len().min(buf.remaining());
+            let (front, back) = connection.received.as_slices();
+            let from_front = length.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..length - from_front]);
+            connection.received.drain(..length);
+            // let the peer know it can send again.
+            if window_was_closed {
+                connection.send_state();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        match connection.state {
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+            _ if connection.eof => Poll::Ready(Ok(())),
+            _ => {
+                connection.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut connection = self.connection.lock().unwrap();
+        if let State::Failed(kind) = connection.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if connection.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let length = buf.len().min(SEND_BUFFER - connection.unsent.len());
+        if length == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        connection.unsent.extend(&buf[..length]);
+        connection.send_data(Instant::now());
+        Poll::Ready(Ok(length))
+    }
+
+    /// Packets go out as fast as the congestion window allows, there's nothing to flush.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        connection.closing = true;
+        connection.send_data(Instant::now());
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    /// The data we wrote is still delivered, then the connection is closed.
+    fn drop(&mut self) {
+        let mut connection = self.connection.lock().unwrap();
+        connection.dropped = true;
+        connection.closing = true;
+        connection.send_data(Instant::now());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Failed(io::ErrorKind),
+}
+
+/// A packet we sent that wasn't acked yet.
+struct Sent {
+    kind: u8,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// The state of a single uTP connection, shared between its stream and the socket.
+struct Connection {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    start: Instant,
+    state: State,
+
+    /// The connection id the peer sends us, and the one we send it.
+    receive_id: u16,
+    send_id: u16,
+
+    /// The sequence number of our next packet, and the last one of the peer we received in order.
+    seq_nr: u16,
+    ack_nr: u16,
+
+    /// Data written to the stream that is not in a packet yet.
+    unsent: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+
+    /// The congestion window, the bytes we may have in flight.
+    max_window: usize,
+    /// The receive window the peer advertised.
+    peer_window: usize,
+
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    /// When the oldest packet in flight times out.
+    timeout_at: Option<Instant>,
+    /// Acks in a row that acked nothing new, three of them mean a packet was lost.
+    duplicate_acks: u32,
+    last_window_decrease: Instant,
+
+    /// The delay between the peer's timestamp and our clock, sent back to it in each packet.
+    reply_micro: u32,
+    /// The lowest delay the peer measured for our packets over the current and previous minute.
+    base_delays: VecDeque<(Instant, u32)>,
+
+    /// Data received in order, waiting to be read.
+    received: VecDeque<u8>,
+    /// Data received after a gap, by sequence number.
+    out_of_order: BTreeMap<u16, Vec<u8>>,
+    /// The sequence number of the peer's fin.
+    fin_nr: Option<u16>,
+    /// The peer closed the connection and everything it sent was read.
+    eof: bool,
+
+    /// We won't write anymore, a fin follows the data.
+    closing: bool,
+    fin_sent: bool,
+    /// The stream is gone, the connection only lives on to deliver what was written.
+    dropped: bool,
+
+    last_sent: Instant,
+    last_received: Instant,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connect_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(
+        socket: Arc<UdpSocket>,
+        addr: SocketAddr,
+        start: Instant,
+        receive_id: u16,
+        send_id: u16,
+        seq_nr: u16,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            socket,
+            addr,
+            start,
+            state: State::Connected,
+            receive_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            unsent: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            max_window: MIN_WINDOW * 2,
+            peer_window: RECEIVE_BUFFER,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            timeout_at: None,
+            duplicate_acks: 0,
+            last_window_decrease: now,
+            reply_micro: 0,
+            base_delays: VecDeque::new(),
+            received: VecDeque::new(),
+            out_of_order: BTreeMap::new(),
+            fin_nr: None,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            dropped: false,
+            last_sent: now,
+            last_received: now,
+            read_waker: None,
+            write_waker: None,
+            connect_waker: None,
+        }
+    }
+
+    /// The receive window we advertise.
+    fn window(&self) -> usize {
+        RECEIVE_BUFFER.saturating_sub(self.received.len())
+    }
+
+    fn in_flight_bytes(&self) -> usize {
+        self.in_flight.iter().map(|sent| sent.payload.len()).sum()
+    }
+
+    fn send_syn(&mut self) {
+        self.send_new(ST_SYN, Vec::new(), Instant::now());
+    }
+
+    /// Acks what we received so far.
+    fn send_state(&mut self) {
+        self.transmit(ST_STATE, self.seq_nr, &[]);
+    }
+
+    /// Sends a packet that takes a sequence number and has to be acked.
+    fn send_new(&mut self, kind: u8, payload: Vec<u8>, now: Instant) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(kind, seq_nr, &payload);
+        self.in_flight.push_back(Sent {
+            kind,
+            seq_nr,
+            payload,
+            sent_at: now,
+            transmissions: 1,
+        });
+        self.timeout_at.get_or_insert(now + self.timeout);
+    }
+
+    /// Puts the unsent data in packets, as far as the windows allow, followed by the fin when closing.
+    fn send_data(&mut self, now: Instant) {
+        if self.state != State::Connected {
+            return;
+        }
+        let window = self.max_window.min(self.peer_window);
+        let mut in_flight = self.in_flight_bytes();
+        while !self.unsent.is_empty() {
+            let length = self.unsent.len().min(MAX_PAYLOAD);
+            // one packet is always allowed, or a closed window would never reopen.
+            if in_flight + length > window && !self.in_flight.is_empty() {
+                break;
+            }
+            let payload: Vec<u8> = self.unsent.drain(..length).collect();
+            self.send_new(ST_DATA, payload, now);
+            in_flight += length;
+        }
+        if self.unsent.len() < SEND_BUFFER {
+            wake(&mut self.write_waker);
+        }
+        if self.closing && self.unsent.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            self.send_new(ST_FIN, Vec::new(), now);
+        }
+    }
+
+    fn transmit(&mut self, kind: u8, seq_nr: u16, payload: &[u8]) {
+        let packet = Packet {
+            kind,
+            connection_id: if kind == ST_SYN {
+                self.receive_id
+            } else {
+                self.send_id
+            },
+            timestamp: micros_since(self.start),
+            timestamp_difference: self.reply_micro,
+            window: self.window() as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: self.selective_ack(),
+            payload: payload.to_vec(),
+        };
+        // a full socket buffer is like a lost packet, it is sent again later.
+        self.socket.try_send_to(&packet.to_bytes(), self.addr).ok();
+        self.last_sent = Instant::now();
+    }
+
+    /// A bitmask of the packets we received past the gap, the first bit is ack_nr + 2.
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0u8; 4];
+        for &seq_nr in self.out_of_order.keys() {
+            let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if bit >= MAX_SELECTIVE_ACK * 8 {
+                continue;
+            }
+            if bit >= mask.len() * 8 {
+                mask.resize((bit / 32 + 1) * 4, 0);
+            }
+            mask[bit / 8] |= 1 << (bit % 8);
+        }
+        Some(mask)
+    }
+
+    fn on_timestamps(&mut self, packet: &Packet, now: Instant) {
+        self.reply_micro = micros_since(self.start).wrapping_sub(packet.timestamp);
+        self.last_received = now;
+    }
+
+    fn on_packet(&mut self, packet: Packet, now: Instant) {
+        self.on_timestamps(&packet, now);
+        self.peer_window = packet.window as usize;
+
+        match packet.kind {
+            ST_RESET => {
+                self.fail(io::ErrorKind::ConnectionReset);
+                return;
+            }
+            ST_STATE if self.state == State::SynSent => {
+                self.state = State::Connected;
+                // the peer's first data packet has the sequence number of this ack.
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                wake(&mut self.connect_waker);
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            return;
+        }
+
+        self.on_ack(&packet, now);
+        if packet.kind == ST_DATA || packet.kind == ST_FIN {
+            self.on_data(packet);
+            self.send_state();
+        }
+        self.send_data(now);
+    }
+
+    /// Forgets the packets the peer acked, measures the round trip and adjusts the window.
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        let mut acked = |sent: &Sent| {
+            acked_bytes += sent.payload.len();
+            if sent.transmissions == 1 {
+                rtt_sample = Some(now - sent.sent_at);
+            }
+        };
+
+        while let Some(sent) = self.in_flight.front() {
+            if !seq_less_or_equal(sent.seq_nr, packet.ack_nr) {
+                break;
+            }
+            acked(sent);
+            self.in_flight.pop_front();
+        }
+
+        // packets acked selectively are done too, the ones before them were probably lost.
+        let mut selectively_acked = 0;
+        if let Some(mask) = &packet.selective_ack {
+            let first = packet.ack_nr.wrapping_add(2);
+            self.in_flight.retain(|sent| {
+                let bit = sent.seq_nr.wrapping_sub(first) as usize;
+                let is_acked = bit < mask.len() * 8 && mask[bit / 8] & (1 << (bit % 8)) != 0;
+                if is_acked {
+                    acked(sent);
+                }
+                !is_acked
+            });
+            selectively_acked = mask.iter().map(|byte| byte.count_ones()).sum();
+        }
+
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+        if acked_bytes > 0 {
+            self.duplicate_acks = 0;
+            self.timeout_at = self.in_flight.front().map(|_| now + self.timeout);
+            self.update_window(packet.timestamp_difference, acked_bytes, now);
+        } else if packet.kind == ST_STATE && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+        }
+
+        if self.duplicate_acks >= 3 || selectively_acked >= 3 {
+            self.duplicate_acks = 0;
+            self.on_loss(now);
+            let rtt = self.rtt.unwrap_or(INITIAL_TIMEOUT);
+            // it was sent again recently, give it time to arrive.
+            if let Some(sent) = self
+                .in_flight
+                .front_mut()
+                .filter(|sent| now - sent.sent_at >= rtt)
+            {
+                sent.transmissions += 1;
+                sent.sent_at = now;
+                let (kind, seq_nr, payload) = (sent.kind, sent.seq_nr, sent.payload.clone());
+                self.transmit(kind, seq_nr, &payload);
+            }
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.max(sample) - rtt.min(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        let rtt = self.rtt.unwrap_or(sample);
+        self.timeout = (rtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /// LEDBAT: the window grows while the delay our packets see stays below the target,
+    /// and shrinks when it goes above, so we back off before the queues on the path fill up.
+    fn update_window(&mut self, delay: u32, acked_bytes: usize, now: Instant) {
+        if delay != 0 {
+            match self.base_delays.back_mut() {
+                Some((since, lowest)) if now - *since < BASE_DELAY_HISTORY => {
+                    *lowest = (*lowest).min(delay);
+                }
+                _ => {
+                    self.base_delays.push_back((now, delay));
+                    if self.base_delays.len() > 2 {
+                        self.base_delays.pop_front();
+                    }
+                }
+            }
+        }
+        // the peer didn't measure a delay yet.
+        let our_delay = match self.base_delays.iter().map(|(_, lowest)| *lowest).min() {
+            Some(base_delay) if delay != 0 => delay.wrapping_sub(base_delay) as i32 as i64,
+            _ => 0,
+        };
+
+        let off_target = (TARGET_DELAY - our_delay) as f64 / TARGET_DELAY as f64;
+        let window_factor =
+            acked_bytes.min(self.max_window) as f64 / acked_bytes.max(self.max_window) as f64;
+        let gain = MAX_WINDOW_INCREASE * off_target.max(-1.0) * window_factor;
+        self.max_window =
+            (self.max_window as f64 + gain).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    /// Halves the window, at most once per round trip.
+    fn on_loss(&mut self, now: Instant) {
+        if now - self.last_window_decrease < self.rtt.unwrap_or(INITIAL_TIMEOUT) {
+            return;
+        }
+        self.last_window_decrease = now;
+        self.max_window = (self.max_window / 2).max(MIN_WINDOW);
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        if packet.kind == ST_FIN {
+            self.fin_nr = Some(packet.seq_nr);
+        }
+
+        let distance = packet.seq_nr.wrapping_sub(self.ack_nr);
+        if distance == 0 || distance > MAX_REORDER {
+            // a duplicate, or too far ahead.
+            return;
+        }
+        // the reader is too slow, the peer sends it again once we ack it.
+        if self.received.len() + packet.payload.len() > RECEIVE_BUFFER {
+            return;
+        }
+        self.out_of_order.insert(packet.seq_nr, packet.payload);
+
+        while let Some(payload) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.received.extend(payload);
+        }
+        if self.fin_nr == Some(self.ack_nr) {
+            self.eof = true;
+        }
+        if !self.received.is_empty() || self.eof {
+            wake(&mut self.read_waker);
+        }
+    }
+
+    /// Sends packets again that weren't acked in time, and keeps the connection alive.
+    fn tick(&mut self, now: Instant) {
+        if let State::Failed(_) = self.state {
+            return;
+        }
+        if now - self.last_received > IDLE_TIMEOUT {
+            self.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+
+        if self.timeout_at.is_some_and(|at| now >= at) {
+            let Some(sent) = self.in_flight.front_mut() else {
+                self.timeout_at = None;
+                return;
+            };
+            let limit = if sent.kind == ST_SYN {
+                MAX_SYN_TRANSMISSIONS
+            } else {
+                MAX_TRANSMISSIONS
+            };
+            if sent.transmissions >= limit {
+                self.fail(io::ErrorKind::TimedOut);
+                return;
+            }
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            let (kind, seq_nr, payload) = (sent.kind, sent.seq_nr, sent.payload.clone());
+            self.transmit(kind, seq_nr, &payload);
+
+            // a timeout means heavy congestion, start over with the smallest window.
+            self.max_window = MIN_WINDOW;
+            self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+            self.timeout_at = Some(now + self.timeout);
+        } else if self.state == State::Connected && now - self.last_sent > KEEPALIVE_INTERVAL {
+            self.send_state();
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Failed(kind);
+        self.in_flight.clear();
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+        wake(&mut self.connect_waker);
+    }
+
+    /// The socket can forget the connection, it failed or nobody uses it and everything was delivered.
+    fn is_finished(&self) -> bool {
+        match self.state {
+            // the stream still has the connection to report the error.
+            State::Failed(_) => true,
+            _ => self.dropped && self.fin_sent && self.in_flight.is_empty(),
+        }
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// Whether sequence number `a` comes before `b` or is `b`, they wrap around.
+fn seq_less_or_equal(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+fn micros_since(start: Instant) -> u32 {
+    start.elapsed().as_micros() as u32
+}
+
+struct Packet {
+    kind: u8,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_difference: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    selective_ack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LENGTH || bytes[0] & 0x0f != VERSION || bytes[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        // the extensions are a linked list of type, length and data.
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut at = HEADER_LENGTH;
+        while extension != 0 {
+            let next = *bytes.get(at)?;
+            let length = *bytes.get(at + 1)? as usize;
+            let data = bytes.get(at + 2..at + 2 + length)?;
+            if extension == SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            at += 2 + length;
+        }
+
+        Some(Self {
+            kind: bytes[0] >> 4,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: bytes[at..].to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len() + 40);
+        bytes.push((self.kind << 4) | VERSION);
+        bytes.push(if self.selective_ack.is_some() {
+            SELECTIVE_ACK
+        } else {
+            0
+        });
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_difference.to_be_bytes());
+        bytes.extend(self.window.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend(mask);
+        }
+        bytes.extend(&self.payload);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn connection() -> Connection {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        Connection::new(Arc::new(socket), addr, Instant::now(), 1, 2, 1)
+    }
+
+    fn packet(kind: u8, seq_nr: u16, payload: &[u8]) -> Packet {
+        Packet {
+            kind,
+            connection_id: 1,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: RECEIVE_BUFFER as u32,
+            seq_nr,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// Two sockets on the loopback, the second accepting a connection from the first.
+    async fn pair() -> (UtpStream, UtpStream) {
+        let (client, server) = (
+            UtpSocket::bind(0).await.unwrap(),
+            UtpSocket::bind(0).await.unwrap(),
+        );
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port()));
+        let (connected, accepted) = tokio::join!(client.connect(addr), server.accept());
+        (connected.unwrap(), accepted.unwrap())
+    }
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn packets_round_trip_with_their_selective_ack() {
+        let mut sent = packet(ST_DATA, 513, b"hello");
+        sent.ack_nr = 65535;
+        sent.selective_ack = Some(vec![0b101, 0, 0, 0x80]);
+        let bytes = sent.to_bytes();
+        assert_eq!(bytes[0], (ST_DATA << 4) | VERSION);
+        assert_eq!(&bytes[HEADER_LENGTH..HEADER_LENGTH + 2], [0, 4]);
+
+        let parsed = Packet::parse(&bytes).unwrap();
+        assert_eq!((parsed.seq_nr, parsed.ack_nr), (513, 65535));
+        assert_eq!(parsed.selective_ack, sent.selective_ack);
+        assert_eq!(parsed.payload, b"hello");
+
+        // a truncated extension or another version is no packet.
+        assert!(Packet::parse(&bytes[..HEADER_LENGTH + 3]).is_none());
+        let mut other_version = bytes.clone();
+        other_version[0] = (ST_DATA << 4) | 2;
+        assert!(Packet::parse(&other_version).is_none());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        assert!(seq_less_or_equal(65535, 0));
+        assert!(seq_less_or_equal(65000, 10));
+        assert!(seq_less_or_equal(7, 7));
+        assert!(!seq_less_or_equal(10, 65000));
+    }
+
+    #[tokio::test]
+    async fn reordered_data_is_delivered_in_order_across_the_wrap() {
+        let mut connection = connection().await;
+        connection.ack_nr = 65533;
+        for seq_nr in [1, 65535, 0, 65534] {
+            connection.on_data(packet(ST_DATA, seq_nr, &[seq_nr as u8]));
+        }
+        assert_eq!(connection.ack_nr, 1);
+        assert_eq!(Vec::from(connection.received.clone()), [254, 255, 0, 1]);
+        assert!(connection.out_of_order.is_empty());
+
+        // duplicates and packets too far ahead are dropped.
+        connection.on_data(packet(ST_DATA, 1, &[9]));
+        connection.on_data(packet(ST_DATA, 1 + MAX_REORDER + 1, &[9]));
+        assert!(connection.out_of_order.is_empty());
+        connection.on_data(packet(ST_DATA, 1 + MAX_REORDER, &[9]));
+        assert_eq!(connection.out_of_order.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn selective_acks_mark_the_packets_past_the_gap() {
+        let mut connection = connection().await;
+        assert_eq!(connection.selective_ack(), None);
+
+        connection.ack_nr = 65534;
+        // the first bit is ack_nr + 2, which wrapped to 0.
+        for seq_nr in [0, 2, 31] {
+            connection.on_data(packet(ST_DATA, seq_nr, b"x"));
+        }
+        assert_eq!(connection.selective_ack().unwrap(), [0b101, 0, 0, 0x80]);
+
+        // the mask grows by four bytes, up to its limit.
+        connection.on_data(packet(ST_DATA, 32, b"x"));
+        assert_eq!(connection.selective_ack().unwrap().len(), 8);
+        connection.on_data(packet(ST_DATA, 600, b"x"));
+        assert_eq!(connection.selective_ack().unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn acks_forget_the_packets_they_cover() {
+        let mut connection = connection().await;
+        connection.seq_nr = 65534;
+        let now = Instant::now();
+        for _ in 0..6 {
+            connection.send_new(ST_DATA, b"x".to_vec(), now);
+        }
+        // 65534 to 3 are in flight, ack up to 65535 and select 1 and 3.
+        let mut ack = packet(ST_STATE, 0, &[]);
+        ack.ack_nr = 65535;
+        ack.selective_ack = Some(vec![0b101, 0, 0, 0]);
+        connection.on_ack(&ack, now);
+        let in_flight: Vec<u16> = connection
+            .in_flight
+            .iter()
+            .map(|sent| sent.seq_nr)
+            .collect();
+        assert_eq!(in_flight, [0, 2]);
+    }
+
+    #[tokio::test]
+    async fn a_fin_ends_the_stream_once_everything_before_it_arrived() {
+        let mut connection = connection().await;
+        connection.on_data(packet(ST_FIN, 2, &[]));
+        assert!(!connection.eof);
+        connection.on_data(packet(ST_DATA, 1, b"last"));
+        assert!(connection.eof);
+        assert_eq!(Vec::from(connection.received.clone()), b"last");
+    }
+
+    #[tokio::test]
+    async fn streams_carry_data_both_ways_until_eof() {
+        let (mut client, mut server) = pair().await;
+        let sent = data(300_000);
+
+        let writer = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                client.write_all(&sent).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut answer = Vec::new();
+                client.read_to_end(&mut answer).await.unwrap();
+                answer
+            }
+        });
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, sent);
+        server.write_all(b"thanks").await.unwrap();
+        drop(server);
+        assert_eq!(writer.await.unwrap(), b"thanks");
+    }
+
+    /// Relays the datagrams between the client and `server`, dropping and swapping some of them.
+    async fn lossy_relay(server: SocketAddr) -> SocketAddr {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = relay.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 1 << 16];
+            let mut client = None;
+            let mut held: Option<(Vec<u8>, SocketAddr)> = None;
+            let mut count = 0;
+            loop {
+                let received =
+                    tokio::time::timeout(Duration::from_millis(20), relay.recv_from(&mut buffer));
+                let Ok(Ok((length, from))) = received.await else {
+                    // nothing came after the held packet, let it go.
+                    if let Some((packet, to)) = held.take() {
+                        relay.send_to(&packet, to).await.ok();
+                    }
+                    continue;
+                };
+                let to = if from == server {
+                    let Some(client) = client else { continue };
+                    client
+                } else {
+                    client = Some(from);
+                    server
+                };
+                let packet = buffer[..length].to_vec();
+                // only data packets are played with, so the handshake goes through.
+                if packet[0] >> 4 != ST_DATA {
+                    relay.send_to(&packet, to).await.ok();
+                    continue;
+                }
+                count += 1;
+                if count % 9 == 0 {
+                    continue;
+                }
+                if count % 5 == 0 && held.is_none() {
+                    held = Some((packet, to));
+                    continue;
+                }
+                relay.send_to(&packet, to).await.ok();
+                if let Some((packet, to)) = held.take() {
+                    relay.send_to(&packet, to).await.ok();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn lost_and_reordered_packets_are_recovered() {
+        let (client, server) = (
+            UtpSocket::bind(0).await.unwrap(),
+            UtpSocket::bind(0).await.unwrap(),
+        );
+        let server_addr =
+            SocketAddr::from((Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port()));
+        let relay = lossy_relay(server_addr).await;
+        let (connected, accepted) = tokio::join!(client.connect(relay), server.accept());
+        let (mut client, mut server) = (connected.unwrap(), accepted.unwrap());
+
+        let sent = data(100_000);
+        tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                client.write_all(&sent).await.unwrap();
+                client.shutdown().await.unwrap();
+                // the stream has to stay around until the server read everything.
+                let mut rest = Vec::new();
+                client.read_to_end(&mut rest).await.ok();
+            }
+        });
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(60), server.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, sent);
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use reqwest::{header::RANGE, StatusCode, Url};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    time::timeout,
+};
+
+use crate::{
+    proxy::{self, Proxy},
+    torrent::{file_slices, FileEntry, Torrent},
+};
+
+/// How long a single request to a web seed may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A server from the url-list of the torrent (BEP 19), serving its files over HTTP or FTP.
+/// Pieces are fetched with range requests, one for each file the piece spans.
+pub struct WebSeed {
+    url: String,
+    client: reqwest::Client,
+    proxy: Option<Arc<Proxy>>,
+    /// Set once the server answered a range request with the whole file,
+    /// fetching pieces from it would download the whole torrent over and over.
+    range_less: AtomicBool,
+}
+
+impl WebSeed {
+    pub fn new(url: impl Into<String>) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("create http client fail")?;
+        Ok(Self {
+            url: url.into(),
+            client,
+            proxy: None,
+            range_less: AtomicBool::new(false),
+        })
+    }
+
+    /// Fetches through `proxy` instead of connecting to the server.
+    pub fn with_proxy(mut self, proxy: Arc<Proxy>) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Whether the server ignores range requests, such a seed is of no use.
+    pub fn is_range_less(&self) -> bool {
+        self.range_less.load(Ordering::Relaxed)
+    }
+
+    /// Downloads the piece at `index`, it still has to be verified.
+    pub async fn fetch_piece(&self, torrent: &Torrent, index: usize) -> anyhow::Result<Vec<u8>> {
+        let files = torrent.files();
+        let size = torrent.piece_size(index);
+
+        let mut data = Vec::with_capacity(size);
+        for slice in file_slices(files, index * torrent.info.piece_length, size) {
+            let entry = &files[slice.file];
+            if entry.pad {
+                data.resize(data.len() + slice.length, 0);
+                continue;
+            }
+            let url = self.file_url(torrent, entry);
+            let bytes = if url.starts_with("ftp://") {
+                let proxy = self.proxy.as_deref();
+                timeout(
+                    REQUEST_TIMEOUT,
+                    fetch_ftp(&url, slice.offset, slice.length, proxy),
+                )
+                .await
+                .with_context(|| format!("web seed {url} timed out"))??
+            } else {
+                self.fetch_http(&url, slice.offset, slice.length).await?
+            };
+            data.extend(bytes);
+        }
+        Ok(data)
+    }
+
+    /// The url of a file of the torrent. A url ending with a slash is the directory
+    /// the torrent is in, multi file torrents always use the directory form.
+    fn file_url(&self, torrent: &Torrent, entry: &FileEntry) -> String {
+        let mut url = self.url.clone();
+        if torrent.is_multi_file() {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str(&percent_encode(&torrent.info.name));
+            for component in &entry.path {
+                url.push('/');
+                url.push_str(&percent_encode(component));
+            }
+        } else if url.ends_with('/') {
+            url.push_str(&percent_encode(&torrent.info.name));
+        }
+        url
+    }
+
+    async fn fetch_http(&self, url: &str, offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        let (status, body) = match &self.proxy {
+            Some(proxy) => timeout(REQUEST_TIMEOUT, proxy.http_get(url, &[("Range", range)]))
+                .await
+                .with_context(|| format!("web seed {url} timed out"))?
+                .with_context(|| format!("web seed {url} request fail"))?,
+            None => {
+                let response = self
+                    .client
+                    .get(url)
+                    .header(RANGE, range)
+                    .send()
+                    .await
+                    .with_context(|| format!("web seed {url} request fail"))?;
+                let status = response.status().as_u16();
+                (status, response.bytes().await?.to_vec())
+            }
+        };
+        match StatusCode::from_u16(status)? {
+            StatusCode::PARTIAL_CONTENT if body.len() == length => Ok(body),
+            // servers that don't support ranges send the whole file.
+            StatusCode::OK => {
+                self.range_less.store(true, Ordering::Relaxed);
+                bail!("web seed {url} doesn't support range requests")
+            }
+            _ => bail!(
+                "web seed {url} answered {status} with {} bytes instead of {length}",
+                body.len()
+            ),
+        }
+    }
+}
+
+/// Downloads `length` bytes at `offset` of the file at the ftp `url`, in passive mode.
+async fn fetch_ftp(
+    url: &str,
+    offset: usize,
+    length: usize,
+    proxy: Option<&Proxy>,
+) -> anyhow::Result<Vec<u8>> {
+    let url = Url::parse(url).with_context(|| format!("invalid web seed url {url}"))?;
+    let host = url.host_str().context("ftp url without host")?;
+    let port = url.port().unwrap_or(21);
+    let user = match url.username() {
+        "" => "anonymous".to_string(),
+        user => percent_decode(user),
+    };
+    let password = percent_decode(url.password().unwrap_or("anonymous@"));
+
+    let stream = proxy::connect(proxy, host, port)
+        .await
+        .with_context(|| format!("connect to ftp server {host} fail"))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    expect_reply(&mut reader, &[220]).await?;
+
+    writer
+        .write_all(format!("USER {user}\r\n").as_bytes())
+        .await?;
+    if expect_reply(&mut reader, &[230, 331]).await?.0 == 331 {
+        writer
+            .write_all(format!("PASS {password}\r\n").as_bytes())
+            .await?;
+        expect_reply(&mut reader, &[230]).await?;
+    }
+    writer.write_all(b"TYPE I\r\n").await?;
+    expect_reply(&mut reader, &[200]).await?;
+
+    // the reply to PASV is like 227 Entering Passive Mode (h1,h2,h3,h4,p1,p2).
+    writer.write_all(b"PASV\r\n").await?;
+    let (_, text) = expect_reply(&mut reader, &[227]).await?;
+    let numbers: Vec<u16> = text
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|number| !number.is_empty())
+        .filter_map(|number| number.parse().ok())
+        .collect();
+    let [.., h1, h2, h3, h4, p1, p2] = numbers[..] else {
+        bail!("invalid ftp passive mode reply {text}");
+    };
+    let data_host = format!("{h1}.{h2}.{h3}.{h4}");
+    let data_port = ((p1 & 0xff) << 8) | (p2 & 0xff);
+    let mut data = proxy::connect(proxy, &data_host, data_port)
+        .await
+        .context("connect to ftp data port fail")?;
+
+    writer
+        .write_all(format!("REST {offset}\r\n").as_bytes())
+        .await?;
+    expect_reply(&mut reader, &[350]).await?;
+    writer
+        .write_all(format!("RETR {}\r\n", percent_decode(url.path())).as_bytes())
+        .await?;
+    expect_reply(&mut reader, &[125, 150]).await?;
+
+    // the server sends the rest of the file, we only read what we need.
+    let mut bytes = vec![0; length];
+    data.read_exact(&mut bytes)
+        .await
+        .context("ftp transfer ended early")?;
+    drop(data);
+    writer.write_all(b"QUIT\r\n").await.ok();
+    Ok(bytes)
+}
+
+/// Reads a reply of the ftp server, failing unless its code is one of `expected`.
+/// Multi line replies start with the code and a dash, and end with the code and a space.
+async fn expect_reply<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R,
+    expected: &[u16],
+) -> anyhow::Result<(u16, String)> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("ftp server closed the connection");
+        }
+        let bytes = line.as_bytes();
+        if bytes.len() >= 4 && bytes[..3].iter().all(u8::is_ascii_digit) && bytes[3] == b' ' {
+            break;
+        }
+    }
+    let code: u16 = line[..3].parse()?;
+    if !expected.contains(&code) {
+        bail!("unexpected ftp reply {}", line.trim_end());
+    }
+    Ok((code, line[4..].trim_end().to_string()))
+}
+
+/// Escapes everything but the unreserved characters of a url path segment.
+pub(crate) fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::collections::HashMap;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serves `files` by path over HTTP, honoring ranges unless `ranges` is false.
+    pub async fn serve(files: HashMap<&'static str, Vec<u8>>, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    if stream.read(&mut byte).await.unwrap() == 0 {
+                        break;
+                    }
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let path = request.split(' ').nth(1).unwrap();
+                let data = &files[path];
+                let range = request
+                    .lines()
+                    .find_map(|line| {
+                        line.to_ascii_lowercase()
+                            .strip_prefix("range: bytes=")
+                            .map(str::to_string)
+                    })
+                    .filter(|_| ranges);
+                let (status, body) = match range {
+                    Some(range) => {
+                        let (start, end) = range.split_once('-').unwrap();
+                        let (start, end): (usize, usize) =
+                            (start.parse().unwrap(), end.parse().unwrap());
+                        ("206 Partial Content", &data[start..=end])
+                    }
+                    None => ("200 OK", &data[..]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        });
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{testing::serve, *};
+    use crate::torrent::testing;
+
+    fn files() -> HashMap<&'static str, Vec<u8>> {
+        HashMap::from([
+            ("/dir/a", vec![1, 2, 3, 4, 5, 6]),
+            ("/dir/b%20c", vec![7, 8, 9, 10, 11, 12]),
+        ])
+    }
+
+    #[tokio::test]
+    async fn pieces_crossing_files_are_fetched_from_both() {
+        let torrent = testing::torrent(
+            "dir",
+            &[("a", &[1, 2, 3, 4, 5, 6]), ("b c", &[7, 8, 9, 10, 11, 12])],
+            4,
+        );
+        let seed = WebSeed::new(serve(files(), true).await).unwrap();
+        assert_eq!(seed.fetch_piece(&torrent, 0).await.unwrap(), [1, 2, 3, 4]);
+        assert_eq!(seed.fetch_piece(&torrent, 1).await.unwrap(), [5, 6, 7, 8]);
+        assert_eq!(
+            seed.fetch_piece(&torrent, 2).await.unwrap(),
+            [9, 10, 11, 12]
+        );
+        assert!(!seed.is_range_less());
+    }
+
+    #[tokio::test]
+    async fn servers_ignoring_ranges_are_marked() {
+        let torrent = testing::torrent(
+            "dir",
+            &[("a", &[1, 2, 3, 4, 5, 6]), ("b c", &[7, 8, 9, 10, 11, 12])],
+            4,
+        );
+        let seed = WebSeed::new(serve(files(), false).await).unwrap();
+        let err = seed.fetch_piece(&torrent, 1).await.unwrap_err();
+        assert!(err.to_string().contains("range"), "{err:#}");
+        assert!(seed.is_range_less());
+    }
+
+    #[test]
+    fn file_urls_follow_the_torrent_layout() {
+        let single = testing::torrent("a b", &[("a b", &[1; 4])], 4);
+        let multi = testing::torrent("dir", &[("x/y", &[1; 4]), ("z", &[2; 4])], 4);
+        let entry = |torrent: &Torrent, index: usize| torrent.files()[index].clone();
+
+        let file = WebSeed::new("http://host/file.iso").unwrap();
+        assert_eq!(
+            file.file_url(&single, &entry(&single, 0)),
+            "http://host/file.iso"
+        );
+        let directory = WebSeed::new("http://host/pub/").unwrap();
+        assert_eq!(
+            directory.file_url(&single, &entry(&single, 0)),
+            "http://host/pub/a%20b"
+        );
+        let bare = WebSeed::new("http://host/pub").unwrap();
+        assert_eq!(
+            bare.file_url(&multi, &entry(&multi, 0)),
+            "http://host/pub/dir/x/y"
+        );
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let text = "a b/ü%";
+        assert_eq!(percent_encode(text), "a%20b%2F%C3%BC%25");
+        assert_eq!(percent_decode(&percent_encode(text)), text);
+        // invalid escapes are kept as they are.
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}